@@ -90,7 +90,7 @@ fn main() {
 fn build_dht(size: usize) -> Arc<BTreeMap<Id, Node>> {
     let mut dht = BTreeMap::new();
     for i in 0..size {
-        let node = Node::new(Id::random(), SocketAddrV4::new((i as u32).into(), i as u16));
+        let node = Node::new(Id::random(), SocketAddrV4::new((i as u32).into(), i as u16).into());
         dht.insert(*node.id(), node);
     }
 
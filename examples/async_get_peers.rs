@@ -0,0 +1,53 @@
+use std::{str::FromStr, time::Instant};
+
+use futures::StreamExt;
+use mainline::{Dht, Id};
+
+use clap::Parser;
+
+use tracing::Level;
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// info_hash to lookup peers for
+    infohash: String,
+}
+
+fn main() {
+    tracing_subscriber::fmt().with_max_level(Level::INFO).init();
+
+    let cli = Cli::parse();
+
+    let info_hash = Id::from_str(cli.infohash.as_str()).expect("Expected info_hash");
+
+    let dht = Dht::client().unwrap().as_async();
+
+    // Any executor works, for example `smol::block_on` or `async_std::task::block_on`,
+    // since the query is driven by the Dht's own thread, not by the executor.
+    futures::executor::block_on(async {
+        println!("Looking up peers for info_hash: {} ...", info_hash);
+
+        let start = Instant::now();
+        let mut count = 0;
+
+        let mut stream = dht.get_peers(info_hash);
+        while let Some(peers) = stream.next().await {
+            if count == 0 {
+                println!(
+                    "Got first result in {:?} milliseconds:",
+                    start.elapsed().as_millis()
+                );
+                println!("peers {:?}", peers);
+            }
+
+            count += peers.len();
+        }
+
+        println!(
+            "\nQuery exhausted in {:?} milliseconds, got {:?} peers.",
+            start.elapsed().as_millis(),
+            count
+        );
+    });
+}
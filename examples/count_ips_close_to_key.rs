@@ -33,7 +33,7 @@ use histo::Histogram;
 use mainline::{Dht, Id, Node};
 use std::{
     collections::{HashMap, HashSet},
-    net::Ipv4Addr,
+    net::IpAddr,
     sync::mpsc::channel,
 };
 use tracing::Level;
@@ -46,7 +46,7 @@ fn main() {
     tracing_subscriber::fmt().with_max_level(Level::WARN).init();
 
     let target = Id::random();
-    let mut ip_hits: HashMap<Ipv4Addr, u16> = HashMap::new();
+    let mut ip_hits: HashMap<IpAddr, u16> = HashMap::new();
     let (tx_interrupted, rx_interrupted) = channel();
 
     println!("Count all IP addresses around a random target_key={target} k={K} max_distance={MAX_DISTANCE} random_boostrap={USE_RANDOM_BOOTSTRAP_NODES}.");
@@ -60,7 +60,7 @@ fn main() {
     })
     .expect("Error setting Ctrl-C handler");
 
-    let mut last_nodes: HashSet<Ipv4Addr> = HashSet::new();
+    let mut last_nodes: HashSet<IpAddr> = HashSet::new();
     let mut lookup_count = 0;
     while rx_interrupted.try_recv().is_err() {
         lookup_count += 1;
@@ -72,18 +72,18 @@ fn main() {
             .cloned()
             .collect();
         let closest_nodes = nodes.iter().take(K).cloned().collect::<Box<[_]>>();
-        let sockets: HashSet<Ipv4Addr> = closest_nodes
+        let sockets: HashSet<IpAddr> = closest_nodes
             .iter()
-            .map(|node| *node.address().ip())
+            .map(|node| node.address().ip())
             .collect();
         for socket in sockets.iter() {
             let previous = ip_hits.get(socket);
             match previous {
                 Some(val) => {
-                    ip_hits.insert(socket.clone(), val + 1);
+                    ip_hits.insert(*socket, val + 1);
                 }
                 None => {
-                    ip_hits.insert(socket.clone(), 1);
+                    ip_hits.insert(*socket, 1);
                 }
             };
         }
@@ -96,8 +96,8 @@ fn main() {
         let furthest_node = closest_nodes.last().unwrap();
         let furthest_distance = target.distance(furthest_node.id());
 
-        let overlap_with_last_lookup: HashSet<Ipv4Addr> =
-            sockets.intersection(&last_nodes).map(|ip| *ip).collect();
+        let overlap_with_last_lookup: HashSet<IpAddr> =
+            sockets.intersection(&last_nodes).copied().collect();
 
         let overlap = overlap_with_last_lookup.len() as f64 / K as f64;
         last_nodes = sockets;
@@ -107,7 +107,7 @@ fn main() {
             ip_hits.len(),
             closest_distance,
             furthest_distance,
-            (overlap*100 as f64) as usize
+            (overlap*100_f64) as usize
         );
     }
 
@@ -116,21 +116,21 @@ fn main() {
     print_histogram(ip_hits, lookup_count);
 }
 
-fn print_histogram(hits: HashMap<Ipv4Addr, u16>, lookup_count: usize) {
+fn print_histogram(hits: HashMap<IpAddr, u16>, lookup_count: usize) {
     /*
 
     */
     let mut histogram = Histogram::with_buckets(10);
-    let percents: HashMap<Ipv4Addr, u64> = hits
+    let percents: HashMap<IpAddr, u64> = hits
         .into_iter()
         .map(|(ip, hits)| {
-            let percent = (hits as f32 / lookup_count as f32) * 100 as f32;
+            let percent = (hits as f32 / lookup_count as f32) * 100_f32;
             (ip, percent as u64)
         })
         .collect();
 
     for (_, percent) in percents.iter() {
-        histogram.add(percent.clone());
+        histogram.add(*percent);
     }
 
     println!("{}", histogram);
@@ -143,14 +143,14 @@ fn get_random_boostrap_nodes2() -> Vec<String> {
         .iter()
         .map(|node| node.address().to_string())
         .collect::<Box<[_]>>();
-    let slice: Vec<String> = addrs[..8].into_iter().map(|va| va.clone()).collect();
+    let slice: Vec<String> = addrs[..8].to_vec();
     slice
 }
 
 fn init_dht(use_random_boostrap_nodes: bool) -> Dht {
     if use_random_boostrap_nodes {
         let bootstrap = get_random_boostrap_nodes2();
-        return Dht::builder().bootstrap(&bootstrap).build().unwrap();
+        Dht::builder().bootstrap(&bootstrap).build().unwrap()
     } else {
         Dht::client().unwrap()
     }
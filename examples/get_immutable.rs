@@ -5,7 +5,6 @@ use mainline::{Dht, Id};
 use clap::Parser;
 
 use tracing::Level;
-use tracing_subscriber;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -1,7 +1,6 @@
 use ed25519_dalek::VerifyingKey;
 use std::convert::TryFrom;
 use tracing::Level;
-use tracing_subscriber;
 
 use std::time::Instant;
 
@@ -79,7 +78,7 @@ fn print_value(item: &MutableItem) {
 }
 
 fn from_hex(s: String) -> VerifyingKey {
-    if s.len() % 2 != 0 {
+    if !s.len().is_multiple_of(2) {
         panic!("Number of Hex characters should be even");
     }
 
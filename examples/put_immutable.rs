@@ -5,7 +5,6 @@ use mainline::Dht;
 use clap::Parser;
 
 use tracing::Level;
-use tracing_subscriber;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -24,10 +23,10 @@ fn main() {
 
     println!("\nStoring immutable data: {} ...\n", cli.value);
     println!("\n=== COLD QUERY ===");
-    put_immutable(&dht, &value);
+    put_immutable(&dht, value);
 
     println!("\n=== SUBSEQUENT QUERY ===");
-    put_immutable(&dht, &value);
+    put_immutable(&dht, value);
 }
 
 fn put_immutable(dht: &Dht, value: &[u8]) {
@@ -0,0 +1,79 @@
+use std::{str::FromStr, time::Instant};
+
+use mainline::{Dht, Id};
+
+use clap::Parser;
+
+use tracing::Level;
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// info_hash to lookup peers for
+    infohash: String,
+    /// Also announce this port as a peer for the info_hash
+    #[arg(long)]
+    announce: Option<u16>,
+}
+
+fn main() {
+    tracing_subscriber::fmt().with_max_level(Level::INFO).init();
+
+    let cli = Cli::parse();
+
+    let info_hash = Id::from_str(cli.infohash.as_str()).expect("Expected info_hash");
+
+    // The Dht owns the socket and the routing table, and drives every query
+    // from its own thread, so there is nothing to wire or poll.
+    let dht = Dht::client().unwrap();
+
+    let start = Instant::now();
+    dht.bootstrapped();
+    println!(
+        "Bootstrapped in {:?} milliseconds, as {}",
+        start.elapsed().as_millis(),
+        dht.local_id()
+    );
+
+    let start = Instant::now();
+    let closest = dht.find_node(info_hash);
+    println!(
+        "Found {} nodes closest to {info_hash} in {:?} milliseconds",
+        closest.len(),
+        start.elapsed().as_millis()
+    );
+
+    // Peers are streamed as soon as they are found, so connecting to them
+    // doesn't have to wait for the whole lookup.
+    let start = Instant::now();
+    if let Some(peer) = dht.stream_peers(info_hash).next() {
+        println!(
+            "Found a first peer {peer} in {:?} milliseconds",
+            start.elapsed().as_millis()
+        );
+    }
+
+    let start = Instant::now();
+    let result = dht.get_peers_result(info_hash);
+    println!(
+        "Got {} peers from {} responding nodes in {:?} milliseconds",
+        result.peers.len(),
+        result.closest.len(),
+        start.elapsed().as_millis()
+    );
+    for peer in result.peers.iter().take(10) {
+        println!("peer {peer}");
+    }
+
+    if let Some(port) = cli.announce {
+        let start = Instant::now();
+        dht.announce_peer(info_hash, Some(port))
+            .expect("announce_peer failed");
+        println!(
+            "Announced port {port} in {:?} milliseconds",
+            start.elapsed().as_millis()
+        );
+    }
+
+    dht.shutdown();
+}
@@ -32,5 +32,7 @@ fn main() {
 
     println!("{:?}", info);
 
-    loop {}
+    loop {
+        std::thread::park();
+    }
 }
@@ -1,21 +1,30 @@
 //! AsyncDht node.
+//!
+//! Queries are driven by the [Dht]'s own thread, which wakes the returned futures and
+//! streams as responses arrive, so they can be awaited on any executor, without tokio.
 
 use std::{
-    net::SocketAddrV4,
+    collections::BTreeSet,
+    net::{Ipv4Addr, SocketAddrV4},
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
 
 use futures_lite::{Stream, StreamExt};
 
 use crate::{
     common::{
-        hash_immutable, AnnouncePeerRequestArguments, FindNodeRequestArguments,
+        hash_immutable, AnnounceOptions, AnnouncePeerRequestArguments, FindNodeRequestArguments,
         GetPeersRequestArguments, GetValueRequestArguments, Id, MutableItem, Node,
         PutImmutableRequestArguments, PutMutableRequestArguments, PutRequestSpecific,
+        SampleInfohashesRequestArguments,
     },
     dht::{ActorMessage, Dht, PutMutableError, ResponseSender},
-    rpc::{GetRequestSpecific, Info, PutError, PutQueryError},
+    rpc::{
+        GetPeersResult, GetRequestSpecific, Info, PutError, PutQueryError, QueryInfo,
+        ScrapeEstimate,
+    },
 };
 
 impl Dht {
@@ -40,6 +49,22 @@ impl AsyncDht {
             .expect("actor thread unexpectedly shutdown")
     }
 
+    /// The [Id] of this node, see [Dht::local_id].
+    pub async fn local_id(&self) -> Id {
+        *self.info().await.id()
+    }
+
+    /// Returns a snapshot of the inflight query to this target, if any,
+    /// see [Dht::query_info].
+    pub async fn query_info(&self, target: Id) -> Option<QueryInfo> {
+        let (tx, rx) = flume::bounded::<Option<QueryInfo>>(1);
+        self.send(ActorMessage::QueryInfo(target, tx));
+
+        rx.recv_async()
+            .await
+            .expect("actor thread unexpectedly shutdown")
+    }
+
     /// Turn this node's routing table to a list of bootstrapping nodes.   
     pub async fn to_bootstrap(&self) -> Vec<String> {
         let (tx, rx) = flume::bounded::<Vec<String>>(1);
@@ -52,6 +77,54 @@ impl AsyncDht {
 
     // === Public Methods ===
 
+    /// Stop sending requests to, and accepting messages from, this ip,
+    /// see [Dht::blacklist].
+    pub fn blacklist(&self, ip: Ipv4Addr) {
+        self.0.blacklist(ip)
+    }
+
+    /// Ping a node, and return its [Id] and the round trip time,
+    /// or None if it didn't respond within the request timeout.
+    pub async fn ping(&self, address: SocketAddrV4) -> Option<(Id, Duration)> {
+        let (tx, rx) = flume::bounded::<Option<(Id, Duration)>>(1);
+        self.send(ActorMessage::Ping(address, tx));
+
+        rx.recv_async()
+            .await
+            .expect("actor thread unexpectedly shutdown")
+    }
+
+    /// Cancel the inflight query to this target, if any, see [Dht::cancel].
+    pub async fn cancel(&self, target: Id) -> bool {
+        let (tx, rx) = flume::bounded::<bool>(1);
+        self.send(ActorMessage::Cancel(target, tx));
+
+        rx.recv_async()
+            .await
+            .expect("actor thread unexpectedly shutdown")
+    }
+
+    /// Bind this node's Ipv4 socket to a new address, see [Dht::rebind].
+    pub async fn rebind(&self, address: SocketAddrV4) -> Result<(), std::io::Error> {
+        let (tx, rx) = flume::bounded::<Result<(), std::io::Error>>(1);
+        self.send(ActorMessage::Rebind(address, tx));
+
+        rx.recv_async()
+            .await
+            .expect("actor thread unexpectedly shutdown")
+    }
+
+    /// Stop sending requests, wait for responses to the inflight requests,
+    /// then stop this node's thread, see [Dht::shutdown].
+    pub async fn shutdown(&self) {
+        let (tx, rx) = flume::bounded::<()>(1);
+        self.send(ActorMessage::Shutdown(tx));
+
+        rx.recv_async()
+            .await
+            .expect("actor thread unexpectedly shutdown")
+    }
+
     /// Await until the bootstrapping query is done.
     ///
     /// Returns true if the bootstrapping was successful.
@@ -77,6 +150,9 @@ impl AsyncDht {
     /// If you are trying to find the closest nodes to a target with intent to [Self::put],
     /// a request directly to these nodes (using `extra_nodes` parameter), then you should
     /// use [Self::get_closest_nodes] instead.
+    ///
+    /// Resolves once the query is done, or its [crate::DhtBuilder::query_deadline] passed,
+    /// in which case the closest nodes found so far are returned.
     pub async fn find_node(&self, target: Id) -> Box<[Node]> {
         let (tx, rx) = flume::bounded::<Box<[Node]>>(1);
         self.send(ActorMessage::Get(
@@ -103,34 +179,128 @@ impl AsyncDht {
     pub fn get_peers(&self, info_hash: Id) -> GetStream<Vec<SocketAddrV4>> {
         let (tx, rx) = flume::unbounded::<Vec<SocketAddrV4>>();
         self.send(ActorMessage::Get(
-            GetRequestSpecific::GetPeers(GetPeersRequestArguments { info_hash }),
+            GetRequestSpecific::GetPeers(GetPeersRequestArguments {
+                info_hash,
+                scrape: false,
+            }),
             ResponseSender::Peers(tx),
         ));
 
         GetStream(rx.into_stream())
     }
 
+    /// Get all unique peers for a given infohash, sorted, once the query is done,
+    /// see [Dht::get_all_peers].
+    ///
+    /// Use [Self::get_peers] instead to start connecting to peers
+    /// as soon as they are found.
+    pub async fn get_all_peers(&self, info_hash: Id) -> Vec<SocketAddrV4> {
+        let mut stream = self.get_peers(info_hash);
+        let mut peers = BTreeSet::new();
+
+        while let Some(batch) = stream.next().await {
+            peers.extend(batch);
+        }
+
+        peers.into_iter().collect()
+    }
+
+    /// Get all unique peers for a given infohash, and the closest nodes that responded with
+    /// their tokens, see [Dht::get_peers_result].
+    pub async fn get_peers_result(&self, info_hash: Id) -> GetPeersResult {
+        let (peers_tx, peers_rx) = flume::unbounded::<Vec<SocketAddrV4>>();
+        let (closest_tx, closest_rx) = flume::bounded::<Box<[Node]>>(1);
+        self.send(ActorMessage::Get(
+            GetRequestSpecific::GetPeers(GetPeersRequestArguments {
+                info_hash,
+                scrape: false,
+            }),
+            ResponseSender::PeersResult(peers_tx, closest_tx),
+        ));
+
+        let closest = closest_rx
+            .recv_async()
+            .await
+            .expect("Query was dropped before sending a response, please open an issue.");
+
+        let mut stream = peers_rx.into_stream();
+        let mut peers = BTreeSet::new();
+
+        while let Some(batch) = stream.next().await {
+            peers.extend(batch);
+        }
+
+        GetPeersResult::new(peers.into_iter().collect(), closest)
+    }
+
+    /// Sample info hashes stored by nodes close to `target`,
+    /// see [Dht::sample_infohashes].
+    pub fn sample_infohashes(&self, target: Id) -> GetStream<Vec<Id>> {
+        let (tx, rx) = flume::unbounded::<Vec<Id>>();
+        self.send(ActorMessage::Get(
+            GetRequestSpecific::SampleInfohashes(SampleInfohashesRequestArguments { target }),
+            ResponseSender::Samples(tx),
+        ));
+
+        GetStream(rx.into_stream())
+    }
+
+    /// Estimate the number of seeders and peers of an infohash, without
+    /// enumerating them, from the bloom filters of nodes supporting
+    /// [BEP_0033](https://www.bittorrent.org/beps/bep_0033.html) scrapes.
+    ///
+    /// Nodes that don't support scrapes don't contribute to the estimate,
+    /// so it is zero if none of them did.
+    pub async fn scrape(&self, info_hash: Id) -> ScrapeEstimate {
+        let (tx, rx) = flume::unbounded::<ScrapeEstimate>();
+        self.send(ActorMessage::Get(
+            GetRequestSpecific::GetPeers(GetPeersRequestArguments {
+                info_hash,
+                scrape: true,
+            }),
+            ResponseSender::Scrape(tx),
+        ));
+
+        // Each estimate accounts for all the filters merged before it.
+        rx.into_stream().last().await.unwrap_or_default()
+    }
+
     /// Announce a peer for a given infohash.
     ///
     /// The peer will be announced on this process IP.
     /// If explicit port is passed, it will be used, otherwise the port will be implicitly
     /// assumed by remote nodes to be the same ase port they received the request from.
+    ///
+    /// Unless a recent lookup of `info_hash` is cached, this first looks up its closest
+    /// nodes, keeping the token each of them returned, then sends `announce_peer` to
+    /// the closest responders with their own token. Responders that didn't return a
+    /// token are skipped.
     pub async fn announce_peer(
         &self,
         info_hash: Id,
         port: Option<u16>,
     ) -> Result<Id, PutQueryError> {
-        let (port, implied_port) = match port {
-            Some(port) => (port, None),
-            None => (0, Some(true)),
-        };
+        self.announce_peer_with(
+            info_hash,
+            AnnounceOptions {
+                port,
+                implied_port: port.is_none(),
+            },
+        )
+        .await
+    }
 
+    /// Announce a peer for a given infohash with [AnnounceOptions].
+    ///
+    /// The peer will be announced on this process IP, and the port is ignored
+    /// if [AnnounceOptions::implied_port] is set.
+    pub async fn announce_peer_with(
+        &self,
+        info_hash: Id,
+        options: AnnounceOptions,
+    ) -> Result<Id, PutQueryError> {
         self.put(
-            PutRequestSpecific::AnnouncePeer(AnnouncePeerRequestArguments {
-                info_hash,
-                port,
-                implied_port,
-            }),
+            PutRequestSpecific::AnnouncePeer(AnnouncePeerRequestArguments::new(info_hash, options)),
             None,
         )
         .await
@@ -414,6 +584,37 @@ mod test {
         futures::executor::block_on(test());
     }
 
+    #[test]
+    fn announce_get_all_peers() {
+        async fn test() {
+            let testnet = Testnet::new(10).unwrap();
+
+            let a = Dht::builder()
+                .bootstrap(&testnet.bootstrap)
+                .build()
+                .unwrap()
+                .as_async();
+            let b = Dht::builder()
+                .bootstrap(&testnet.bootstrap)
+                .build()
+                .unwrap()
+                .as_async();
+
+            let info_hash = Id::random();
+
+            a.announce_peer(info_hash, Some(45555))
+                .await
+                .expect("failed to announce");
+
+            let peers = b.get_all_peers(info_hash).await;
+
+            assert_eq!(peers.len(), 1);
+            assert_eq!(peers[0].port(), 45555);
+        }
+
+        futures::executor::block_on(test());
+    }
+
     #[test]
     fn put_get_immutable() {
         async fn test() {
@@ -657,7 +858,7 @@ mod test {
                 futures::executor::block_on(async {
                     let result = dht.put_mutable(item, None).await;
                     if i == 0 {
-                        assert!(matches!(result, Ok(_)))
+                        assert!(result.is_ok())
                     } else {
                         assert!(matches!(
                             result,
@@ -1,15 +1,33 @@
 //! Miscellaneous common structs used throughout the library.
 
+mod bloom_filter;
+mod bogon;
+mod bootstrap_sources;
+mod clock;
+mod dual_table;
+mod eviction;
 mod id;
 mod immutable;
 pub mod messages;
 mod mutable;
 mod node;
+mod rng;
 mod routing_table;
 
+pub use bloom_filter::*;
+pub(crate) use bogon::is_bogon;
+pub use bootstrap_sources::*;
+#[cfg(test)]
+pub(crate) use clock::MockClock;
+pub(crate) use clock::{Clock, SystemClock};
+pub use dual_table::*;
+pub use eviction::*;
 pub use id::*;
 pub use immutable::*;
 pub use messages::*;
 pub use mutable::*;
 pub use node::*;
+#[cfg(test)]
+pub(crate) use rng::MockRng;
+pub(crate) use rng::{Rng, SystemRng};
 pub use routing_table::*;
@@ -0,0 +1,130 @@
+//! Bloom filters of IP addresses used in `get_peers` scrape responses.
+//!
+//! Read [BEP_0033](https://www.bittorrent.org/beps/bep_0033.html) for more information.
+
+use std::fmt::{self, Debug, Formatter};
+use std::net::Ipv4Addr;
+
+use sha1_smol::Sha1;
+
+/// Size of a scrape bloom filter in bytes.
+pub const BLOOM_FILTER_SIZE: usize = 256;
+
+/// Number of bits (m) in a scrape bloom filter.
+const BLOOM_FILTER_BITS: usize = BLOOM_FILTER_SIZE * 8;
+
+#[derive(Clone, PartialEq, Eq)]
+/// A 2048 bits bloom filter of peers' IP addresses, as carried in the `BFsd` (seeders)
+/// and `BFpe` (peers) fields of a `get_peers` scrape response.
+// Boxed to keep messages carrying filters small.
+pub struct BloomFilter(Box<[u8; BLOOM_FILTER_SIZE]>);
+
+impl BloomFilter {
+    /// Create an empty bloom filter.
+    pub fn new() -> Self {
+        Self(Box::new([0; BLOOM_FILTER_SIZE]))
+    }
+
+    /// Create a bloom filter from its wire format.
+    ///
+    /// Returns `None` if the bytes are not exactly [BLOOM_FILTER_SIZE] long.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        bytes.try_into().ok().map(|bytes| Self(Box::new(bytes)))
+    }
+
+    /// Returns the wire format of this bloom filter.
+    pub fn as_bytes(&self) -> &[u8; BLOOM_FILTER_SIZE] {
+        &self.0
+    }
+
+    /// Add an IP address to this filter.
+    pub fn insert(&mut self, ip: Ipv4Addr) {
+        let hash = Sha1::from(ip.octets()).digest().bytes();
+
+        let index1 = (hash[0] as usize | (hash[1] as usize) << 8) % BLOOM_FILTER_BITS;
+        let index2 = (hash[2] as usize | (hash[3] as usize) << 8) % BLOOM_FILTER_BITS;
+
+        self.0[index1 / 8] |= 1 << (index1 % 8);
+        self.0[index2 / 8] |= 1 << (index2 % 8);
+    }
+
+    /// Add all the addresses of another filter to this one.
+    pub fn merge(&mut self, other: &BloomFilter) {
+        for (byte, other) in self.0.iter_mut().zip(other.0.iter()) {
+            *byte |= other;
+        }
+    }
+
+    /// Estimate the number of unique addresses inserted in this filter
+    /// from its population count.
+    pub fn estimate(&self) -> usize {
+        let m = BLOOM_FILTER_BITS as f64;
+        let zeros = self
+            .0
+            .iter()
+            .map(|byte| byte.count_zeros() as usize)
+            .sum::<usize>()
+            // A saturated filter would otherwise estimate to infinity.
+            .max(1) as f64;
+
+        ((zeros / m).ln() / (2.0 * (1.0 - 1.0 / m).ln())).round() as usize
+    }
+}
+
+impl Default for BloomFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debug for BloomFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "BloomFilter (~{})", self.estimate())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn estimate() {
+        let mut filter = BloomFilter::new();
+        assert_eq!(filter.estimate(), 0);
+
+        for i in 0..100 {
+            filter.insert(Ipv4Addr::new(10, 0, 0, i));
+        }
+
+        let estimate = filter.estimate();
+        assert!((90..=110).contains(&estimate), "estimate: {estimate}");
+    }
+
+    #[test]
+    fn merge() {
+        let mut a = BloomFilter::new();
+        let mut b = BloomFilter::new();
+
+        for i in 0..50 {
+            a.insert(Ipv4Addr::new(10, 0, 0, i));
+            b.insert(Ipv4Addr::new(10, 0, 1, i));
+        }
+        // Overlapping addresses don't count twice.
+        b.insert(Ipv4Addr::new(10, 0, 0, 0));
+
+        a.merge(&b);
+
+        let estimate = a.estimate();
+        assert!((90..=110).contains(&estimate), "estimate: {estimate}");
+    }
+
+    #[test]
+    fn from_bytes() {
+        assert!(BloomFilter::from_bytes(&[0; 255]).is_none());
+
+        let mut filter = BloomFilter::new();
+        filter.insert(Ipv4Addr::new(192, 168, 1, 1));
+
+        assert_eq!(BloomFilter::from_bytes(filter.as_bytes()), Some(filter));
+    }
+}
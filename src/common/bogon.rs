@@ -0,0 +1,123 @@
+//! Reserved (bogon) addresses that no node on the public internet can be reached at.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Returns `true` if this address is private, loopback, link-local, multicast,
+/// reserved for documentation or future use, or has port 0.
+///
+/// Such addresses are either unreachable, or point at the local network of
+/// whoever contacts them, so they should never be queried nor stored
+/// unless the Dht is deliberately run on a local network.
+pub(crate) fn is_bogon(address: &SocketAddr) -> bool {
+    address.port() == 0 || is_bogon_ip(address.ip())
+}
+
+fn is_bogon_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_bogon_ipv4(ip),
+        IpAddr::V6(ip) => is_bogon_ipv6(ip),
+    }
+}
+
+fn is_bogon_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+
+    // 0.0.0.0/8 "this network"
+    a == 0
+        // 10.0.0.0/8, 172.16.0.0/12, 192.168.0.0/16
+        || ip.is_private()
+        // 100.64.0.0/10 carrier-grade NAT
+        || (a == 100 && (b & 0b1100_0000) == 64)
+        // 127.0.0.0/8
+        || ip.is_loopback()
+        // 169.254.0.0/16
+        || ip.is_link_local()
+        // 192.0.0.0/24 IETF protocol assignments
+        || (a == 192 && b == 0 && c == 0)
+        // 192.0.2.0/24, 198.51.100.0/24, 203.0.113.0/24
+        || ip.is_documentation()
+        // 198.18.0.0/15 benchmarking
+        || (a == 198 && (b & 0b1111_1110) == 18)
+        // 224.0.0.0/4
+        || ip.is_multicast()
+        // 240.0.0.0/4 reserved, including the broadcast address
+        || a >= 240
+}
+
+fn is_bogon_ipv6(ip: Ipv6Addr) -> bool {
+    if let Some(ipv4) = ip.to_ipv4_mapped() {
+        return is_bogon_ipv4(ipv4);
+    }
+
+    let first = ip.segments()[0];
+
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // fc00::/7 unique local
+        || (first & 0xfe00) == 0xfc00
+        // fe80::/10 link-local
+        || (first & 0xffc0) == 0xfe80
+        // 2001:db8::/32 documentation
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bogons() {
+        for address in [
+            "0.0.0.0:6881",
+            "0.1.2.3:6881",
+            "10.0.0.1:6881",
+            "100.64.0.1:6881",
+            "100.127.255.255:6881",
+            "127.0.0.1:6881",
+            "169.254.1.1:6881",
+            "172.16.0.1:6881",
+            "172.31.255.255:6881",
+            "192.0.0.1:6881",
+            "192.0.2.1:6881",
+            "192.168.1.1:6881",
+            "198.18.0.1:6881",
+            "198.19.255.255:6881",
+            "198.51.100.1:6881",
+            "203.0.113.1:6881",
+            "224.0.0.1:6881",
+            "239.255.255.250:6881",
+            "240.0.0.1:6881",
+            "255.255.255.255:6881",
+            "[::]:6881",
+            "[::1]:6881",
+            "[fc00::1]:6881",
+            "[fd12:3456::1]:6881",
+            "[fe80::1]:6881",
+            "[ff02::1]:6881",
+            "[2001:db8::1]:6881",
+            "[::ffff:192.168.1.1]:6881",
+            // Port 0 can't be sent to.
+            "1.1.1.1:0",
+        ] {
+            assert!(is_bogon(&address.parse().unwrap()), "{address}");
+        }
+    }
+
+    #[test]
+    fn public() {
+        for address in [
+            "1.1.1.1:6881",
+            "8.8.8.8:53",
+            "100.128.0.1:6881",
+            "172.32.0.1:6881",
+            "192.169.0.1:6881",
+            "198.20.0.1:6881",
+            "223.255.255.255:6881",
+            "[2001:4860:4860::8888]:6881",
+            "[::ffff:8.8.8.8]:6881",
+        ] {
+            assert!(!is_bogon(&address.parse().unwrap()), "{address}");
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Bootstrapping sources ranked by how often they yielded live nodes.
+
+use std::collections::{BTreeMap, HashSet};
+use std::net::{SocketAddrV4, ToSocketAddrs};
+use std::path::Path;
+
+use crate::rpc::to_socket_address;
+
+/// Highest (and lowest, negated) score of a source, so a source that failed
+/// for a long time can recover after a few successes, and vice versa.
+const MAX_SCORE: i32 = 8;
+
+/// Named sources of bootstrapping nodes, like DNS routers, a saved routing table,
+/// or hardcoded fallback addresses, each with a score of how often they
+/// yielded live nodes, see [RoutingTable::bootstrap_weighted](crate::RoutingTable::bootstrap_weighted).
+///
+/// Scores can be persisted with [Self::save_scores] and [Self::load_scores],
+/// so the next cold start tries the most reliable sources first.
+#[derive(Debug, Clone, Default)]
+pub struct BootstrapSources {
+    sources: Vec<(String, Vec<SocketAddrV4>)>,
+    scores: BTreeMap<String, i32>,
+}
+
+impl BootstrapSources {
+    /// Create an empty set of sources.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a source, resolving its nodes to Ipv4 addresses and skipping the ones
+    /// that fail to resolve, or replace the nodes of a source with the same name.
+    ///
+    /// A source with no resolved nodes, like a failed DNS lookup, is kept,
+    /// and scored as a failure.
+    pub fn add<T: ToSocketAddrs>(&mut self, name: &str, nodes: &[T]) -> &mut Self {
+        let nodes = to_socket_address(nodes);
+
+        match self
+            .sources
+            .iter_mut()
+            .find(|(existing, _)| existing == name)
+        {
+            Some((_, existing)) => *existing = nodes,
+            None => self.sources.push((name.to_string(), nodes)),
+        }
+
+        self
+    }
+
+    // === Getters ===
+
+    /// Returns the score of a source, if it was scored before.
+    pub fn score(&self, name: &str) -> Option<i32> {
+        self.scores.get(name).copied()
+    }
+
+    /// Returns the addresses of all sources, from the highest scored source to the lowest,
+    /// in the order they were added for equal scores, without duplicates.
+    pub fn addresses(&self) -> Vec<SocketAddrV4> {
+        let mut sources = self.sources.iter().collect::<Vec<_>>();
+        // Stable, so ties keep the order they were added in.
+        sources.sort_by_key(|(name, _)| -self.score(name).unwrap_or_default());
+
+        let mut seen = HashSet::new();
+
+        sources
+            .into_iter()
+            .flat_map(|(_, nodes)| nodes)
+            .filter(|address| seen.insert(**address))
+            .copied()
+            .collect()
+    }
+
+    // === Public Methods ===
+
+    /// Score every source by whether any of its nodes is among the `live` addresses.
+    pub fn record(&mut self, live: &[SocketAddrV4]) {
+        for (name, nodes) in &self.sources {
+            let delta = if nodes.iter().any(|node| live.contains(node)) {
+                1
+            } else {
+                -1
+            };
+
+            let score = self.scores.entry(name.clone()).or_default();
+            *score = (*score + delta).clamp(-MAX_SCORE, MAX_SCORE);
+        }
+    }
+
+    /// Save the scores of all sources to a file, one `<score> <name>` per line.
+    pub fn save_scores<P: AsRef<Path>>(&self, path: P) -> Result<(), std::io::Error> {
+        let lines = self
+            .scores
+            .iter()
+            .map(|(name, score)| format!("{score} {name}\n"))
+            .collect::<String>();
+
+        std::fs::write(path, lines)
+    }
+
+    /// Load scores from a file written by [Self::save_scores], including scores of
+    /// sources that are not added yet, skipping malformed lines.
+    pub fn load_scores<P: AsRef<Path>>(&mut self, path: P) -> Result<(), std::io::Error> {
+        let lines = std::fs::read_to_string(path)?;
+
+        for line in lines.lines() {
+            let Some((score, name)) = line.split_once(' ') else {
+                continue;
+            };
+            let Ok(score) = score.parse::<i32>() else {
+                continue;
+            };
+
+            self.scores
+                .insert(name.to_string(), score.clamp(-MAX_SCORE, MAX_SCORE));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,52 @@
+//! Source of the current time, for time-dependent logic to be testable without sleeping.
+
+use std::fmt::Debug;
+use std::time::Instant;
+
+#[cfg(test)]
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Source of the current time.
+pub(crate) trait Clock: Debug + Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+}
+
+/// [Clock] returning [Instant::now].
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// [Clock] that only moves forward when [MockClock::advance] is called.
+///
+/// Clones share the same time.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub(crate) struct MockClock(Arc<Mutex<Instant>>);
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    /// Move the time of this clock and all its clones forward.
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().expect("poisoned lock") += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().expect("poisoned lock")
+    }
+}
@@ -0,0 +1,156 @@
+//! Ipv4 and Ipv6 routing tables of a dual-stack node.
+
+use std::cmp::Ordering;
+use std::net::SocketAddr;
+
+use crate::common::{Id, Node, RoutingTable};
+
+/// A pair of [RoutingTable]s sharing the same [Id], one for Ipv4 nodes and one for Ipv6 nodes,
+/// as kept by a dual-stack node following [BEP_0032](https://www.bittorrent.org/beps/bep_0032.html).
+#[derive(Debug, Clone)]
+pub struct DualTable {
+    v4: RoutingTable,
+    v6: RoutingTable,
+}
+
+impl DualTable {
+    /// Create a new [DualTable] with a given id.
+    pub fn new(id: Id) -> Self {
+        Self {
+            v4: RoutingTable::new(id),
+            v6: RoutingTable::new(id),
+        }
+    }
+
+    /// Returns the [Id] of this node, where the distance is measured from.
+    pub fn id(&self) -> &Id {
+        self.v4.id()
+    }
+
+    /// Returns the table of Ipv4 nodes.
+    pub fn v4(&self) -> &RoutingTable {
+        &self.v4
+    }
+
+    /// Returns the table of Ipv6 nodes.
+    pub fn v6(&self) -> &RoutingTable {
+        &self.v6
+    }
+
+    /// Add a node to the table of its address family, see [RoutingTable::add].
+    pub fn add(&mut self, node: Node) -> bool {
+        match node.address() {
+            SocketAddr::V4(_) => self.v4.add(node),
+            SocketAddr::V6(_) => self.v6.add(node),
+        }
+    }
+
+    /// Return the closest [k](RoutingTable::k) nodes to the target from both tables,
+    /// ranked by XOR distance regardless of their address family.
+    pub fn closest(&self, target: Id) -> Box<[Node]> {
+        self.closest_n(target, self.v4.k())
+    }
+
+    /// Same as [Self::closest] but returns up to `n` nodes instead of `k`.
+    ///
+    /// Unlike [RoutingTable::closest], secure nodes are not ranked first. The same Id
+    /// can be in both tables, at the same distance, in which case the Ipv4 node comes first.
+    pub fn closest_n(&self, target: Id, n: usize) -> Box<[Node]> {
+        let v4 = sorted_by_distance(&self.v4, target);
+        let v6 = sorted_by_distance(&self.v6, target);
+
+        let mut closest = Vec::with_capacity(n.min(v4.len() + v6.len()));
+        let (mut v4, mut v6) = (v4.into_iter().peekable(), v6.into_iter().peekable());
+
+        while closest.len() < n {
+            let next = match (v4.peek(), v6.peek()) {
+                (Some(a), Some(b)) => {
+                    if target.cmp_distance(a.id(), b.id()) == Ordering::Greater {
+                        v6.next()
+                    } else {
+                        v4.next()
+                    }
+                }
+                (Some(_), None) => v4.next(),
+                (None, _) => v6.next(),
+            };
+
+            match next {
+                Some(node) => closest.push(node),
+                None => break,
+            }
+        }
+
+        closest.into()
+    }
+}
+
+fn sorted_by_distance(table: &RoutingTable, target: Id) -> Vec<Node> {
+    let mut nodes = table.nodes().collect::<Vec<_>>();
+    nodes.sort_by(|a, b| target.cmp_distance(a.id(), b.id()));
+
+    nodes
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{SocketAddrV4, SocketAddrV6};
+
+    use super::*;
+
+    #[test]
+    fn closest_by_xor_distance() {
+        let id = Id::random();
+        let mut table = DualTable::new(id);
+        let target = Id::random();
+
+        let v4 = |i: u16| {
+            Node::new(
+                Id::random(),
+                SocketAddrV4::new([1, 1, 1, i as u8].into(), 6881).into(),
+            )
+        };
+        let v6 = |i: u16| {
+            Node::new(
+                Id::random(),
+                SocketAddrV6::new([0x2001, 0x4860, 0, 0, 0, 0, 1, i].into(), 6881, 0, 0).into(),
+            )
+        };
+
+        let mut nodes = Vec::new();
+        for i in 1..=10 {
+            for node in [v4(i), v6(i)] {
+                if table.add(node.clone()) {
+                    nodes.push(node);
+                }
+            }
+        }
+
+        // The same Id in both tables.
+        let dual = Id::random();
+        let dual_v4 = Node::new(dual, SocketAddrV4::new([8, 8, 8, 8].into(), 1).into());
+        let dual_v6 = Node::new(
+            dual,
+            SocketAddrV6::new([0x2001, 0x4860, 0, 0, 0, 0, 2, 1].into(), 1, 0, 0).into(),
+        );
+        assert!(table.add(dual_v6.clone()));
+        assert!(table.add(dual_v4.clone()));
+        nodes.extend([dual_v4, dual_v6]);
+        assert_eq!(nodes.len(), 22);
+
+        // Distances only tie for the same Id, where the Ipv4 node comes first.
+        nodes.sort_by(|a, b| {
+            target
+                .cmp_distance(a.id(), b.id())
+                .then_with(|| a.address().is_ipv6().cmp(&b.address().is_ipv6()))
+        });
+
+        let closest = table.closest_n(target, 8);
+        assert_eq!(closest.as_ref(), &nodes[..8]);
+
+        let all = table.closest_n(target, 100);
+        assert_eq!(all.as_ref(), nodes.as_slice());
+
+        assert_eq!(table.closest(target).len(), nodes.len().min(table.v4().k()));
+    }
+}
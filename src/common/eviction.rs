@@ -0,0 +1,50 @@
+//! Policies choosing which node a full [KBucket](crate::common::KBucket) evicts for a new one.
+
+use std::fmt::Debug;
+
+use crate::common::{Node, NodeStatus};
+
+/// Chooses which node of a full bucket, if any, is replaced by a new node,
+/// see [RoutingTable::with_eviction](crate::RoutingTable::with_eviction).
+pub trait EvictionPolicy: Debug + Send + Sync {
+    /// Returns the index in `bucket` of the node to replace with `candidate`,
+    /// or `None` to drop the candidate instead.
+    ///
+    /// `bucket` is ordered from the least to the most recently added or seen again,
+    /// and an out of bounds index is treated as `None`.
+    fn choose_victim(&self, bucket: &[Node], candidate: &Node) -> Option<usize>;
+}
+
+/// Never evicts a live node, replacing the least recently seen
+/// [questionable](NodeStatus::Questionable) node instead, preferring to keep
+/// verified nodes (that responded to us, with a known [Node::rtt]) over the rest.
+///
+/// This is the default policy of a [RoutingTable](crate::RoutingTable).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KeepVerifiedLive;
+
+impl EvictionPolicy for KeepVerifiedLive {
+    fn choose_victim(&self, bucket: &[Node], _candidate: &Node) -> Option<usize> {
+        let questionable = |node: &Node| node.status() == NodeStatus::Questionable;
+
+        bucket
+            .iter()
+            .position(|node| questionable(node) && node.rtt().is_none())
+            .or_else(|| bucket.iter().position(questionable))
+    }
+}
+
+/// Always replaces the node with the oldest [Node::last_seen], even if it is still live,
+/// favoring fresh nodes over long lived ones.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LeastRecentlySeen;
+
+impl EvictionPolicy for LeastRecentlySeen {
+    fn choose_victim(&self, bucket: &[Node], _candidate: &Node) -> Option<usize> {
+        bucket
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, node)| node.last_seen())
+            .map(|(index, _)| index)
+    }
+}
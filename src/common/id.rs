@@ -1,11 +1,13 @@
 //! Kademlia node Id or a lookup target
 use crc::{Crc, CRC_32_ISCSI};
 use getrandom::getrandom;
-use serde::{Deserialize, Serialize};
+#[cfg(feature = "serde")]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::convert::TryInto;
 use std::{
+    cmp::Ordering,
     fmt::{self, Debug, Display, Formatter},
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     str::FromStr,
 };
 
@@ -14,10 +16,14 @@ pub const ID_SIZE: usize = 20;
 pub const MAX_DISTANCE: u8 = ID_SIZE as u8 * 8;
 
 const IPV4_MASK: u32 = 0x030f3fff;
+const IPV6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
 const CASTAGNOLI: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
 
-#[derive(Clone, Copy, PartialEq, Ord, PartialOrd, Eq, Hash, Serialize, Deserialize)]
+#[derive(Clone, Copy, PartialEq, Ord, PartialOrd, Eq, Hash)]
 /// Kademlia node Id or a lookup target
+///
+/// With the `serde` feature, serialized as a 40 characters hex string in human
+/// readable formats like JSON, and as 20 bytes otherwise.
 pub struct Id([u8; ID_SIZE]);
 
 impl Id {
@@ -53,6 +59,38 @@ impl Id {
         MAX_DISTANCE - self.xor(other).leading_zeros()
     }
 
+    /// Full XOR distance between this Id and a target Id, see [Distance].
+    ///
+    /// Unlike [Self::distance], which only tells which bucket `other` belongs to,
+    /// this can be used to sort Ids by closeness to `self`.
+    pub fn xor_distance(&self, other: &Id) -> Distance {
+        Distance(self.xor(other).0)
+    }
+
+    /// Compare the XOR distances of `a` and `b` to `self`,
+    /// returning [Ordering::Less] if `a` is closer.
+    pub fn cmp_distance(&self, a: &Id, b: &Id) -> Ordering {
+        self.xor_distance(a).cmp(&self.xor_distance(b))
+    }
+
+    /// Returns a random Id at a given [Self::distance] from `self`,
+    /// for example to refresh the corresponding bucket in the routing table.
+    pub fn random_at_distance(&self, distance: u8) -> Id {
+        if distance == 0 {
+            return *self;
+        }
+
+        let mut xor = *Id::random().as_bytes();
+
+        let leading_zeros = (MAX_DISTANCE - distance.min(MAX_DISTANCE)) as usize;
+        for i in 0..leading_zeros {
+            xor[i / 8] &= !(0x80 >> (i % 8));
+        }
+        xor[leading_zeros / 8] |= 0x80 >> (leading_zeros % 8);
+
+        self.xor(&Id(xor))
+    }
+
     /// Returns the number of leading zeros in the binary representation of `self`.
     pub fn leading_zeros(&self) -> u8 {
         for (i, byte) in self.0.iter().enumerate() {
@@ -88,29 +126,33 @@ impl Id {
         Id::from_ip(ip)
     }
 
-    /// Create a new Id from an Ipv4 address according to [BEP_0042](http://bittorrent.org/beps/bep_0042.html).
+    /// Create a new Id from an Ipv4 or Ipv6 address according to [BEP_0042](http://bittorrent.org/beps/bep_0042.html).
     pub fn from_ip(ip: IpAddr) -> Id {
-        match ip {
-            IpAddr::V4(addr) => Id::from_ipv4(addr),
-            IpAddr::V6(_addr) => unimplemented!("Ipv6 is not supported"),
-        }
+        let mut bytes = [0_u8; 21];
+        getrandom(&mut bytes).expect("getrandom");
+
+        from_ip_and_r(bytes[1..].try_into().expect("infallible"), ip, bytes[0])
     }
 
     /// Create a new Id from an Ipv4 address according to [BEP_0042](http://bittorrent.org/beps/bep_0042.html).
     pub fn from_ipv4(ipv4: Ipv4Addr) -> Id {
-        let mut bytes = [0_u8; 21];
-        getrandom(&mut bytes).expect("getrandom");
+        Id::from_ip(ipv4.into())
+    }
 
-        from_ipv4_and_r(bytes[1..].try_into().expect("infallible"), ipv4, bytes[0])
+    /// Create a new Id from an Ipv6 address according to [BEP_0042](http://bittorrent.org/beps/bep_0042.html).
+    pub fn from_ipv6(ipv6: Ipv6Addr) -> Id {
+        Id::from_ip(ipv6.into())
     }
 
     /// Validate that this Id is valid with respect to [BEP_0042](http://bittorrent.org/beps/bep_0042.html).
-    pub fn is_valid_for_ip(&self, ipv4: Ipv4Addr) -> bool {
-        if ipv4.is_private() || ipv4.is_link_local() || ipv4.is_loopback() {
+    ///
+    /// Local addresses (private, link-local and loopback) are always valid.
+    pub fn is_valid_for_ip(&self, ip: IpAddr) -> bool {
+        if is_local(ip) {
             return true;
         }
 
-        let expected = first_21_bits(&id_prefix_ipv4(ipv4, self.0[ID_SIZE - 1]));
+        let expected = first_21_bits(&id_prefix(ip, self.0[ID_SIZE - 1]));
 
         self.first_21_bits() == expected
     }
@@ -124,9 +166,9 @@ fn first_21_bits(bytes: &[u8]) -> [u8; 3] {
     [bytes[0], bytes[1], bytes[2] & 0xf8]
 }
 
-fn from_ipv4_and_r(bytes: [u8; 20], ip: Ipv4Addr, r: u8) -> Id {
+fn from_ip_and_r(bytes: [u8; 20], ip: IpAddr, r: u8) -> Id {
     let mut bytes = bytes;
-    let prefix = id_prefix_ipv4(ip, r);
+    let prefix = id_prefix(ip, r);
 
     // Set first 21 bits to the prefix
     bytes[0] = prefix[0];
@@ -140,13 +182,43 @@ fn from_ipv4_and_r(bytes: [u8; 20], ip: Ipv4Addr, r: u8) -> Id {
     Id(bytes)
 }
 
-fn id_prefix_ipv4(ip: Ipv4Addr, r: u8) -> [u8; 3] {
-    let r32: u32 = r.into();
-    let ip_int: u32 = u32::from_be_bytes(ip.octets());
-    let masked_ip: u32 = (ip_int & IPV4_MASK) | (r32 << 29);
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ipv4) => ipv4.is_private() || ipv4.is_link_local() || ipv4.is_loopback(),
+        IpAddr::V6(ipv6) => {
+            let first_segment = ipv6.segments()[0];
+
+            ipv6.is_loopback()
+                // Unique local fc00::/7
+                || (first_segment & 0xfe00) == 0xfc00
+                // Link local fe80::/10
+                || (first_segment & 0xffc0) == 0xfe80
+        }
+    }
+}
 
+fn id_prefix(ip: IpAddr, r: u8) -> [u8; 3] {
     let mut digest = CASTAGNOLI.digest();
-    digest.update(&masked_ip.to_be_bytes());
+
+    match ip {
+        IpAddr::V4(ipv4) => {
+            let r32: u32 = r.into();
+            let ip_int: u32 = u32::from_be_bytes(ipv4.octets());
+            let masked_ip: u32 = (ip_int & IPV4_MASK) | (r32 << 29);
+
+            digest.update(&masked_ip.to_be_bytes());
+        }
+        IpAddr::V6(ipv6) => {
+            let mut masked_ip = [0_u8; 8];
+
+            for (i, byte) in ipv6.octets()[..8].iter().enumerate() {
+                masked_ip[i] = byte & IPV6_MASK[i];
+            }
+            masked_ip[0] |= (r & 0x7) << 5;
+
+            digest.update(&masked_ip);
+        }
+    }
 
     let crc = digest.finalize();
 
@@ -157,10 +229,27 @@ fn id_prefix_ipv4(ip: Ipv4Addr, r: u8) -> [u8; 3] {
 
 impl Display for Id {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        #[allow(clippy::format_collect)]
-        let hex_chars: String = self.0.iter().map(|byte| format!("{:02x}", byte)).collect();
+        fmt::LowerHex::fmt(self, f)
+    }
+}
+
+impl fmt::LowerHex for Id {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::UpperHex for Id {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02X}", byte)?;
+        }
 
-        write!(f, "{}", hex_chars)
+        Ok(())
     }
 }
 
@@ -186,18 +275,22 @@ impl FromStr for Id {
     type Err = DecodeIdError;
 
     fn from_str(s: &str) -> Result<Id, DecodeIdError> {
-        if s.len() % 2 != 0 {
+        if !s.len().is_multiple_of(2) {
             return Err(DecodeIdError::OddNumberOfCharacters);
         }
 
         let mut bytes = Vec::with_capacity(s.len() / 2);
 
-        for i in 0..s.len() / 2 {
-            let byte_str = &s[i * 2..(i * 2) + 2];
-            if let Ok(byte) = u8::from_str_radix(byte_str, 16) {
-                bytes.push(byte);
-            } else {
-                return Err(DecodeIdError::InvalidHexCharacter(byte_str.into()));
+        // Work on bytes, so that multi-byte characters can't split a pair,
+        // and so that signs accepted by `from_str_radix` are rejected.
+        for pair in s.as_bytes().chunks(2) {
+            match (hex_value(pair[0]), hex_value(pair[1])) {
+                (Some(high), Some(low)) => bytes.push(high << 4 | low),
+                _ => {
+                    return Err(DecodeIdError::InvalidHexCharacter(
+                        String::from_utf8_lossy(pair).into(),
+                    ))
+                }
             }
         }
 
@@ -205,13 +298,96 @@ impl FromStr for Id {
     }
 }
 
+fn hex_value(char: u8) -> Option<u8> {
+    match char {
+        b'0'..=b'9' => Some(char - b'0'),
+        b'a'..=b'f' => Some(char - b'a' + 10),
+        b'A'..=b'F' => Some(char - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for Id {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Id {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Id, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(IdVisitor)
+        } else {
+            deserializer.deserialize_bytes(IdVisitor)
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+struct IdVisitor;
+
+#[cfg(feature = "serde")]
+impl<'de> de::Visitor<'de> for IdVisitor {
+    type Value = Id;
+
+    fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "a 40 characters hex string or 20 bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Id, E> {
+        Id::from_str(v).map_err(E::custom)
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Id, E> {
+        Id::from_bytes(v).map_err(E::custom)
+    }
+
+    // Formats without a bytes type encode them as a sequence.
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Id, A::Error> {
+        let mut bytes = Vec::with_capacity(ID_SIZE);
+
+        while let Some(byte) = seq.next_element::<u8>()? {
+            if bytes.len() == ID_SIZE {
+                return Err(de::Error::invalid_length(ID_SIZE + 1, &self));
+            }
+            bytes.push(byte);
+        }
+
+        Id::from_bytes(bytes).map_err(de::Error::custom)
+    }
+}
+
 impl Debug for Id {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "Id({})", self)
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// XOR distance between two [Id]s, ordered from closest to furthest.
+pub struct Distance([u8; ID_SIZE]);
+
+impl Distance {
+    /// Returns a byte slice of this distance.
+    pub fn as_bytes(&self) -> &[u8; ID_SIZE] {
+        &self.0
+    }
+}
+
+impl Debug for Distance {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Distance({})", Id(self.0))
+    }
+}
+
 #[derive(Debug)]
+/// Returned by [Id::from_bytes] when the input is not 20 bytes long.
 pub struct InvalidIdSize(usize);
 
 impl std::error::Error for InvalidIdSize {}
@@ -242,6 +418,76 @@ pub enum DecodeIdError {
 mod test {
     use super::*;
 
+    #[test]
+    fn hex_round_trip() {
+        let id = Id::random();
+
+        assert_eq!(Id::from_str(&id.to_string()).unwrap(), id);
+        assert_eq!(Id::from_str(&format!("{:X}", id)).unwrap(), id);
+        assert_eq!(format!("{:x}", id), id.to_string());
+
+        let hex = "0639a1e24fbb8ab277df033476ab0de10fab3bdc";
+        assert_eq!(Id::from_str(hex).unwrap().to_string(), hex);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde() {
+        use serde::de::value::{BytesDeserializer, Error, SeqDeserializer};
+
+        let id = Id::random();
+
+        // Human readable formats get the hex string.
+        let bytes = serde_bencode::to_bytes(&id).unwrap();
+        assert_eq!(bytes, format!("40:{id}").as_bytes());
+        assert_eq!(serde_bencode::from_bytes::<Id>(&bytes).unwrap(), id);
+
+        // Bytes and sequences of bytes are accepted as well.
+        let deserializer = BytesDeserializer::<Error>::new(id.as_bytes());
+        assert_eq!(Id::deserialize(deserializer).unwrap(), id);
+
+        let deserializer = SeqDeserializer::<_, Error>::new(id.as_bytes().iter().copied());
+        assert_eq!(Id::deserialize(deserializer).unwrap(), id);
+
+        let deserializer = BytesDeserializer::<Error>::new(&[0; 19]);
+        assert!(Id::deserialize(deserializer).is_err());
+
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, format!("\"{id}\""));
+        assert_eq!(serde_json::from_str::<Id>(&json).unwrap(), id);
+
+        let bytes = bincode::serialize(&id).unwrap();
+        assert_eq!(bincode::deserialize::<Id>(&bytes).unwrap(), id);
+    }
+
+    #[test]
+    fn invalid_hex() {
+        assert!(matches!(
+            Id::from_str("0639a1e24fbb8ab277df033476ab0de10fab3bd"),
+            Err(DecodeIdError::OddNumberOfCharacters)
+        ));
+        assert!(matches!(
+            Id::from_str("0639a1e24fbb8ab277df033476ab0de10fab3b"),
+            Err(DecodeIdError::InvalidIdSize(_))
+        ));
+        assert!(matches!(
+            Id::from_str("+639a1e24fbb8ab277df033476ab0de10fab3bdc"),
+            Err(DecodeIdError::InvalidHexCharacter(_))
+        ));
+        assert!(matches!(
+            Id::from_str("0639a1e24fbb8ab277df033476ab0de10fab3bzz"),
+            Err(DecodeIdError::InvalidHexCharacter(_))
+        ));
+        // A multi-byte character straddling a pair must not panic.
+        assert!(matches!(
+            Id::from_str("0é39a1e24fbb8ab277df033476ab0de10fab3bd"),
+            Err(DecodeIdError::InvalidHexCharacter(_))
+        ));
+
+        assert!(Id::from_bytes([0; 19]).is_err());
+        assert!(Id::from_bytes([0; 21]).is_err());
+    }
+
     #[test]
     fn distance_to_self() {
         let id = Id::random();
@@ -260,6 +506,40 @@ mod test {
         assert_eq!(distance, 155)
     }
 
+    #[test]
+    fn xor_distance() {
+        let id = Id::from_str("0639a1e24fbb8ab277df033476ab0de10fab3bdc").unwrap();
+        let target = Id::from_str("035b1aeb9737ade1a80933594f405d3f772aa08e").unwrap();
+
+        let distance = id.xor_distance(&target);
+
+        assert_eq!(
+            distance.as_bytes(),
+            Id::from_str("0562bb09d88c2753dfd6306d39eb50de78819b52")
+                .unwrap()
+                .as_bytes()
+        );
+        assert_eq!(id.xor_distance(&id).as_bytes(), &[0; 20]);
+    }
+
+    #[test]
+    fn cmp_distance() {
+        let target = Id::from_str("0000000000000000000000000000000000000000").unwrap();
+
+        let a = Id::from_str("0000000000000000000000000000000000000001").unwrap();
+        let b = Id::from_str("0000000000000000000000000000000000000010").unwrap();
+        let c = Id::from_str("8000000000000000000000000000000000000000").unwrap();
+
+        assert_eq!(target.cmp_distance(&a, &b), Ordering::Less);
+        assert_eq!(target.cmp_distance(&c, &b), Ordering::Greater);
+        assert_eq!(target.cmp_distance(&a, &a), Ordering::Equal);
+
+        let mut ids = vec![c, a, b];
+        ids.sort_by(|x, y| target.cmp_distance(x, y));
+
+        assert_eq!(ids, vec![a, b, c]);
+    }
+
     #[test]
     fn distance_to_random_id() {
         let id = Id::random();
@@ -310,7 +590,7 @@ mod test {
 
         fn test(ip: Ipv4Addr, r: u8, expected_prefix: [u8; 3]) {
             let id = Id::random();
-            let result = from_ipv4_and_r(*id.as_bytes(), ip, r);
+            let result = from_ip_and_r(*id.as_bytes(), ip.into(), r);
             let prefix = first_21_bits(result.as_bytes());
 
             assert_eq!(prefix, first_21_bits(&expected_prefix));
@@ -350,7 +630,32 @@ mod test {
         fn test(ip: Ipv4Addr, hex: &str) {
             let id = Id::from_str(hex).unwrap();
 
-            assert!(id.is_valid_for_ip(ip));
+            assert!(id.is_valid_for_ip(ip.into()));
+        }
+    }
+
+    #[test]
+    fn is_valid_for_ipv6() {
+        let ip: Ipv6Addr = "2001:db8:85a3::8a2e:370:7334".parse().unwrap();
+        let id = Id::from_ipv6(ip);
+
+        assert!(id.is_valid_for_ip(ip.into()));
+
+        // Only the first 64 bits of the address are used.
+        let same_prefix: Ipv6Addr = "2001:db8:85a3::1".parse().unwrap();
+        assert!(id.is_valid_for_ip(same_prefix.into()));
+
+        // Local addresses are always valid.
+        assert!(Id::random().is_valid_for_ip(Ipv6Addr::LOCALHOST.into()));
+        assert!(Id::random().is_valid_for_ip("fe80::1".parse::<Ipv6Addr>().unwrap().into()));
+    }
+
+    #[test]
+    fn random_at_distance() {
+        let id = Id::random();
+
+        for distance in 0..=160 {
+            assert_eq!(id.distance(&id.random_at_distance(distance)), distance);
         }
     }
 }
@@ -41,6 +41,18 @@ mod test {
         assert!(!validate_immutable(&v[1..], target));
     }
 
+    #[test]
+    fn reject_tampered_immutable() {
+        let v = b"Hello World!";
+        let target = Id::from_bytes(hash_immutable(v)).unwrap();
+
+        let mut tampered = *v;
+        tampered[0] ^= 1;
+
+        assert!(validate_immutable(v, target));
+        assert!(!validate_immutable(&tampered, target));
+    }
+
     #[test]
 
     fn test_hash_immutable() {
@@ -7,14 +7,16 @@
 mod internal;
 
 use std::convert::TryInto;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
-use crate::common::{Id, Node, ID_SIZE};
+use serde_bencode::value::Value;
+
+use crate::common::{BloomFilter, Id, Node, ID_SIZE};
 
 use super::InvalidIdSize;
 
 #[derive(Debug, PartialEq, Clone)]
-pub(crate) struct Message {
+pub struct Message {
     pub transaction_id: u16,
 
     /// The version of the requester or responder.
@@ -50,6 +52,90 @@ pub struct ErrorSpecific {
 pub struct RequestSpecific {
     pub requester_id: Id,
     pub request_type: RequestTypeSpecific,
+    /// Address families of the nodes wanted in the response, as defined in
+    /// [BEP_0032](https://www.bittorrent.org/beps/bep_0032.html).
+    ///
+    /// Empty means the same address family the request was sent over.
+    pub want: Vec<Want>,
+}
+
+impl RequestSpecific {
+    /// Create a `ping` request.
+    pub fn ping(requester_id: Id) -> Self {
+        Self::new(requester_id, RequestTypeSpecific::Ping)
+    }
+
+    /// Create a `find_node` request for the nodes closest to `target`.
+    pub fn find_node(requester_id: Id, target: Id) -> Self {
+        Self::new(
+            requester_id,
+            RequestTypeSpecific::FindNode(FindNodeRequestArguments { target }),
+        )
+    }
+
+    /// Create a `get_peers` request for the peers of `info_hash`.
+    pub fn get_peers(requester_id: Id, info_hash: Id) -> Self {
+        Self::new(
+            requester_id,
+            RequestTypeSpecific::GetPeers(GetPeersRequestArguments {
+                info_hash,
+                scrape: false,
+            }),
+        )
+    }
+
+    /// Create a `sample_infohashes` request (BEP_0051), with `target` to find closer nodes.
+    pub fn sample_infohashes(requester_id: Id, target: Id) -> Self {
+        Self::new(
+            requester_id,
+            RequestTypeSpecific::SampleInfohashes(SampleInfohashesRequestArguments { target }),
+        )
+    }
+
+    /// Create an `announce_peer` request for `info_hash`, with the `token`
+    /// received from the node in response to a `get_peers` request.
+    ///
+    /// Returns an error if the token is empty, or if the port is explicitly
+    /// `0` without [AnnounceOptions::implied_port].
+    pub fn announce_peer(
+        requester_id: Id,
+        info_hash: Id,
+        options: AnnounceOptions,
+        token: Box<[u8]>,
+    ) -> Result<Self, InvalidRequestError> {
+        if token.is_empty() {
+            return Err(InvalidRequestError::EmptyToken);
+        }
+        if options.port == Some(0) && !options.implied_port {
+            return Err(InvalidRequestError::ZeroPort);
+        }
+
+        Ok(Self::new(
+            requester_id,
+            RequestTypeSpecific::Put(PutRequest {
+                token,
+                put_request_type: PutRequestSpecific::AnnouncePeer(
+                    AnnouncePeerRequestArguments::new(info_hash, options),
+                ),
+            }),
+        ))
+    }
+
+    fn new(requester_id: Id, request_type: RequestTypeSpecific) -> Self {
+        Self {
+            requester_id,
+            request_type,
+            want: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Want {
+    /// Ipv4 nodes (`nodes`).
+    N4,
+    /// Ipv6 nodes (`nodes6`).
+    N6,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -60,6 +146,8 @@ pub enum RequestTypeSpecific {
     GetValue(GetValueRequestArguments),
 
     Put(PutRequest),
+    /// Sample of the info hashes a node stores (BEP_0051).
+    SampleInfohashes(SampleInfohashesRequestArguments),
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -96,6 +184,85 @@ pub enum ResponseSpecific {
     GetMutable(GetMutableResponseArguments),
     NoValues(NoValuesResponseArguments),
     NoMoreRecentValue(NoMoreRecentValueResponseArguments),
+    SampleInfohashes(SampleInfohashesResponseArguments),
+}
+
+impl ResponseSpecific {
+    /// Returns `true` if this response is shaped like an answer to `request`.
+    ///
+    /// Responses are decoded by their shape, so for example a response to a
+    /// `get_peers` request without a token is a protocol violation. A `find_node`
+    /// response without nodes looks like a ping response, and is accepted, and so
+    /// is a `find_node` response to `sample_infohashes` from nodes that don't
+    /// support [BEP_0051](https://www.bittorrent.org/beps/bep_0051.html).
+    pub fn answers(&self, request: &RequestTypeSpecific) -> bool {
+        match request {
+            RequestTypeSpecific::Ping | RequestTypeSpecific::Put(_) => {
+                matches!(self, ResponseSpecific::Ping(_))
+            }
+            RequestTypeSpecific::FindNode(_) => matches!(
+                self,
+                ResponseSpecific::FindNode(_) | ResponseSpecific::Ping(_)
+            ),
+            RequestTypeSpecific::GetPeers(_) => matches!(
+                self,
+                ResponseSpecific::GetPeers(_) | ResponseSpecific::NoValues(_)
+            ),
+            RequestTypeSpecific::GetValue(_) => matches!(
+                self,
+                ResponseSpecific::GetImmutable(_)
+                    | ResponseSpecific::GetMutable(_)
+                    | ResponseSpecific::NoMoreRecentValue(_)
+                    | ResponseSpecific::NoValues(_)
+            ),
+            RequestTypeSpecific::SampleInfohashes(_) => matches!(
+                self,
+                ResponseSpecific::SampleInfohashes(_) | ResponseSpecific::FindNode(_)
+            ),
+        }
+    }
+
+    /// Drop closer nodes from the end, or once there are none left, peers or samples,
+    /// to save about `excess` encoded bytes. Returns `false` if there was nothing to drop.
+    pub(crate) fn trim(&mut self, excess: usize) -> bool {
+        let nodes = match self {
+            ResponseSpecific::Ping(_) => None,
+            ResponseSpecific::FindNode(arguments) => Some(&mut arguments.nodes),
+            ResponseSpecific::GetPeers(arguments) => arguments.nodes.as_mut(),
+            ResponseSpecific::GetImmutable(arguments) => arguments.nodes.as_mut(),
+            ResponseSpecific::GetMutable(arguments) => arguments.nodes.as_mut(),
+            ResponseSpecific::NoValues(arguments) => arguments.nodes.as_mut(),
+            ResponseSpecific::NoMoreRecentValue(arguments) => arguments.nodes.as_mut(),
+            ResponseSpecific::SampleInfohashes(arguments) => arguments.nodes.as_mut(),
+        };
+
+        if let Some(nodes) = nodes.filter(|nodes| !nodes.is_empty()) {
+            let len = nodes.len().saturating_sub(excess.div_ceil(NODE_BYTE_SIZE));
+            *nodes = nodes[..len].into();
+
+            return true;
+        }
+
+        match self {
+            ResponseSpecific::GetPeers(arguments) if !arguments.values.is_empty() => {
+                // Each peer is encoded as a `6:` prefixed byte string.
+                let len = arguments.values.len().saturating_sub(excess.div_ceil(8));
+                arguments.values.truncate(len);
+
+                true
+            }
+            ResponseSpecific::SampleInfohashes(arguments) if !arguments.samples.is_empty() => {
+                let len = arguments
+                    .samples
+                    .len()
+                    .saturating_sub(excess.div_ceil(ID_SIZE));
+                arguments.samples = arguments.samples[..len].into();
+
+                true
+            }
+            _ => false,
+        }
+    }
 }
 
 // === PING ===
@@ -133,6 +300,10 @@ pub struct NoValuesResponseArguments {
     pub responder_id: Id,
     pub token: Box<[u8]>,
     pub nodes: Option<Box<[Node]>>,
+    /// Bloom filter of seeders, in response to a scrape request (BEP_0033).
+    pub bf_seeders: Option<BloomFilter>,
+    /// Bloom filter of peers, in response to a scrape request (BEP_0033).
+    pub bf_peers: Option<BloomFilter>,
 }
 
 // === Get Peers ===
@@ -140,6 +311,8 @@ pub struct NoValuesResponseArguments {
 #[derive(Debug, PartialEq, Clone)]
 pub struct GetPeersRequestArguments {
     pub info_hash: Id,
+    /// Request bloom filters of seeders and peers (BEP_0033).
+    pub scrape: bool,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -148,6 +321,30 @@ pub struct GetPeersResponseArguments {
     pub token: Box<[u8]>,
     pub values: Vec<SocketAddrV4>,
     pub nodes: Option<Box<[Node]>>,
+    /// Bloom filter of seeders, in response to a scrape request (BEP_0033).
+    pub bf_seeders: Option<BloomFilter>,
+    /// Bloom filter of peers, in response to a scrape request (BEP_0033).
+    pub bf_peers: Option<BloomFilter>,
+}
+
+// === Sample Infohashes ===
+
+/// Read [BEP_0051](https://www.bittorrent.org/beps/bep_0051.html) for more information.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SampleInfohashesRequestArguments {
+    /// Like a `find_node` target, used to find closer nodes to sample next.
+    pub target: Id,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SampleInfohashesResponseArguments {
+    pub responder_id: Id,
+    /// Seconds the responder asks to wait before sampling it again.
+    pub interval: u32,
+    pub nodes: Option<Box<[Node]>>,
+    /// Number of info hashes the responder stores, of which `samples` is a subset.
+    pub num: u32,
+    pub samples: Box<[Id]>,
 }
 
 // === Announce Peer ===
@@ -159,6 +356,42 @@ pub struct AnnouncePeerRequestArguments {
     pub implied_port: Option<bool>,
 }
 
+impl AnnouncePeerRequestArguments {
+    /// Create the arguments of announcing a peer for `info_hash`.
+    ///
+    /// If [AnnounceOptions::implied_port] is set, or no port is given, the port is
+    /// sent as `0` with `implied_port=1`, so receivers never get both a port
+    /// and an instruction to ignore it.
+    pub fn new(info_hash: Id, options: AnnounceOptions) -> Self {
+        match options.port {
+            Some(port) if !options.implied_port => Self {
+                info_hash,
+                port,
+                implied_port: None,
+            },
+            _ => Self {
+                info_hash,
+                port: 0,
+                implied_port: Some(true),
+            },
+        }
+    }
+}
+
+/// Options for announcing a peer, see [crate::Dht::announce_peer_with].
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct AnnounceOptions {
+    /// The port the peer is listening on.
+    ///
+    /// Ignored if [Self::implied_port] is set.
+    pub port: Option<u16>,
+    /// Tell receivers to use the source port of the `announce_peer` request instead of
+    /// [Self::port], which is useful behind a NAT that changes the port.
+    ///
+    /// Implied if no port is given.
+    pub implied_port: bool,
+}
+
 // === Get Immutable ===
 
 #[derive(Debug, PartialEq, Clone)]
@@ -224,6 +457,7 @@ impl Message {
                 MessageType::Request(RequestSpecific {
                     requester_id,
                     request_type,
+                    want,
                 }) => internal::DHTMessageVariant::Request(match request_type {
                     RequestTypeSpecific::Ping => internal::DHTRequestSpecific::Ping {
                         arguments: internal::DHTPingRequestArguments {
@@ -235,6 +469,7 @@ impl Message {
                             arguments: internal::DHTFindNodeRequestArguments {
                                 id: requester_id.into(),
                                 target: find_node_args.target.into(),
+                                want: want_to_bytes(&want),
                             },
                         }
                     }
@@ -243,6 +478,8 @@ impl Message {
                             arguments: internal::DHTGetPeersRequestArguments {
                                 id: requester_id.into(),
                                 info_hash: get_peers_args.info_hash.into(),
+                                want: want_to_bytes(&want),
+                                scrape: get_peers_args.scrape.then_some(1),
                             },
                         }
                     }
@@ -252,6 +489,16 @@ impl Message {
                                 id: requester_id.into(),
                                 target: get_mutable_args.target.into(),
                                 seq: get_mutable_args.seq,
+                                want: want_to_bytes(&want),
+                            },
+                        }
+                    }
+                    RequestTypeSpecific::SampleInfohashes(args) => {
+                        internal::DHTRequestSpecific::SampleInfohashes {
+                            arguments: internal::DHTSampleInfohashesRequestArguments {
+                                id: requester_id.into(),
+                                target: args.target.into(),
+                                want: want_to_bytes(&want),
                             },
                         }
                     }
@@ -267,7 +514,7 @@ impl Message {
 
                                     info_hash: announce_peer_args.info_hash.into(),
                                     port: announce_peer_args.port,
-                                    implied_port: if announce_peer_args.implied_port.is_some() {
+                                    implied_port: if announce_peer_args.implied_port == Some(true) {
                                         Some(1)
                                     } else {
                                         Some(0)
@@ -321,6 +568,7 @@ impl Message {
                             arguments: internal::DHTFindNodeResponseArguments {
                                 id: find_node_args.responder_id.into(),
                                 nodes: nodes4_to_bytes(&find_node_args.nodes),
+                                nodes6: nodes6_to_bytes(&find_node_args.nodes),
                             },
                         }
                     }
@@ -333,7 +581,13 @@ impl Message {
                                     .nodes
                                     .as_ref()
                                     .map(|nodes| nodes4_to_bytes(nodes)),
+                                nodes6: get_peers_args
+                                    .nodes
+                                    .as_ref()
+                                    .and_then(|nodes| nodes6_to_bytes(nodes)),
                                 values: peers_to_bytes(&get_peers_args.values),
+                                bf_seeders: bloom_filter_to_bytes(get_peers_args.bf_seeders),
+                                bf_peers: bloom_filter_to_bytes(get_peers_args.bf_peers),
                             },
                         }
                     }
@@ -346,6 +600,12 @@ impl Message {
                                     .nodes
                                     .as_ref()
                                     .map(|nodes| nodes4_to_bytes(nodes)),
+                                nodes6: no_values_arguments
+                                    .nodes
+                                    .as_ref()
+                                    .and_then(|nodes| nodes6_to_bytes(nodes)),
+                                bf_seeders: bloom_filter_to_bytes(no_values_arguments.bf_seeders),
+                                bf_peers: bloom_filter_to_bytes(no_values_arguments.bf_peers),
                             },
                         }
                     }
@@ -358,6 +618,10 @@ impl Message {
                                     .nodes
                                     .as_ref()
                                     .map(|nodes| nodes4_to_bytes(nodes)),
+                                nodes6: get_immutable_args
+                                    .nodes
+                                    .as_ref()
+                                    .and_then(|nodes| nodes6_to_bytes(nodes)),
                                 v: get_immutable_args.v,
                             },
                         }
@@ -371,6 +635,10 @@ impl Message {
                                     .nodes
                                     .as_ref()
                                     .map(|nodes| nodes4_to_bytes(nodes)),
+                                nodes6: get_mutable_args
+                                    .nodes
+                                    .as_ref()
+                                    .and_then(|nodes| nodes6_to_bytes(nodes)),
                                 v: get_mutable_args.v,
                                 k: get_mutable_args.k,
                                 seq: get_mutable_args.seq,
@@ -384,10 +652,33 @@ impl Message {
                                 id: args.responder_id.into(),
                                 token: args.token,
                                 nodes: args.nodes.as_ref().map(|nodes| nodes4_to_bytes(nodes)),
+                                nodes6: args
+                                    .nodes
+                                    .as_ref()
+                                    .and_then(|nodes| nodes6_to_bytes(nodes)),
                                 seq: args.seq,
                             },
                         }
                     }
+                    ResponseSpecific::SampleInfohashes(args) => {
+                        internal::DHTResponseSpecific::SampleInfohashes {
+                            arguments: internal::DHTSampleInfohashesResponseArguments {
+                                id: args.responder_id.into(),
+                                interval: args.interval,
+                                nodes: args.nodes.as_ref().map(|nodes| nodes4_to_bytes(nodes)),
+                                nodes6: args
+                                    .nodes
+                                    .as_ref()
+                                    .and_then(|nodes| nodes6_to_bytes(nodes)),
+                                num: args.num,
+                                samples: args
+                                    .samples
+                                    .iter()
+                                    .flat_map(|id| *id.as_bytes())
+                                    .collect(),
+                            },
+                        }
+                    }
                 }),
 
                 MessageType::Error(err) => {
@@ -418,18 +709,22 @@ impl Message {
                         internal::DHTRequestSpecific::Ping { arguments } => RequestSpecific {
                             requester_id: Id::from_bytes(arguments.id)?,
                             request_type: RequestTypeSpecific::Ping,
+                            want: Vec::new(),
                         },
                         internal::DHTRequestSpecific::FindNode { arguments } => RequestSpecific {
                             requester_id: Id::from_bytes(arguments.id)?,
                             request_type: RequestTypeSpecific::FindNode(FindNodeRequestArguments {
                                 target: Id::from_bytes(arguments.target)?,
                             }),
+                            want: bytes_to_want(arguments.want),
                         },
                         internal::DHTRequestSpecific::GetPeers { arguments } => RequestSpecific {
                             requester_id: Id::from_bytes(arguments.id)?,
                             request_type: RequestTypeSpecific::GetPeers(GetPeersRequestArguments {
                                 info_hash: Id::from_bytes(arguments.info_hash)?,
+                                scrape: arguments.scrape.is_some_and(|scrape| scrape != 0),
                             }),
+                            want: bytes_to_want(arguments.want),
                         },
                         internal::DHTRequestSpecific::GetValue { arguments } => RequestSpecific {
                             requester_id: Id::from_bytes(arguments.id)?,
@@ -439,7 +734,19 @@ impl Message {
                                 seq: arguments.seq,
                                 salt: None,
                             }),
+                            want: bytes_to_want(arguments.want),
                         },
+                        internal::DHTRequestSpecific::SampleInfohashes { arguments } => {
+                            RequestSpecific {
+                                requester_id: Id::from_bytes(arguments.id)?,
+                                request_type: RequestTypeSpecific::SampleInfohashes(
+                                    SampleInfohashesRequestArguments {
+                                        target: Id::from_bytes(arguments.target)?,
+                                    },
+                                ),
+                                want: bytes_to_want(arguments.want),
+                            }
+                        }
                         internal::DHTRequestSpecific::AnnouncePeer { arguments } => {
                             RequestSpecific {
                                 requester_id: Id::from_bytes(arguments.id)?,
@@ -455,6 +762,7 @@ impl Message {
                                         },
                                     ),
                                 }),
+                                want: Vec::new(),
                             }
                         }
                         internal::DHTRequestSpecific::PutValue { arguments } => {
@@ -480,6 +788,7 @@ impl Message {
                                             },
                                         ),
                                     }),
+                                    want: Vec::new(),
                                 }
                             } else {
                                 RequestSpecific {
@@ -494,6 +803,7 @@ impl Message {
                                             },
                                         ),
                                     }),
+                                    want: Vec::new(),
                                 }
                             }
                         }
@@ -510,38 +820,34 @@ impl Message {
                         internal::DHTResponseSpecific::FindNode { arguments } => {
                             ResponseSpecific::FindNode(FindNodeResponseArguments {
                                 responder_id: Id::from_bytes(arguments.id)?,
-                                nodes: bytes_to_nodes4(&arguments.nodes)?,
+                                nodes: bytes_to_nodes(Some(arguments.nodes), arguments.nodes6)?
+                                    .unwrap_or_default(),
                             })
                         }
                         internal::DHTResponseSpecific::GetPeers { arguments } => {
                             ResponseSpecific::GetPeers(GetPeersResponseArguments {
                                 responder_id: Id::from_bytes(arguments.id)?,
                                 token: arguments.token,
-                                nodes: match arguments.nodes {
-                                    Some(nodes) => Some(bytes_to_nodes4(nodes)?),
-                                    None => None,
-                                },
+                                nodes: bytes_to_nodes(arguments.nodes, arguments.nodes6)?,
                                 values: bytes_to_peers(arguments.values)?,
+                                bf_seeders: bytes_to_bloom_filter(arguments.bf_seeders),
+                                bf_peers: bytes_to_bloom_filter(arguments.bf_peers),
                             })
                         }
                         internal::DHTResponseSpecific::NoValues { arguments } => {
                             ResponseSpecific::NoValues(NoValuesResponseArguments {
                                 responder_id: Id::from_bytes(arguments.id)?,
                                 token: arguments.token,
-                                nodes: match arguments.nodes {
-                                    Some(nodes) => Some(bytes_to_nodes4(nodes)?),
-                                    None => None,
-                                },
+                                nodes: bytes_to_nodes(arguments.nodes, arguments.nodes6)?,
+                                bf_seeders: bytes_to_bloom_filter(arguments.bf_seeders),
+                                bf_peers: bytes_to_bloom_filter(arguments.bf_peers),
                             })
                         }
                         internal::DHTResponseSpecific::GetImmutable { arguments } => {
                             ResponseSpecific::GetImmutable(GetImmutableResponseArguments {
                                 responder_id: Id::from_bytes(arguments.id)?,
                                 token: arguments.token,
-                                nodes: match arguments.nodes {
-                                    Some(nodes) => Some(bytes_to_nodes4(nodes)?),
-                                    None => None,
-                                },
+                                nodes: bytes_to_nodes(arguments.nodes, arguments.nodes6)?,
                                 v: arguments.v,
                             })
                         }
@@ -549,25 +855,28 @@ impl Message {
                             ResponseSpecific::GetMutable(GetMutableResponseArguments {
                                 responder_id: Id::from_bytes(arguments.id)?,
                                 token: arguments.token,
-                                nodes: match arguments.nodes {
-                                    Some(nodes) => Some(bytes_to_nodes4(nodes)?),
-                                    None => None,
-                                },
+                                nodes: bytes_to_nodes(arguments.nodes, arguments.nodes6)?,
                                 v: arguments.v,
                                 k: arguments.k,
                                 seq: arguments.seq,
                                 sig: arguments.sig,
                             })
                         }
+                        internal::DHTResponseSpecific::SampleInfohashes { arguments } => {
+                            ResponseSpecific::SampleInfohashes(SampleInfohashesResponseArguments {
+                                responder_id: Id::from_bytes(arguments.id)?,
+                                interval: arguments.interval,
+                                nodes: bytes_to_nodes(arguments.nodes, arguments.nodes6)?,
+                                num: arguments.num,
+                                samples: bytes_to_samples(&arguments.samples)?,
+                            })
+                        }
                         internal::DHTResponseSpecific::NoMoreRecentValue { arguments } => {
                             ResponseSpecific::NoMoreRecentValue(
                                 NoMoreRecentValueResponseArguments {
                                     responder_id: Id::from_bytes(arguments.id)?,
                                     token: arguments.token,
-                                    nodes: match arguments.nodes {
-                                        Some(nodes) => Some(bytes_to_nodes4(nodes)?),
-                                        None => None,
-                                    },
+                                    nodes: bytes_to_nodes(arguments.nodes, arguments.nodes6)?,
                                     seq: arguments.seq,
                                 },
                             )
@@ -597,6 +906,42 @@ impl Message {
         Message::from_serde_message(internal::DHTMessage::from_bytes(bytes)?)
     }
 
+    /// Like [Self::from_bytes], but tolerates minor deviations from [BEP_0005](https://www.bittorrent.org/beps/bep_0005.html)
+    /// seen from some clients, by ignoring malformed optional fields:
+    /// a version (`v`) that isn't 4 bytes, an `ip` that isn't a compact
+    /// Ipv4 address, or a non integer `ro` flag.
+    ///
+    /// Messages missing a transaction id, with an unknown type (`y`) or with
+    /// node ids that aren't exactly 20 bytes are still rejected.
+    pub fn from_bytes_lenient(bytes: &[u8]) -> Result<Message, DecodeMessageError> {
+        let error = match Message::from_bytes(bytes) {
+            Err(DecodeMessageError::BencodeError(error)) => error,
+            result => return result,
+        };
+
+        let Ok(Value::Dict(mut dict)) = serde_bencode::from_bytes::<Value>(bytes) else {
+            return Err(error.into());
+        };
+
+        let len = dict.len();
+
+        dict.retain(|key, value| match (key.as_slice(), value) {
+            (b"v", Value::Bytes(version)) => version.len() == 4,
+            (b"ip", Value::Bytes(ip)) => ip.len() == 6,
+            (b"v" | b"ip", _) => false,
+            (b"ro", value) => matches!(value, Value::Int(_)),
+            _ => true,
+        });
+
+        if dict.len() == len {
+            return Err(error.into());
+        }
+
+        Message::from_serde_message(internal::DHTMessage::from_bytes(&serde_bencode::to_bytes(
+            &Value::Dict(dict),
+        )?)?)
+    }
+
     /// Return the Id of the sender of the Message
     ///
     /// This is less straightforward than it seems because not *all* messages are sent
@@ -615,6 +960,7 @@ impl Message {
                 ResponseSpecific::GetMutable(arguments) => arguments.responder_id,
                 ResponseSpecific::NoValues(arguments) => arguments.responder_id,
                 ResponseSpecific::NoMoreRecentValue(arguments) => arguments.responder_id,
+                ResponseSpecific::SampleInfohashes(arguments) => arguments.responder_id,
             },
             MessageType::Error(_) => {
                 return None;
@@ -635,6 +981,7 @@ impl Message {
                 ResponseSpecific::GetImmutable(arguments) => arguments.nodes.as_deref(),
                 ResponseSpecific::NoValues(arguments) => arguments.nodes.as_deref(),
                 ResponseSpecific::NoMoreRecentValue(arguments) => arguments.nodes.as_deref(),
+                ResponseSpecific::SampleInfohashes(arguments) => arguments.nodes.as_deref(),
             },
             _ => None,
         }
@@ -645,6 +992,7 @@ impl Message {
             MessageType::Response(response_variant) => match response_variant {
                 ResponseSpecific::Ping(_) => None,
                 ResponseSpecific::FindNode(_) => None,
+                ResponseSpecific::SampleInfohashes(_) => None,
                 ResponseSpecific::GetPeers(arguments) => {
                     Some((arguments.responder_id, &arguments.token))
                 }
@@ -695,20 +1043,73 @@ pub fn sockaddr_to_bytes(sockaddr: &SocketAddrV4) -> [u8; 6] {
     bytes
 }
 
-const NODE_BYTE_SIZE: usize = ID_SIZE + 6;
+fn bytes_to_sockaddr6<T: AsRef<[u8]>>(bytes: T) -> Result<SocketAddrV6, DecodeMessageError> {
+    let bytes = bytes.as_ref();
+    match bytes.len() {
+        18 => {
+            let ip_bytes_as_array: [u8; 16] = bytes[0..16]
+                .try_into()
+                .map_err(|_| DecodeMessageError::InvalidSocketAddrEncodingLength)?;
+            let ip = Ipv6Addr::from(ip_bytes_as_array);
+
+            let port_bytes_as_array: [u8; 2] = bytes[16..18]
+                .try_into()
+                .map_err(|_| DecodeMessageError::InvalidPortEncoding)?;
+
+            let port: u16 = u16::from_be_bytes(port_bytes_as_array);
+
+            Ok(SocketAddrV6::new(ip, port, 0, 0))
+        }
+        _ => Err(DecodeMessageError::InvalidSocketAddrEncodingLength),
+    }
+}
 
-fn nodes4_to_bytes(nodes: &[Node]) -> Box<[u8]> {
+pub(crate) fn sockaddr6_to_bytes(sockaddr: &SocketAddrV6) -> [u8; 18] {
+    let mut bytes = [0u8; 18];
+
+    bytes[0..16].copy_from_slice(&sockaddr.ip().octets());
+
+    bytes[16..18].copy_from_slice(&sockaddr.port().to_be_bytes());
+
+    bytes
+}
+
+pub(crate) const NODE_BYTE_SIZE: usize = ID_SIZE + 6;
+pub(crate) const NODE6_BYTE_SIZE: usize = ID_SIZE + 18;
+
+/// Encode the Ipv4 nodes in compact format, Ipv6 nodes are skipped.
+pub(crate) fn nodes4_to_bytes(nodes: &[Node]) -> Box<[u8]> {
     let mut bytes = Vec::with_capacity(NODE_BYTE_SIZE * nodes.len());
 
     for node in nodes {
-        bytes.extend_from_slice(node.id().as_bytes());
-        bytes.extend_from_slice(&sockaddr_to_bytes(&node.address()));
+        if let SocketAddr::V4(address) = node.address() {
+            bytes.extend_from_slice(node.id().as_bytes());
+            bytes.extend_from_slice(&sockaddr_to_bytes(&address));
+        }
     }
 
     bytes.into_boxed_slice()
 }
 
-fn bytes_to_nodes4<T: AsRef<[u8]>>(bytes: T) -> Result<Box<[Node]>, DecodeMessageError> {
+/// Encode the Ipv6 nodes in compact format, returns `None` if there are no Ipv6 nodes.
+fn nodes6_to_bytes(nodes: &[Node]) -> Option<Box<[u8]>> {
+    let mut bytes = Vec::new();
+
+    for node in nodes {
+        if let SocketAddr::V6(address) = node.address() {
+            bytes.extend_from_slice(node.id().as_bytes());
+            bytes.extend_from_slice(&sockaddr6_to_bytes(&address));
+        }
+    }
+
+    if bytes.is_empty() {
+        None
+    } else {
+        Some(bytes.into_boxed_slice())
+    }
+}
+
+pub(crate) fn bytes_to_nodes4<T: AsRef<[u8]>>(bytes: T) -> Result<Box<[Node]>, DecodeMessageError> {
     let bytes = bytes.as_ref();
 
     if bytes.len() % NODE_BYTE_SIZE != 0 {
@@ -721,13 +1122,97 @@ fn bytes_to_nodes4<T: AsRef<[u8]>>(bytes: T) -> Result<Box<[Node]>, DecodeMessag
         let i = i * NODE_BYTE_SIZE;
         let id = Id::from_bytes(&bytes[i..i + ID_SIZE])?;
         let sockaddr = bytes_to_sockaddr(&bytes[i + ID_SIZE..i + NODE_BYTE_SIZE])?;
-        let node = Node::new(id, sockaddr);
+        let node = Node::new(id, sockaddr.into());
         to_ret.push(node);
     }
 
     Ok(to_ret.into_boxed_slice())
 }
 
+pub(crate) fn bytes_to_nodes6<T: AsRef<[u8]>>(bytes: T) -> Result<Box<[Node]>, DecodeMessageError> {
+    let bytes = bytes.as_ref();
+
+    if bytes.len() % NODE6_BYTE_SIZE != 0 {
+        return Err(DecodeMessageError::InvalidNodes6);
+    }
+
+    let expected_num = bytes.len() / NODE6_BYTE_SIZE;
+    let mut to_ret = Vec::with_capacity(expected_num);
+    for i in 0..bytes.len() / NODE6_BYTE_SIZE {
+        let i = i * NODE6_BYTE_SIZE;
+        let id = Id::from_bytes(&bytes[i..i + ID_SIZE])?;
+        let sockaddr = bytes_to_sockaddr6(&bytes[i + ID_SIZE..i + NODE6_BYTE_SIZE])?;
+        let node = Node::new(id, sockaddr.into());
+        to_ret.push(node);
+    }
+
+    Ok(to_ret.into_boxed_slice())
+}
+
+/// Decode the concatenated info hashes of a `sample_infohashes` response.
+fn bytes_to_samples(bytes: &[u8]) -> Result<Box<[Id]>, DecodeMessageError> {
+    if !bytes.len().is_multiple_of(ID_SIZE) {
+        return Err(DecodeMessageError::InvalidSamples);
+    }
+
+    bytes
+        .chunks_exact(ID_SIZE)
+        .map(|chunk| Ok(Id::from_bytes(chunk)?))
+        .collect()
+}
+
+/// Decode and merge `nodes` and `nodes6` into one list, Ipv4 nodes first.
+fn bytes_to_nodes(
+    nodes: Option<Box<[u8]>>,
+    nodes6: Option<Box<[u8]>>,
+) -> Result<Option<Box<[Node]>>, DecodeMessageError> {
+    match (nodes, nodes6) {
+        (None, None) => Ok(None),
+        (nodes, nodes6) => {
+            let mut to_ret = match nodes {
+                Some(nodes) => bytes_to_nodes4(nodes)?.into_vec(),
+                None => Vec::new(),
+            };
+
+            if let Some(nodes6) = nodes6 {
+                to_ret.extend(bytes_to_nodes6(nodes6)?.into_vec());
+            }
+
+            Ok(Some(to_ret.into_boxed_slice()))
+        }
+    }
+}
+
+/// Ask responders for both Ipv4 and Ipv6 nodes.
+///
+/// Read [BEP_0032](https://www.bittorrent.org/beps/bep_0032.html) for more information.
+fn want_to_bytes(want: &[Want]) -> Option<Vec<serde_bytes::ByteBuf>> {
+    if want.is_empty() {
+        return None;
+    }
+
+    Some(
+        want.iter()
+            .map(|want| match want {
+                Want::N4 => serde_bytes::ByteBuf::from(b"n4".to_vec()),
+                Want::N6 => serde_bytes::ByteBuf::from(b"n6".to_vec()),
+            })
+            .collect(),
+    )
+}
+
+/// Unknown address families are ignored.
+fn bytes_to_want(want: Option<Vec<serde_bytes::ByteBuf>>) -> Vec<Want> {
+    want.unwrap_or_default()
+        .iter()
+        .filter_map(|want| match want.as_slice() {
+            b"n4" => Some(Want::N4),
+            b"n6" => Some(Want::N6),
+            _ => None,
+        })
+        .collect()
+}
+
 fn peers_to_bytes(peers: &[SocketAddrV4]) -> Vec<serde_bytes::ByteBuf> {
     peers
         .iter()
@@ -742,6 +1227,16 @@ fn bytes_to_peers<T: AsRef<[serde_bytes::ByteBuf]>>(
     bytes.iter().map(bytes_to_sockaddr).collect()
 }
 
+fn bloom_filter_to_bytes(filter: Option<BloomFilter>) -> Option<Box<[u8]>> {
+    filter.map(|filter| filter.as_bytes().as_slice().into())
+}
+
+/// Malformed bloom filters are ignored rather than failing the whole message,
+/// so the response still contributes its nodes and values.
+fn bytes_to_bloom_filter(bytes: Option<Box<[u8]>>) -> Option<BloomFilter> {
+    bytes.and_then(|bytes| BloomFilter::from_bytes(&bytes))
+}
+
 #[derive(thiserror::Error, Debug)]
 /// Mainline crate error enum.
 pub enum DecodeMessageError {
@@ -754,6 +1249,12 @@ pub enum DecodeMessageError {
     #[error("Wrong number of bytes for nodes")]
     InvalidNodes4,
 
+    #[error("Wrong number of bytes for nodes6")]
+    InvalidNodes6,
+
+    #[error("Wrong number of bytes for samples")]
+    InvalidSamples,
+
     #[error("wrong number of bytes for port")]
     InvalidPortEncoding,
 
@@ -770,6 +1271,16 @@ pub enum DecodeMessageError {
     InvalidIdSize(#[from] InvalidIdSize),
 }
 
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+/// Returned when creating a [RequestSpecific] from invalid arguments.
+pub enum InvalidRequestError {
+    #[error("announce_peer requests need the token received from the node")]
+    EmptyToken,
+
+    #[error("announce_peer requests need a non-zero port, or an implied port")]
+    ZeroPort,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -784,6 +1295,7 @@ mod tests {
             message_type: MessageType::Request(RequestSpecific {
                 requester_id: Id::random(),
                 request_type: RequestTypeSpecific::Ping,
+                want: Vec::new(),
             }),
         };
 
@@ -825,6 +1337,7 @@ mod tests {
                 request_type: RequestTypeSpecific::FindNode(FindNodeRequestArguments {
                     target: Id::random(),
                 }),
+                want: vec![Want::N4, Want::N6],
             }),
         };
 
@@ -847,6 +1360,7 @@ mod tests {
                 request_type: RequestTypeSpecific::FindNode(FindNodeRequestArguments {
                     target: Id::random(),
                 }),
+                want: Vec::new(),
             }),
         };
 
@@ -867,8 +1381,7 @@ mod tests {
             message_type: MessageType::Response(ResponseSpecific::FindNode(
                 FindNodeResponseArguments {
                     responder_id: Id::random(),
-                    nodes: [Node::new(Id::random(), "49.50.52.52:5354".parse().unwrap()).into()]
-                        .into(),
+                    nodes: [Node::new(Id::random(), "49.50.52.52:5354".parse().unwrap())].into(),
                 },
             )),
         };
@@ -890,6 +1403,68 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_find_node_response_nodes6() {
+        let original_msg = Message {
+            transaction_id: 258,
+            version: Some([1, 2, 3, 4]),
+            requester_ip: Some("50.51.52.53:5455".parse().unwrap()),
+            read_only: false,
+            message_type: MessageType::Response(ResponseSpecific::FindNode(
+                FindNodeResponseArguments {
+                    responder_id: Id::random(),
+                    nodes: [
+                        Node::new(Id::random(), "49.50.52.52:5354".parse().unwrap()),
+                        Node::new(Id::random(), "[2001:db8::1]:6881".parse().unwrap()),
+                    ]
+                    .into(),
+                },
+            )),
+        };
+
+        let serde_msg = original_msg.clone().into_serde_message();
+
+        if let internal::DHTMessageVariant::Response(internal::DHTResponseSpecific::FindNode {
+            arguments,
+        }) = &serde_msg.variant
+        {
+            assert_eq!(arguments.nodes.len(), NODE_BYTE_SIZE);
+            assert_eq!(
+                arguments.nodes6.as_ref().map(|nodes6| nodes6.len()),
+                Some(NODE6_BYTE_SIZE)
+            );
+        } else {
+            panic!("expected find_node response");
+        }
+
+        let bytes = serde_msg.to_bytes().unwrap();
+        let parsed_serde_msg = internal::DHTMessage::from_bytes(&bytes).unwrap();
+        let parsed_msg = Message::from_serde_message(parsed_serde_msg).unwrap();
+        assert_eq!(
+            parsed_msg.get_closer_nodes().map(|nodes| nodes
+                .iter()
+                .map(|n| (n.id(), n.address()))
+                .collect::<Vec<_>>()),
+            original_msg.get_closer_nodes().map(|nodes| nodes
+                .iter()
+                .map(|n| (n.id(), n.address()))
+                .collect::<Vec<_>>())
+        );
+    }
+
+    #[test]
+    fn test_nodes6_ipv4_mapped() {
+        let id = Id::random();
+        let mapped: SocketAddrV6 = "[::ffff:49.50.52.52]:5354".parse().unwrap();
+
+        let nodes6 = [id.as_bytes().as_slice(), &sockaddr6_to_bytes(&mapped)].concat();
+        let nodes = bytes_to_nodes(None, Some(nodes6.into())).unwrap().unwrap();
+
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(*nodes[0].id(), id);
+        assert_eq!(nodes[0].address(), "49.50.52.52:5354".parse().unwrap());
+    }
+
     #[test]
     fn test_get_peers_request() {
         let original_msg = Message {
@@ -901,7 +1476,9 @@ mod tests {
                 requester_id: Id::random(),
                 request_type: RequestTypeSpecific::GetPeers(GetPeersRequestArguments {
                     info_hash: Id::random(),
+                    scrape: false,
                 }),
+                want: Vec::new(),
             }),
         };
 
@@ -924,9 +1501,10 @@ mod tests {
                     responder_id: Id::random(),
                     token: [99, 100, 101, 102].into(),
                     nodes: Some(
-                        [Node::new(Id::random(), "49.50.52.52:5354".parse().unwrap()).into()]
-                            .into(),
+                        [Node::new(Id::random(), "49.50.52.52:5354".parse().unwrap())].into(),
                     ),
+                    bf_seeders: None,
+                    bf_peers: None,
                 },
             )),
         };
@@ -965,6 +1543,36 @@ mod tests {
                     token: vec![99, 100, 101, 102].into(),
                     nodes: None,
                     values: ["123.123.123.123:123".parse().unwrap()].into(),
+                    bf_seeders: None,
+                    bf_peers: None,
+                },
+            )),
+        };
+
+        let serde_msg = original_msg.clone().into_serde_message();
+        let bytes = serde_msg.to_bytes().unwrap();
+        let parsed_serde_msg = internal::DHTMessage::from_bytes(&bytes).unwrap();
+        let parsed_msg = Message::from_serde_message(parsed_serde_msg).unwrap();
+        assert_eq!(parsed_msg, original_msg);
+    }
+
+    #[test]
+    fn test_get_peers_response_scrape() {
+        let mut bf_peers = BloomFilter::new();
+        bf_peers.insert([123, 123, 123, 123].into());
+
+        let original_msg = Message {
+            transaction_id: 3,
+            version: Some([1, 2, 3, 4]),
+            requester_ip: None,
+            read_only: false,
+            message_type: MessageType::Response(ResponseSpecific::NoValues(
+                NoValuesResponseArguments {
+                    responder_id: Id::random(),
+                    token: [99, 100, 101, 102].into(),
+                    nodes: None,
+                    bf_seeders: Some(BloomFilter::new()),
+                    bf_peers: Some(bf_peers),
                 },
             )),
         };
@@ -989,6 +1597,9 @@ mod tests {
                         id: Id::random().into(),
                         token: vec![0, 1].into(),
                         nodes: None,
+                        nodes6: None,
+                        bf_seeders: None,
+                        bf_peers: None,
                     },
                 },
             ),
@@ -1000,6 +1611,152 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_response_with_unknown_keys() {
+        fn string(bytes: &[u8]) -> Vec<u8> {
+            [format!("{}:", bytes.len()).as_bytes(), bytes].concat()
+        }
+        fn dict(mut entries: Vec<(&[u8], Vec<u8>)>) -> Vec<u8> {
+            entries.sort();
+
+            let mut bytes = vec![b'd'];
+            for (key, value) in entries {
+                bytes.extend(string(key));
+                bytes.extend(value);
+            }
+            bytes.push(b'e');
+
+            bytes
+        }
+
+        let responder_id = Id::random();
+        let node = Node::new(Id::random(), "1.2.3.4:6881".parse().unwrap());
+        let node6 = Node::new(Id::random(), "[2001:4860::1]:6881".parse().unwrap());
+        let peer: SocketAddrV4 = "5.6.7.8:9".parse().unwrap();
+        let requester_ip: SocketAddrV4 = "50.51.52.53:5455".parse().unwrap();
+
+        let arguments = dict(vec![
+            (b"id", string(responder_id.as_bytes())),
+            (b"token", string(&[1, 2, 3, 4])),
+            (b"nodes", string(&node.to_compact().unwrap())),
+            (b"nodes6", string(&node6.to_compact6().unwrap())),
+            (
+                b"values",
+                [b"l", &string(&sockaddr_to_bytes(&peer))[..], b"e"].concat(),
+            ),
+            // Nonstandard keys of all types.
+            (b"p", b"i6881e".to_vec()),
+            (b"junk", b"li1ei2ee".to_vec()),
+            (b"zz", b"d1:ai1ee".to_vec()),
+        ]);
+        let bytes = dict(vec![
+            (b"t", string(&[0, 7])),
+            (b"y", string(b"r")),
+            (b"r", arguments),
+            (b"v", string(b"LT01")),
+            (b"ip", string(&sockaddr_to_bytes(&requester_ip))),
+            (b"x_junk", string(b"foo")),
+            (b"extra", b"i-1e".to_vec()),
+        ]);
+
+        let parsed = Message::from_bytes(&bytes).unwrap();
+
+        assert_eq!(parsed.transaction_id, 7);
+        assert_eq!(parsed.version, Some(*b"LT01"));
+        assert_eq!(parsed.requester_ip, Some(requester_ip));
+
+        let MessageType::Response(ResponseSpecific::GetPeers(arguments)) = parsed.message_type
+        else {
+            panic!(
+                "expected a get_peers response, got {:?}",
+                parsed.message_type
+            )
+        };
+        assert_eq!(arguments.responder_id, responder_id);
+        assert_eq!(arguments.token.as_ref(), &[1, 2, 3, 4]);
+        assert_eq!(arguments.values, vec![peer]);
+        let addresses = arguments
+            .nodes
+            .unwrap()
+            .iter()
+            .map(|node| (*node.id(), node.address()))
+            .collect::<Vec<_>>();
+        assert!(addresses.contains(&(*node.id(), node.address())));
+        assert!(addresses.contains(&(*node6.id(), node6.address())));
+    }
+
+    #[test]
+    fn test_sample_infohashes_request() {
+        let original_msg = Message {
+            transaction_id: 258,
+            version: None,
+            requester_ip: None,
+            read_only: false,
+            message_type: MessageType::Request(RequestSpecific::sample_infohashes(
+                Id::random(),
+                Id::random(),
+            )),
+        };
+
+        let serde_msg = original_msg.clone().into_serde_message();
+        let bytes = serde_msg.to_bytes().unwrap();
+        let parsed_serde_msg = internal::DHTMessage::from_bytes(&bytes).unwrap();
+        let parsed_msg = Message::from_serde_message(parsed_serde_msg).unwrap();
+        assert_eq!(parsed_msg, original_msg);
+    }
+
+    #[test]
+    fn test_sample_infohashes_response() {
+        let original_msg = Message {
+            transaction_id: 3,
+            version: Some([1, 2, 3, 4]),
+            requester_ip: None,
+            read_only: false,
+            message_type: MessageType::Response(ResponseSpecific::SampleInfohashes(
+                SampleInfohashesResponseArguments {
+                    responder_id: Id::random(),
+                    interval: 21600,
+                    nodes: None,
+                    num: 10,
+                    samples: [Id::random(), Id::random()].into(),
+                },
+            )),
+        };
+
+        let serde_msg = original_msg.clone().into_serde_message();
+        let bytes = serde_msg.to_bytes().unwrap();
+        let parsed_serde_msg = internal::DHTMessage::from_bytes(&bytes).unwrap();
+        let parsed_msg = Message::from_serde_message(parsed_serde_msg).unwrap();
+        assert_eq!(parsed_msg, original_msg);
+    }
+
+    #[test]
+    fn test_sample_infohashes_invalid_samples() {
+        let serde_message = internal::DHTMessage {
+            ip: None,
+            read_only: None,
+            transaction_id: [1, 2],
+            version: None,
+            variant: internal::DHTMessageVariant::Response(
+                internal::DHTResponseSpecific::SampleInfohashes {
+                    arguments: internal::DHTSampleInfohashesResponseArguments {
+                        id: Id::random().into(),
+                        interval: 0,
+                        nodes: None,
+                        nodes6: None,
+                        num: 1,
+                        samples: vec![0; 30].into(),
+                    },
+                },
+            ),
+        };
+
+        assert!(matches!(
+            Message::from_serde_message(serde_message),
+            Err(DecodeMessageError::InvalidSamples)
+        ));
+    }
+
     #[test]
     fn test_get_immutable_request() {
         let original_msg = Message {
@@ -1014,6 +1771,7 @@ mod tests {
                     seq: Some(1231),
                     salt: None,
                 }),
+                want: Vec::new(),
             }),
         };
 
@@ -1066,6 +1824,7 @@ mod tests {
                         },
                     ),
                 }),
+                want: Vec::new(),
             }),
         };
 
@@ -1076,6 +1835,142 @@ mod tests {
         assert_eq!(parsed_msg, original_msg);
     }
 
+    #[test]
+    fn request_constructors() {
+        let requester_id = Id::random();
+        let target = Id::random();
+
+        for request in [
+            RequestSpecific::ping(requester_id),
+            RequestSpecific::find_node(requester_id, target),
+            RequestSpecific::get_peers(requester_id, target),
+            RequestSpecific::announce_peer(
+                requester_id,
+                target,
+                AnnounceOptions {
+                    port: Some(6881),
+                    implied_port: false,
+                },
+                [1, 2, 3, 4].into(),
+            )
+            .unwrap(),
+        ] {
+            let message = Message {
+                transaction_id: 1,
+                version: None,
+                requester_ip: None,
+                read_only: false,
+                message_type: MessageType::Request(request),
+            };
+
+            // Survives encoding, where a missing implied_port is decoded as `Some(false)`.
+            let bytes = message.to_bytes().unwrap();
+            let decoded = Message::from_bytes(&bytes).unwrap();
+            assert_eq!(decoded.to_bytes().unwrap(), bytes);
+            assert_eq!(decoded.get_author_id(), Some(requester_id));
+        }
+
+        assert_eq!(
+            RequestSpecific::announce_peer(
+                requester_id,
+                target,
+                AnnounceOptions::default(),
+                [].into()
+            ),
+            Err(InvalidRequestError::EmptyToken)
+        );
+        assert_eq!(
+            RequestSpecific::announce_peer(
+                requester_id,
+                target,
+                AnnounceOptions {
+                    port: Some(0),
+                    implied_port: false,
+                },
+                [1].into()
+            ),
+            Err(InvalidRequestError::ZeroPort)
+        );
+    }
+
+    #[test]
+    fn test_announce_peer_request() {
+        let info_hash = Id::random();
+
+        for (options, expected_port, implied_port) in [
+            (
+                AnnounceOptions {
+                    port: Some(6881),
+                    implied_port: false,
+                },
+                6881,
+                0,
+            ),
+            (
+                AnnounceOptions {
+                    port: None,
+                    implied_port: false,
+                },
+                0,
+                1,
+            ),
+            // The contradicting port isn't sent.
+            (
+                AnnounceOptions {
+                    port: Some(6881),
+                    implied_port: true,
+                },
+                0,
+                1,
+            ),
+        ] {
+            let original_msg = Message {
+                transaction_id: 3,
+                version: None,
+                requester_ip: None,
+                read_only: false,
+                message_type: MessageType::Request(RequestSpecific {
+                    requester_id: Id::random(),
+                    request_type: RequestTypeSpecific::Put(PutRequest {
+                        token: [99, 100, 101, 102].into(),
+                        put_request_type: PutRequestSpecific::AnnouncePeer(
+                            AnnouncePeerRequestArguments::new(info_hash, options),
+                        ),
+                    }),
+                    want: Vec::new(),
+                }),
+            };
+
+            let bytes = original_msg.to_bytes().unwrap();
+            let encoded = String::from_utf8_lossy(&bytes);
+            assert!(
+                encoded.contains(&format!("12:implied_porti{implied_port}e")),
+                "{encoded}"
+            );
+            assert!(
+                encoded.contains(&format!("4:porti{expected_port}e")),
+                "{encoded}"
+            );
+
+            let parsed_msg = Message::from_bytes(&bytes).unwrap();
+            let expected_implied_port = Some(implied_port == 1);
+            match parsed_msg.message_type {
+                MessageType::Request(RequestSpecific {
+                    request_type:
+                        RequestTypeSpecific::Put(PutRequest {
+                            put_request_type: PutRequestSpecific::AnnouncePeer(arguments),
+                            ..
+                        }),
+                    ..
+                }) => {
+                    assert_eq!(arguments.port, expected_port);
+                    assert_eq!(arguments.implied_port, expected_implied_port);
+                }
+                _ => panic!("expected announce_peer request"),
+            }
+        }
+    }
+
     #[test]
     fn test_put_mutable_request() {
         let original_msg = Message {
@@ -1097,6 +1992,7 @@ mod tests {
                         cas: Some(100),
                     }),
                 }),
+                want: Vec::new(),
             }),
         };
 
@@ -1106,4 +2002,63 @@ mod tests {
         let parsed_msg = Message::from_serde_message(parsed_serde_msg).unwrap();
         assert_eq!(parsed_msg, original_msg);
     }
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut bytes = vec![0; len];
+        getrandom::getrandom(&mut bytes).unwrap();
+
+        bytes
+    }
+
+    #[test]
+    fn decode_random_bytes() {
+        for len in 0..1024 {
+            let mut bytes = random_bytes(len);
+            let _ = Message::from_bytes(&bytes);
+
+            // Get past the cheap checks, into the bencode parser.
+            if let Some(first) = bytes.first_mut() {
+                *first = b'd';
+            }
+            let _ = Message::from_bytes(&bytes);
+
+            let _ = bytes_to_nodes4(&bytes);
+            let _ = bytes_to_nodes6(&bytes);
+        }
+    }
+
+    #[test]
+    fn decode_truncated_message() {
+        let message = Message {
+            transaction_id: 3,
+            version: Some([1, 2, 3, 4]),
+            requester_ip: Some("50.51.52.53:5455".parse().unwrap()),
+            read_only: false,
+            message_type: MessageType::Response(ResponseSpecific::FindNode(
+                FindNodeResponseArguments {
+                    responder_id: Id::random(),
+                    nodes: (0..8)
+                        .map(|i| Node::new(Id::random(), ([10, 0, 0, i], 6881).into()))
+                        .collect(),
+                },
+            )),
+        };
+        let bytes = message.to_bytes().unwrap();
+
+        for len in 0..bytes.len() {
+            assert!(Message::from_bytes(&bytes[..len]).is_err());
+        }
+
+        // Corrupt the nodes length so it isn't a multiple of 26 anymore.
+        let nodes = b"5:nodes208:";
+        let index = bytes
+            .windows(nodes.len())
+            .position(|window| window == nodes)
+            .unwrap();
+        let mut corrupted = bytes[..index].to_vec();
+        corrupted.extend_from_slice(b"5:nodes207:");
+        corrupted.extend_from_slice(&bytes[index + nodes.len() + 1..]);
+
+        assert!(Message::from_bytes(&corrupted).is_err());
+    }
 }
@@ -87,6 +87,12 @@ pub enum DHTRequestSpecific {
         #[serde(rename = "a")]
         arguments: DHTPutValueRequestArguments,
     },
+
+    #[serde(rename = "sample_infohashes")]
+    SampleInfohashes {
+        #[serde(rename = "a")]
+        arguments: DHTSampleInfohashesRequestArguments,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -117,6 +123,11 @@ pub enum DHTResponseSpecific {
         arguments: DHTNoValuesResponseArguments,
     },
 
+    SampleInfohashes {
+        #[serde(rename = "r")]
+        arguments: DHTSampleInfohashesResponseArguments,
+    },
+
     FindNode {
         #[serde(rename = "r")]
         arguments: DHTFindNodeResponseArguments,
@@ -134,13 +145,6 @@ pub struct DHTErrorSpecific {
     pub error_info: (i32, String),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
-pub enum DHTErrorValue {
-    #[serde(rename = "")]
-    ErrorCode(i32),
-    ErrorDescription(String),
-}
-
 // === PING ===
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -164,6 +168,9 @@ pub struct DHTFindNodeRequestArguments {
 
     #[serde(with = "serde_bytes")]
     pub target: [u8; 20],
+
+    #[serde(default)]
+    pub want: Option<Vec<ByteBuf>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -173,6 +180,10 @@ pub struct DHTFindNodeResponseArguments {
 
     #[serde(with = "serde_bytes")]
     pub nodes: Box<[u8]>,
+
+    #[serde(with = "serde_bytes")]
+    #[serde(default)]
+    pub nodes6: Option<Box<[u8]>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -186,6 +197,18 @@ pub struct DHTNoValuesResponseArguments {
     #[serde(with = "serde_bytes")]
     #[serde(default)]
     pub nodes: Option<Box<[u8]>>,
+
+    #[serde(with = "serde_bytes")]
+    #[serde(default)]
+    pub nodes6: Option<Box<[u8]>>,
+
+    #[serde(rename = "BFsd", with = "serde_bytes")]
+    #[serde(default)]
+    pub bf_seeders: Option<Box<[u8]>>,
+
+    #[serde(rename = "BFpe", with = "serde_bytes")]
+    #[serde(default)]
+    pub bf_peers: Option<Box<[u8]>>,
 }
 
 // === Get Peers ===
@@ -197,6 +220,12 @@ pub struct DHTGetPeersRequestArguments {
 
     #[serde(with = "serde_bytes")]
     pub info_hash: [u8; 20],
+
+    #[serde(default)]
+    pub want: Option<Vec<ByteBuf>>,
+
+    #[serde(default)]
+    pub scrape: Option<u8>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -211,9 +240,59 @@ pub struct DHTGetPeersResponseArguments {
     #[serde(default)]
     pub nodes: Option<Box<[u8]>>,
 
+    #[serde(with = "serde_bytes")]
+    #[serde(default)]
+    pub nodes6: Option<Box<[u8]>>,
+
     // values are not optional, because if they are missing this missing
     // we can just treat this as DHTNoValuesResponseArguments
     pub values: Vec<ByteBuf>,
+
+    #[serde(rename = "BFsd", with = "serde_bytes")]
+    #[serde(default)]
+    pub bf_seeders: Option<Box<[u8]>>,
+
+    #[serde(rename = "BFpe", with = "serde_bytes")]
+    #[serde(default)]
+    pub bf_peers: Option<Box<[u8]>>,
+}
+
+// === Sample Infohashes ===
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DHTSampleInfohashesRequestArguments {
+    #[serde(with = "serde_bytes")]
+    pub id: [u8; 20],
+
+    #[serde(with = "serde_bytes")]
+    pub target: [u8; 20],
+
+    #[serde(default)]
+    pub want: Option<Vec<ByteBuf>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DHTSampleInfohashesResponseArguments {
+    #[serde(with = "serde_bytes")]
+    pub id: [u8; 20],
+
+    #[serde(default)]
+    pub interval: u32,
+
+    #[serde(with = "serde_bytes")]
+    #[serde(default)]
+    pub nodes: Option<Box<[u8]>>,
+
+    #[serde(with = "serde_bytes")]
+    #[serde(default)]
+    pub nodes6: Option<Box<[u8]>>,
+
+    #[serde(default)]
+    pub num: u32,
+
+    // Not optional, distinguishing this response from a find_node response.
+    #[serde(with = "serde_bytes")]
+    pub samples: Box<[u8]>,
 }
 
 // === Announce Peer ===
@@ -247,6 +326,9 @@ pub struct DHTGetValueRequestArguments {
 
     #[serde(default)]
     pub seq: Option<i64>,
+
+    #[serde(default)]
+    pub want: Option<Vec<ByteBuf>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -261,6 +343,10 @@ pub struct DHTGetImmutableResponseArguments {
     #[serde(default)]
     pub nodes: Option<Box<[u8]>>,
 
+    #[serde(with = "serde_bytes")]
+    #[serde(default)]
+    pub nodes6: Option<Box<[u8]>>,
+
     #[serde(with = "serde_bytes")]
     pub v: Box<[u8]>,
 }
@@ -277,6 +363,10 @@ pub struct DHTNoMoreRecentValueResponseArguments {
     #[serde(default)]
     pub nodes: Option<Box<[u8]>>,
 
+    #[serde(with = "serde_bytes")]
+    #[serde(default)]
+    pub nodes6: Option<Box<[u8]>>,
+
     pub seq: i64,
 }
 
@@ -292,6 +382,10 @@ pub struct DHTGetMutableResponseArguments {
     #[serde(default)]
     pub nodes: Option<Box<[u8]>>,
 
+    #[serde(with = "serde_bytes")]
+    #[serde(default)]
+    pub nodes6: Option<Box<[u8]>>,
+
     #[serde(with = "serde_bytes")]
     pub v: Box<[u8]>,
 
@@ -1,6 +1,7 @@
 //! Helper functions and structs for mutable items.
 
 use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 use sha1_smol::Sha1;
 use std::convert::TryFrom;
@@ -9,7 +10,8 @@ use crate::Id;
 
 use super::PutMutableRequestArguments;
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 /// [BEP_0044](https://www.bittorrent.org/beps/bep_0044.html)'s Mutable item.
 pub struct MutableItem {
     /// hash of the key and optional salt
@@ -21,7 +23,7 @@ pub struct MutableItem {
     /// mutable value
     pub(crate) value: Box<[u8]>,
     /// ed25519 signature
-    #[serde(with = "serde_bytes")]
+    #[cfg_attr(feature = "serde", serde(with = "serde_bytes"))]
     signature: [u8; 64],
     /// Optional salt
     salt: Option<Box<[u8]>>,
@@ -1,12 +1,21 @@
 //! Struct and implementation of the Node entry in the Kademlia routing table
 use std::{
     fmt::{self, Debug, Formatter},
-    net::SocketAddrV4,
+    net::{SocketAddr, SocketAddrV4},
     sync::Arc,
     time::{Duration, Instant},
 };
 
-use crate::common::Id;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::common::{
+    messages::{
+        bytes_to_nodes4, bytes_to_nodes6, sockaddr6_to_bytes, sockaddr_to_bytes, NODE6_BYTE_SIZE,
+        NODE_BYTE_SIZE,
+    },
+    DecodeMessageError, Id, ID_SIZE,
+};
 
 /// The age of a node's last_seen time before it is considered stale and removed from a full bucket
 /// on inserting a new node.
@@ -14,11 +23,26 @@ pub const STALE_TIME: Duration = Duration::from_secs(15 * 60);
 const MIN_PING_BACKOFF_INTERVAL: Duration = Duration::from_secs(10);
 pub const TOKEN_ROTATE_INTERVAL: Duration = Duration::from_secs(60 * 5);
 
+/// Liveness status of a [Node] in the routing table.
+///
+/// Read [BEP_0005](https://www.bittorrent.org/beps/bep_0005.html#routing-table) for more information.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeStatus {
+    /// Node responded to one of our requests within the last [STALE_TIME].
+    Good,
+    /// Node has not been seen for longer than [STALE_TIME], and should be pinged or replaced.
+    Questionable,
+}
+
 #[derive(PartialEq)]
 pub(crate) struct NodeInner {
     pub(crate) id: Id,
-    pub(crate) address: SocketAddrV4,
+    pub(crate) address: SocketAddr,
     pub(crate) token: Option<Box<[u8]>>,
+    /// Round trip time of the last request to this node that got a response.
+    pub(crate) rtt: Option<Duration>,
+    /// Client version from the `v` field of this node's last message.
+    pub(crate) version: Option<[u8; 4]>,
     pub(crate) last_seen: Instant,
 }
 
@@ -26,8 +50,10 @@ impl NodeInner {
     pub fn random() -> Self {
         Self {
             id: Id::random(),
-            address: SocketAddrV4::new(0.into(), 0),
+            address: SocketAddrV4::new(0.into(), 0).into(),
             token: None,
+            rtt: None,
+            version: None,
             last_seen: Instant::now(),
         }
     }
@@ -42,31 +68,97 @@ impl Debug for Node {
         fmt.debug_struct("Node")
             .field("id", &self.0.id)
             .field("address", &self.0.address)
+            .field("rtt", &self.0.rtt)
+            .field("version", &self.0.version)
             .field("last_seen", &self.0.last_seen.elapsed().as_secs())
             .finish()
     }
 }
 
 impl Node {
-    /// Creates a new Node from an id and socket address.
-    pub fn new(id: Id, address: SocketAddrV4) -> Node {
+    /// Creates a new Node from an id and an Ipv4 or Ipv6 socket address.
+    ///
+    /// Ipv4-mapped Ipv6 addresses (`::ffff:a.b.c.d`) are converted to the Ipv4
+    /// address they map to.
+    pub fn new(id: Id, address: SocketAddr) -> Node {
         Node(Arc::new(NodeInner {
             id,
-            address,
+            address: canonical_address(address),
             token: None,
+            rtt: None,
+            version: None,
             last_seen: Instant::now(),
         }))
     }
 
-    pub(crate) fn new_with_token(id: Id, address: SocketAddrV4, token: Box<[u8]>) -> Self {
+    pub(crate) fn new_with_token(id: Id, address: SocketAddr, token: Box<[u8]>) -> Self {
         Node(Arc::new(NodeInner {
             id,
-            address,
+            address: canonical_address(address),
             token: Some(token),
+            rtt: None,
+            version: None,
             last_seen: Instant::now(),
         }))
     }
 
+    /// Returns a copy of this node with a measured round trip time.
+    pub(crate) fn with_rtt(self, rtt: Option<Duration>) -> Self {
+        Node(Arc::new(NodeInner {
+            id: self.0.id,
+            address: self.0.address,
+            token: self.0.token.clone(),
+            rtt,
+            version: self.0.version,
+            last_seen: self.0.last_seen,
+        }))
+    }
+
+    /// Returns a copy of this node with the client version from its message.
+    pub(crate) fn with_version(self, version: Option<[u8; 4]>) -> Self {
+        Node(Arc::new(NodeInner {
+            id: self.0.id,
+            address: self.0.address,
+            token: self.0.token.clone(),
+            rtt: self.0.rtt,
+            version,
+            last_seen: self.0.last_seen,
+        }))
+    }
+
+    /// Returns a copy of this node with a token to store data at it.
+    pub(crate) fn with_token(self, token: Option<Box<[u8]>>) -> Self {
+        Node(Arc::new(NodeInner {
+            id: self.0.id,
+            address: self.0.address,
+            token,
+            rtt: self.0.rtt,
+            version: self.0.version,
+            last_seen: self.0.last_seen,
+        }))
+    }
+
+    /// Returns this node, an entry for the same Id seen again, keeping what
+    /// `previous` knew but this entry doesn't, like its round trip time.
+    ///
+    /// The token is only kept if the address didn't change, since it is bound to it.
+    pub(crate) fn refreshed_from(self, previous: &Node) -> Self {
+        let same_address = self.same_address(previous);
+
+        Node(Arc::new(NodeInner {
+            id: self.0.id,
+            address: self.0.address,
+            token: self
+                .0
+                .token
+                .clone()
+                .or_else(|| same_address.then(|| previous.0.token.clone()).flatten()),
+            rtt: self.0.rtt.or(previous.0.rtt),
+            version: self.0.version.or(previous.0.version),
+            last_seen: self.0.last_seen.max(previous.0.last_seen),
+        }))
+    }
+
     /// Creates a node with random Id for testing purposes.
     pub fn random() -> Node {
         Node(Arc::new(NodeInner::random()))
@@ -75,7 +167,45 @@ impl Node {
     /// Create a node that is unique per `i` as it has a random Id and sets IP and port to `i`
     #[cfg(test)]
     pub fn unique(i: usize) -> Node {
-        Node::new(Id::random(), SocketAddrV4::new((i as u32).into(), i as u16))
+        Node::new(
+            Id::random(),
+            SocketAddrV4::new((i as u32).into(), i as u16).into(),
+        )
+    }
+
+    /// Decode a node from its compact node info (20 bytes Id + 6 bytes Ipv4 address).
+    ///
+    /// Returns an error if `bytes` is not exactly 26 bytes long.
+    pub fn from_compact(bytes: &[u8]) -> Result<Node, DecodeMessageError> {
+        if bytes.len() != NODE_BYTE_SIZE {
+            return Err(DecodeMessageError::InvalidNodes4);
+        }
+
+        Ok(bytes_to_nodes4(bytes)?[0].clone())
+    }
+
+    /// Decode a node from its compact Ipv6 node info (20 bytes Id + 18 bytes Ipv6 address),
+    /// as used in the `nodes6` field of responses, see [BEP_0032](https://www.bittorrent.org/beps/bep_0032.html).
+    ///
+    /// Returns an error if `bytes` is not exactly 38 bytes long. An Ipv4-mapped
+    /// address is decoded as an Ipv4 node.
+    pub fn from_compact6(bytes: &[u8]) -> Result<Node, DecodeMessageError> {
+        if bytes.len() != NODE6_BYTE_SIZE {
+            return Err(DecodeMessageError::InvalidNodes6);
+        }
+
+        Ok(bytes_to_nodes6(bytes)?[0].clone())
+    }
+
+    /// Decode a list of nodes in the compact format used in the `nodes` field of responses.
+    ///
+    /// Trailing bytes that don't make up a whole node are ignored.
+    pub fn from_compact_list(bytes: &[u8]) -> Vec<Node> {
+        let len = bytes.len() - (bytes.len() % NODE_BYTE_SIZE);
+
+        bytes_to_nodes4(&bytes[..len])
+            .map(Vec::from)
+            .unwrap_or_default()
     }
 
     // === Getters ===
@@ -86,7 +216,7 @@ impl Node {
     }
 
     /// Returns the address of this node
-    pub fn address(&self) -> SocketAddrV4 {
+    pub fn address(&self) -> SocketAddr {
         self.0.address
     }
 
@@ -95,6 +225,66 @@ impl Node {
         self.0.token.clone()
     }
 
+    /// Returns the round trip time of the last request to this node that got a response, if known.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.0.rtt
+    }
+
+    /// Returns the client version from the `v` field of this node's last message, if any.
+    ///
+    /// Usually two bytes identifying the client, followed by two bytes of its version.
+    pub fn version(&self) -> Option<[u8; 4]> {
+        self.0.version
+    }
+
+    /// Returns the last time this node responded to one of our requests.
+    pub fn last_seen(&self) -> Instant {
+        self.0.last_seen
+    }
+
+    /// Returns the liveness status of this node.
+    pub fn status(&self) -> NodeStatus {
+        if self.is_stale() {
+            NodeStatus::Questionable
+        } else {
+            NodeStatus::Good
+        }
+    }
+
+    /// Encode this node in the compact node info format (20 bytes Id + 6 bytes Ipv4 address).
+    ///
+    /// Returns `None` for Ipv6 nodes.
+    pub fn to_compact(&self) -> Option<[u8; NODE_BYTE_SIZE]> {
+        match self.0.address {
+            SocketAddr::V4(address) => {
+                let mut bytes = [0; NODE_BYTE_SIZE];
+
+                bytes[..ID_SIZE].copy_from_slice(self.0.id.as_bytes());
+                bytes[ID_SIZE..].copy_from_slice(&sockaddr_to_bytes(&address));
+
+                Some(bytes)
+            }
+            SocketAddr::V6(_) => None,
+        }
+    }
+
+    /// Encode this node in the compact Ipv6 node info format (20 bytes Id + 18 bytes Ipv6 address).
+    ///
+    /// Returns `None` for Ipv4 nodes.
+    pub fn to_compact6(&self) -> Option<[u8; NODE6_BYTE_SIZE]> {
+        match self.0.address {
+            SocketAddr::V4(_) => None,
+            SocketAddr::V6(address) => {
+                let mut bytes = [0; NODE6_BYTE_SIZE];
+
+                bytes[..ID_SIZE].copy_from_slice(self.0.id.as_bytes());
+                bytes[ID_SIZE..].copy_from_slice(&sockaddr6_to_bytes(&address));
+
+                Some(bytes)
+            }
+        }
+    }
+
     /// Node is last seen more than a threshold ago.
     pub fn is_stale(&self) -> bool {
         self.0.last_seen.elapsed() > STALE_TIME
@@ -123,20 +313,170 @@ impl Node {
     ///
     /// Check [BEP_0042](https://www.bittorrent.org/beps/bep_0042.html).
     pub fn is_secure(&self) -> bool {
-        self.0.id.is_valid_for_ip(*self.0.address.ip())
+        self.0.id.is_valid_for_ip(self.0.address.ip())
     }
 
     /// Returns true if Any of the existing nodes:
     ///  - Have the same IP as this node, And:
-    ///     = The existing nodes is Not secure.
-    ///     = The existing nodes is secure And shares the same first 21 bits.
+    ///    = The existing nodes is Not secure.
+    ///    = The existing nodes is secure And shares the same first 21 bits.
     ///
     /// Effectively, allows only One non-secure node or Eight secure nodes from the same IP, in the routing table or ClosestNodes.
-    pub(crate) fn already_exists(&self, nodes: &[Self]) -> bool {
-        nodes.iter().any(|existing| {
+    pub(crate) fn already_exists<'a>(&self, nodes: impl IntoIterator<Item = &'a Self>) -> bool {
+        nodes.into_iter().any(|existing| {
             self.same_ip(existing)
                 && (!existing.is_secure()
                     || self.id().first_21_bits() == existing.id().first_21_bits())
         })
     }
 }
+
+#[cfg(feature = "serde")]
+/// Serialized form of a [Node], skipping its session-relative state,
+/// like its token and when it was last seen.
+#[derive(Serialize, Deserialize)]
+struct SerializedNode {
+    id: Id,
+    address: SocketAddr,
+    #[serde(default, with = "serde_bytes")]
+    version: Option<[u8; 4]>,
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for Node {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerializedNode {
+            id: self.0.id,
+            address: self.0.address,
+            version: self.0.version,
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Node {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Node, D::Error> {
+        let node = SerializedNode::deserialize(deserializer)?;
+
+        Ok(Node::new(node.id, node.address).with_version(node.version))
+    }
+}
+
+/// Some nodes encode Ipv4 addresses as Ipv4-mapped Ipv6 addresses, which are
+/// only reachable, and only comparable to other addresses, as Ipv4.
+fn canonical_address(address: SocketAddr) -> SocketAddr {
+    match address {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddrV4::new(ip, v6.port()).into(),
+            None => address,
+        },
+        address => address,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde() {
+        let node = Node::unique(1).with_version(Some(*b"LT01"));
+
+        let bytes = serde_bencode::to_bytes(&node).unwrap();
+        let decoded: Node = serde_bencode::from_bytes(&bytes).unwrap();
+
+        assert_eq!(decoded.id(), node.id());
+        assert_eq!(decoded.address(), node.address());
+        assert_eq!(decoded.version(), node.version());
+
+        // Ipv6 and missing versions.
+        let node = Node::new(Id::random(), "[::1]:6881".parse().unwrap());
+
+        let bytes = serde_bencode::to_bytes(&node).unwrap();
+        let decoded: Node = serde_bencode::from_bytes(&bytes).unwrap();
+
+        assert_eq!(decoded.address(), node.address());
+        assert_eq!(decoded.version(), None);
+
+        // Other formats.
+        for node in [node, Node::unique(2).with_version(Some(*b"LT01"))] {
+            let json = serde_json::to_string(&node).unwrap();
+            let decoded: Node = serde_json::from_str(&json).unwrap();
+            assert_eq!(decoded.id(), node.id());
+            assert_eq!(decoded.address(), node.address());
+            assert_eq!(decoded.version(), node.version());
+
+            let bytes = bincode::serialize(&node).unwrap();
+            let decoded: Node = bincode::deserialize(&bytes).unwrap();
+            assert_eq!(decoded.id(), node.id());
+            assert_eq!(decoded.address(), node.address());
+            assert_eq!(decoded.version(), node.version());
+        }
+    }
+
+    #[test]
+    fn compact() {
+        let node = Node::new(
+            Id::random(),
+            SocketAddrV4::new([127, 0, 0, 1].into(), 6881).into(),
+        );
+
+        let bytes = node.to_compact().unwrap();
+        let decoded = Node::from_compact(&bytes).unwrap();
+
+        assert_eq!(decoded.id(), node.id());
+        assert_eq!(decoded.address(), node.address());
+
+        assert!(Node::from_compact(&bytes[..25]).is_err());
+        assert!(Node::from_compact(&[bytes, bytes].concat()).is_err());
+
+        let ipv6 = Node::new(Id::random(), "[::1]:6881".parse().unwrap());
+        assert_eq!(ipv6.to_compact(), None);
+    }
+
+    #[test]
+    fn compact6() {
+        let node = Node::new(Id::random(), "[2001:4860::1]:6881".parse().unwrap());
+
+        let bytes = node.to_compact6().unwrap();
+        let decoded = Node::from_compact6(&bytes).unwrap();
+
+        assert_eq!(decoded.id(), node.id());
+        assert_eq!(decoded.address(), node.address());
+
+        assert!(Node::from_compact6(&bytes[..37]).is_err());
+        assert!(Node::from_compact6(&[bytes, bytes].concat()).is_err());
+        // A compact Ipv4 node info is not mistaken for an Ipv6 one.
+        assert!(Node::from_compact6(&Node::unique(1).to_compact().unwrap()).is_err());
+
+        assert_eq!(Node::unique(1).to_compact6(), None);
+    }
+
+    #[test]
+    fn ipv4_mapped() {
+        let node = Node::new(Id::random(), "[::ffff:1.2.3.4]:6881".parse().unwrap());
+
+        assert_eq!(node.address(), "1.2.3.4:6881".parse().unwrap());
+        assert!(node.to_compact().is_some());
+
+        let ipv6 = Node::new(Id::random(), "[2001:4860::1]:6881".parse().unwrap());
+        assert!(ipv6.address().is_ipv6());
+    }
+
+    #[test]
+    fn compact_list() {
+        let a = Node::unique(1);
+        let b = Node::unique(2);
+
+        let mut bytes = [a.to_compact().unwrap(), b.to_compact().unwrap()].concat();
+        bytes.extend_from_slice(&[0; 10]);
+
+        let nodes = Node::from_compact_list(&bytes);
+
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].id(), a.id());
+        assert_eq!(nodes[1].address(), b.address());
+    }
+}
@@ -0,0 +1,77 @@
+//! Source of randomness for jittering timers, for them to be testable deterministically.
+
+use std::fmt::Debug;
+use std::time::Duration;
+
+/// Source of random numbers.
+pub(crate) trait Rng: Debug + Send + Sync {
+    /// Returns a random number in `[0, 1)`.
+    fn next_f64(&self) -> f64;
+
+    /// Returns `duration` randomly shifted by up to `fraction` (clamped to `[0, 1]`)
+    /// of itself, earlier or later.
+    ///
+    /// Returns `duration` unchanged if `fraction` is NaN or infinite.
+    fn jitter(&self, duration: Duration, fraction: f64) -> Duration {
+        if !fraction.is_finite() {
+            return duration;
+        }
+
+        let fraction = fraction.clamp(0.0, 1.0);
+
+        duration.mul_f64(1.0 + fraction * (2.0 * self.next_f64() - 1.0))
+    }
+}
+
+/// [Rng] backed by [getrandom].
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SystemRng;
+
+impl Rng for SystemRng {
+    fn next_f64(&self) -> f64 {
+        let mut bytes = [0_u8; 8];
+        getrandom::getrandom(&mut bytes).expect("getrandom");
+
+        // Keep the 53 bits an f64 can represent exactly.
+        (u64::from_be_bytes(bytes) >> 11) as f64 / (1_u64 << 53) as f64
+    }
+}
+
+/// [Rng] always returning the same number.
+#[cfg(test)]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct MockRng(pub f64);
+
+#[cfg(test)]
+impl Rng for MockRng {
+    fn next_f64(&self) -> f64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn jitter_bounds() {
+        let duration = Duration::from_secs(100);
+
+        assert_eq!(MockRng(0.0).jitter(duration, 0.1), Duration::from_secs(90));
+        assert_eq!(MockRng(0.5).jitter(duration, 0.1), duration);
+        assert_eq!(MockRng(0.0).jitter(duration, 0.0), duration);
+        // Never negative.
+        assert_eq!(MockRng(0.0).jitter(duration, 2.0), Duration::ZERO);
+        // Not a fraction.
+        assert_eq!(MockRng(0.0).jitter(duration, f64::NAN), duration);
+        assert_eq!(MockRng(0.0).jitter(duration, f64::INFINITY), duration);
+        assert_eq!(MockRng(0.0).jitter(duration, f64::NEG_INFINITY), duration);
+
+        for _ in 0..100 {
+            let jittered = SystemRng.jitter(duration, 0.1);
+
+            assert!(jittered >= Duration::from_secs(90));
+            assert!(jittered < Duration::from_secs(110));
+        }
+    }
+}
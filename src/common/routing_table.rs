@@ -1,19 +1,74 @@
 //! Simplified Kademlia routing table
 
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::path::Path;
 use std::slice::Iter;
+use std::sync::{mpsc::SyncSender, Arc};
+use std::time::{Duration, Instant};
 
-use crate::common::{Id, Node};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::common::{
+    messages::{bytes_to_nodes4, nodes4_to_bytes},
+    BootstrapSources, DecodeMessageError, EvictionPolicy, Id, KeepVerifiedLive, Node, NodeStatus,
+};
 use crate::rpc::ClosestNodes;
 
 /// K = the default maximum size of a k-bucket.
 pub const MAX_BUCKET_SIZE_K: usize = 20;
 
+/// Minimum number of non-empty buckets for a [RoutingTable] to be
+/// [bootstrapped](RoutingTable::is_bootstrapped).
+pub const MIN_BOOTSTRAPPED_BUCKETS: usize = 4;
+
 #[derive(Debug, Clone)]
 /// Simplified Kademlia routing table
+///
+/// Nodes are kept in one [KBucket] per [distance](Id::distance) from this table's [Id],
+/// which is the layout of a Kademlia table where the bucket covering the local Id
+/// was split all the way down, so nodes near the local Id are never discarded
+/// for lack of a split. Each bucket is capped at [Self::k] nodes, evicting
+/// nodes chosen by an [EvictionPolicy] to make room for new ones.
 pub struct RoutingTable {
     id: Id,
     buckets: BTreeMap<u8, KBucket>,
+    k: usize,
+    capacity: Option<usize>,
+    events: Option<SyncSender<RoutingTableEvent>>,
+    eviction: Arc<dyn EvictionPolicy>,
+    max_per_subnet: Option<usize>,
+    /// Number of nodes from each subnet, see [Self::subnet_counts].
+    subnets: BTreeMap<IpAddr, usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Changes to a [RoutingTable], see [RoutingTable::with_events].
+pub enum RoutingTableEvent {
+    /// A new node was added.
+    NodeAdded(Node),
+    /// A node was removed to make room for another.
+    NodeEvicted(Node),
+    /// An existing node was seen again, possibly at a new address.
+    NodeRefreshed(Id),
+}
+
+/// Summary of how populated a [RoutingTable] is, see [RoutingTable::health].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RoutingHealth {
+    /// Number of nodes in the table.
+    pub total_nodes: usize,
+    /// Number of buckets with at least one node.
+    pub buckets: usize,
+    /// Number of buckets with [k](RoutingTable::k) nodes.
+    pub full_buckets: usize,
+    /// Time since the least recently changed non-empty bucket had a node added or seen again,
+    /// `None` if the table is empty.
+    pub oldest_bucket_age: Option<Duration>,
+    /// Fraction of nodes that are not questionable, `0.0` if the table is empty.
+    pub live_ratio: f64,
 }
 
 impl RoutingTable {
@@ -21,7 +76,84 @@ impl RoutingTable {
     pub fn new(id: Id) -> Self {
         let buckets = BTreeMap::new();
 
-        RoutingTable { id, buckets }
+        RoutingTable {
+            id,
+            buckets,
+            k: MAX_BUCKET_SIZE_K,
+            capacity: None,
+            events: None,
+            eviction: Arc::new(KeepVerifiedLive),
+            max_per_subnet: None,
+            subnets: BTreeMap::new(),
+        }
+    }
+
+    /// Send [RoutingTableEvent]s to `tx` as nodes are added, evicted, or refreshed.
+    ///
+    /// Events are dropped if the channel is full or disconnected,
+    /// so a slow receiver never blocks adding nodes.
+    pub fn with_events(mut self, tx: SyncSender<RoutingTableEvent>) -> Self {
+        self.events = Some(tx);
+
+        self
+    }
+
+    /// Set the maximum number of nodes in this table, beyond which the furthest
+    /// node from this table's [Self::id] is evicted on insert.
+    ///
+    /// Defaults to None, where the table is only bounded by its buckets size.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity);
+
+        while self.size() > capacity {
+            self.remove_furthest();
+        }
+
+        self
+    }
+
+    /// Set the maximum number of nodes per bucket, and the number of nodes
+    /// returned from [Self::closest].
+    ///
+    /// Larger buckets make the table more resilient to churn, at the cost of more memory,
+    /// and larger responses to `find_node` and `get_peers` requests.
+    ///
+    /// Defaults to [MAX_BUCKET_SIZE_K]
+    pub fn with_k(mut self, k: usize) -> Self {
+        self.k = k.max(1);
+
+        for bucket in self.buckets.values_mut() {
+            bucket.k = self.k;
+        }
+
+        self
+    }
+
+    /// Set the [EvictionPolicy] choosing which node a full bucket replaces with a new one.
+    ///
+    /// Defaults to [KeepVerifiedLive]
+    pub fn with_eviction(mut self, policy: impl EvictionPolicy + 'static) -> Self {
+        self.eviction = Arc::new(policy);
+
+        for bucket in self.buckets.values_mut() {
+            bucket.eviction = self.eviction.clone();
+        }
+
+        self
+    }
+
+    /// Set the maximum number of nodes from the same /24 Ipv4 or /48 Ipv6 subnet,
+    /// beyond which nodes with new Ids from that subnet are rejected.
+    ///
+    /// Forces diversity of ips in the table, raising the cost of eclipsing this node
+    /// with many Ids from a few ips. Only applies to nodes added afterwards,
+    /// see [Self::subnet_counts].
+    ///
+    /// Defaults to None, where any number of nodes can share a subnet.
+    pub fn with_max_per_subnet(mut self, max: usize) -> Self {
+        self.max_per_subnet = Some(max.max(1));
+
+        self
     }
 
     /// Returns the [Id] of this node, where the distance is measured from.
@@ -29,6 +161,11 @@ impl RoutingTable {
         &self.id
     }
 
+    /// Returns the maximum number of nodes per bucket, see [Self::with_k].
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
     /// Returns the map of distances and their [KBucket]
     pub(crate) fn buckets(&self) -> &BTreeMap<u8, KBucket> {
         &self.buckets
@@ -37,6 +174,16 @@ impl RoutingTable {
     // === Public Methods ===
 
     /// Attempts to add a node to this routing table, and return `true` if it did.
+    ///
+    /// Adding a node with the same Id as an existing one updates it instead of
+    /// duplicating it: its address (if changed) and `last_seen` are refreshed, what
+    /// the new entry lacks (like its round trip time) is kept, and it moves to the
+    /// most recently seen end of its bucket. An address change is only accepted
+    /// if the new address is [secure](Node::is_secure), on the same ip, or the
+    /// existing entry is questionable, so a spoofed Id can't hijack a good node.
+    ///
+    /// A node with a new Id is added if its bucket has room, or the
+    /// [EvictionPolicy] chooses a node to replace.
     pub fn add(&mut self, node: Node) -> bool {
         let distance = self.id.distance(node.id());
 
@@ -45,17 +192,83 @@ impl RoutingTable {
             return false;
         }
 
-        if self
-            .buckets()
-            .values()
-            .any(|bucket| node.already_exists(&bucket.nodes))
-        {
+        // Nodes with the same Id are handled by the bucket, which either
+        // replaces or keeps the existing entry.
+        if self.buckets().values().any(|bucket| {
+            node.already_exists(bucket.iter().filter(|existing| existing.id() != node.id()))
+        }) {
             return false;
         };
 
-        let bucket = self.buckets.entry(distance).or_default();
+        if let Some(max) = self.max_per_subnet {
+            let subnet = subnet(node.address());
+            let is_new = !self
+                .buckets
+                .get(&distance)
+                .is_some_and(|bucket| bucket.contains(node.id()));
+
+            if is_new && self.subnets.get(&subnet).copied().unwrap_or_default() >= max {
+                return false;
+            }
+        }
+
+        let k = self.k;
+        let eviction = &self.eviction;
+        let bucket = self
+            .buckets
+            .entry(distance)
+            .or_insert_with(|| KBucket::with_eviction(k, eviction.clone()));
+
+        let refreshed = bucket.contains(node.id());
+        let before = self.events.as_ref().map(|_| bucket.nodes.clone());
+        // The bucket may evict a node, or move an existing one to a new address.
+        let subnets_before = bucket
+            .iter()
+            .map(|existing| subnet(existing.address()))
+            .collect::<Vec<_>>();
+
+        if !bucket.add(node.clone()) {
+            return false;
+        }
+
+        for subnet in subnets_before {
+            uncount_subnet(&mut self.subnets, subnet);
+        }
+        for existing in bucket.iter() {
+            *self.subnets.entry(subnet(existing.address())).or_default() += 1;
+        }
+
+        if let Some(before) = before {
+            let evicted = before
+                .into_iter()
+                .filter(|existing| existing.id() != node.id() && !bucket.contains(existing.id()))
+                .collect::<Vec<_>>();
+
+            for evicted in evicted {
+                self.emit(RoutingTableEvent::NodeEvicted(evicted));
+            }
+
+            self.emit(if refreshed {
+                RoutingTableEvent::NodeRefreshed(*node.id())
+            } else {
+                RoutingTableEvent::NodeAdded(node.clone())
+            });
+        }
+
+        if let Some(capacity) = self.capacity {
+            if self.size() > capacity {
+                let furthest = self.remove_furthest();
+                let added = furthest.as_ref().map(|furthest| furthest.id()) != Some(node.id());
 
-        bucket.add(node)
+                if let Some(furthest) = furthest {
+                    self.emit(RoutingTableEvent::NodeEvicted(furthest));
+                }
+
+                return added;
+            }
+        }
+
+        true
     }
 
     /// Remove a node from this routing table.
@@ -63,13 +276,37 @@ impl RoutingTable {
         let distance = self.id.distance(node_id);
 
         if let Some(bucket) = self.buckets.get_mut(&distance) {
+            if let Some(node) = bucket.iter().find(|node| node.id() == node_id) {
+                uncount_subnet(&mut self.subnets, subnet(node.address()));
+            }
+
             bucket.remove(node_id)
         }
     }
 
-    /// Return the closest nodes to the target while prioritizing secure nodes,
+    /// Returns the least recently seen node of the bucket `node` belongs to, if that bucket
+    /// is full and doesn't contain `node`, so adding `node` would evict a node.
+    ///
+    /// Useful to ping that node before adding `node`, and only [remove](Self::remove) it
+    /// if it doesn't respond, as in Kademlia.
+    pub fn oldest_if_full(&self, node: &Node) -> Option<&Node> {
+        let bucket = self.buckets.get(&self.id.distance(node.id()))?;
+
+        if bucket.nodes.len() < self.k || bucket.contains(node.id()) {
+            return None;
+        }
+
+        bucket.nodes.first()
+    }
+
+    /// Return the closest `k` nodes to the target while prioritizing secure nodes,
     /// as defined in [BEP_0042](https://www.bittorrent.org/beps/bep_0042.html)
     pub fn closest(&self, target: Id) -> Box<[Node]> {
+        self.closest_n(target, self.k)
+    }
+
+    /// Same as [Self::closest] but returns up to `n` nodes instead of `k`.
+    pub fn closest_n(&self, target: Id, n: usize) -> Box<[Node]> {
         let mut closest = ClosestNodes::new(target);
 
         for bucket in self.buckets.values() {
@@ -78,7 +315,35 @@ impl RoutingTable {
             }
         }
 
-        closest.nodes()[..MAX_BUCKET_SIZE_K.min(closest.len())].into()
+        let nodes = &closest.nodes()[..n.min(closest.len())];
+
+        // Secure nodes come first, each group is sorted by distance to the target.
+        debug_assert!(
+            nodes
+                .windows(2)
+                .all(|pair| match (pair[0].is_secure(), pair[1].is_secure()) {
+                    (false, true) => false,
+                    (true, false) => true,
+                    _ => target.cmp_distance(pair[0].id(), pair[1].id()) != Ordering::Greater,
+                }),
+            "closest nodes are not sorted secure first, then by distance to the target"
+        );
+
+        nodes.into()
+    }
+
+    /// Same as [Self::closest] but only returns Ipv6 nodes, for example for the
+    /// `nodes6` field of a response, see [Node::to_compact6].
+    ///
+    /// Distances are measured between Ids as for Ipv4 nodes, only the address family differs.
+    pub fn closest6(&self, target: Id) -> Box<[Node]> {
+        let mut closest = ClosestNodes::new(target);
+
+        for node in self.nodes().filter(|node| node.address().is_ipv6()) {
+            closest.add(node);
+        }
+
+        closest.nodes().iter().take(self.k).cloned().collect()
     }
 
     /// Secure version of [Self::closest] that tries to circumvent sybil attacks.
@@ -111,8 +376,22 @@ impl RoutingTable {
             .fold(0, |acc, bucket| acc + bucket.nodes.len())
     }
 
-    /// Returns an iterator over the nodes in this routing table.
-    pub fn nodes(&self) -> RoutingTableIterator {
+    /// Alias for [Self::size].
+    pub fn len(&self) -> usize {
+        self.size()
+    }
+
+    /// Returns an iterator over references to the nodes in this routing table,
+    /// from the closest bucket to the furthest, each from its least recently seen node.
+    ///
+    /// Same order as [Self::nodes], without cloning the nodes, for example
+    /// to snapshot or analyze a large table.
+    pub fn iter(&self) -> impl Iterator<Item = &Node> {
+        self.buckets.values().flat_map(KBucket::iter)
+    }
+
+    /// Returns an iterator over the nodes in this routing table, see [Self::iter].
+    pub fn nodes(&self) -> RoutingTableIterator<'_> {
         RoutingTableIterator {
             bucket_index: 1,
             node_index: 0,
@@ -120,6 +399,91 @@ impl RoutingTable {
         }
     }
 
+    /// Returns nodes that were not seen for a while, and should be pinged.
+    pub fn questionable_nodes(&self) -> Vec<Node> {
+        self.nodes()
+            .filter(|node| node.status() == NodeStatus::Questionable)
+            .collect()
+    }
+
+    /// Returns a random target Id for each bucket without any node seen in the past `older_than`.
+    ///
+    /// Running a `find_node` query for each target will repopulate the corresponding buckets.
+    pub fn buckets_needing_refresh(&self, older_than: Duration) -> Vec<Id> {
+        self.buckets
+            .iter()
+            .filter(|(_, bucket)| {
+                !bucket
+                    .iter()
+                    .any(|node| node.last_seen().elapsed() <= older_than)
+            })
+            .map(|(distance, _)| self.id.random_at_distance(*distance))
+            .collect()
+    }
+
+    /// Returns the distance of each bucket from this table's [Id], and the last time
+    /// a node was added to it or seen again, see [KBucket::last_changed].
+    ///
+    /// Buckets that weren't changed for a while can be refreshed by a `find_node`
+    /// query for a [random Id](Id::random_at_distance) at their distance.
+    pub fn bucket_last_changed(&self) -> Vec<(usize, Instant)> {
+        self.buckets
+            .iter()
+            .map(|(distance, bucket)| (*distance as usize, bucket.last_changed()))
+            .collect()
+    }
+
+    /// Returns the number of nodes in this table from each /24 Ipv4 or /48 Ipv6 subnet,
+    /// by the first address of the subnet, see [Self::with_max_per_subnet].
+    pub fn subnet_counts(&self) -> BTreeMap<IpAddr, usize> {
+        self.subnets.clone()
+    }
+
+    /// Returns a summary of how populated this table is, for example to wait
+    /// until it is good enough to serve lookups.
+    pub fn health(&self) -> RoutingHealth {
+        let mut health = RoutingHealth {
+            total_nodes: 0,
+            buckets: 0,
+            full_buckets: 0,
+            oldest_bucket_age: None,
+            live_ratio: 0.0,
+        };
+        let mut live = 0;
+
+        for bucket in self.buckets.values().filter(|bucket| !bucket.is_empty()) {
+            let len = bucket.iter().len();
+
+            health.total_nodes += len;
+            health.buckets += 1;
+            if len >= self.k {
+                health.full_buckets += 1;
+            }
+
+            let age = bucket.last_changed().elapsed();
+            health.oldest_bucket_age = health.oldest_bucket_age.max(Some(age));
+
+            live += bucket
+                .iter()
+                .filter(|node| node.status() != NodeStatus::Questionable)
+                .count();
+        }
+
+        if health.total_nodes > 0 {
+            health.live_ratio = live as f64 / health.total_nodes as f64;
+        }
+
+        health
+    }
+
+    /// Returns `true` once this table has at least [k](Self::k) nodes spread
+    /// over at least [MIN_BOOTSTRAPPED_BUCKETS] buckets.
+    pub fn is_bootstrapped(&self) -> bool {
+        let health = self.health();
+
+        health.total_nodes >= self.k && health.buckets >= MIN_BOOTSTRAPPED_BUCKETS
+    }
+
     /// Export an owned vector of nodes from this routing table.
     pub fn to_owned_nodes(&self) -> Vec<Node> {
         self.nodes().collect()
@@ -133,10 +497,84 @@ impl RoutingTable {
             .collect()
     }
 
+    /// Score each of the `sources` this table was bootstrapped from, by whether any
+    /// of its nodes is in this table and not stale, and return the addresses
+    /// to bootstrap from next time, from the best scored source to the worst,
+    /// see [BootstrapSources::addresses].
+    pub fn bootstrap_weighted(&self, sources: &mut BootstrapSources) -> Vec<SocketAddrV4> {
+        let live = self
+            .nodes()
+            .filter(|node| !node.is_stale())
+            .filter_map(|node| match node.address() {
+                SocketAddr::V4(address) => Some(address),
+                SocketAddr::V6(_) => None,
+            })
+            .collect::<Vec<_>>();
+
+        sources.record(&live);
+
+        sources.addresses()
+    }
+
+    /// Encode the nodes of this routing table in the compact node format
+    /// (20 bytes Id + 6 bytes Ipv4 address), Ipv6 nodes are skipped.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        nodes4_to_bytes(&self.to_owned_nodes()).into_vec()
+    }
+
+    /// Create a routing table with a given id, from nodes encoded with [Self::to_bytes].
+    ///
+    /// All nodes are added regardless of how long ago they were last seen,
+    /// and the next queries or maintenance pings will tell which are still alive.
+    pub fn from_bytes(id: Id, bytes: &[u8]) -> Result<Self, DecodeMessageError> {
+        let mut table = Self::new(id);
+
+        for node in bytes_to_nodes4(bytes)? {
+            table.add(node);
+        }
+
+        Ok(table)
+    }
+
+    /// Save the nodes of this routing table to a file, see [Self::to_bytes].
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), std::io::Error> {
+        std::fs::write(path, self.to_bytes())
+    }
+
+    /// Load a routing table with a given id, from a file written by [Self::save].
+    pub fn load<P: AsRef<Path>>(id: Id, path: P) -> Result<Self, std::io::Error> {
+        let bytes = std::fs::read(path)?;
+
+        Self::from_bytes(id, &bytes)
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))
+    }
+
     // === Private Methods ===
 
-    #[cfg(test)]
-    fn contains(&self, node_id: &Id) -> bool {
+    /// Remove the furthest node from this table's id, and return it.
+    fn remove_furthest(&mut self) -> Option<Node> {
+        let furthest = self
+            .buckets
+            .values()
+            .rev()
+            .find(|bucket| !bucket.is_empty())?
+            .iter()
+            .max_by(|a, b| self.id.cmp_distance(a.id(), b.id()))
+            .cloned()?;
+
+        self.remove(furthest.id());
+
+        Some(furthest)
+    }
+
+    fn emit(&self, event: RoutingTableEvent) {
+        if let Some(tx) = &self.events {
+            let _ = tx.try_send(event);
+        }
+    }
+
+    /// Returns true if a node with this Id is in this table.
+    pub(crate) fn contains(&self, node_id: &Id) -> bool {
         let distance = self.id.distance(node_id);
 
         if let Some(bucket) = self.buckets.get(&distance) {
@@ -148,6 +586,51 @@ impl RoutingTable {
     }
 }
 
+#[cfg(feature = "serde")]
+/// Serialized form of a [RoutingTable], see [Node]'s serialization.
+#[derive(Serialize, Deserialize)]
+struct SerializedRoutingTable {
+    id: Id,
+    k: usize,
+    #[serde(default)]
+    capacity: Option<usize>,
+    nodes: Vec<Node>,
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for RoutingTable {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerializedRoutingTable {
+            id: self.id,
+            k: self.k,
+            capacity: self.capacity,
+            nodes: self.to_owned_nodes(),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for RoutingTable {
+    /// Nodes are added like in [RoutingTable::from_bytes], and neither events
+    /// nor a custom [EvictionPolicy] are restored.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<RoutingTable, D::Error> {
+        let serialized = SerializedRoutingTable::deserialize(deserializer)?;
+
+        let mut table = RoutingTable::new(serialized.id).with_k(serialized.k);
+
+        if let Some(capacity) = serialized.capacity {
+            table = table.with_capacity(capacity);
+        }
+
+        for node in serialized.nodes {
+            table.add(node);
+        }
+
+        Ok(table)
+    }
+}
+
 pub struct RoutingTableIterator<'a> {
     bucket_index: u8,
     node_index: usize,
@@ -180,25 +663,71 @@ impl Iterator for RoutingTableIterator<'_> {
 }
 
 /// Kbuckets are similar to LRU caches that checks and evicts unresponsive nodes,
-/// without dropping any responsive nodes in the process.
+/// without dropping any responsive nodes in the process, unless their
+/// [EvictionPolicy] says otherwise.
 #[derive(Debug, Clone)]
 pub struct KBucket {
     /// Nodes in the k-bucket, sorted by the least recently seen.
     nodes: Vec<Node>,
+    /// Maximum number of nodes in this bucket.
+    k: usize,
+    /// Last time a node was added to this bucket, or seen again.
+    last_changed: Instant,
+    eviction: Arc<dyn EvictionPolicy>,
 }
 
 impl KBucket {
     pub fn new() -> Self {
+        Self::with_k(MAX_BUCKET_SIZE_K)
+    }
+
+    pub fn with_k(k: usize) -> Self {
+        Self::with_eviction(k, Arc::new(KeepVerifiedLive))
+    }
+
+    fn with_eviction(k: usize, eviction: Arc<dyn EvictionPolicy>) -> Self {
         KBucket {
-            nodes: Vec::with_capacity(MAX_BUCKET_SIZE_K),
+            nodes: Vec::with_capacity(k),
+            k,
+            last_changed: Instant::now(),
+            eviction,
         }
     }
 
     // === Getters ===
 
+    /// Returns the last time a node was added to this bucket, or seen again.
+    pub fn last_changed(&self) -> Instant {
+        self.last_changed
+    }
+
     // === Public Methods ===
 
     pub fn add(&mut self, incoming: Node) -> bool {
+        let added = self.insert(incoming);
+
+        if added {
+            self.last_changed = Instant::now();
+        }
+
+        added
+    }
+
+    pub fn remove(&mut self, node_id: &Id) {
+        self.nodes.retain(|node| node.id() != node_id);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn iter(&self) -> Iter<'_, Node> {
+        self.nodes.iter()
+    }
+
+    // === Private Methods ===
+
+    fn insert(&mut self, incoming: Node) -> bool {
         if let Some(index) = self.iter().position(|n| n.id() == incoming.id()) {
             let existing = self.nodes[index].clone();
 
@@ -212,20 +741,29 @@ impl KBucket {
             // for the old port to timeout (not responding to Pings).
             //
             // Using same ip instead of same address, allow
-            if incoming.is_secure() || (!existing.is_secure() && existing.same_ip(&incoming)) {
+            //
+            // If the existing node is questionable, it probably moved to the incoming
+            // address, so the newer one replaces it.
+            if incoming.is_secure()
+                || (!existing.is_secure() && existing.same_ip(&incoming))
+                || existing.status() == NodeStatus::Questionable
+            {
                 self.nodes.remove(index);
-                self.nodes.push(incoming);
+                self.nodes.push(incoming.refreshed_from(&existing));
 
                 true
             } else {
                 false
             }
-        } else if self.nodes.len() < MAX_BUCKET_SIZE_K {
+        } else if self.nodes.len() < self.k {
             self.nodes.push(incoming);
             true
-        } else if self.nodes[0].is_stale() {
-            // Remove the least recently seen node and add the new one
-            self.nodes.remove(0);
+        } else if let Some(index) = self
+            .eviction
+            .choose_victim(&self.nodes, &incoming)
+            .filter(|index| *index < self.nodes.len())
+        {
+            self.nodes.remove(index);
             self.nodes.push(incoming);
 
             true
@@ -234,19 +772,6 @@ impl KBucket {
         }
     }
 
-    pub fn remove(&mut self, node_id: &Id) {
-        self.nodes.retain(|node| node.id() != node_id);
-    }
-
-    pub fn is_empty(&self) -> bool {
-        self.nodes.is_empty()
-    }
-
-    pub fn iter(&self) -> Iter<'_, Node> {
-        self.nodes.iter()
-    }
-
-    #[cfg(test)]
     fn contains(&self, id: &Id) -> bool {
         self.iter().any(|node| node.id() == id)
     }
@@ -258,14 +783,162 @@ impl Default for KBucket {
     }
 }
 
+/// First address of the /24 Ipv4 or /48 Ipv6 subnet of an address.
+fn subnet(address: SocketAddr) -> IpAddr {
+    match address.ip() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            Ipv4Addr::new(a, b, c, 0).into()
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0).into()
+        }
+    }
+}
+
+/// Decrement the count of a subnet, forgetting it once it reaches zero.
+fn uncount_subnet(counts: &mut BTreeMap<IpAddr, usize>, subnet: IpAddr) {
+    if let Some(count) = counts.get_mut(&subnet) {
+        *count -= 1;
+
+        if *count == 0 {
+            counts.remove(&subnet);
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use std::net::SocketAddrV4;
+    use std::cmp::Ordering;
+    use std::net::{SocketAddr, SocketAddrV4};
     use std::str::FromStr;
     use std::sync::Arc;
-    use std::time::Instant;
+    use std::time::{Duration, Instant};
+
+    use crate::common::{
+        BootstrapSources, Id, KBucket, KeepVerifiedLive, LeastRecentlySeen, Node, NodeInner,
+        NodeStatus, RoutingTable, RoutingTableEvent, MAX_BUCKET_SIZE_K,
+    };
+
+    #[test]
+    fn bootstrap_weighted() {
+        let live = Node::unique(1);
+        let mut table = RoutingTable::new(Id::random());
+        table.add(live.clone());
+
+        let mut sources = BootstrapSources::new();
+        sources
+            .add("dead", &["127.0.0.1:2", "127.0.0.1:3"])
+            .add("live", &[live.address()]);
+
+        // Equal scores keep the order the sources were added in.
+        assert_eq!(sources.addresses()[0].port(), 2);
+
+        let addresses = table.bootstrap_weighted(&mut sources);
+
+        assert_eq!(sources.score("dead"), Some(-1));
+        assert_eq!(sources.score("live"), Some(1));
+        assert_eq!(SocketAddr::from(addresses[0]), live.address());
+        assert_eq!(addresses.len(), 3);
+
+        // Scores persist across runs, and are bounded.
+        for _ in 0..20 {
+            table.bootstrap_weighted(&mut sources);
+        }
+
+        let path = std::env::temp_dir().join(format!("mainline-scores-{}", Id::random()));
+        sources.save_scores(&path).unwrap();
+
+        let mut loaded = BootstrapSources::new();
+        loaded.load_scores(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        loaded
+            .add("dead", &["127.0.0.1:2"])
+            .add("live", &[live.address()]);
+        assert_eq!(loaded.score("dead"), Some(-8));
+        assert_eq!(loaded.score("live"), Some(8));
+        assert_eq!(SocketAddr::from(loaded.addresses()[0]), live.address());
+    }
+
+    #[test]
+    fn iter() {
+        let mut table = RoutingTable::new(Id::random());
 
-    use crate::common::{Id, KBucket, Node, NodeInner, RoutingTable, MAX_BUCKET_SIZE_K};
+        for i in 0..50 {
+            table.add(Node::unique(i));
+        }
+
+        let ids = table.iter().map(|node| *node.id()).collect::<Vec<_>>();
+
+        assert_eq!(ids.len(), table.size());
+        assert_eq!(
+            ids,
+            table.nodes().map(|node| *node.id()).collect::<Vec<_>>()
+        );
+        assert_eq!(ids, table.iter().map(|node| *node.id()).collect::<Vec<_>>());
+
+        // Closest bucket first.
+        let distances = table
+            .iter()
+            .map(|node| table.id().distance(node.id()))
+            .collect::<Vec<_>>();
+        assert!(distances.is_sorted());
+    }
+
+    #[test]
+    fn oldest_if_full() {
+        let id = Id::random();
+        let mut table = RoutingTable::new(id).with_k(2);
+
+        let node = |i: u8| {
+            Node::new(
+                id.random_at_distance(150),
+                SocketAddrV4::new([1, 1, 1, i].into(), 6881).into(),
+            )
+        };
+        let (oldest, newer, candidate) = (node(1), node(2), node(3));
+
+        table.add(oldest.clone());
+        assert!(table.oldest_if_full(&candidate).is_none());
+
+        table.add(newer.clone());
+        assert_eq!(table.oldest_if_full(&candidate).unwrap().id(), oldest.id());
+        // Already in the table, nothing to evict.
+        assert!(table.oldest_if_full(&newer).is_none());
+        // Another bucket.
+        assert!(table.oldest_if_full(&Node::unique(1)).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde() {
+        let mut table = RoutingTable::new(Id::random()).with_k(4).with_capacity(100);
+
+        for i in 0..20 {
+            table.add(Node::unique(i));
+        }
+
+        let check = |decoded: RoutingTable| {
+            assert_eq!(decoded.id(), table.id());
+            assert_eq!(decoded.k(), table.k());
+            assert_eq!(decoded.capacity, table.capacity);
+            assert_eq!(
+                decoded.nodes().map(|node| *node.id()).collect::<Vec<_>>(),
+                table.nodes().map(|node| *node.id()).collect::<Vec<_>>()
+            );
+        };
+
+        let bytes = serde_bencode::to_bytes(&table).unwrap();
+        check(serde_bencode::from_bytes(&bytes).unwrap());
+
+        let json = serde_json::to_string(&table).unwrap();
+        check(serde_json::from_str(&json).unwrap());
+
+        let bytes = bincode::serialize(&table).unwrap();
+        check(bincode::deserialize(&bytes).unwrap());
+    }
 
     #[test]
     fn table_is_empty() {
@@ -305,10 +978,10 @@ mod test {
 
         let node = Node::random();
 
-        assert!(!table.contains(&node.id()));
+        assert!(!table.contains(node.id()));
 
         table.add(node.clone());
-        assert!(table.contains(&node.id()));
+        assert!(table.contains(node.id()));
     }
 
     #[test]
@@ -318,10 +991,10 @@ mod test {
         let node = Node::random();
 
         table.add(node.clone());
-        assert!(table.contains(&node.id()));
+        assert!(table.contains(node.id()));
 
         table.remove(node.id());
-        assert!(!table.contains(&node.id()));
+        assert!(!table.contains(node.id()));
     }
 
     #[test]
@@ -340,7 +1013,7 @@ mod test {
     #[test]
     fn should_not_add_self() {
         let mut table = RoutingTable::new(Id::random());
-        let node = Node::new(*table.id(), SocketAddrV4::new(0.into(), 0));
+        let node = Node::new(*table.id(), SocketAddrV4::new(0.into(), 0).into());
 
         table.add(node.clone());
 
@@ -362,6 +1035,119 @@ mod test {
         assert!(!bucket.add(node));
     }
 
+    #[test]
+    fn configurable_k() {
+        let mut table = RoutingTable::new(Id::random()).with_k(8);
+        let distance = 160;
+
+        for i in 0..10 {
+            table.add(Node::new(
+                table.id().random_at_distance(distance),
+                SocketAddrV4::new([0, 0, 0, i].into(), 1).into(),
+            ));
+        }
+
+        assert_eq!(table.size(), 8);
+        assert_eq!(table.closest(*table.id()).len(), 8);
+
+        let mut table = RoutingTable::new(Id::random()).with_k(MAX_BUCKET_SIZE_K);
+
+        for i in 0..(MAX_BUCKET_SIZE_K + 5) as u8 {
+            table.add(Node::new(
+                table.id().random_at_distance(distance),
+                SocketAddrV4::new([0, 0, 0, i].into(), 1).into(),
+            ));
+        }
+
+        assert_eq!(table.size(), MAX_BUCKET_SIZE_K);
+        assert_eq!(table.closest(*table.id()).len(), MAX_BUCKET_SIZE_K);
+    }
+
+    #[test]
+    fn closest_n() {
+        let mut table = RoutingTable::new(Id::random());
+
+        for i in 0..100 {
+            table.add(Node::new(
+                Id::random(),
+                SocketAddrV4::new([0, 0, 0, i].into(), 1).into(),
+            ));
+        }
+
+        let target = Id::random();
+        let closest = table.closest_n(target, 5);
+
+        assert_eq!(closest.len(), 5);
+        assert_eq!(&closest[..], &table.closest(target)[..5]);
+        assert!(closest
+            .windows(2)
+            .all(|pair| target.cmp_distance(pair[0].id(), pair[1].id()) == Ordering::Less));
+
+        assert_eq!(table.closest_n(target, 1000).len(), table.size());
+    }
+
+    #[test]
+    fn events() {
+        let (tx, rx) = std::sync::mpsc::sync_channel(10);
+        let mut table = RoutingTable::new(Id::random())
+            .with_capacity(1)
+            .with_events(tx);
+
+        let a = Node::unique(1);
+        let b = Node::unique(2);
+
+        table.add(a.clone());
+        table.add(a.clone());
+        table.add(b.clone());
+
+        assert_eq!(rx.try_recv(), Ok(RoutingTableEvent::NodeAdded(a.clone())));
+        assert_eq!(rx.try_recv(), Ok(RoutingTableEvent::NodeRefreshed(*a.id())));
+        assert_eq!(rx.try_recv(), Ok(RoutingTableEvent::NodeAdded(b.clone())));
+
+        let evicted = match rx.try_recv() {
+            Ok(RoutingTableEvent::NodeEvicted(node)) => node,
+            event => panic!("expected an eviction, got {event:?}"),
+        };
+        assert!(evicted == a || evicted == b);
+        assert!(!table.contains(evicted.id()));
+
+        // A full channel doesn't block adding nodes.
+        let (tx, _rx) = std::sync::mpsc::sync_channel(0);
+        let mut table = RoutingTable::new(Id::random()).with_events(tx);
+
+        assert!(table.add(Node::unique(1)));
+    }
+
+    #[test]
+    fn capacity() {
+        let mut table = RoutingTable::new(Id::random()).with_capacity(10);
+
+        let mut nodes = (0..30)
+            .map(|i| {
+                Node::new(
+                    table.id().random_at_distance(140 + (i % 20) as u8),
+                    SocketAddrV4::new([0, 0, 1, i as u8].into(), 1).into(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        for node in &nodes {
+            table.add(node.clone());
+        }
+
+        assert_eq!(table.len(), 10);
+
+        nodes.sort_by(|a, b| table.id().cmp_distance(a.id(), b.id()));
+
+        let mut expected = nodes[..10].to_vec();
+        expected.sort_by(|a, b| a.id().cmp(b.id()));
+
+        let mut actual = table.to_owned_nodes();
+        actual.sort_by(|a, b| a.id().cmp(b.id()));
+
+        assert_eq!(actual, expected);
+    }
+
     #[test]
     fn should_update_existing_node() {
         // Same address
@@ -387,7 +1173,7 @@ mod test {
             let mut bucket = KBucket::new();
 
             let node1 = Node::random();
-            let node2 = Node::new(*node1.id(), SocketAddrV4::new(*node1.address().ip(), 1));
+            let node2 = Node::new(*node1.id(), SocketAddr::new(node1.address().ip(), 1));
 
             bucket.add(node1.clone());
             bucket.add(Node::random());
@@ -405,12 +1191,17 @@ mod test {
 
             let secure = Node(Arc::new(NodeInner {
                 id: Id::from_str("5a3ce9c14e7a08645677bbd1cfe7d8f956d53256").unwrap(),
-                address: SocketAddrV4::new([21, 75, 31, 124].into(), 0),
+                address: SocketAddrV4::new([21, 75, 31, 124].into(), 0).into(),
                 token: None,
+                rtt: None,
+                version: None,
                 last_seen: Instant::now(),
             }));
 
-            let unsecure = Node::new(*secure.id(), SocketAddrV4::new([0, 0, 0, 0].into(), 1));
+            let unsecure = Node::new(
+                *secure.id(),
+                SocketAddrV4::new([0, 0, 0, 0].into(), 1).into(),
+            );
 
             {
                 bucket.add(unsecure.clone());
@@ -432,7 +1223,10 @@ mod test {
             let mut bucket = KBucket::new();
 
             let node1 = Node::random();
-            let node2 = Node::new(*node1.id(), SocketAddrV4::new([0, 0, 0, 1].into(), 1));
+            let node2 = Node::new(
+                *node1.id(),
+                SocketAddrV4::new([0, 0, 0, 1].into(), 1).into(),
+            );
 
             bucket.add(node1.clone());
             bucket.add(Node::random());
@@ -447,6 +1241,116 @@ mod test {
         }
     }
 
+    #[test]
+    fn same_id_different_address() {
+        let mut table = RoutingTable::new(Id::random());
+
+        let id = Id::random();
+
+        // Same ip, different port.
+        let old = Node::new(id, SocketAddrV4::new([127, 0, 0, 1].into(), 1).into());
+        let new = Node::new(id, SocketAddrV4::new([127, 0, 0, 1].into(), 2).into());
+
+        assert!(table.add(old));
+        assert!(table.add(new.clone()));
+        assert_eq!(table.to_owned_nodes(), vec![new]);
+
+        // Different ip, after the existing node became questionable.
+        let mut table = RoutingTable::new(Id::random());
+
+        let questionable = Node(Arc::new(NodeInner {
+            id,
+            address: SocketAddrV4::new([127, 0, 0, 1].into(), 1).into(),
+            token: None,
+            rtt: None,
+            version: None,
+            last_seen: Instant::now()
+                .checked_sub(crate::common::STALE_TIME * 2)
+                .unwrap(),
+        }));
+        let moved = Node::new(id, SocketAddrV4::new([127, 0, 0, 2].into(), 1).into());
+
+        assert!(table.add(questionable));
+        assert!(table.add(moved.clone()));
+        assert_eq!(table.to_owned_nodes(), vec![moved]);
+    }
+
+    #[test]
+    fn max_per_subnet() {
+        let mut table = RoutingTable::new(Id::random()).with_max_per_subnet(3);
+
+        let mut added = 0;
+        for i in 0..50 {
+            let address = SocketAddrV4::new([1, 2, 3, i].into(), 6881);
+            if table.add(Node::new(Id::random(), address.into())) {
+                added += 1;
+            }
+        }
+        assert_eq!(added, 3);
+
+        // Other subnets are unaffected.
+        assert!(table.add(Node::new(
+            Id::random(),
+            SocketAddrV4::new([1, 2, 4, 1].into(), 6881).into()
+        )));
+
+        // Known Ids from a full subnet can still be refreshed.
+        let existing = table
+            .nodes()
+            .find(|node| node.address().ip() == std::net::Ipv4Addr::new(1, 2, 3, 0))
+            .unwrap();
+        assert!(table.add(Node::new(*existing.id(), existing.address())));
+
+        let counts = table.subnet_counts();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[&std::net::Ipv4Addr::new(1, 2, 3, 0).into()], 3);
+        assert_eq!(counts[&std::net::Ipv4Addr::new(1, 2, 4, 0).into()], 1);
+
+        // Removing a node makes room in its subnet.
+        let existing = *existing.id();
+        table.remove(&existing);
+        assert_eq!(
+            table.subnet_counts()[&std::net::Ipv4Addr::new(1, 2, 3, 0).into()],
+            2
+        );
+        assert!(table.add(Node::new(
+            Id::random(),
+            SocketAddrV4::new([1, 2, 3, 100].into(), 6881).into()
+        )));
+    }
+
+    #[test]
+    fn closest6() {
+        let mut table = RoutingTable::new(Id::random());
+        let target = Id::random();
+
+        let mut v6 = Vec::new();
+        for i in 0..50u16 {
+            let address = SocketAddr::from((
+                std::net::Ipv6Addr::new(0x2001, 0x4860, 0, 0, 0, 0, 1, i),
+                6881,
+            ));
+            let node = Node::new(Id::random(), address);
+
+            if table.add(node.clone()) {
+                v6.push(node);
+            }
+            table.add(Node::unique(i as usize + 1));
+        }
+
+        v6.sort_by(|a, b| target.cmp_distance(a.id(), b.id()));
+
+        let closest = table.closest6(target);
+
+        assert_eq!(closest.len(), MAX_BUCKET_SIZE_K.min(v6.len()));
+        // Ordered by xor distance like Ipv4 nodes, and encoded with their 18 bytes addresses.
+        for (node, expected) in closest.iter().zip(&v6) {
+            assert_eq!(node.id(), expected.id());
+            let decoded = Node::from_compact6(&node.to_compact6().unwrap()).unwrap();
+            assert_eq!(decoded.address(), expected.address());
+        }
+    }
+
     #[test]
     fn closest() {
         let ids = [
@@ -559,8 +1463,10 @@ mod test {
                 let id = Id::from_str(str).unwrap();
                 Node(Arc::new(NodeInner {
                     id,
-                    address: SocketAddrV4::new((i as u32).into(), i as u16),
+                    address: SocketAddrV4::new((i as u32).into(), i as u16).into(),
                     token: None,
+                    rtt: None,
+                    version: None,
                     last_seen: Instant::now(),
                 }))
             })
@@ -646,4 +1552,313 @@ mod test {
             assert_eq!(closest_ids, expected_closest_ids);
         }
     }
+
+    #[test]
+    fn to_bytes_from_bytes() {
+        let mut table = RoutingTable::new(Id::random());
+
+        for i in 0..100 {
+            table.add(Node::unique(i));
+        }
+
+        let bytes = table.to_bytes();
+        assert_eq!(bytes.len(), table.size() * 26);
+
+        let loaded = RoutingTable::from_bytes(*table.id(), &bytes).unwrap();
+
+        assert_eq!(
+            loaded
+                .nodes()
+                .map(|node| (*node.id(), node.address()))
+                .collect::<Vec<_>>(),
+            table
+                .nodes()
+                .map(|node| (*node.id(), node.address()))
+                .collect::<Vec<_>>()
+        );
+
+        assert!(RoutingTable::from_bytes(*table.id(), &bytes[1..]).is_err());
+    }
+
+    #[test]
+    fn save_load() {
+        let mut table = RoutingTable::new(Id::random());
+
+        for i in 0..100 {
+            table.add(Node::unique(i));
+        }
+
+        let path = std::env::temp_dir().join(format!("mainline-routing-table-{}", table.id()));
+
+        table.save(&path).unwrap();
+        let loaded = RoutingTable::load(*table.id(), &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.size(), table.size());
+    }
+
+    #[test]
+    fn replace_questionable_node_in_full_bucket() {
+        let mut table = RoutingTable::new(Id::random());
+
+        let questionable = Node(Arc::new(NodeInner {
+            id: Id::random(),
+            address: SocketAddrV4::new([0, 0, 0, 100].into(), 100).into(),
+            token: None,
+            rtt: None,
+            version: None,
+            last_seen: Instant::now()
+                .checked_sub(crate::common::STALE_TIME * 2)
+                .unwrap(),
+        }));
+
+        let mut bucket = KBucket::new();
+        bucket.add(Node::unique(0));
+        bucket.add(questionable.clone());
+        for i in 1..MAX_BUCKET_SIZE_K - 1 {
+            bucket.add(Node::unique(i));
+        }
+        assert_eq!(bucket.nodes.len(), MAX_BUCKET_SIZE_K);
+
+        assert_eq!(questionable.status(), NodeStatus::Questionable);
+
+        table.buckets.insert(1, bucket.clone());
+        assert_eq!(table.questionable_nodes(), vec![questionable.clone()]);
+
+        let incoming = Node::unique(MAX_BUCKET_SIZE_K);
+        assert!(bucket.add(incoming.clone()));
+        assert!(!bucket.contains(questionable.id()));
+        assert!(bucket.contains(incoming.id()));
+
+        // No more questionable nodes to replace.
+        assert!(!bucket.add(Node::unique(MAX_BUCKET_SIZE_K + 1)));
+    }
+
+    #[test]
+    fn eviction_policies() {
+        let id = Id::random();
+        let node = |ago: u64| {
+            Node(Arc::new(NodeInner {
+                id: id.random_at_distance(150),
+                address: SocketAddrV4::new([1, 1, 1, ago as u8].into(), 6881).into(),
+                token: None,
+                rtt: None,
+                version: None,
+                last_seen: Instant::now()
+                    .checked_sub(Duration::from_secs(ago))
+                    .unwrap(),
+            }))
+        };
+
+        // Both live, the second one seen longer ago.
+        let stream = [node(1), node(60), node(0)];
+
+        let retained = |table: RoutingTable| {
+            let mut table = table.with_k(2);
+            for node in &stream {
+                table.add(node.clone());
+            }
+
+            table.nodes().map(|node| *node.id()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            retained(RoutingTable::new(id).with_eviction(KeepVerifiedLive)),
+            vec![*stream[0].id(), *stream[1].id()]
+        );
+        assert_eq!(
+            retained(RoutingTable::new(id).with_eviction(LeastRecentlySeen)),
+            vec![*stream[0].id(), *stream[2].id()]
+        );
+    }
+
+    #[test]
+    fn upsert() {
+        let mut table = RoutingTable::new(Id::random());
+
+        let id = table.id().random_at_distance(150);
+        let old = Node(Arc::new(NodeInner {
+            id,
+            address: SocketAddrV4::new([0, 0, 0, 1].into(), 1).into(),
+            token: None,
+            rtt: Some(Duration::from_millis(100)),
+            version: Some([1, 2, 3, 4]),
+            last_seen: Instant::now().checked_sub(Duration::from_secs(60)).unwrap(),
+        }));
+        let other = Node::new(
+            table.id().random_at_distance(150),
+            SocketAddrV4::new([0, 0, 0, 2].into(), 1).into(),
+        );
+
+        assert!(table.add(old.clone()));
+        assert!(table.add(other.clone()));
+
+        // Seen again, at a new port.
+        let seen = Node::new(id, SocketAddrV4::new([0, 0, 0, 1].into(), 2).into());
+        assert!(table.add(seen.clone()));
+
+        assert_eq!(table.size(), 2);
+
+        let bucket = &table.buckets()[&150];
+        let updated = bucket.iter().last().unwrap();
+        assert_eq!(*updated.id(), id);
+        assert_eq!(updated.address(), seen.address());
+        assert!(updated.last_seen() > old.last_seen());
+        assert_eq!(updated.rtt(), old.rtt());
+        assert_eq!(updated.version(), old.version());
+    }
+
+    #[test]
+    fn health() {
+        let mut table = RoutingTable::new(Id::random()).with_k(4);
+
+        let empty = table.health();
+        assert_eq!(empty.total_nodes, 0);
+        assert_eq!(empty.oldest_bucket_age, None);
+        assert_eq!(empty.live_ratio, 0.0);
+        assert!(!table.is_bootstrapped());
+
+        let mut ip = 0;
+        let mut add = |table: &mut RoutingTable, distance: u8, count: usize| {
+            for _ in 0..count {
+                ip += 1;
+                table.add(Node::new(
+                    table.id().random_at_distance(distance),
+                    SocketAddrV4::new(ip.into(), 6881).into(),
+                ));
+            }
+        };
+
+        // Enough nodes, but all in one bucket.
+        add(&mut table, 160, 4);
+        let health = table.health();
+        assert_eq!(health.total_nodes, 4);
+        assert_eq!(health.buckets, 1);
+        assert_eq!(health.full_buckets, 1);
+        assert_eq!(health.live_ratio, 1.0);
+        assert!(health.oldest_bucket_age.is_some());
+        assert!(!table.is_bootstrapped());
+
+        // Spread, but too few nodes.
+        let mut sparse = RoutingTable::new(Id::random()).with_k(8);
+        for distance in [157, 158, 159, 160] {
+            add(&mut sparse, distance, 1);
+        }
+        assert_eq!(sparse.health().buckets, 4);
+        assert!(!sparse.is_bootstrapped());
+
+        for distance in [157, 158, 159] {
+            add(&mut table, distance, 2);
+        }
+        let health = table.health();
+        assert_eq!(health.total_nodes, 10);
+        assert_eq!(health.buckets, 4);
+        assert_eq!(health.full_buckets, 1);
+        assert!(table.is_bootstrapped());
+
+        let questionable = Node(Arc::new(NodeInner {
+            id: table.id().random_at_distance(150),
+            address: SocketAddrV4::new([0, 0, 0, 100].into(), 100).into(),
+            token: None,
+            rtt: None,
+            version: None,
+            last_seen: Instant::now()
+                .checked_sub(crate::common::STALE_TIME * 2)
+                .unwrap(),
+        }));
+        table.add(questionable);
+        assert_eq!(table.health().live_ratio, 10.0 / 11.0);
+    }
+
+    #[test]
+    fn buckets_needing_refresh() {
+        let mut table = RoutingTable::new(Id::random());
+
+        let stale = Node(Arc::new(NodeInner {
+            id: table.id().random_at_distance(150),
+            address: SocketAddrV4::new([0, 0, 0, 1].into(), 1).into(),
+            token: None,
+            rtt: None,
+            version: None,
+            last_seen: Instant::now()
+                .checked_sub(Duration::from_secs(60 * 60))
+                .unwrap(),
+        }));
+        let fresh = Node::new(
+            table.id().random_at_distance(160),
+            SocketAddrV4::new([0, 0, 0, 2].into(), 1).into(),
+        );
+
+        table.add(stale);
+        table.add(fresh);
+
+        let targets = table.buckets_needing_refresh(Duration::from_secs(15 * 60));
+
+        assert_eq!(targets.len(), 1);
+        assert_eq!(table.id().distance(&targets[0]), 150);
+    }
+
+    #[test]
+    fn bucket_last_changed() {
+        let mut table = RoutingTable::new(Id::random());
+        assert!(table.bucket_last_changed().is_empty());
+
+        let node = Node::new(
+            table.id().random_at_distance(150),
+            SocketAddrV4::new([0, 0, 0, 1].into(), 1).into(),
+        );
+
+        let before = Instant::now();
+        table.add(node.clone());
+
+        let last_changed = table.bucket_last_changed();
+        assert_eq!(last_changed.len(), 1);
+        assert_eq!(last_changed[0].0, 150);
+        assert!(last_changed[0].1 >= before);
+
+        // Rejected: same Id from another ip.
+        let moved = Node::new(*node.id(), SocketAddrV4::new([0, 0, 0, 2].into(), 1).into());
+        assert!(!table.add(moved));
+        assert_eq!(table.bucket_last_changed(), last_changed);
+
+        // Seen again.
+        std::thread::sleep(Duration::from_millis(1));
+        table.add(node);
+        assert!(table.bucket_last_changed()[0].1 > last_changed[0].1);
+    }
+
+    #[test]
+    fn buckets_near_local_id() {
+        let mut table = RoutingTable::new(Id::random());
+
+        let mut port = 0;
+        let mut add_at_distance = |table: &mut RoutingTable, distance: u8, n: usize| {
+            for _ in 0..n {
+                port += 1;
+                table.add(Node::new(
+                    table.id().random_at_distance(distance),
+                    SocketAddrV4::new([10, 0, (port >> 8) as u8, port as u8].into(), port).into(),
+                ));
+            }
+        };
+
+        // The furthest bucket, covering half of the Id space, is capped.
+        add_at_distance(&mut table, 160, MAX_BUCKET_SIZE_K * 2);
+        // Filling the ranges closer to the local Id keeps every node.
+        for distance in 150..160 {
+            add_at_distance(&mut table, distance, MAX_BUCKET_SIZE_K);
+        }
+
+        assert_eq!(table.buckets().len(), 11);
+        assert_eq!(table.size(), MAX_BUCKET_SIZE_K * 11);
+
+        for (distance, bucket) in table.buckets() {
+            assert_eq!(bucket.iter().count(), MAX_BUCKET_SIZE_K);
+
+            for node in bucket.iter() {
+                // Shares exactly the first `160 - distance` bits with the local Id.
+                assert_eq!(table.id().xor(node.id()).leading_zeros(), 160 - distance);
+            }
+        }
+    }
 }
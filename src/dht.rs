@@ -1,8 +1,10 @@
 //! Dht node.
 
 use std::{
-    collections::HashMap,
-    net::{Ipv4Addr, SocketAddrV4, ToSocketAddrs},
+    collections::{BTreeSet, HashMap},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs},
+    num::{NonZeroU32, NonZeroUsize},
+    sync::Arc,
     thread,
     time::Duration,
 };
@@ -13,18 +15,19 @@ use tracing::info;
 
 use crate::{
     common::{
-        hash_immutable, AnnouncePeerRequestArguments, FindNodeRequestArguments,
+        hash_immutable, AnnounceOptions, AnnouncePeerRequestArguments, FindNodeRequestArguments,
         GetPeersRequestArguments, GetValueRequestArguments, Id, MutableItem,
         PutImmutableRequestArguments, PutMutableRequestArguments, PutRequestSpecific,
+        SampleInfohashesRequestArguments,
     },
     rpc::{
-        to_socket_address, ConcurrencyError, GetRequestSpecific, Info, PutError, PutQueryError,
-        Response, Rpc,
+        to_socket_address, ConcurrencyError, GetPeersResult, GetRequestSpecific, Info, PutError,
+        PutQueryError, QueryInfo, Response, Rpc, ScrapeEstimate, SpillStore,
     },
-    Node, ServerSettings,
+    Node, QueryStrategy, ServerSettings,
 };
 
-use crate::rpc::config::Config;
+use crate::rpc::config::{Config, TimeoutCallback};
 
 #[derive(Debug, Clone)]
 /// Mainline Dht node.
@@ -42,6 +45,13 @@ impl DhtBuilder {
         self
     }
 
+    /// Stay a read-only node, never switching to server mode, see [Config::read_only].
+    pub fn read_only(&mut self) -> &mut Self {
+        self.0.read_only = true;
+
+        self
+    }
+
     /// Set a custom settings for the node to use at server mode.
     ///
     /// Defaults to [ServerSettings::default]
@@ -86,6 +96,27 @@ impl DhtBuilder {
         self
     }
 
+    /// Set the local Ipv4 address to listen on, useful on hosts with
+    /// multiple network interfaces.
+    ///
+    /// Defaults to all interfaces (`0.0.0.0`).
+    pub fn bind_ip(&mut self, ip: Ipv4Addr) -> &mut Self {
+        self.0.bind_ip = Some(ip);
+
+        self
+    }
+
+    /// Bind an additional Ipv6 socket to this address, to exchange messages with Ipv6 nodes,
+    /// see [Dht::info].
+    ///
+    /// Requests to Ipv6 nodes are sent from this socket, while requests from
+    /// Ipv6 nodes are not served yet, even in server mode.
+    pub fn bind_address_v6(&mut self, address: SocketAddrV6) -> &mut Self {
+        self.0.bind_address_v6 = Some(address);
+
+        self
+    }
+
     /// A known public IPv4 address for this node to generate
     /// a secure node Id from according to [BEP_0042](https://www.bittorrent.org/beps/bep_0042.html)
     ///
@@ -96,12 +127,24 @@ impl DhtBuilder {
         self
     }
 
+    /// Explicit [Id] for this node, for example one persisted from a previous session.
+    ///
+    /// Defaults to an Id generated from [Self::public_ip] if set, or a random one.
+    pub fn id(&mut self, id: Id) -> &mut Self {
+        self.0.id = Some(id);
+
+        self
+    }
+
     /// UDP socket request timeout duration.
     ///
     /// The longer this duration is, the longer queries take until they are deemeed "done".
     /// The shortet this duration is, the more responses from busy nodes we miss out on,
     /// which affects the accuracy of queries trying to find closest nodes to a target.
     ///
+    /// Requests time out sooner on fast networks, as the timeout adapts to the
+    /// round trip times of received responses, but never later than this duration.
+    ///
     /// Defaults to [crate::DEFAULT_REQUEST_TIMEOUT]
     pub fn request_timeout(&mut self, request_timeout: Duration) -> &mut Self {
         self.0.request_timeout = request_timeout;
@@ -109,6 +152,249 @@ impl DhtBuilder {
         self
     }
 
+    /// Duration a request is remembered after receiving its response, so that
+    /// duplicates of that response delivered by UDP are recognized as such.
+    ///
+    /// Defaults to [crate::DEFAULT_DUPLICATE_RESPONSE_WINDOW]
+    pub fn duplicate_response_window(&mut self, window: Duration) -> &mut Self {
+        self.0.duplicate_response_window = window;
+
+        self
+    }
+
+    /// Size of the OS receive buffer (`SO_RCVBUF`) to request for the socket,
+    /// so bursts of responses aren't silently dropped on a busy node.
+    ///
+    /// The OS may not honor it exactly: Linux doubles it, and caps it at
+    /// `net.core.rmem_max`, which has to be raised for larger buffers to apply.
+    /// Only supported on unix platforms.
+    ///
+    /// Defaults to [crate::DEFAULT_RECV_BUFFER_SIZE]
+    pub fn recv_buffer_size(&mut self, bytes: usize) -> &mut Self {
+        self.0.recv_buffer_size = Some(bytes);
+
+        self
+    }
+
+    /// Maximum size in bytes of a sent datagram, to avoid fragmentation or drops
+    /// on paths with a smaller MTU.
+    ///
+    /// Responses with many nodes or peers are trimmed to fit, and larger requests
+    /// are not sent.
+    ///
+    /// Defaults to [crate::DEFAULT_MAX_DATAGRAM_SIZE]
+    pub fn max_datagram_size(&mut self, bytes: usize) -> &mut Self {
+        self.0.max_datagram_size = bytes;
+
+        self
+    }
+
+    /// Maximum duration of a single query, bounding the worst-case
+    /// latency of lookups that keep finding unreachable nodes.
+    ///
+    /// Defaults to [crate::DEFAULT_QUERY_DEADLINE]
+    pub fn query_deadline(&mut self, query_deadline: Duration) -> &mut Self {
+        self.0.query_deadline = query_deadline;
+
+        self
+    }
+
+    /// Maximum number of nodes a query waits for responses from concurrently.
+    ///
+    /// Defaults to [crate::DEFAULT_ALPHA]
+    pub fn alpha(&mut self, alpha: usize) -> &mut Self {
+        self.0.alpha = alpha;
+
+        self
+    }
+
+    /// Number of times a query retransmits a timed out request to the same node,
+    /// before considering it unresponsive.
+    ///
+    /// Defaults to [crate::DEFAULT_MAX_RETRIES]
+    pub fn max_retries(&mut self, max_retries: u8) -> &mut Self {
+        self.0.max_retries = max_retries;
+
+        self
+    }
+
+    /// Maximum number of requests a single query sends, including retransmissions,
+    /// after which it finishes with whatever it found.
+    ///
+    /// Defaults to None, where queries are only limited by their deadline.
+    pub fn max_requests_per_query(&mut self, max_requests: usize) -> &mut Self {
+        self.0.max_requests_per_query = Some(max_requests);
+
+        self
+    }
+
+    /// Number of unique peers after which a `get_peers` query stops visiting nodes
+    /// and finishes, so [Dht::stream_peers] yields at most this many peers.
+    ///
+    /// Defaults to None, where queries keep all the peers they find.
+    pub fn max_peers_per_query(&mut self, max_peers: usize) -> &mut Self {
+        self.0.max_peers_per_query = Some(max_peers);
+
+        self
+    }
+
+    /// Send `find_node` requests instead of `get_peers` requests once a `get_peers`
+    /// query found this many unique peers, see [Config::find_node_after_peers].
+    ///
+    /// Defaults to None, where `get_peers` requests are sent throughout the query.
+    pub fn find_node_after_peers(&mut self, peers: usize) -> &mut Self {
+        self.0.find_node_after_peers = Some(peers);
+
+        self
+    }
+
+    /// Finish queries as soon as a node sharing at least `matching_bits` leading bits
+    /// with their target responds, see [Config::closeness_floor].
+    ///
+    /// Defaults to None, where queries run until they converge.
+    pub fn closeness_floor(&mut self, matching_bits: u8) -> &mut Self {
+        self.0.closeness_floor = Some(matching_bits);
+
+        self
+    }
+
+    /// Fraction of the routing table refresh interval, and of queries'
+    /// retransmission backoffs, they are randomly shifted by, so that nodes
+    /// started together don't send bursts of requests in lockstep.
+    ///
+    /// Clamped to `[0, 1]`, and a NaN or infinite jitter disables jittering.
+    ///
+    /// Defaults to [crate::DEFAULT_JITTER]
+    pub fn jitter(&mut self, jitter: f64) -> &mut Self {
+        self.0.jitter = jitter;
+
+        self
+    }
+
+    /// Maximum number of iterations (hops) of sending requests to closer nodes,
+    /// after which a query finishes with whatever it found.
+    ///
+    /// Defaults to None, where queries are only limited by their deadline.
+    pub fn max_iterations(&mut self, max_iterations: usize) -> &mut Self {
+        self.0.max_iterations = Some(max_iterations);
+
+        self
+    }
+
+    /// Visit the closest nodes to a query's target strictly by distance, instead of
+    /// favoring nodes that responded to us before.
+    pub fn strict_kademlia(&mut self) -> &mut Self {
+        self.0.prefer_verified_nodes = false;
+
+        self
+    }
+
+    /// Handle the responses of every query with `strategy`, instead of following
+    /// the closer nodes in every response, see [Config::query_strategy].
+    pub fn query_strategy(&mut self, strategy: impl QueryStrategy + 'static) -> &mut Self {
+        self.0.query_strategy = Some(Box::new(strategy));
+
+        self
+    }
+
+    /// Call `on_timeout` with the target, the closest nodes and the peers found so far
+    /// by every query that hits its deadline, see [Config::on_timeout].
+    ///
+    /// It runs on the actor thread, so it should return quickly.
+    pub fn on_timeout(
+        &mut self,
+        on_timeout: impl Fn(Id, &[Node], &[SocketAddr]) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.0.on_timeout = Some(TimeoutCallback(Arc::new(on_timeout)));
+
+        self
+    }
+
+    /// Record the decisions of every query, collected with [Dht::query_info],
+    /// see [Config::trace_queries].
+    pub fn trace_queries(&mut self) -> &mut Self {
+        self.0.trace_queries = true;
+
+        self
+    }
+
+    /// Maximum number of outgoing requests per second.
+    ///
+    /// Defaults to None, where outgoing requests are not limited.
+    pub fn max_requests_per_sec(&mut self, max_requests_per_sec: NonZeroU32) -> &mut Self {
+        self.0.max_requests_per_sec = Some(max_requests_per_sec);
+
+        self
+    }
+
+    /// Maximum number of requests inflight to the same node at once, across all queries,
+    /// for example 1 to never send a node simultaneous requests.
+    ///
+    /// Defaults to None, where any number of requests can be inflight to the same node.
+    pub fn max_inflight_per_node(&mut self, max_inflight_per_node: NonZeroUsize) -> &mut Self {
+        self.0.max_inflight_per_node = Some(max_inflight_per_node);
+
+        self
+    }
+
+    /// Number of malformed messages received from an ip, after which it is blacklisted,
+    /// see [Dht::blacklist].
+    ///
+    /// Defaults to None, where ips are never blacklisted automatically.
+    pub fn max_protocol_errors(&mut self, max_protocol_errors: NonZeroU32) -> &mut Self {
+        self.0.max_protocol_errors = Some(max_protocol_errors);
+
+        self
+    }
+
+    /// Query, and add to the routing table, nodes at private, loopback,
+    /// and other reserved addresses, to run a Dht on a local network.
+    ///
+    /// Implied by bootstrapping from such an address.
+    pub fn allow_private_addresses(&mut self) -> &mut Self {
+        self.0.allow_private_addresses = true;
+
+        self
+    }
+
+    /// Send a single request, and share its response, when identical requests
+    /// to the same node are sent while the first one is still inflight,
+    /// see [Config::coalesce_requests].
+    pub fn coalesce_requests(&mut self) -> &mut Self {
+        self.0.coalesce_requests = true;
+
+        self
+    }
+
+    /// Ping the least recently seen node of a full bucket before replacing it
+    /// with a new node, see [Config::ping_before_evict].
+    pub fn ping_before_evict(&mut self) -> &mut Self {
+        self.0.ping_before_evict = true;
+
+        self
+    }
+
+    /// Drop received messages that aren't strictly valid according to
+    /// [BEP_0005](https://www.bittorrent.org/beps/bep_0005.html), instead of
+    /// ignoring their malformed optional fields, like a version that isn't 4 bytes.
+    pub fn strict_messages(&mut self) -> &mut Self {
+        self.0.strict_messages = true;
+
+        self
+    }
+
+    /// Maximum number of queries sending their requests from their own ephemeral
+    /// UDP port, instead of this node's port, so the nodes they visit can't correlate them.
+    ///
+    /// Each of these queries holds a file descriptor until it is done.
+    ///
+    /// Defaults to 0, where all queries share this node's port.
+    pub fn ephemeral_query_ports(&mut self, max: usize) -> &mut Self {
+        self.0.ephemeral_query_ports = max;
+
+        self
+    }
+
     /// Create a Dht node.
     pub fn build(&self) -> Result<Dht, std::io::Error> {
         Dht::new(self.0.clone())
@@ -171,6 +457,22 @@ impl Dht {
         rx.recv().expect("actor thread unexpectedly shutdown")
     }
 
+    /// Returns this node's [Id], which stays the same for the rest of the session,
+    /// unless it is replaced by a [secure](Id::is_valid_for_ip) one once
+    /// this node learns its public address (see [DhtBuilder::id]).
+    pub fn local_id(&self) -> Id {
+        *self.info().id()
+    }
+
+    /// Returns a snapshot of the inflight query to this target, if any,
+    /// for example to see which clients responded to a slow lookup.
+    pub fn query_info(&self, target: Id) -> Option<QueryInfo> {
+        let (tx, rx) = flume::bounded::<Option<QueryInfo>>(1);
+        self.send(ActorMessage::QueryInfo(target, tx));
+
+        rx.recv().expect("actor thread unexpectedly shutdown")
+    }
+
     /// Turn this node's routing table to a list of bootstrapping nodes.   
     pub fn to_bootstrap(&self) -> Vec<String> {
         let (tx, rx) = flume::bounded::<Vec<String>>(1);
@@ -181,6 +483,64 @@ impl Dht {
 
     // === Public Methods ===
 
+    /// Stop sending requests to, and accepting messages from, this ip,
+    /// for example after it kept responding with bogus data.
+    ///
+    /// Nodes with this ip are also removed from the routing table.
+    pub fn blacklist(&self, ip: Ipv4Addr) {
+        self.send(ActorMessage::Blacklist(ip));
+    }
+
+    /// Ping a node, and return its [Id] and the round trip time,
+    /// or None if it didn't respond within the request timeout.
+    ///
+    /// Useful to check that nodes from a persisted routing table are still alive.
+    pub fn ping(&self, address: SocketAddrV4) -> Option<(Id, Duration)> {
+        let (tx, rx) = flume::bounded::<Option<(Id, Duration)>>(1);
+        self.send(ActorMessage::Ping(address, tx));
+
+        rx.recv().expect("actor thread unexpectedly shutdown")
+    }
+
+    /// Cancel the inflight query to this target, if any, for example a `get_peers`
+    /// lookup that already found enough peers.
+    ///
+    /// Callers waiting for its results get what was found so far, as if its
+    /// [DhtBuilder::query_deadline] passed, and its iterators end.
+    ///
+    /// Returns `false` if there was no inflight query to this target.
+    pub fn cancel(&self, target: Id) -> bool {
+        let (tx, rx) = flume::bounded::<bool>(1);
+        self.send(ActorMessage::Cancel(target, tx));
+
+        rx.recv().expect("actor thread unexpectedly shutdown")
+    }
+
+    /// Bind this node's Ipv4 socket to a new address, for example after moving
+    /// to another network interface.
+    ///
+    /// Inflight queries carry on through the new socket: they visit the nodes that
+    /// didn't respond yet again, and keep what they found so far.
+    pub fn rebind(&self, address: SocketAddrV4) -> Result<(), std::io::Error> {
+        let (tx, rx) = flume::bounded::<Result<(), std::io::Error>>(1);
+        self.send(ActorMessage::Rebind(address, tx));
+
+        rx.recv().expect("actor thread unexpectedly shutdown")
+    }
+
+    /// Stop sending requests, wait up to the [DhtBuilder::request_timeout] for
+    /// responses to the inflight requests, then stop this node's thread,
+    /// for applications that create and destroy Dht nodes repeatedly.
+    ///
+    /// Inflight queries are done with whatever they found by the time this returns.
+    /// Calling any other method afterwards, on this node or its clones, panics.
+    pub fn shutdown(&self) {
+        let (tx, rx) = flume::bounded::<()>(1);
+        self.send(ActorMessage::Shutdown(tx));
+
+        rx.recv().expect("actor thread unexpectedly shutdown")
+    }
+
     /// Block until the bootstrapping query is done.
     ///
     /// Returns true if the bootstrapping was successful.
@@ -206,6 +566,9 @@ impl Dht {
     /// If you are trying to find the closest nodes to a target with intent to [Self::put],
     /// a request directly to these nodes (using `extra_nodes` parameter), then you should
     /// use [Self::get_closest_nodes] instead.
+    ///
+    /// Blocks until the query is done, or its [DhtBuilder::query_deadline] passed,
+    /// in which case the closest nodes found so far are returned.
     pub fn find_node(&self, target: Id) -> Box<[Node]> {
         let (tx, rx) = flume::bounded::<Box<[Node]>>(1);
         self.send(ActorMessage::Get(
@@ -231,30 +594,138 @@ impl Dht {
     pub fn get_peers(&self, info_hash: Id) -> GetIterator<Vec<SocketAddrV4>> {
         let (tx, rx) = flume::unbounded::<Vec<SocketAddrV4>>();
         self.send(ActorMessage::Get(
-            GetRequestSpecific::GetPeers(GetPeersRequestArguments { info_hash }),
+            GetRequestSpecific::GetPeers(GetPeersRequestArguments {
+                info_hash,
+                scrape: false,
+            }),
             ResponseSender::Peers(tx),
         ));
 
         GetIterator(rx.into_iter())
     }
 
+    /// Get all unique peers for a given infohash, sorted.
+    ///
+    /// Blocks until the query is done, or its [DhtBuilder::query_deadline] has passed.
+    ///
+    /// Use [Self::get_peers] instead to start connecting to peers
+    /// as soon as they are found.
+    pub fn get_all_peers(&self, info_hash: Id) -> Vec<SocketAddrV4> {
+        self.get_peers(info_hash)
+            .flatten()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Same as [Self::get_peers], but yields every unique peer once, as soon as
+    /// it is found, instead of the batches of peers in each response.
+    ///
+    /// Ends once the query is done, or its [DhtBuilder::query_deadline] has passed.
+    pub fn stream_peers(&self, info_hash: Id) -> PeersIterator {
+        let (tx, rx) = std::sync::mpsc::channel::<SocketAddr>();
+        self.send(ActorMessage::StreamPeers(info_hash, tx));
+
+        PeersIterator(rx.into_iter())
+    }
+
+    /// Same as [Self::get_all_peers], but also returns the closest nodes that
+    /// responded, and their tokens, to [announce](Self::put) to them later
+    /// without looking them up again.
+    ///
+    /// Blocks until the query is done, or its [DhtBuilder::query_deadline] has passed.
+    pub fn get_peers_result(&self, info_hash: Id) -> GetPeersResult {
+        let (peers_tx, peers_rx) = flume::unbounded::<Vec<SocketAddrV4>>();
+        let (closest_tx, closest_rx) = flume::bounded::<Box<[Node]>>(1);
+        self.send(ActorMessage::Get(
+            GetRequestSpecific::GetPeers(GetPeersRequestArguments {
+                info_hash,
+                scrape: false,
+            }),
+            ResponseSender::PeersResult(peers_tx, closest_tx),
+        ));
+
+        let closest = closest_rx
+            .recv()
+            .expect("Query was dropped before sending a response, please open an issue.");
+        let peers = peers_rx
+            .into_iter()
+            .flatten()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+
+        GetPeersResult::new(peers, closest)
+    }
+
+    /// Sample info hashes stored by nodes close to `target`, for example a random
+    /// [Id] to index the network, using
+    /// [BEP_0051](https://www.bittorrent.org/beps/bep_0051.html) `sample_infohashes` requests.
+    ///
+    /// Each item is a batch of info hashes from one node, not already sampled from
+    /// other nodes by the same query. Nodes are not sampled again before the interval
+    /// they ask for elapsed, and nodes that don't support BEP_0051 don't contribute any.
+    pub fn sample_infohashes(&self, target: Id) -> GetIterator<Vec<Id>> {
+        let (tx, rx) = flume::unbounded::<Vec<Id>>();
+        self.send(ActorMessage::Get(
+            GetRequestSpecific::SampleInfohashes(SampleInfohashesRequestArguments { target }),
+            ResponseSender::Samples(tx),
+        ));
+
+        GetIterator(rx.into_iter())
+    }
+
+    /// Estimate the number of seeders and peers of an infohash, without
+    /// enumerating them, from the bloom filters of nodes supporting
+    /// [BEP_0033](https://www.bittorrent.org/beps/bep_0033.html) scrapes.
+    ///
+    /// Blocks until the query is done. Nodes that don't support scrapes don't
+    /// contribute to the estimate, so it is zero if none of them did.
+    pub fn scrape(&self, info_hash: Id) -> ScrapeEstimate {
+        let (tx, rx) = flume::unbounded::<ScrapeEstimate>();
+        self.send(ActorMessage::Get(
+            GetRequestSpecific::GetPeers(GetPeersRequestArguments {
+                info_hash,
+                scrape: true,
+            }),
+            ResponseSender::Scrape(tx),
+        ));
+
+        // Each estimate accounts for all the filters merged before it.
+        rx.into_iter().last().unwrap_or_default()
+    }
+
     /// Announce a peer for a given infohash.
     ///
     /// The peer will be announced on this process IP.
     /// If explicit port is passed, it will be used, otherwise the port will be implicitly
     /// assumed by remote nodes to be the same ase port they received the request from.
+    ///
+    /// Unless a recent lookup of `info_hash` is cached, this first looks up its closest
+    /// nodes, keeping the token each of them returned, then sends `announce_peer` to
+    /// the closest responders with their own token. Responders that didn't return a
+    /// token are skipped.
     pub fn announce_peer(&self, info_hash: Id, port: Option<u16>) -> Result<Id, PutQueryError> {
-        let (port, implied_port) = match port {
-            Some(port) => (port, None),
-            None => (0, Some(true)),
-        };
+        self.announce_peer_with(
+            info_hash,
+            AnnounceOptions {
+                port,
+                implied_port: port.is_none(),
+            },
+        )
+    }
 
+    /// Announce a peer for a given infohash with [AnnounceOptions].
+    ///
+    /// The peer will be announced on this process IP, and the port is ignored
+    /// if [AnnounceOptions::implied_port] is set.
+    pub fn announce_peer_with(
+        &self,
+        info_hash: Id,
+        options: AnnounceOptions,
+    ) -> Result<Id, PutQueryError> {
         self.put(
-            PutRequestSpecific::AnnouncePeer(AnnouncePeerRequestArguments {
-                info_hash,
-                port,
-                implied_port,
-            }),
+            PutRequestSpecific::AnnouncePeer(AnnouncePeerRequestArguments::new(info_hash, options)),
             None,
         )
         .map_err(|error| match error {
@@ -265,6 +736,42 @@ impl Dht {
         })
     }
 
+    // === Crawl ===
+
+    /// Crawl the network with an endless series of `find_node` queries to random
+    /// targets, yielding every newly discovered responding node.
+    ///
+    /// The crawl goes on until [Self::stop_crawl] is called or the returned iterator
+    /// is dropped, and replaces the current crawl, if any. Nodes evicted from the
+    /// crawl's table of discovered nodes (10,000 nodes) can be yielded again if
+    /// discovered again.
+    pub fn crawl(&self) -> CrawlIterator {
+        let (tx, rx) = std::sync::mpsc::channel::<Node>();
+        self.send(ActorMessage::Crawl(tx, None));
+
+        CrawlIterator(rx.into_iter())
+    }
+
+    /// Same as [Self::crawl], but nodes discovered once the crawl's table of discovered
+    /// nodes is full are appended to `spill` instead, so even crawls larger than the
+    /// memory never yield the same node twice.
+    ///
+    /// The crawl stops if appending to `spill` fails.
+    pub fn crawl_with_spill(&self, spill: SpillStore) -> CrawlIterator {
+        let (tx, rx) = std::sync::mpsc::channel::<Node>();
+        self.send(ActorMessage::Crawl(tx, Some(spill)));
+
+        CrawlIterator(rx.into_iter())
+    }
+
+    /// Stop the current crawl, returning `false` if there was none.
+    pub fn stop_crawl(&self) -> bool {
+        let (tx, rx) = flume::bounded::<bool>(1);
+        self.send(ActorMessage::StopCrawl(tx));
+
+        rx.recv().expect("actor thread unexpectedly shutdown")
+    }
+
     // === Immutable data ===
 
     /// Get an Immutable data by its sha1 hash.
@@ -486,7 +993,33 @@ impl<T> Iterator for GetIterator<T> {
     }
 }
 
+pub struct PeersIterator(std::sync::mpsc::IntoIter<SocketAddr>);
+
+impl Iterator for PeersIterator {
+    type Item = SocketAddrV4;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Peers are only received in Ipv4 compact form.
+        self.0.find_map(|peer| match peer {
+            SocketAddr::V4(peer) => Some(peer),
+            SocketAddr::V6(_) => None,
+        })
+    }
+}
+
+pub struct CrawlIterator(std::sync::mpsc::IntoIter<Node>);
+
+impl Iterator for CrawlIterator {
+    type Item = Node;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
 fn run(config: Config, receiver: Receiver<ActorMessage>) {
+    let request_timeout = config.request_timeout;
+
     match Rpc::new(config) {
         Ok(mut rpc) => {
             let address = rpc.local_addr();
@@ -494,8 +1027,12 @@ fn run(config: Config, receiver: Receiver<ActorMessage>) {
 
             let mut put_senders = HashMap::new();
             let mut get_senders = HashMap::new();
+            let mut ping_senders = HashMap::new();
+            let mut shutdown_sender = None;
 
             loop {
+                let mut shutdown_responses = Vec::new();
+
                 match receiver.try_recv() {
                     Ok(actor_message) => match actor_message {
                         ActorMessage::Check(sender) => {
@@ -504,6 +1041,9 @@ fn run(config: Config, receiver: Receiver<ActorMessage>) {
                         ActorMessage::Info(sender) => {
                             let _ = sender.send(rpc.info());
                         }
+                        ActorMessage::QueryInfo(target, sender) => {
+                            let _ = sender.send(rpc.query_info(&target));
+                        }
                         ActorMessage::Put(request, sender, extra_nodes) => {
                             let target = *request.target();
 
@@ -534,6 +1074,39 @@ fn run(config: Config, receiver: Receiver<ActorMessage>) {
                         ActorMessage::ToBootstrap(sender) => {
                             let _ = sender.send(rpc.routing_table().to_bootstrap());
                         }
+                        ActorMessage::Blacklist(ip) => {
+                            rpc.blacklist(ip);
+                        }
+                        ActorMessage::StreamPeers(info_hash, tx) => {
+                            rpc.stream_peers(info_hash, tx);
+                        }
+                        ActorMessage::Cancel(target, sender) => {
+                            let _ = sender.send(rpc.cancel(&target));
+                        }
+                        ActorMessage::Rebind(address, sender) => {
+                            let _ = sender.send(rpc.rebind(address));
+                        }
+                        ActorMessage::Crawl(tx, spill) => match spill {
+                            Some(spill) => rpc.crawl_with_spill(tx, spill),
+                            None => rpc.crawl(tx),
+                        },
+                        ActorMessage::StopCrawl(sender) => {
+                            let _ = sender.send(rpc.stop_crawl());
+                        }
+                        ActorMessage::Shutdown(sender) => {
+                            shutdown_responses = rpc.shutdown(request_timeout);
+                            shutdown_sender = Some(sender);
+                        }
+                        ActorMessage::Ping(address, sender) => {
+                            match rpc.ping_node(address.into()) {
+                                Ok(tid) => {
+                                    ping_senders.insert(tid, sender);
+                                }
+                                Err(_) => {
+                                    let _ = sender.send(None);
+                                }
+                            }
+                        }
                     },
                     Err(TryRecvError::Disconnected) => {
                         // Node was dropped, kill this thread.
@@ -548,7 +1121,10 @@ fn run(config: Config, receiver: Receiver<ActorMessage>) {
                 let report = rpc.tick();
 
                 // Response for an ongoing GET query
-                if let Some((target, response)) = report.new_query_response {
+                for (target, response) in shutdown_responses
+                    .into_iter()
+                    .chain(report.new_query_response)
+                {
                     if let Some(senders) = get_senders.get(&target) {
                         for sender in senders {
                             send(sender, response.clone());
@@ -561,13 +1137,23 @@ fn run(config: Config, receiver: Receiver<ActorMessage>) {
                     if let Some(senders) = get_senders.remove(&id) {
                         for sender in senders {
                             // return closest_nodes to whoever was asking
-                            if let ResponseSender::ClosestNodes(sender) = sender {
-                                let _ = sender.send(closest_nodes.clone());
+                            match sender {
+                                ResponseSender::ClosestNodes(sender)
+                                | ResponseSender::PeersResult(_, sender) => {
+                                    let _ = sender.send(closest_nodes.clone());
+                                }
+                                _ => {}
                             }
                         }
                     }
                 }
 
+                for (tid, result) in report.done_pings {
+                    if let Some(sender) = ping_senders.remove(&tid) {
+                        let _ = sender.send(result);
+                    }
+                }
+
                 // Cleanup done PUT query and send a resulting error if any.
                 for (id, error) in report.done_put_queries {
                     if let Some(senders) = put_senders.remove(&id) {
@@ -582,7 +1168,15 @@ fn run(config: Config, receiver: Receiver<ActorMessage>) {
                         }
                     }
                 }
-            }
+
+                // Queries are done after the tick following the shutdown.
+                if let Some(sender) = shutdown_sender.take() {
+                    tracing::debug!("mainline::Dht's actor thread was shutdown.");
+
+                    let _ = sender.send(());
+                    break;
+                }
+            }
         }
         Err(err) => {
             if let Ok(ActorMessage::Check(sender)) = receiver.try_recv() {
@@ -594,7 +1188,8 @@ fn run(config: Config, receiver: Receiver<ActorMessage>) {
 
 fn send(sender: &ResponseSender, response: Response) {
     match (sender, response) {
-        (ResponseSender::Peers(s), Response::Peers(r)) => {
+        (ResponseSender::Peers(s), Response::Peers(r))
+        | (ResponseSender::PeersResult(s, _), Response::Peers(r)) => {
             let _ = s.send(r);
         }
         (ResponseSender::Mutable(s), Response::Mutable(r)) => {
@@ -603,6 +1198,12 @@ fn send(sender: &ResponseSender, response: Response) {
         (ResponseSender::Immutable(s), Response::Immutable(r)) => {
             let _ = s.send(r);
         }
+        (ResponseSender::Scrape(s), Response::Scrape(r)) => {
+            let _ = s.send(r);
+        }
+        (ResponseSender::Samples(s), Response::Samples(r)) => {
+            let _ = s.send(r);
+        }
         _ => {}
     }
 }
@@ -610,6 +1211,7 @@ fn send(sender: &ResponseSender, response: Response) {
 #[derive(Debug)]
 pub(crate) enum ActorMessage {
     Info(Sender<Info>),
+    QueryInfo(Id, Sender<Option<QueryInfo>>),
     Put(
         PutRequestSpecific,
         Sender<Result<Id, PutError>>,
@@ -618,6 +1220,14 @@ pub(crate) enum ActorMessage {
     Get(GetRequestSpecific, ResponseSender),
     Check(Sender<Result<(), std::io::Error>>),
     ToBootstrap(Sender<Vec<String>>),
+    Blacklist(Ipv4Addr),
+    Ping(SocketAddrV4, Sender<Option<(Id, Duration)>>),
+    StreamPeers(Id, std::sync::mpsc::Sender<SocketAddr>),
+    Cancel(Id, Sender<bool>),
+    Rebind(SocketAddrV4, Sender<Result<(), std::io::Error>>),
+    Crawl(std::sync::mpsc::Sender<Node>, Option<SpillStore>),
+    StopCrawl(Sender<bool>),
+    Shutdown(Sender<()>),
 }
 
 #[derive(Debug, Clone)]
@@ -626,6 +1236,10 @@ pub enum ResponseSender {
     Peers(Sender<Vec<SocketAddrV4>>),
     Mutable(Sender<MutableItem>),
     Immutable(Sender<Box<[u8]>>),
+    Scrape(Sender<ScrapeEstimate>),
+    Samples(Sender<Vec<Id>>),
+    /// Peers as they are found, then the closest nodes once the query is done.
+    PeersResult(Sender<Vec<SocketAddrV4>>, Sender<Box<[Node]>>),
 }
 
 /// Create a testnet of Dht nodes to run tests against instead of the real mainline network.
@@ -649,7 +1263,11 @@ impl Testnet {
 
         for i in 0..count {
             if i == 0 {
-                let node = Dht::builder().server_mode().no_bootstrap().build()?;
+                let node = Dht::builder()
+                    .server_mode()
+                    .allow_private_addresses()
+                    .no_bootstrap()
+                    .build()?;
 
                 let info = node.info();
                 let addr = info.local_addr();
@@ -658,7 +1276,11 @@ impl Testnet {
 
                 nodes.push(node)
             } else {
-                let node = Dht::builder().server_mode().bootstrap(&bootstrap).build()?;
+                let node = Dht::builder()
+                    .server_mode()
+                    .allow_private_addresses()
+                    .bootstrap(&bootstrap)
+                    .build()?;
                 nodes.push(node)
             }
         }
@@ -696,10 +1318,13 @@ pub enum PutMutableError {
 #[cfg(test)]
 mod test {
     use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
 
     use ed25519_dalek::SigningKey;
 
     use crate::rpc::ConcurrencyError;
+    use crate::{ClosestNodesStrategy, Message, QueryEvent};
 
     use super::*;
 
@@ -714,6 +1339,14 @@ mod test {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn explicit_id() {
+        let id = Id::random();
+        let dht = Dht::builder().id(id).no_bootstrap().build().unwrap();
+
+        assert_eq!(dht.local_id(), id);
+    }
+
     #[test]
     fn announce_get_peer() {
         let testnet = Testnet::new(10).unwrap();
@@ -737,6 +1370,290 @@ mod test {
         assert_eq!(peers.first().unwrap().port(), 45555);
     }
 
+    #[test]
+    fn announce_stream_peers() {
+        let testnet = Testnet::new(10).unwrap();
+
+        let a = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .build()
+            .unwrap();
+        let b = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .build()
+            .unwrap();
+
+        let info_hash = Id::random();
+
+        a.announce_peer(info_hash, Some(45555))
+            .expect("failed to announce");
+
+        let peers = b.stream_peers(info_hash).collect::<Vec<_>>();
+
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].port(), 45555);
+    }
+
+    #[test]
+    fn read_only() {
+        let testnet = Testnet::new(10).unwrap();
+
+        let read_only = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .server_mode()
+            .read_only()
+            .build()
+            .unwrap();
+        assert!(!read_only.info().server_mode());
+
+        let id = *read_only.info().id();
+        assert!(!read_only.find_node(Id::random()).is_empty());
+
+        let client = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .build()
+            .unwrap();
+
+        // No node added the read-only node to their routing table.
+        assert!(!client.find_node(id).iter().any(|node| *node.id() == id));
+    }
+
+    #[test]
+    fn get_peers_result() {
+        let testnet = Testnet::new(10).unwrap();
+
+        let a = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .build()
+            .unwrap();
+        let b = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .build()
+            .unwrap();
+
+        let info_hash = Id::random();
+
+        a.announce_peer(info_hash, Some(45555))
+            .expect("failed to announce");
+
+        let result = b.get_peers_result(info_hash);
+
+        assert_eq!(result.peers.len(), 1);
+        assert_eq!(result.peers[0].port(), 45555);
+        assert!(!result.closest.is_empty());
+        assert_eq!(result.tokens.len(), result.closest.len());
+
+        // Enough to announce to the same nodes without another lookup.
+        b.put(
+            PutRequestSpecific::AnnouncePeer(AnnouncePeerRequestArguments::new(
+                info_hash,
+                AnnounceOptions {
+                    port: Some(45556),
+                    implied_port: false,
+                },
+            )),
+            Some(result.closest.into()),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn sample_infohashes() {
+        let testnet = Testnet::new(10).unwrap();
+
+        let a = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .build()
+            .unwrap();
+        let b = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .build()
+            .unwrap();
+
+        let info_hash = Id::random();
+
+        a.announce_peer(info_hash, Some(45555))
+            .expect("failed to announce");
+
+        let samples = b.sample_infohashes(info_hash).flatten().collect::<Vec<_>>();
+
+        assert_eq!(samples, vec![info_hash]);
+    }
+
+    #[test]
+    fn ephemeral_query_ports() {
+        let testnet = Testnet::new(10).unwrap();
+
+        let a = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .build()
+            .unwrap();
+        let b = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .ephemeral_query_ports(4)
+            .build()
+            .unwrap();
+
+        let info_hash = Id::random();
+
+        a.announce_peer(info_hash, Some(45555))
+            .expect("failed to announce");
+
+        let peers = b.get_peers(info_hash).next().expect("No peers");
+
+        assert_eq!(peers.first().unwrap().port(), 45555);
+    }
+
+    #[test]
+    fn routing_table_learns_from_queries() {
+        let testnet = Testnet::new(10).unwrap();
+
+        let dht = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .build()
+            .unwrap();
+
+        let closest = dht.find_node(Id::random());
+        assert!(!closest.is_empty());
+
+        // Responding nodes are written back into the routing table,
+        // seeding the next queries.
+        let table = dht.to_bootstrap();
+        for node in closest.iter() {
+            assert!(table.contains(&node.address().to_string()));
+        }
+    }
+
+    #[test]
+    fn lookup_metrics() {
+        let testnet = Testnet::new(10).unwrap();
+
+        let dht = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .build()
+            .unwrap();
+
+        dht.find_node(Id::random());
+        let _ = dht.get_peers(Id::random()).collect::<Vec<_>>();
+
+        let metrics = dht.info().lookup_metrics();
+        // Bootstrapping, plus the explicit lookup.
+        assert!(metrics.find_node.count() >= 2);
+        assert_eq!(metrics.get_peers.count(), 1);
+        assert_eq!(metrics.get_value.count(), 0);
+    }
+
+    #[test]
+    fn announce_get_all_peers() {
+        let testnet = Testnet::new(10).unwrap();
+
+        let a = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .build()
+            .unwrap();
+        let b = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .build()
+            .unwrap();
+
+        let info_hash = Id::random();
+
+        a.announce_peer(info_hash, Some(45556))
+            .expect("failed to announce");
+        a.announce_peer(info_hash, Some(45555))
+            .expect("failed to announce");
+
+        let ports = b
+            .get_all_peers(info_hash)
+            .iter()
+            .map(|peer| peer.port())
+            .collect::<Vec<_>>();
+
+        assert_eq!(ports, vec![45555, 45556]);
+    }
+
+    #[test]
+    fn max_peers_per_query() {
+        let testnet = Testnet::new(10).unwrap();
+
+        let a = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .build()
+            .unwrap();
+        let b = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .max_peers_per_query(1)
+            .build()
+            .unwrap();
+
+        let info_hash = Id::random();
+
+        a.announce_peer(info_hash, Some(45556))
+            .expect("failed to announce");
+        a.announce_peer(info_hash, Some(45555))
+            .expect("failed to announce");
+
+        assert_eq!(b.stream_peers(info_hash).count(), 1);
+    }
+
+    #[test]
+    fn find_node_after_peers() {
+        let testnet = Testnet::new(10).unwrap();
+
+        let a = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .build()
+            .unwrap();
+        let b = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .find_node_after_peers(1)
+            .build()
+            .unwrap();
+
+        let info_hash = Id::random();
+
+        a.announce_peer(info_hash, Some(45555))
+            .expect("failed to announce");
+
+        let result = b.get_peers_result(info_hash);
+
+        assert_eq!(result.peers.len(), 1);
+        assert!(!result.closest.is_empty());
+    }
+
+    #[test]
+    fn parallel_get_peers() {
+        let testnet = Testnet::new(10).unwrap();
+
+        let a = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .build()
+            .unwrap();
+        let b = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .build()
+            .unwrap();
+
+        let info_hashes = [Id::random(), Id::random(), Id::random()];
+
+        for (i, info_hash) in info_hashes.iter().enumerate() {
+            a.announce_peer(*info_hash, Some(45550 + i as u16))
+                .expect("failed to announce");
+        }
+
+        // Start all lookups before consuming any of them.
+        let lookups = info_hashes
+            .iter()
+            .map(|info_hash| b.get_peers(*info_hash))
+            .collect::<Vec<_>>();
+
+        for (i, mut lookup) in lookups.into_iter().enumerate() {
+            let peers = lookup.next().expect("No peers");
+
+            assert_eq!(peers.first().unwrap().port(), 45550 + i as u16);
+        }
+    }
+
     #[test]
     fn put_get_immutable() {
         let testnet = Testnet::new(10).unwrap();
@@ -761,6 +1678,245 @@ mod test {
         assert_eq!(response, value.to_vec().into_boxed_slice());
     }
 
+    #[test]
+    fn ping() {
+        let testnet = Testnet::new(1).unwrap();
+        let node = Dht::builder().no_bootstrap().build().unwrap();
+
+        let address = SocketAddrV4::new(
+            [127, 0, 0, 1].into(),
+            testnet.nodes[0].info().local_addr().port(),
+        );
+
+        let (id, rtt) = node.ping(address).expect("no response");
+
+        assert_eq!(id, *testnet.nodes[0].info().id());
+        assert!(rtt < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn cancel() {
+        // Never responds, so the query would run until its deadline.
+        let dht = Dht::builder()
+            .bootstrap(&["127.0.0.1:1"])
+            .query_deadline(Duration::from_secs(60))
+            .build()
+            .unwrap();
+
+        let info_hash = Id::random();
+        let peers = dht.get_peers(info_hash);
+
+        let info = dht.query_info(info_hash).unwrap();
+        assert_eq!(*info.target(), info_hash);
+        assert!(!info.state().is_done());
+        assert!(info.client_versions().is_empty());
+        assert!(info.peers().is_empty());
+        assert!(!info.found_peers());
+        assert_eq!(info.peer_responses(), 0);
+        assert!(info.responding_nodes().is_empty());
+
+        assert!(dht.cancel(info_hash));
+        assert_eq!(peers.count(), 0);
+        assert!(dht.query_info(info_hash).is_none());
+
+        assert!(!dht.cancel(info_hash));
+    }
+
+    #[test]
+    fn trace_queries() {
+        let dht = Dht::builder()
+            .bootstrap(&["127.0.0.1:1"])
+            .query_deadline(Duration::from_secs(60))
+            .trace_queries()
+            .build()
+            .unwrap();
+
+        let info_hash = Id::random();
+        let _peers = dht.get_peers(info_hash);
+
+        let info = dht.query_info(info_hash).unwrap();
+        let bootstrap = "127.0.0.1:1".parse().unwrap();
+        assert!(info.trace().iter().any(|event| matches!(
+            event,
+            QueryEvent::Visited { address, .. } if *address == bootstrap
+        )));
+
+        // Events are only collected once.
+        let info = dht.query_info(info_hash).unwrap();
+        assert!(info.trace().is_empty());
+    }
+
+    #[test]
+    fn on_timeout() {
+        let (tx, rx) = flume::unbounded();
+
+        // Never responds, so the query runs until its deadline.
+        let dht = Dht::builder()
+            .bootstrap(&["127.0.0.1:1"])
+            .query_deadline(Duration::from_millis(200))
+            .on_timeout(move |target, closest, _| {
+                let _ = tx.send((target, closest.len()));
+            })
+            .build()
+            .unwrap();
+
+        let target = Id::random();
+        assert!(dht.find_node(target).is_empty());
+
+        // The bootstrapping query to the node's own Id times out as well.
+        assert!(
+            std::iter::from_fn(|| rx.recv_timeout(Duration::from_secs(5)).ok())
+                .any(|timed_out| timed_out == (target, 0))
+        );
+    }
+
+    #[test]
+    fn shutdown() {
+        // Never responds, so the query would run until its deadline.
+        let dht = Dht::builder()
+            .bootstrap(&["127.0.0.1:1"])
+            .request_timeout(Duration::from_millis(100))
+            .query_deadline(Duration::from_secs(60))
+            .build()
+            .unwrap();
+
+        let peers = dht.get_peers(Id::random());
+        let closest = thread::spawn({
+            let dht = dht.clone();
+            move || dht.find_node(Id::random())
+        });
+        // Let the find_node query start before shutting down.
+        thread::sleep(Duration::from_millis(50));
+
+        dht.shutdown();
+        assert_eq!(peers.count(), 0);
+        assert!(closest.join().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rebind() {
+        let testnet = Testnet::new(10).unwrap();
+        let dht = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .build()
+            .unwrap();
+        dht.bootstrapped();
+
+        let old_address = dht.info().local_addr();
+        dht.rebind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .unwrap();
+        assert_ne!(dht.info().local_addr(), old_address);
+
+        // Responses reach the new socket.
+        assert!(!dht.find_node(Id::random()).is_empty());
+    }
+
+    #[test]
+    fn crawl() {
+        let testnet = Testnet::new(10).unwrap();
+        let dht = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .build()
+            .unwrap();
+        dht.bootstrapped();
+
+        let ids = testnet
+            .nodes
+            .iter()
+            .map(|node| node.local_id())
+            .collect::<Vec<_>>();
+
+        // Testnet nodes share an ip, so only one of them is discovered.
+        let crawled = dht.crawl().next().unwrap();
+        assert!(ids.contains(crawled.id()));
+
+        // Ends once stopped, after whatever it discovered meanwhile.
+        let crawl = dht.crawl();
+        assert!(dht.stop_crawl());
+        assert!(crawl.count() <= 1);
+
+        assert!(!dht.stop_crawl());
+
+        let path = std::env::temp_dir().join(format!("mainline-dht-spill-{}", Id::random()));
+        let spill = SpillStore::create(&path, 1000, 0.01).unwrap();
+
+        let crawled = dht.crawl_with_spill(spill).next().unwrap();
+        assert!(ids.contains(crawled.id()));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn query_strategy() {
+        /// Counts responses, following closer nodes as the default strategy does.
+        #[derive(Debug, Clone)]
+        struct Counting(Arc<AtomicUsize>);
+
+        impl QueryStrategy for Counting {
+            fn on_response(&mut self, node: &Node, message: &Message) -> Vec<Node> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+
+                ClosestNodesStrategy.on_response(node, message)
+            }
+
+            fn clone_box(&self) -> Box<dyn QueryStrategy> {
+                Box::new(self.clone())
+            }
+        }
+
+        let testnet = Testnet::new(10).unwrap();
+        let responses = Arc::new(AtomicUsize::new(0));
+
+        let dht = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .query_strategy(Counting(responses.clone()))
+            .build()
+            .unwrap();
+
+        assert!(!dht.find_node(Id::random()).is_empty());
+        assert!(responses.load(Ordering::Relaxed) > 0);
+
+        /// Drops every response.
+        #[derive(Debug, Clone)]
+        struct RejectAll;
+
+        impl QueryStrategy for RejectAll {
+            fn on_response(&mut self, node: &Node, message: &Message) -> Vec<Node> {
+                ClosestNodesStrategy.on_response(node, message)
+            }
+
+            fn accept(&mut self, _node: &Node, _message: &Message) -> bool {
+                false
+            }
+
+            fn clone_box(&self) -> Box<dyn QueryStrategy> {
+                Box::new(self.clone())
+            }
+        }
+
+        let dht = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .query_strategy(RejectAll)
+            .build()
+            .unwrap();
+
+        assert!(dht.find_node(Id::random()).is_empty());
+    }
+
+    #[test]
+    fn closeness_floor() {
+        let testnet = Testnet::new(10).unwrap();
+
+        // Any responding node is close enough.
+        let dht = Dht::builder()
+            .bootstrap(&testnet.bootstrap)
+            .closeness_floor(0)
+            .build()
+            .unwrap();
+
+        assert!(!dht.find_node(Id::random()).is_empty());
+    }
+
     #[test]
     fn find_node_no_values() {
         let client = Dht::builder().no_bootstrap().build().unwrap();
@@ -957,7 +2113,7 @@ mod test {
             let handle = std::thread::spawn(move || {
                 let result = client.put_mutable(item, None);
                 if i == 0 {
-                    assert!(matches!(result, Ok(_)))
+                    assert!(result.is_ok())
                 } else {
                     assert!(matches!(
                         result,
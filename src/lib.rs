@@ -16,15 +16,27 @@ mod rpc;
 #[cfg(feature = "async")]
 pub mod async_dht;
 
-pub use common::{Id, MutableItem, Node, RoutingTable};
+pub use common::{
+    AnnounceOptions, BootstrapSources, Distance, DualTable, EvictionPolicy, Id, KeepVerifiedLive,
+    LeastRecentlySeen, MutableItem, Node, RoutingHealth, RoutingTable, RoutingTableEvent,
+    MIN_BOOTSTRAPPED_BUCKETS,
+};
 
 #[cfg(feature = "node")]
 pub use dht::{Dht, DhtBuilder, Testnet};
 #[cfg(feature = "node")]
 pub use rpc::{
-    messages::{MessageType, PutRequestSpecific, RequestSpecific},
-    server::{RequestFilter, ServerSettings, MAX_INFO_HASHES, MAX_PEERS, MAX_VALUES},
-    ClosestNodes, DEFAULT_REQUEST_TIMEOUT,
+    messages::{Message, MessageType, PutRequestSpecific, RequestSpecific, Want},
+    server::{
+        RequestFilter, ServerSettings, DEFAULT_MAX_RESPONSE_NODES, MAX_INFO_HASHES, MAX_PEERS,
+        MAX_RESPONSE_NODES, MAX_SAMPLES, MAX_VALUES, SAMPLE_INTERVAL,
+    },
+    ClosestNodes, ClosestNodesStrategy, GetPeersResult, IgnoreReason, LatencyHistogram,
+    LookupMetrics, QueryEvent, QueryInfo, QueryState, QueryStrategy, ScrapeEstimate, SocketMetrics,
+    SpillStore, VisitOutcome, DEFAULT_ALPHA, DEFAULT_BOOTSTRAP_NODES,
+    DEFAULT_DUPLICATE_RESPONSE_WINDOW, DEFAULT_JITTER, DEFAULT_MAX_DATAGRAM_SIZE,
+    DEFAULT_MAX_RETRIES, DEFAULT_QUERY_DEADLINE, DEFAULT_RECV_BUFFER_SIZE, DEFAULT_REQUEST_TIMEOUT,
+    LATENCY_BUCKETS,
 };
 
 pub use ed25519_dalek::SigningKey;
@@ -36,8 +48,11 @@ pub mod errors {
     #[cfg(feature = "node")]
     pub use super::dht::PutMutableError;
     #[cfg(feature = "node")]
-    pub use super::rpc::{ConcurrencyError, PutError, PutQueryError};
+    pub use super::rpc::{ConcurrencyError, PutError, PutQueryError, SocketError};
 
     pub use super::common::DecodeIdError;
+    pub use super::common::DecodeMessageError;
+    pub use super::common::InvalidIdSize;
+    pub use super::common::InvalidRequestError;
     pub use super::common::MutableError;
 }
@@ -2,29 +2,36 @@
 
 mod closest_nodes;
 pub(crate) mod config;
+mod crawl_query;
 mod info;
 mod iterative_query;
+mod metrics;
 mod put_query;
+mod query_set;
 pub(crate) mod server;
 mod socket;
 
 use std::collections::HashMap;
-use std::net::{SocketAddr, SocketAddrV4, ToSocketAddrs};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
 use std::num::NonZeroUsize;
+use std::sync::{mpsc::Sender, Arc, Mutex, MutexGuard};
 use std::time::{Duration, Instant};
 
 use lru::LruCache;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, trace};
 
+use crawl_query::{CrawlQuery, DEFAULT_CRAWL_CAPACITY};
 use iterative_query::IterativeQuery;
 use put_query::PutQuery;
+use query_set::QuerySet;
 
 use crate::common::{
-    validate_immutable, ErrorSpecific, FindNodeRequestArguments, GetImmutableResponseArguments,
-    GetMutableResponseArguments, GetPeersResponseArguments, GetValueRequestArguments, Id, Message,
-    MessageType, MutableItem, NoMoreRecentValueResponseArguments, NoValuesResponseArguments, Node,
-    PutRequestSpecific, RequestSpecific, RequestTypeSpecific, ResponseSpecific, RoutingTable,
-    MAX_BUCKET_SIZE_K,
+    is_bogon, validate_immutable, ErrorSpecific, FindNodeRequestArguments,
+    GetImmutableResponseArguments, GetMutableResponseArguments, GetPeersResponseArguments,
+    GetValueRequestArguments, Id, Message, MessageType, MutableItem,
+    NoMoreRecentValueResponseArguments, NoValuesResponseArguments, Node, PutRequestSpecific,
+    RequestSpecific, RequestTypeSpecific, ResponseSpecific, Rng, RoutingTable,
+    SampleInfohashesRequestArguments, SampleInfohashesResponseArguments, SystemRng,
 };
 use server::Server;
 
@@ -34,11 +41,20 @@ use socket::KrpcSocket;
 
 pub use crate::common::messages;
 pub use closest_nodes::ClosestNodes;
-pub use info::Info;
-pub use iterative_query::GetRequestSpecific;
+pub use crawl_query::SpillStore;
+pub use info::{Info, QueryInfo};
+pub use iterative_query::{
+    ClosestNodesStrategy, GetRequestSpecific, IgnoreReason, QueryEvent, QueryState, QueryStrategy,
+    VisitOutcome, DEFAULT_ALPHA, DEFAULT_MAX_RETRIES, DEFAULT_QUERY_DEADLINE,
+};
+pub use metrics::{LatencyHistogram, LookupMetrics, LATENCY_BUCKETS};
 pub use put_query::{ConcurrencyError, PutError, PutQueryError};
-pub use socket::DEFAULT_REQUEST_TIMEOUT;
+pub use socket::{
+    SocketError, SocketMetrics, DEFAULT_DUPLICATE_RESPONSE_WINDOW, DEFAULT_MAX_DATAGRAM_SIZE,
+    DEFAULT_RECV_BUFFER_SIZE, DEFAULT_REQUEST_TIMEOUT,
+};
 
+/// Default routers used to bootstrap the routing table, when no bootstrapping nodes are configured.
 pub const DEFAULT_BOOTSTRAP_NODES: [&str; 4] = [
     "router.bittorrent.com:6881",
     "dht.transmissionbt.com:6881",
@@ -46,10 +62,16 @@ pub const DEFAULT_BOOTSTRAP_NODES: [&str; 4] = [
     "relay.pkarr.org:6881",
 ];
 
+/// Default fraction of a timer's duration it is randomly shifted by, so nodes
+/// started together don't refresh their routing tables, or retransmit, in lockstep.
+pub const DEFAULT_JITTER: f64 = 0.1;
+
 const REFRESH_TABLE_INTERVAL: Duration = Duration::from_secs(15 * 60);
 const PING_TABLE_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
 const MAX_CACHED_ITERATIVE_QUERIES: usize = 1000;
+/// Maximum number of nodes whose `sample_infohashes` interval is remembered.
+const MAX_SAMPLED_NODES: usize = 1000;
 
 #[derive(Debug)]
 /// Internal Rpc called in the Dht thread loop, useful to create your own actor setup.
@@ -60,10 +82,15 @@ pub struct Rpc {
     socket: KrpcSocket,
 
     // Routing
-    /// Closest nodes to this node
-    routing_table: RoutingTable,
+    /// This node's Id, the same as the routing table's.
+    id: Id,
+    /// Closest nodes to this node, shared with the inflight queries,
+    /// see [IterativeQuery::with_routing_table].
+    routing_table: Arc<Mutex<RoutingTable>>,
     /// Last time we refreshed the routing table with a find_node query.
     last_table_refresh: Instant,
+    /// Jittered duration until the next routing table refresh.
+    table_refresh_interval: Duration,
     /// Last time we pinged nodes in the routing table.
     last_table_ping: Instant,
     /// Closest responding nodes to specific target
@@ -75,11 +102,55 @@ pub struct Rpc {
     cached_iterative_queries: LruCache<Id, CachedIterativeQuery>,
 
     // Active IterativeQueries
-    iterative_queries: HashMap<Id, IterativeQuery>,
+    iterative_queries: QuerySet,
+    /// Endless crawl of the network, if started.
+    crawl: Option<CrawlQuery>,
+    /// Maximum duration of an IterativeQuery.
+    query_deadline: Duration,
+    /// Maximum concurrent visits of an IterativeQuery.
+    alpha: usize,
+    /// Maximum retransmissions per visited node in an IterativeQuery.
+    max_retries: u8,
+    /// Maximum iterations of an IterativeQuery, if any.
+    max_iterations: Option<usize>,
+    /// Maximum requests of an IterativeQuery, if any.
+    max_requests_per_query: Option<usize>,
+    /// Maximum peers of a `get_peers` IterativeQuery, if any.
+    max_peers_per_query: Option<usize>,
+    /// Matching bits with the target after which an IterativeQuery finishes, if any.
+    closeness_floor: Option<u8>,
+    /// Peers after which a `get_peers` IterativeQuery sends `find_node` requests, if any.
+    find_node_after_peers: Option<usize>,
+    /// Fraction of timers' durations they are randomly shifted by.
+    jitter: f64,
+    /// Whether an IterativeQuery visits previously responding nodes first.
+    prefer_verified_nodes: bool,
+    /// Whether every IterativeQuery records its decisions, see [Config::trace_queries].
+    trace_queries: bool,
+    /// Strategy handling the responses of every IterativeQuery, if not the default.
+    query_strategy: Option<Box<dyn QueryStrategy>>,
+    /// Called by every IterativeQuery that times out, see [Config::on_timeout].
+    on_timeout: Option<config::TimeoutCallback>,
     /// Put queries are special, since they have to wait for a corresponding
     /// get query to finish, update the closest_nodes, then `query_all` these.
     put_queries: HashMap<Id, PutQuery>,
 
+    /// Transaction ids of pings sent from [Rpc::ping_node].
+    inflight_pings: Vec<u16>,
+    /// Responded or timed out pings since the last tick.
+    done_pings: Vec<(u16, Option<(Id, Duration)>)>,
+    /// See [Config::ping_before_evict].
+    ping_before_evict: bool,
+    /// Pings to the least recently seen node of a full bucket, by transaction_id,
+    /// with that node's Id and the node waiting to replace it.
+    eviction_pings: Vec<(u16, Id, Node)>,
+
+    /// Latencies of done iterative queries.
+    lookup_metrics: LookupMetrics,
+
+    /// Earliest time each node asked to be sent another `sample_infohashes` request.
+    sample_intervals: LruCache<SocketAddr, Instant>,
+
     /// Sum of Dht size estimates from closest nodes from get queries.
     dht_size_estimates_sum: f64,
 
@@ -94,38 +165,87 @@ pub struct Rpc {
 
     public_address: Option<SocketAddrV4>,
     firewalled: bool,
+    /// Whether the Id was explicitly configured, and should never be regenerated.
+    explicit_id: bool,
+    /// Whether to never switch to server mode, see [Config::read_only].
+    read_only: bool,
+    /// Whether to query and store nodes at bogon addresses.
+    allow_private_addresses: bool,
 }
 
 impl Rpc {
     /// Create a new Rpc
     pub fn new(config: config::Config) -> Result<Self, std::io::Error> {
-        let id = if let Some(ip) = config.public_ip {
+        let id = if let Some(id) = config.id {
+            id
+        } else if let Some(ip) = config.public_ip {
             Id::from_ip(ip.into())
         } else {
             Id::random()
         };
 
-        let socket = KrpcSocket::new(&config)?;
+        let bootstrap = config
+            .bootstrap
+            .clone()
+            .unwrap_or(to_socket_address(&DEFAULT_BOOTSTRAP_NODES));
+
+        // Bootstrapping from a local network means nodes are expected there.
+        let allow_private_addresses = config.allow_private_addresses
+            || bootstrap
+                .iter()
+                .any(|address| is_bogon(&SocketAddr::V4(*address)));
+
+        let mut socket = KrpcSocket::new(&config)?
+            .with_timeout(config.request_timeout)
+            .with_strict(config.strict_messages)
+            .with_coalescing(config.coalesce_requests)
+            .with_ephemeral_ports(config.ephemeral_query_ports);
+        if let Some(bytes) = config.recv_buffer_size {
+            socket = socket.with_recv_buffer(bytes);
+        }
 
         Ok(Rpc {
-            bootstrap: config
-                .bootstrap
-                .unwrap_or(to_socket_address(&DEFAULT_BOOTSTRAP_NODES))
-                .into(),
+            bootstrap: bootstrap.into(),
             socket,
 
-            routing_table: RoutingTable::new(id),
-            iterative_queries: HashMap::new(),
+            id,
+            routing_table: Arc::new(Mutex::new(RoutingTable::new(id))),
+            iterative_queries: QuerySet::new(),
+            crawl: None,
+            query_deadline: config.query_deadline,
+            alpha: config.alpha,
+            max_retries: config.max_retries,
+            max_iterations: config.max_iterations,
+            max_requests_per_query: config.max_requests_per_query,
+            max_peers_per_query: config.max_peers_per_query,
+            closeness_floor: config.closeness_floor,
+            find_node_after_peers: config.find_node_after_peers,
+            jitter: config.jitter,
+            prefer_verified_nodes: config.prefer_verified_nodes,
+            trace_queries: config.trace_queries,
+            query_strategy: config.query_strategy,
+            on_timeout: config.on_timeout,
             put_queries: HashMap::new(),
 
+            inflight_pings: Vec::new(),
+            done_pings: Vec::new(),
+            ping_before_evict: config.ping_before_evict,
+            eviction_pings: Vec::new(),
+
             cached_iterative_queries: LruCache::new(
                 NonZeroUsize::new(MAX_CACHED_ITERATIVE_QUERIES)
                     .expect("MAX_CACHED_BUCKETS is NonZeroUsize"),
             ),
+            sample_intervals: LruCache::new(
+                NonZeroUsize::new(MAX_SAMPLED_NODES).expect("MAX_SAMPLED_NODES is NonZeroUsize"),
+            ),
 
             last_table_refresh: Instant::now(),
+            table_refresh_interval: SystemRng.jitter(REFRESH_TABLE_INTERVAL, config.jitter),
             last_table_ping: Instant::now(),
 
+            lookup_metrics: LookupMetrics::default(),
+
             dht_size_estimates_sum: 0.0,
             responders_based_dht_size_estimates_count: 0,
 
@@ -137,6 +257,9 @@ impl Rpc {
 
             public_address: None,
             firewalled: true,
+            explicit_id: config.id.is_some(),
+            read_only: config.read_only,
+            allow_private_addresses,
         })
     }
 
@@ -144,7 +267,7 @@ impl Rpc {
 
     /// Returns the node's Id
     pub fn id(&self) -> &Id {
-        self.routing_table.id()
+        &self.id
     }
 
     /// Returns the address the server is listening to.
@@ -153,6 +276,11 @@ impl Rpc {
         self.socket.local_addr()
     }
 
+    /// Returns the address the Ipv6 socket is listening to, if any.
+    pub fn local_addr6(&self) -> Option<SocketAddrV6> {
+        self.socket.local_addr6()
+    }
+
     /// Returns the best guess for this node's Public address.
     ///
     /// If [crate::DhtBuilder::public_ip] was set, this is what will be returned
@@ -176,8 +304,19 @@ impl Rpc {
         self.socket.server_mode
     }
 
-    pub fn routing_table(&self) -> &RoutingTable {
-        &self.routing_table
+    /// Returns counters of messages sent and received by the socket.
+    pub fn socket_metrics(&self) -> SocketMetrics {
+        self.socket.metrics()
+    }
+
+    /// Returns histograms of the latencies of done lookups.
+    pub fn lookup_metrics(&self) -> LookupMetrics {
+        self.lookup_metrics
+    }
+
+    /// Returns this node's routing table, locked until the guard is dropped.
+    pub fn routing_table(&self) -> MutexGuard<'_, RoutingTable> {
+        self.routing_table.lock().expect("poisoned lock")
     }
 
     /// Returns:
@@ -201,8 +340,33 @@ impl Rpc {
         Info::from(self)
     }
 
+    /// Returns a snapshot of the inflight query to this target, if any,
+    /// with the events it recorded since the previous snapshot.
+    pub fn query_info(&mut self, target: &Id) -> Option<QueryInfo> {
+        self.iterative_queries
+            .get_mut(target)
+            .map(QueryInfo::from_query)
+    }
+
     // === Public Methods ===
 
+    /// Stop sending requests to, and accepting messages from, this ip,
+    /// and remove its nodes from the routing table.
+    pub fn blacklist(&mut self, ip: Ipv4Addr) {
+        self.socket.blacklist(ip.into());
+
+        let to_remove = self
+            .routing_table()
+            .nodes()
+            .filter(|node| node.address().ip() == ip)
+            .map(|node| *node.id())
+            .collect::<Vec<_>>();
+
+        for id in to_remove {
+            self.routing_table().remove(&id);
+        }
+    }
+
     /// Advance the inflight queries, receive incoming requests,
     /// maintain the routing table, and everything else that needs
     /// to happen at every tick.
@@ -224,18 +388,17 @@ impl Rpc {
         }
 
         let self_id = *self.id();
-        let table_size = self.routing_table.size();
+        let table_size = self.routing_table().size();
+        let k = self.routing_table().k();
 
         let responders_based_dht_size_estimate = self.responders_based_dht_size_estimate();
         let average_subnets = self.average_subnets();
 
-        for (id, query) in self.iterative_queries.iter_mut() {
-            let is_done = query.tick(&mut self.socket);
-
-            if is_done {
+        for (id, _) in self.iterative_queries.tick(&mut self.socket) {
+            if let Some(query) = self.iterative_queries.get(&id) {
                 let closest_nodes =
-                    if let RequestTypeSpecific::FindNode(_) = query.request.request_type {
-                        if *id == self_id {
+                    if let RequestTypeSpecific::FindNode(_) = query.request().request_type {
+                        if id == self_id {
                             if table_size == 0 {
                                 error!("Could not bootstrap the routing table");
                             } else {
@@ -247,31 +410,74 @@ impl Rpc {
                             .closest()
                             .nodes()
                             .iter()
-                            .take(MAX_BUCKET_SIZE_K)
+                            .take(k)
                             .cloned()
                             .collect::<Box<[_]>>()
                     } else {
                         query
                             .responders()
                             .take_until_secure(responders_based_dht_size_estimate, average_subnets)
-                            .to_vec()
-                            .into_boxed_slice()
+                            .iter()
+                            .map(|node| query.attach_token(node))
+                            .collect::<Box<[_]>>()
                     };
 
-                done_get_queries.push((*id, closest_nodes));
+                done_get_queries.push((id, closest_nodes));
             };
         }
 
+        if let Some(crawl) = &mut self.crawl {
+            crawl.tick(&mut self.socket);
+
+            if crawl.is_done() {
+                debug!(rounds = crawl.rounds(), "Crawl stopped");
+
+                self.crawl = None;
+            }
+        }
+
         // === Cleanup done queries ===
 
         // Has to happen _before_ `self.socket.recv_from()`.
         for (id, closest_nodes) in &done_get_queries {
             if let Some(query) = self.iterative_queries.remove(id) {
-                self.update_address_votes_from_iterative_query(&query);
-                self.cache_iterative_query(&query, closest_nodes);
+                if let Some(port) = query.source_port() {
+                    self.socket.close_ephemeral(port);
+                }
+
+                if let RequestTypeSpecific::GetPeers(_) = query.request().request_type {
+                    debug!(
+                        ?id,
+                        peers = query.peers_unordered().len(),
+                        "Done get_peers query"
+                    );
+                }
+
+                let histogram = match query.request().request_type {
+                    RequestTypeSpecific::FindNode(_) => Some(&mut self.lookup_metrics.find_node),
+                    RequestTypeSpecific::GetPeers(_) => Some(&mut self.lookup_metrics.get_peers),
+                    RequestTypeSpecific::GetValue(_) => Some(&mut self.lookup_metrics.get_value),
+                    _ => None,
+                };
+                if let Some(histogram) = histogram {
+                    histogram.record(query.elapsed());
+                }
+
+                if !query.errors().is_empty() {
+                    debug!(?id, errors = ?query.errors(), "Query got error responses");
+                }
+
+                self.update_public_address();
+                // A cancelled query didn't finish traversing, so its closest nodes are partial.
+                if !query.is_cancelled() {
+                    self.cache_iterative_query(&query, closest_nodes);
+                }
 
                 // Only for get queries, not find node.
-                if !matches!(query.request.request_type, RequestTypeSpecific::FindNode(_)) {
+                if !matches!(
+                    query.request().request_type,
+                    RequestTypeSpecific::FindNode(_)
+                ) {
                     if let Some(put_query) = self.put_queries.get_mut(id) {
                         if !put_query.started() {
                             if let Err(error) = put_query.start(&mut self.socket, closest_nodes) {
@@ -303,22 +509,68 @@ impl Rpc {
                 _ => self.handle_response(from, message),
             });
 
+        // Pings that didn't get a response are timed out by now.
+        let socket = &self.socket;
+        let done_pings = &mut self.done_pings;
+        self.inflight_pings.retain(|tid| {
+            if socket.inflight(tid) {
+                true
+            } else {
+                done_pings.push((*tid, None));
+                false
+            }
+        });
+
+        // Nodes that didn't respond to their eviction ping are replaced.
+        let mut unresponsive = Vec::new();
+        self.eviction_pings.retain(|(tid, oldest, candidate)| {
+            if socket.inflight(tid) {
+                true
+            } else {
+                unresponsive.push((*oldest, candidate.clone()));
+                false
+            }
+        });
+        for (oldest, candidate) in unresponsive {
+            debug!(?oldest, ?candidate, "Replacing unresponsive node");
+
+            self.routing_table().remove(&oldest);
+            self.routing_table().add(candidate);
+        }
+
         RpcTickReport {
             done_get_queries,
             done_put_queries,
             new_query_response,
+            done_pings: std::mem::take(&mut self.done_pings),
         }
     }
 
     /// Send a request to the given address and return the transaction_id
-    pub fn request(&mut self, address: SocketAddrV4, request: RequestSpecific) -> u16 {
+    pub fn request(
+        &mut self,
+        address: SocketAddr,
+        request: RequestSpecific,
+    ) -> Result<u16, SocketError> {
         self.socket.request(address, request)
     }
 
+    /// Send a ping request to the given address and return the transaction_id,
+    /// the response's Id and round trip time are then returned in [RpcTickReport::done_pings].
+    pub fn ping_node(&mut self, address: SocketAddr) -> Result<u16, SocketError> {
+        let tid = self
+            .socket
+            .request(address, RequestSpecific::ping(*self.id()))?;
+
+        self.inflight_pings.push(tid);
+
+        Ok(tid)
+    }
+
     /// Send a response to the given address.
     pub fn response(
         &mut self,
-        address: SocketAddrV4,
+        address: SocketAddr,
         transaction_id: u16,
         response: ResponseSpecific,
     ) {
@@ -326,7 +578,7 @@ impl Rpc {
     }
 
     /// Send an error to the given address.
-    pub fn error(&mut self, address: SocketAddrV4, transaction_id: u16, error: ErrorSpecific) {
+    pub fn error(&mut self, address: SocketAddr, transaction_id: u16, error: ErrorSpecific) {
         self.socket.error(address, transaction_id, error)
     }
 
@@ -416,9 +668,9 @@ impl Rpc {
     /// to traverse it.
     ///
     /// - `request` [RequestTypeSpecific], except [RequestTypeSpecific::Ping] and
-    ///     [RequestTypeSpecific::Put] which will be ignored.
+    ///   [RequestTypeSpecific::Put] which will be ignored.
     /// - `extra_nodes` option allows the query to visit specific nodes, that won't necessesarily be visited
-    ///     through the query otherwise.
+    ///   through the query otherwise.
     pub fn get(
         &mut self,
         request: GetRequestSpecific,
@@ -428,7 +680,11 @@ impl Rpc {
             GetRequestSpecific::FindNode(FindNodeRequestArguments { target }) => target,
             GetRequestSpecific::GetPeers(GetPeersRequestArguments { info_hash, .. }) => info_hash,
             GetRequestSpecific::GetValue(GetValueRequestArguments { target, .. }) => target,
+            GetRequestSpecific::SampleInfohashes(SampleInfohashesRequestArguments { target }) => {
+                target
+            }
         };
+        let sampling = matches!(request, GetRequestSpecific::SampleInfohashes(_));
 
         let response_from_inflight_put_mutable_request =
             self.put_queries.get(&target).and_then(|existing| {
@@ -450,55 +706,111 @@ impl Rpc {
             return Some(responses);
         }
 
-        let node_id = self.routing_table.id();
+        let node_id = self.id;
 
-        if target == *node_id {
+        if target == node_id {
             debug!(?node_id, "Bootstrapping the routing table");
         }
 
-        let mut query = IterativeQuery::new(*self.id(), target, request);
-
         // Seed the query either with the closest nodes from the routing table, or the
         // bootstrapping nodes if the closest nodes are not enough.
 
-        let routing_table_closest = self.routing_table.closest_secure(
+        let routing_table_closest = self.routing_table().closest_secure(
             target,
             self.responders_based_dht_size_estimate(),
             self.average_subnets(),
         );
+        let should_bootstrap =
+            routing_table_closest.is_empty() || routing_table_closest.len() < self.bootstrap.len();
+
+        // The table seeds the k closest nodes, the rest of the secure closest nodes follow.
+        let k = self.routing_table().k();
+        let mut query = IterativeQuery::new(*self.id(), target, request, self.query_deadline)
+            .with_routing_table(self.routing_table.clone());
+        query.add_seeds(routing_table_closest.get(k..).unwrap_or_default());
+
+        query = query
+            .with_alpha(self.alpha)
+            .with_max_retries(self.max_retries)
+            .with_jitter(self.jitter)
+            .with_bogon_filter(!self.allow_private_addresses)
+            .with_prefer_verified(self.prefer_verified_nodes)
+            .with_ipv6(self.local_addr6().is_some())
+            .with_own_address(self.local_addr().into());
+
+        if let Some(public_address) = self.public_address {
+            query = query.with_own_address(public_address.into());
+        }
+        if let Some(local_addr6) = self.local_addr6() {
+            query = query.with_own_address(local_addr6.into());
+        }
+        if let Some(max_iterations) = self.max_iterations {
+            query = query.with_max_iterations(max_iterations);
+        }
+        if let Some(max_requests) = self.max_requests_per_query {
+            query = query.with_max_requests(max_requests);
+        }
+        if let Some(max_peers) = self.max_peers_per_query {
+            query = query.with_max_peers(max_peers);
+        }
+        if let Some(matching_bits) = self.closeness_floor {
+            query = query.with_closeness_floor(matching_bits);
+        }
+        if let Some(peers) = self.find_node_after_peers {
+            query = query.with_find_node_after(peers);
+        }
+        if self.trace_queries {
+            query = query.with_trace();
+        }
+        if let Some(strategy) = &self.query_strategy {
+            query = query.with_strategy(strategy.clone());
+        }
+        if let Some(config::TimeoutCallback(on_timeout)) = &self.on_timeout {
+            let on_timeout = on_timeout.clone();
+            query = query.with_on_timeout(move |closest, peers| on_timeout(target, closest, peers));
+        }
+        if let Some(port) = self.socket.open_ephemeral() {
+            query = query.with_source_port(port);
+        }
+        if sampling {
+            let now = Instant::now();
+
+            for (address, _) in self
+                .sample_intervals
+                .iter()
+                .filter(|(_, next_sample)| **next_sample > now)
+            {
+                query.skip(*address);
+            }
+        }
 
         // If we don't have enough or any closest nodes, call the bootstrapping nodes.
-        if routing_table_closest.is_empty() || routing_table_closest.len() < self.bootstrap.len() {
+        if should_bootstrap {
             for bootstrapping_node in self.bootstrap.clone() {
-                query.visit(&mut self.socket, bootstrapping_node);
+                query.visit(&mut self.socket, bootstrapping_node.into());
             }
         }
 
         if let Some(extra_nodes) = extra_nodes {
             for extra_node in extra_nodes {
-                query.visit(&mut self.socket, *extra_node)
+                query.visit(&mut self.socket, (*extra_node).into());
             }
         }
 
-        // Seed this query with the closest nodes we know about.
-        for node in routing_table_closest {
-            query.add_candidate(node)
-        }
-
+        // Nodes that responded to the previous query to this target.
         if let Some(CachedIterativeQuery {
             closest_responding_nodes,
             ..
         }) = self.cached_iterative_queries.get(&target)
         {
-            for node in closest_responding_nodes {
-                query.add_candidate(node.clone())
-            }
+            let seeds = query.add_seeds(closest_responding_nodes);
+            trace!(?target, seeds, "Seeded query with previous responders");
         }
 
         // After adding the nodes, we need to start the query.
         query.start(&mut self.socket);
 
-        self.iterative_queries.insert(target, query);
+        self.iterative_queries.insert(query);
 
         // If there is an inflight PutQuery for mutable item return its value
         if let Some(response) = response_from_inflight_put_mutable_request {
@@ -508,38 +820,164 @@ impl Rpc {
         None
     }
 
+    /// Same as [Self::get] with a `get_peers` request, but every unique peer found
+    /// is sent to `tx` once, as soon as it is found, including the peers an inflight
+    /// query to this info hash already found.
+    ///
+    /// The sender is dropped, closing the channel, once the query is done.
+    pub fn stream_peers(&mut self, info_hash: Id, tx: Sender<SocketAddr>) {
+        if !self.iterative_queries.contains(&info_hash) {
+            self.get(
+                GetRequestSpecific::GetPeers(GetPeersRequestArguments {
+                    info_hash,
+                    scrape: false,
+                }),
+                None,
+            );
+        }
+
+        if let Some(query) = self.iterative_queries.get_mut(&info_hash) {
+            query.stream_peers(tx);
+        }
+    }
+
+    /// Cancel the inflight query to this target, if any, so it is reported
+    /// among the [RpcTickReport::done_get_queries] on the next tick without
+    /// being cached.
+    ///
+    /// Returns `false` if there was no inflight query to this target.
+    pub fn cancel(&mut self, target: &Id) -> bool {
+        match self.iterative_queries.get_mut(target) {
+            Some(query) => {
+                query.cancel(&mut self.socket);
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Bind the Ipv4 socket to a new address, for example after moving to another
+    /// network interface, and move every inflight query to it, see [KrpcSocket::rebind].
+    pub fn rebind(&mut self, address: SocketAddrV4) -> Result<(), std::io::Error> {
+        self.socket.rebind(address)?;
+        self.iterative_queries.rebind(&mut self.socket);
+
+        Ok(())
+    }
+
+    /// Start crawling the network, sending every newly discovered responding node to `tx`,
+    /// until [Self::stop_crawl] is called or the receiver is dropped.
+    ///
+    /// Replaces the current crawl, if any.
+    pub fn crawl(&mut self, tx: Sender<Node>) {
+        self.start_crawl(tx, None)
+    }
+
+    /// Same as [Self::crawl], but nodes discovered once the crawl's in-memory table
+    /// is full are appended to `spill` instead of being forgotten, so even crawls
+    /// larger than the memory never send the same node twice.
+    pub fn crawl_with_spill(&mut self, tx: Sender<Node>, spill: SpillStore) {
+        self.start_crawl(tx, Some(spill))
+    }
+
+    fn start_crawl(&mut self, tx: Sender<Node>, spill: Option<SpillStore>) {
+        if let Some(mut crawl) = self.crawl.take() {
+            crawl.cancel(&mut self.socket);
+        }
+
+        let mut crawl = CrawlQuery::new(
+            *self.id(),
+            self.routing_table().to_owned_nodes(),
+            self.query_deadline,
+            DEFAULT_CRAWL_CAPACITY,
+            tx,
+        )
+        .with_own_address(self.local_addr().into());
+
+        if let Some(public_address) = self.public_address {
+            crawl = crawl.with_own_address(public_address.into());
+        }
+        if let Some(local_addr6) = self.local_addr6() {
+            crawl = crawl.with_own_address(local_addr6.into());
+        }
+        if let Some(spill) = spill {
+            crawl = crawl.with_spill(spill);
+        }
+
+        self.crawl = Some(crawl);
+    }
+
+    /// Stop the current crawl, returning `false` if there was none.
+    pub fn stop_crawl(&mut self) -> bool {
+        match self.crawl.take() {
+            Some(mut crawl) => {
+                crawl.cancel(&mut self.socket);
+                debug!(rounds = crawl.rounds(), "Crawl stopped");
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stop sending requests, and wait up to `timeout` for responses to the inflight
+    /// requests, returning the responses to queries among them, as
+    /// [RpcTickReport::new_query_response] would.
+    ///
+    /// Every query is done on the next [Self::tick] with whatever it found, after which
+    /// this Rpc neither sends nor receives anything, and should be dropped.
+    pub fn shutdown(&mut self, timeout: Duration) -> Vec<(Id, Response)> {
+        self.stop_crawl();
+
+        self.socket
+            .shutdown(timeout)
+            .into_iter()
+            .filter_map(|(message, from)| self.handle_response(from, message))
+            .collect()
+    }
+
     // === Private Methods ===
 
     fn handle_request(
         &mut self,
-        from: SocketAddrV4,
+        from: SocketAddr,
         transaction_id: u16,
         request_specific: RequestSpecific,
     ) {
         let is_ping = matches!(request_specific.request_type, RequestTypeSpecific::Ping);
 
         if self.server_mode() {
-            let server = &mut self.server;
+            // Tokens and stored peers are Ipv4 only, so Ipv6 nodes aren't served yet.
+            if let SocketAddr::V4(requester) = from {
+                let server = &mut self.server;
 
-            match server.handle_request(&self.routing_table, from, request_specific) {
-                Some(MessageType::Error(error)) => {
-                    self.error(from, transaction_id, error);
-                }
-                Some(MessageType::Response(response)) => {
-                    self.response(from, transaction_id, response);
-                }
-                _ => {}
-            };
+                let routing_table = self.routing_table.lock().expect("poisoned lock");
+                let response = server.handle_request(&routing_table, requester, request_specific);
+                drop(routing_table);
+
+                match response {
+                    Some(MessageType::Error(error)) => {
+                        self.error(from, transaction_id, error);
+                    }
+                    Some(MessageType::Response(response)) => {
+                        self.response(from, transaction_id, response);
+                    }
+                    _ => {}
+                };
+            } else {
+                trace!(?from, "Ignoring request from an Ipv6 node");
+            }
         }
 
         if let Some(our_address) = self.public_address {
-            if from == our_address && is_ping {
+            if from == SocketAddr::V4(our_address) && is_ping {
                 self.firewalled = false;
 
                 let ipv4 = our_address.ip();
 
                 // Restarting our routing table with new secure Id if necessary.
-                if !self.id().is_valid_for_ip(*ipv4) {
+                if !self.explicit_id && !self.id().is_valid_for_ip((*ipv4).into()) {
                     let new_id = Id::from_ipv4(*ipv4);
 
                     info!(
@@ -554,13 +992,53 @@ impl Rpc {
                         None,
                     );
 
-                    self.routing_table = RoutingTable::new(new_id);
+                    let mut routing_table = self.routing_table();
+                    *routing_table = RoutingTable::new(new_id).with_k(routing_table.k());
+                    drop(routing_table);
+                    self.id = new_id;
                 }
             }
         }
     }
 
-    fn handle_response(&mut self, from: SocketAddrV4, message: Message) -> Option<(Id, Response)> {
+    fn handle_response(&mut self, from: SocketAddr, message: Message) -> Option<(Id, Response)> {
+        if let Some(index) = self
+            .inflight_pings
+            .iter()
+            .position(|tid| *tid == message.transaction_id)
+        {
+            self.inflight_pings.remove(index);
+
+            let result = match (message.get_author_id(), self.socket.last_rtt()) {
+                (Some(id), Some(rtt)) => Some((id, rtt)),
+                _ => None,
+            };
+            self.done_pings.push((message.transaction_id, result));
+        }
+
+        if let Some(index) = self
+            .eviction_pings
+            .iter()
+            .position(|(tid, _, _)| *tid == message.transaction_id)
+        {
+            let author_id = message.get_author_id();
+
+            // Otherwise, someone else is at its address now, and it is replaced on the next tick.
+            if author_id == Some(self.eviction_pings[index].1) {
+                let (_, _, candidate) = self.eviction_pings.remove(index);
+                trace!(
+                    ?candidate,
+                    "Dropping candidate, the node it would replace responded"
+                );
+
+                if !message.read_only {
+                    self.add_responder(from, author_id, message.version);
+                }
+            }
+
+            return None;
+        }
+
         // If someone claims to be readonly, then let's not store anything even if they respond.
         if message.read_only {
             return None;
@@ -588,37 +1066,85 @@ impl Rpc {
         let author_id = message.get_author_id();
         let from_version = message.version.to_owned();
 
-        // Get corresponding query for message.transaction_id
-        if let Some(query) = self
-            .iterative_queries
-            .values_mut()
-            .find(|query| query.inflight(message.transaction_id))
+        if let Some(crawl) = self
+            .crawl
+            .as_mut()
+            .filter(|crawl| crawl.inflight(message.transaction_id))
         {
+            match (&message.message_type, author_id) {
+                (MessageType::Error(error), _) => {
+                    crawl.handle_error(message.transaction_id, from, error.clone());
+                }
+                (_, Some(responder_id)) => {
+                    let node = Node::new(responder_id, from).with_version(message.version);
+                    crawl.add_response(message.transaction_id, &node, &message);
+                }
+                (_, None) => {}
+            }
+
+            // A coalesced request is shared with an iterative query, see [Config::coalesce_requests].
+            let shared = self.iterative_queries.owns(message.transaction_id);
+
+            if !shared {
+                self.add_responder(from, author_id, from_version);
+
+                return None;
+            }
+        }
+
+        // Get corresponding query for message.transaction_id
+        if let Some(query) = self.iterative_queries.owner_mut(message.transaction_id) {
             // KrpcSocket would not give us a response from the wrong address for the transaction_id
             should_add_node = true;
 
-            if let Some(nodes) = message.get_closer_nodes() {
-                for node in nodes {
-                    query.add_candidate(node.clone());
+            match (&message.message_type, author_id) {
+                (MessageType::Error(error), _) => {
+                    query.handle_error(message.transaction_id, from, error.clone());
+                }
+                (_, Some(responder_id)) => {
+                    let node = Node::new(responder_id, from).with_version(message.version);
+
+                    if !query.add_response(message.transaction_id, &node, &message) {
+                        // Not added to the routing table either.
+                        return None;
+                    }
                 }
+                (_, None) => {}
             }
 
             if let Some((responder_id, token)) = message.get_token() {
                 query.add_responding_node(Node::new_with_token(responder_id, from, token.into()));
             }
 
-            if let Some(proposed_ip) = message.requester_ip {
-                query.add_address_vote(proposed_ip);
-            }
-
             let target = query.target();
 
             match message.message_type {
                 MessageType::Response(ResponseSpecific::GetPeers(GetPeersResponseArguments {
                     values,
+                    bf_seeders,
+                    bf_peers,
                     ..
                 })) => {
-                    let response = Response::Peers(values);
+                    query.add_peers(&values);
+
+                    let response = if query.add_scrape(bf_seeders.as_ref(), bf_peers.as_ref()) {
+                        Response::Scrape(ScrapeEstimate::from_query(query))
+                    } else {
+                        Response::Peers(values)
+                    };
+                    query.response(from, response.clone());
+
+                    return Some((target, response));
+                }
+                MessageType::Response(ResponseSpecific::SampleInfohashes(
+                    SampleInfohashesResponseArguments {
+                        interval, samples, ..
+                    },
+                )) => {
+                    self.sample_intervals
+                        .put(from, Instant::now() + Duration::from_secs(interval.into()));
+
+                    let response = Response::Samples(query.add_samples(&samples));
                     query.response(from, response.clone());
 
                     return Some((target, response));
@@ -655,13 +1181,26 @@ impl Rpc {
                         ..
                     },
                 )) => {
-                    let salt = match query.request.request_type.clone() {
+                    let salt = match query.request().request_type.clone() {
                         RequestTypeSpecific::GetValue(args) => args.salt,
                         _ => None,
                     };
                     let target = query.target();
 
                     match MutableItem::from_dht_message(query.target(), &k, v, seq, &sig, salt) {
+                        Ok(item)
+                            if query
+                                .most_recent_mutable()
+                                .is_some_and(|most_recent| item.seq() < most_recent.seq()) =>
+                        {
+                            debug!(
+                                ?target,
+                                seq = item.seq(),
+                                ?from,
+                                ?responder_id,
+                                "Ignoring mutable item older than already received"
+                            );
+                        }
                         Ok(item) => {
                             let response = Response::Mutable(item);
                             query.response(from, response.clone());
@@ -686,7 +1225,7 @@ impl Rpc {
                 )) => {
                     debug!(
                         target= ?query.target(),
-                        salt= ?match query.request.request_type.clone() {
+                        salt= ?match query.request().request_type.clone() {
                             RequestTypeSpecific::GetValue(args) => args.salt,
                             _ => None,
                         },
@@ -699,11 +1238,20 @@ impl Rpc {
                 }
                 MessageType::Response(ResponseSpecific::NoValues(NoValuesResponseArguments {
                     responder_id,
+                    bf_seeders,
+                    bf_peers,
                     ..
                 })) => {
+                    if query.add_scrape(bf_seeders.as_ref(), bf_peers.as_ref()) {
+                        let response = Response::Scrape(ScrapeEstimate::from_query(query));
+                        query.response(from, response.clone());
+
+                        return Some((target, response));
+                    }
+
                     debug!(
                         target= ?query.target(),
-                        salt= ?match query.request.request_type.clone() {
+                        salt= ?match query.request().request_type.clone() {
                             RequestTypeSpecific::GetValue(args) => args.salt,
                             _ => None,
                         },
@@ -713,9 +1261,8 @@ impl Rpc {
                         "No values"
                     );
                 }
-                MessageType::Error(error) => {
-                    debug!(?error, ?from_version, "Get query got error response");
-                }
+                // Error response is already handled in query.handle_error()
+                MessageType::Error(_) => {}
                 // Ping response is already handled in add_node()
                 // FindNode response is already handled in query.add_candidate()
                 // Requests are handled elsewhere
@@ -726,27 +1273,75 @@ impl Rpc {
         };
 
         if should_add_node {
-            // Add a node to our routing table on any expected incoming response.
+            self.add_responder(from, author_id, from_version);
+        }
 
-            if let Some(id) = author_id {
-                self.routing_table.add(Node::new(id, from));
+        None
+    }
+
+    /// Add a node to our routing table on any expected incoming response.
+    ///
+    /// Every query is seeded from this table, so the nodes that answered one
+    /// lookup are reused by the next ones.
+    fn add_responder(&mut self, from: SocketAddr, id: Option<Id>, version: Option<[u8; 4]>) {
+        if !self.allow_private_addresses && is_bogon(&from) {
+            return;
+        }
+
+        if let Some(id) = id {
+            let node = Node::new(id, from)
+                .with_rtt(self.socket.last_rtt())
+                .with_version(version);
+
+            if self.ping_before_evict && self.ping_oldest(&node) {
+                return;
             }
+
+            self.routing_table().add(node);
         }
+    }
 
-        None
+    /// Ping the least recently seen node of `node`'s bucket if it is full,
+    /// holding `node` until the ping times out, see [Config::ping_before_evict].
+    ///
+    /// Returns `false` if `node` should be added right away.
+    fn ping_oldest(&mut self, node: &Node) -> bool {
+        let Some(oldest) = self.routing_table().oldest_if_full(node).cloned() else {
+            return false;
+        };
+        let address = oldest.address();
+        let oldest = *oldest.id();
+
+        if self.eviction_pings.iter().any(|(_, id, _)| *id == oldest) {
+            // Only one candidate waits for each node.
+            return true;
+        }
+
+        match self
+            .socket
+            .request(address, RequestSpecific::ping(*self.id()))
+        {
+            Ok(tid) => {
+                self.eviction_pings.push((tid, oldest, node.clone()));
+
+                true
+            }
+            Err(_) => false,
+        }
     }
 
     fn periodic_node_maintaenance(&mut self) {
         // Bootstrap if necessary
-        if self.routing_table.is_empty() {
+        if self.routing_table().is_empty() {
             self.populate();
         }
 
         // Every 15 minutes refresh the routing table.
-        if self.last_table_refresh.elapsed() > REFRESH_TABLE_INTERVAL {
+        if self.last_table_refresh.elapsed() > self.table_refresh_interval {
             self.last_table_refresh = Instant::now();
+            self.table_refresh_interval = SystemRng.jitter(REFRESH_TABLE_INTERVAL, self.jitter);
 
-            if !self.server_mode() && !self.firewalled() {
+            if !self.server_mode() && !self.firewalled() && !self.read_only {
                 info!("Adaptive mode: have been running long enough (not firewalled), switching to server mode");
 
                 self.socket.server_mode = true;
@@ -758,10 +1353,10 @@ impl Rpc {
         if self.last_table_ping.elapsed() > PING_TABLE_INTERVAL {
             self.last_table_ping = Instant::now();
 
-            let mut to_remove = Vec::with_capacity(self.routing_table.size());
-            let mut to_ping = Vec::with_capacity(self.routing_table.size());
+            let mut to_remove = Vec::with_capacity(self.routing_table().size());
+            let mut to_ping = Vec::with_capacity(self.routing_table().size());
 
-            for node in self.routing_table.nodes() {
+            for node in self.routing_table().nodes() {
                 if node.is_stale() {
                     to_remove.push(*node.id())
                 } else if node.should_ping() {
@@ -770,7 +1365,7 @@ impl Rpc {
             }
 
             for id in to_remove {
-                self.routing_table.remove(&id);
+                self.routing_table().remove(&id);
             }
 
             for address in to_ping {
@@ -791,18 +1386,17 @@ impl Rpc {
         );
     }
 
-    fn ping(&mut self, address: SocketAddrV4) {
-        self.socket.request(
-            address,
-            RequestSpecific {
-                requester_id: *self.id(),
-                request_type: RequestTypeSpecific::Ping,
-            },
-        );
+    fn ping(&mut self, address: SocketAddr) {
+        let _ = self
+            .socket
+            .request(address, RequestSpecific::ping(*self.id()))
+            .map_err(|error| {
+                debug!(?error, ?address, "Error sending ping request");
+            });
     }
 
-    fn update_address_votes_from_iterative_query(&mut self, query: &IterativeQuery) {
-        if let Some(new_address) = query.best_address() {
+    fn update_public_address(&mut self) {
+        if let Some(new_address) = self.socket.external_address() {
             if self.public_address.is_none()
                 || new_address
                     != self
@@ -811,11 +1405,11 @@ impl Rpc {
             {
                 debug!(
                     ?new_address,
-                    "Query responses suggest a different public_address, trying to confirm.."
+                    "Responses suggest a different public_address, trying to confirm.."
                 );
 
                 self.firewalled = true;
-                self.ping(new_address);
+                self.ping(new_address.into());
             }
 
             self.public_address = Some(new_address)
@@ -849,7 +1443,7 @@ impl Rpc {
                 subnets: subnets_count,
 
                 is_find_node: matches!(
-                    query.request.request_type,
+                    query.request().request_type,
                     RequestTypeSpecific::FindNode(_)
                 ),
             },
@@ -915,6 +1509,9 @@ pub struct RpcTickReport {
     pub done_put_queries: Vec<(Id, Option<PutError>)>,
     /// Received GET query response.
     pub new_query_response: Option<(Id, Response)>,
+    /// Transaction ids of [Rpc::ping_node] requests, and the responding node's [Id]
+    /// and round trip time, or None if the request timed out.
+    pub done_pings: Vec<(u16, Option<(Id, Duration)>)>,
 }
 
 #[derive(Debug, Clone)]
@@ -922,21 +1519,191 @@ pub enum Response {
     Peers(Vec<SocketAddrV4>),
     Immutable(Box<[u8]>),
     Mutable(MutableItem),
+    Scrape(ScrapeEstimate),
+    /// Info hashes from a `sample_infohashes` response, not already
+    /// sampled from other nodes by the same query.
+    Samples(Vec<Id>),
 }
 
+/// Estimated swarm size of an info hash, from the merged bloom filters
+/// of `get_peers` scrape responses.
+///
+/// Read [BEP_0033](https://www.bittorrent.org/beps/bep_0033.html) for more information.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScrapeEstimate {
+    /// Estimated number of seeders.
+    pub seeders: usize,
+    /// Estimated number of peers that are not seeding.
+    pub peers: usize,
+}
+
+impl ScrapeEstimate {
+    fn from_query(query: &IterativeQuery) -> Self {
+        Self {
+            seeders: query.estimated_seeders(),
+            peers: query.estimated_peers(),
+        }
+    }
+}
+
+/// Everything a `get_peers` lookup found, including what's needed to announce
+/// to the same nodes later without looking them up again, see [crate::Dht::get_peers_result].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GetPeersResult {
+    /// Unique peers, sorted.
+    pub peers: Vec<SocketAddrV4>,
+    /// Closest nodes that responded, with the tokens they gave us if any,
+    /// to be passed as `extra_nodes` to [crate::Dht::put] while their tokens are valid.
+    pub closest: Vec<Node>,
+    /// Tokens of the closest nodes, by their address.
+    pub tokens: Vec<(SocketAddr, Box<[u8]>)>,
+}
+
+impl GetPeersResult {
+    pub(crate) fn new(peers: Vec<SocketAddrV4>, closest: Box<[Node]>) -> Self {
+        let tokens = closest
+            .iter()
+            .filter_map(|node| Some((node.address(), node.token()?)))
+            .collect();
+
+        Self {
+            peers,
+            closest: closest.into_vec(),
+            tokens,
+        }
+    }
+}
+
+/// Resolve bootstrapping nodes to their Ipv4 addresses, skipping the ones that fail to resolve.
 pub(crate) fn to_socket_address<T: ToSocketAddrs>(bootstrap: &[T]) -> Vec<SocketAddrV4> {
     bootstrap
         .iter()
         .flat_map(|s| {
-            s.to_socket_addrs().map(|addrs| {
-                addrs
-                    .filter_map(|addr| match addr {
-                        SocketAddr::V4(addr_v4) => Some(addr_v4),
-                        _ => None,
-                    })
-                    .collect::<Box<[_]>>()
-            })
+            s.to_socket_addrs()
+                .map(|addrs| {
+                    addrs
+                        .filter_map(|addr| match addr {
+                            SocketAddr::V4(addr_v4) => Some(addr_v4),
+                            _ => None,
+                        })
+                        .collect::<Box<[_]>>()
+                })
+                .map_err(|error| debug!(?error, "Failed to resolve bootstrapping node"))
         })
         .flatten()
         .collect()
 }
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use crate::common::PingResponseArguments;
+    use crate::rpc::config::Config;
+
+    use super::*;
+
+    fn evicting_rpc() -> Rpc {
+        let rpc = Rpc::new(Config {
+            bootstrap: Some(Vec::new()),
+            request_timeout: Duration::from_millis(100),
+            allow_private_addresses: true,
+            ping_before_evict: true,
+            ..Default::default()
+        })
+        .unwrap();
+        *rpc.routing_table() = RoutingTable::new(*rpc.id()).with_k(1);
+
+        rpc
+    }
+
+    #[test]
+    fn ping_before_evict() {
+        let candidate = |rpc: &Rpc| {
+            Node::new(
+                rpc.id().random_at_distance(150),
+                SocketAddrV4::new([127, 0, 0, 2].into(), 6881).into(),
+            )
+        };
+        let add = |rpc: &mut Rpc, node: &Node| {
+            rpc.add_responder(node.address(), Some(*node.id()), None);
+        };
+
+        // Live oldest node is retained.
+        let mut rpc = evicting_rpc();
+        let mut live = KrpcSocket::server().unwrap();
+        let oldest = Node::new(rpc.id().random_at_distance(150), live.local_addr().into());
+        rpc.routing_table().add(oldest.clone());
+
+        let new = candidate(&rpc);
+        add(&mut rpc, &new);
+        assert!(!rpc.routing_table().contains(new.id()));
+        assert_eq!(rpc.eviction_pings.len(), 1);
+
+        let (message, from) = loop {
+            if let Some(received) = live.recv_from() {
+                break received;
+            }
+        };
+        live.response(
+            from,
+            message.transaction_id,
+            ResponseSpecific::Ping(PingResponseArguments {
+                responder_id: *oldest.id(),
+            }),
+        );
+
+        while !rpc.eviction_pings.is_empty() {
+            rpc.tick();
+        }
+        assert!(rpc.routing_table().contains(oldest.id()));
+        assert!(!rpc.routing_table().contains(new.id()));
+
+        // Even if it responds as read-only.
+        let mut rpc = evicting_rpc();
+        let mut live = KrpcSocket::client().unwrap();
+        let oldest = Node::new(rpc.id().random_at_distance(150), live.local_addr().into());
+        rpc.routing_table().add(oldest.clone());
+
+        let new = candidate(&rpc);
+        add(&mut rpc, &new);
+
+        let (message, from) = loop {
+            if let Some(received) = live.recv_from() {
+                break received;
+            }
+        };
+        live.response(
+            from,
+            message.transaction_id,
+            ResponseSpecific::Ping(PingResponseArguments {
+                responder_id: *oldest.id(),
+            }),
+        );
+
+        while !rpc.eviction_pings.is_empty() {
+            rpc.tick();
+        }
+        assert!(rpc.routing_table().contains(oldest.id()));
+        assert!(!rpc.routing_table().contains(new.id()));
+
+        // Dead oldest node is replaced.
+        let mut rpc = evicting_rpc();
+        let dead = Node::new(
+            rpc.id().random_at_distance(150),
+            SocketAddrV4::new([127, 0, 0, 1].into(), 1).into(),
+        );
+        rpc.routing_table().add(dead.clone());
+
+        let new = candidate(&rpc);
+        add(&mut rpc, &new);
+        assert!(!rpc.routing_table().contains(new.id()));
+
+        thread::sleep(Duration::from_millis(150));
+        rpc.tick();
+
+        assert!(rpc.eviction_pings.is_empty());
+        assert!(!rpc.routing_table().contains(dead.id()));
+        assert!(rpc.routing_table().contains(new.id()));
+    }
+}
@@ -1,4 +1,8 @@
-use std::{collections::HashSet, convert::TryInto};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    net::IpAddr,
+};
 
 use crate::{common::MAX_BUCKET_SIZE_K, Id, Node};
 
@@ -8,7 +12,11 @@ use crate::{common::MAX_BUCKET_SIZE_K, Id, Node};
 /// Useful to estimate the Dht size.
 pub struct ClosestNodes {
     target: Id,
+    /// Nodes sorted by distance to the target, secure nodes first.
     nodes: Vec<Node>,
+    /// The same nodes by ip, so checking for existing nodes at the same ip
+    /// doesn't scan all nodes on every insert.
+    by_ip: HashMap<IpAddr, Vec<Node>>,
 }
 
 impl ClosestNodes {
@@ -17,6 +25,7 @@ impl ClosestNodes {
         Self {
             target,
             nodes: Vec::with_capacity(200),
+            by_ip: HashMap::new(),
         }
     }
 
@@ -48,14 +57,18 @@ impl ClosestNodes {
     pub fn add(&mut self, node: Node) {
         let seek = node.id().xor(&self.target);
 
-        if node.already_exists(&self.nodes) {
+        let same_ip = self.by_ip.entry(node.address().ip()).or_default();
+
+        if node.already_exists(same_ip.iter()) {
             return;
         }
 
+        let secure = node.is_secure();
+
         if let Err(pos) = self.nodes.binary_search_by(|prope| {
-            if prope.is_secure() && !node.is_secure() {
+            if prope.is_secure() && !secure {
                 std::cmp::Ordering::Less
-            } else if !prope.is_secure() && node.is_secure() {
+            } else if !prope.is_secure() && secure {
                 std::cmp::Ordering::Greater
             } else if prope.id() == node.id() {
                 std::cmp::Ordering::Equal
@@ -63,6 +76,7 @@ impl ClosestNodes {
                 prope.id().xor(&self.target).cmp(&seek)
             }
         }) {
+            same_ip.push(node.clone());
             self.nodes.insert(pos, node)
         }
     }
@@ -72,7 +86,7 @@ impl ClosestNodes {
     /// 2. The last node should be at a distance `edk` which is the expected distance of the 20th
     ///    node given previous estimations of the DHT size.
     /// 3. The number of subnets with unique 6 bits prefix in nodes ipv4 addresses match or exceeds
-    ///     the average from previous queries.
+    ///    the average from previous queries.
     ///
     /// If one or more of these conditions are not met, then we just take all responding nodes
     /// and store data at them.
@@ -135,7 +149,10 @@ impl ClosestNodes {
 }
 
 fn subnet(node: &Node) -> u8 {
-    ((node.address().ip().to_bits() >> 26) & 0b0011_1111) as u8
+    match node.address().ip() {
+        IpAddr::V4(ip) => ((ip.to_bits() >> 26) & 0b0011_1111) as u8,
+        IpAddr::V6(ip) => ((ip.to_bits() >> 122) & 0b0011_1111) as u8,
+    }
 }
 
 fn distance(target: &Id, node: &Node) -> u128 {
@@ -202,13 +219,35 @@ mod tests {
         assert_eq!(sorted, distances);
     }
 
+    #[test]
+    fn one_unsecure_node_per_ip() {
+        let mut closest_nodes = ClosestNodes::new(Id::random());
+
+        let address = SocketAddrV4::new([1, 1, 1, 1].into(), 6881).into();
+
+        closest_nodes.add(Node::new(Id::random(), address));
+        closest_nodes.add(Node::new(Id::random(), address));
+        closest_nodes.add(Node::new(
+            Id::random(),
+            SocketAddrV4::new([1, 1, 1, 1].into(), 6882).into(),
+        ));
+        closest_nodes.add(Node::new(
+            Id::random(),
+            SocketAddrV4::new([1, 1, 1, 2].into(), 6881).into(),
+        ));
+
+        assert_eq!(closest_nodes.len(), 2);
+    }
+
     #[test]
     fn order_by_secure_id() {
         let unsecure = Node::random();
         let secure = Node(Arc::new(NodeInner {
             id: Id::from_str("5a3ce9c14e7a08645677bbd1cfe7d8f956d53256").unwrap(),
-            address: SocketAddrV4::new([21, 75, 31, 124].into(), 0),
+            address: SocketAddrV4::new([21, 75, 31, 124].into(), 0).into(),
             token: None,
+            rtt: None,
+            version: None,
             last_seen: Instant::now(),
         }));
 
@@ -239,7 +278,7 @@ mod tests {
         for _ in 0..20 {
             let mut bytes = target_bytes.to_vec();
             bytes[18..].copy_from_slice(&Id::random().as_bytes()[18..]);
-            let node = Node::new(Id::random(), SocketAddrV4::new(0.into(), 0));
+            let node = Node::new(Id::random(), SocketAddrV4::new(0.into(), 0).into());
 
             sybil.add(node.clone());
             closest_nodes.add(node);
@@ -281,10 +320,10 @@ mod tests {
                 let mut closest_nodes = ClosestNodes::new(target);
 
                 for (_, node) in nodes.range(target..).take(100) {
-                    closest_nodes.add(node.clone().into())
+                    closest_nodes.add(node.clone())
                 }
                 for (_, node) in nodes.range(..target).rev().take(100) {
-                    closest_nodes.add(node.clone().into())
+                    closest_nodes.add(node.clone())
                 }
 
                 let estimate = closest_nodes.dht_size_estimate();
@@ -1,9 +1,18 @@
 use std::{
-    net::{Ipv4Addr, SocketAddrV4},
+    fmt::{self, Debug, Formatter},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    num::{NonZeroU32, NonZeroUsize},
+    sync::Arc,
     time::Duration,
 };
 
-use super::{ServerSettings, DEFAULT_REQUEST_TIMEOUT};
+use crate::{Id, Node};
+
+use super::{
+    QueryStrategy, ServerSettings, DEFAULT_ALPHA, DEFAULT_DUPLICATE_RESPONSE_WINDOW,
+    DEFAULT_JITTER, DEFAULT_MAX_DATAGRAM_SIZE, DEFAULT_MAX_RETRIES, DEFAULT_QUERY_DEADLINE,
+    DEFAULT_RECV_BUFFER_SIZE, DEFAULT_REQUEST_TIMEOUT,
+};
 
 #[derive(Debug, Clone)]
 /// Dht Configurations
@@ -16,25 +25,216 @@ pub struct Config {
     ///
     /// Defaults to None
     pub port: Option<u16>,
+    /// Local Ipv4 address to listen on, useful on hosts with multiple network interfaces.
+    ///
+    /// Defaults to None, where it listens on all interfaces (`0.0.0.0`).
+    pub bind_ip: Option<Ipv4Addr>,
+    /// Address to bind an additional Ipv6 socket to, used to send messages to Ipv6 nodes.
+    ///
+    /// Defaults to None, where no Ipv6 socket is bound.
+    pub bind_address_v6: Option<SocketAddrV6>,
     /// UDP socket request timeout duration.
     ///
     /// The longer this duration is, the longer queries take until they are deemeed "done".
     /// The shortet this duration is, the more responses from busy nodes we miss out on,
     /// which affects the accuracy of queries trying to find closest nodes to a target.
     ///
+    /// Requests time out sooner on fast networks, as the timeout adapts to the
+    /// round trip times of received responses, but never later than this duration.
+    ///
     /// Defaults to [DEFAULT_REQUEST_TIMEOUT]
     pub request_timeout: Duration,
+    /// Duration a request is remembered after receiving its response, so that
+    /// duplicates of that response delivered by UDP are counted as
+    /// [duplicate_responses](crate::SocketMetrics::duplicate_responses) instead
+    /// of [unexpected_responses](crate::SocketMetrics::unexpected_responses).
+    ///
+    /// Zero disables remembering completed requests.
+    ///
+    /// Defaults to [DEFAULT_DUPLICATE_RESPONSE_WINDOW]
+    pub duplicate_response_window: Duration,
+    /// Size of the OS receive buffer to request for the socket, to avoid
+    /// dropping responses under load. `None` keeps the OS default.
+    ///
+    /// The OS may cap it, see [DhtBuilder::recv_buffer_size](crate::DhtBuilder::recv_buffer_size).
+    ///
+    /// Defaults to [DEFAULT_RECV_BUFFER_SIZE]
+    pub recv_buffer_size: Option<usize>,
+    /// Maximum size in bytes of a sent datagram. Responses are trimmed of their
+    /// closer nodes, then peers or samples, to fit, and larger requests are not sent.
+    ///
+    /// Defaults to [DEFAULT_MAX_DATAGRAM_SIZE]
+    pub max_datagram_size: usize,
+    /// Maximum duration of a single query, after which it is considered
+    /// done even if it is still waiting for responses.
+    ///
+    /// Defaults to [DEFAULT_QUERY_DEADLINE]
+    pub query_deadline: Duration,
+    /// Maximum number of nodes a query waits for responses from concurrently.
+    ///
+    /// Defaults to [DEFAULT_ALPHA]
+    pub alpha: usize,
+    /// Number of times a query retransmits a timed out request to the same node,
+    /// before considering it unresponsive.
+    ///
+    /// Defaults to [DEFAULT_MAX_RETRIES]
+    pub max_retries: u8,
+    /// Maximum number of iterations (hops) of sending requests to closer nodes,
+    /// after which a query finishes with whatever it found.
+    ///
+    /// Defaults to None, where queries are only limited by [Self::query_deadline].
+    pub max_iterations: Option<usize>,
+    /// Maximum number of requests a single query sends, including retransmissions,
+    /// after which it finishes with whatever it found.
+    ///
+    /// Useful on metered connections, to cap the traffic of a lookup regardless
+    /// of how fast the network is.
+    ///
+    /// Defaults to None, where queries are only limited by [Self::query_deadline].
+    pub max_requests_per_query: Option<usize>,
+    /// Number of unique peers after which a `get_peers` query stops visiting
+    /// nodes and finishes, dropping further peers.
+    ///
+    /// Useful to join a swarm, where a few peers introduce you to the rest of it.
+    ///
+    /// Defaults to None, where queries keep all the peers they find.
+    pub max_peers_per_query: Option<usize>,
+    /// Number of leading bits a responding node has to share with a query's target
+    /// for the query to finish right away, even if closer nodes could still be found.
+    ///
+    /// Trades finding the closest nodes for latency, for applications where any node
+    /// close enough to the target will do. Items are stored at, and looked up from,
+    /// fewer of the closest nodes as a result.
+    ///
+    /// Defaults to None, where queries run until they converge.
+    pub closeness_floor: Option<u8>,
+    /// Number of unique peers after which a `get_peers` query sends `find_node`
+    /// requests instead, to finish converging to the closest nodes without asking
+    /// any more nodes to look up their peer stores.
+    ///
+    /// Nodes visited after the switch don't give tokens, so they can't be
+    /// announced to, unless they were visited with `get_peers` before.
+    ///
+    /// Defaults to None, where `get_peers` requests are sent throughout the query.
+    pub find_node_after_peers: Option<usize>,
+    /// Fraction of the routing table refresh interval, and of queries'
+    /// retransmission backoffs, they are randomly shifted by, so that nodes
+    /// started together don't send bursts of requests in lockstep.
+    ///
+    /// For example, 0.1 refreshes the routing table every 15 minutes ± 90 seconds.
+    ///
+    /// Defaults to [DEFAULT_JITTER]
+    pub jitter: f64,
+    /// Whether queries visit nodes that responded to us before first, among the
+    /// closest nodes to their target, instead of strictly by distance.
+    ///
+    /// Defaults to true
+    pub prefer_verified_nodes: bool,
+    /// Strategy handling the responses of every query, to extract and validate
+    /// what BEP specific queries need without forking the query engine.
+    ///
+    /// Defaults to None, where [ClosestNodesStrategy](super::ClosestNodesStrategy)
+    /// follows the closer nodes in every response.
+    pub query_strategy: Option<Box<dyn QueryStrategy>>,
+    /// Whether every query records its decisions, like adding, ignoring or visiting
+    /// a candidate, as [QueryEvent](super::QueryEvent)s collected with
+    /// [Dht::query_info](crate::Dht::query_info), for example to study how lookups converge.
+    ///
+    /// Defaults to false, where nothing is recorded.
+    pub trace_queries: bool,
+    /// Called with the target, the closest nodes and the peers found so far
+    /// by every query that hits its deadline.
+    ///
+    /// Defaults to None, where timeouts are only logged.
+    pub on_timeout: Option<TimeoutCallback>,
+    /// Maximum number of outgoing requests per second, requests over that
+    /// budget are deferred by queries to a later tick.
+    ///
+    /// Useful on metered connections or networks that throttle bursts of packets.
+    ///
+    /// Defaults to None, where outgoing requests are not limited.
+    pub max_requests_per_sec: Option<NonZeroU32>,
+    /// Maximum number of requests inflight to the same address at once, across
+    /// all queries, requests over that are deferred by queries to a later tick,
+    /// visiting other nodes in the meantime.
+    ///
+    /// Avoids sending simultaneous requests to a node close to the targets of
+    /// many concurrent queries, which could trigger its own rate limiting.
+    ///
+    /// Defaults to None, where any number of requests can be inflight to the same address.
+    pub max_inflight_per_node: Option<NonZeroUsize>,
+    /// Number of malformed messages received from an ip, after which it is blacklisted,
+    /// see [crate::Dht::blacklist].
+    ///
+    /// Defaults to None, where ips are never blacklisted automatically.
+    pub max_protocol_errors: Option<NonZeroU32>,
+    /// Whether to drop received messages that aren't strictly valid according to
+    /// [BEP_0005](https://www.bittorrent.org/beps/bep_0005.html), instead of
+    /// ignoring their malformed optional fields, like a version that isn't 4 bytes.
+    ///
+    /// Defaults to false
+    pub strict_messages: bool,
+    /// Whether to send a single request, and share its response, when identical requests
+    /// to the same node are sent while the first one is still inflight.
+    ///
+    /// Defaults to false
+    pub coalesce_requests: bool,
+    /// Whether to ping the least recently seen node of a full bucket before adding
+    /// a new node to it, and only replace it if it doesn't respond, as in Kademlia,
+    /// instead of letting the [EvictionPolicy](crate::EvictionPolicy) choose right away.
+    ///
+    /// The new node is dropped if the pinged node responds.
+    ///
+    /// Defaults to false
+    pub ping_before_evict: bool,
+    /// Whether to query, and add to the routing table, nodes at private, loopback,
+    /// and other reserved addresses, which are otherwise ignored.
+    ///
+    /// Useful to run a Dht on a local network. Bootstrapping from such an
+    /// address implies this option.
+    ///
+    /// Defaults to false
+    pub allow_private_addresses: bool,
+    /// Maximum number of queries sending their requests from their own ephemeral
+    /// UDP port, instead of this node's port, so the nodes they visit can't
+    /// correlate them. Further queries share this node's port.
+    ///
+    /// Each of these queries holds a file descriptor until it is done.
+    /// Storing data (put and announce requests) still uses this node's port.
+    ///
+    /// Defaults to 0, where all queries share this node's port.
+    pub ephemeral_query_ports: usize,
     /// Server to respond to incoming Requests
     pub server_settings: ServerSettings,
     /// Whether or not to start in server mode from the get go.
     ///
     /// Defaults to false where it will run in [Adaptive mode](https://github.com/pubky/mainline?tab=readme-ov-file#adaptive-mode).
     pub server_mode: bool,
+    /// Whether to stay a read-only node ([BEP_0043](https://www.bittorrent.org/beps/bep_0043.html)),
+    /// flagging all outgoing messages with `ro=1` and never responding to requests,
+    /// so other nodes don't add this node to their routing tables.
+    ///
+    /// Useful for nodes behind restrictive NATs, that can't be reached
+    /// by nodes they didn't contact first.
+    ///
+    /// Takes precedence over [Self::server_mode], and disables switching to
+    /// server mode in adaptive mode.
+    ///
+    /// Defaults to false
+    pub read_only: bool,
     /// A known public IPv4 address for this node to generate
     /// a secure node Id from according to [BEP_0042](https://www.bittorrent.org/beps/bep_0042.html)
     ///
     /// Defaults to None, where we depend on suggestions from responding nodes.
     pub public_ip: Option<Ipv4Addr>,
+    /// Explicit [Id] for this node, for example one persisted from a previous session.
+    ///
+    /// It is kept for the whole session, even if it is not [secure](Id::is_valid_for_ip)
+    /// for this node's public address.
+    ///
+    /// Defaults to None, where the Id is generated from [Self::public_ip] if set,
+    /// or randomly otherwise.
+    pub id: Option<Id>,
 }
 
 impl Default for Config {
@@ -42,10 +242,50 @@ impl Default for Config {
         Self {
             bootstrap: None,
             port: None,
+            bind_ip: None,
+            bind_address_v6: None,
             request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            duplicate_response_window: DEFAULT_DUPLICATE_RESPONSE_WINDOW,
+            recv_buffer_size: Some(DEFAULT_RECV_BUFFER_SIZE),
+            max_datagram_size: DEFAULT_MAX_DATAGRAM_SIZE,
+            query_deadline: DEFAULT_QUERY_DEADLINE,
+            alpha: DEFAULT_ALPHA,
+            max_retries: DEFAULT_MAX_RETRIES,
+            max_iterations: None,
+            max_requests_per_query: None,
+            max_peers_per_query: None,
+            closeness_floor: None,
+            find_node_after_peers: None,
+            jitter: DEFAULT_JITTER,
+            prefer_verified_nodes: true,
+            query_strategy: None,
+            trace_queries: false,
+            on_timeout: None,
+            max_requests_per_sec: None,
+            max_inflight_per_node: None,
+            max_protocol_errors: None,
+            strict_messages: false,
+            coalesce_requests: false,
+            ping_before_evict: false,
+            allow_private_addresses: false,
+            ephemeral_query_ports: 0,
             server_settings: Default::default(),
             server_mode: false,
+            read_only: false,
             public_ip: None,
+            id: None,
         }
     }
 }
+
+type TimeoutFn = dyn Fn(Id, &[Node], &[SocketAddr]) + Send + Sync;
+
+/// Callback of every query that times out, see [Config::on_timeout].
+#[derive(Clone)]
+pub struct TimeoutCallback(pub Arc<TimeoutFn>);
+
+impl Debug for TimeoutCallback {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("TimeoutCallback")
+    }
+}
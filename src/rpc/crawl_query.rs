@@ -0,0 +1,346 @@
+//! Crawl the network with an endless series of find_node queries to random targets.
+
+mod spill;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+use tracing::{debug, warn};
+
+use super::{
+    iterative_query::{GetRequestSpecific, IterativeQuery, TickResult},
+    socket::KrpcSocket,
+};
+use crate::common::{ErrorSpecific, FindNodeRequestArguments, Id, Message, Node, RoutingTable};
+
+pub use spill::SpillStore;
+
+/// Default maximum number of discovered nodes a [CrawlQuery] keeps in memory.
+pub(crate) const DEFAULT_CRAWL_CAPACITY: usize = 10_000;
+
+/// Continuously discovers nodes, by starting a new [IterativeQuery] to a random
+/// target every time the previous one is done, and sends every newly discovered
+/// responding node through a channel.
+///
+/// Unlike an [IterativeQuery], a crawl is never done until it is [cancelled](Self::cancel),
+/// or the receiver of discovered nodes is dropped.
+///
+/// Memory is bounded by the capacity of the table of discovered nodes, and the
+/// rate of requests by the socket's rate limiter, if any. With a [SpillStore],
+/// nodes discovered once that table is full are appended to disk instead of
+/// being evicted, so they are never sent twice.
+#[derive(Debug)]
+pub(crate) struct CrawlQuery {
+    requester_id: Id,
+    deadline: Duration,
+    query: IterativeQuery,
+    /// Discovered nodes, to seed the next queries and avoid sending the same node twice.
+    ///
+    /// Nodes evicted from this table can be sent again if discovered again,
+    /// unless they are spilled to disk instead.
+    discovered: RoutingTable,
+    capacity: usize,
+    /// Nodes discovered once [Self::discovered] is full.
+    spill: Option<SpillStore>,
+    /// Nodes to start from while no nodes are discovered yet.
+    seeds: Vec<Node>,
+    /// Addresses of this node, that are never visited.
+    own_addresses: Vec<SocketAddr>,
+    tx: Sender<Node>,
+    /// Number of done queries.
+    rounds: usize,
+    cancelled: bool,
+}
+
+impl CrawlQuery {
+    pub fn new(
+        requester_id: Id,
+        seeds: Vec<Node>,
+        deadline: Duration,
+        capacity: usize,
+        tx: Sender<Node>,
+    ) -> Self {
+        let mut crawl = Self {
+            requester_id,
+            deadline,
+            query: IterativeQuery::new(
+                requester_id,
+                requester_id,
+                GetRequestSpecific::FindNode(FindNodeRequestArguments {
+                    target: requester_id,
+                }),
+                deadline,
+            ),
+            discovered: RoutingTable::new(Id::random()).with_capacity(capacity),
+            capacity,
+            spill: None,
+            seeds,
+            own_addresses: Vec::new(),
+            tx,
+            rounds: 0,
+            cancelled: false,
+        };
+
+        crawl.query = crawl.next_query();
+
+        crawl
+    }
+
+    /// Add an address of this node, so the crawl never visits itself.
+    pub fn with_own_address(mut self, address: SocketAddr) -> Self {
+        self.own_addresses.push(address);
+        self.query = self.next_query();
+
+        self
+    }
+
+    /// Append nodes discovered once the in-memory table is full to `spill`,
+    /// instead of evicting them.
+    pub fn with_spill(mut self, spill: SpillStore) -> Self {
+        self.spill = Some(spill);
+
+        self
+    }
+
+    // === Getters ===
+
+    /// Returns true if this crawl was cancelled.
+    pub fn is_done(&self) -> bool {
+        self.cancelled
+    }
+
+    /// Number of queries this crawl finished so far.
+    pub fn rounds(&self) -> usize {
+        self.rounds
+    }
+
+    /// Returns true if this crawl sent a request with this transaction_id.
+    pub fn inflight(&self, tid: u16) -> bool {
+        self.query.inflight(tid)
+    }
+
+    // === Public Methods ===
+
+    /// Handle a response from a visited node, and send it through the channel
+    /// if it wasn't discovered before.
+    pub fn add_response(&mut self, tid: u16, node: &Node, message: &Message) {
+        if !self.query.add_response(tid, node, message) {
+            return;
+        }
+
+        match self.discover(node) {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(error) => {
+                warn!(
+                    ?error,
+                    "Could not spill discovered node, stopping the crawl"
+                );
+                self.cancelled = true;
+
+                return;
+            }
+        }
+
+        if self.tx.send(node.clone()).is_err() {
+            debug!(rounds = self.rounds, "Crawl receiver dropped");
+
+            self.cancelled = true;
+        }
+    }
+
+    /// Handle an error response from a visited node.
+    pub fn handle_error(&mut self, tid: u16, from: SocketAddr, error: ErrorSpecific) {
+        self.query.handle_error(tid, from, error);
+    }
+
+    /// Advance the current query, and start the next one once it is done.
+    ///
+    /// Returns [TickResult::InProgress] until the crawl is cancelled,
+    /// then [TickResult::Cancelled].
+    pub fn tick(&mut self, socket: &mut KrpcSocket) -> TickResult {
+        if self.cancelled {
+            self.query.cancel(socket);
+
+            return TickResult::Cancelled;
+        }
+
+        if self.query.tick(socket) != TickResult::InProgress {
+            self.rounds += 1;
+
+            debug!(
+                rounds = self.rounds,
+                discovered = self.discovered.size(),
+                spilled = self.spill.as_ref().map_or(0, |spill| spill.len()),
+                "Crawl query done, starting the next one"
+            );
+
+            self.query = self.next_query();
+        }
+
+        TickResult::InProgress
+    }
+
+    /// Stop this crawl, dropping its inflight requests from the socket.
+    pub fn cancel(&mut self, socket: &mut KrpcSocket) {
+        self.query.cancel(socket);
+        self.cancelled = true;
+    }
+
+    // === Private Methods ===
+
+    /// Remember a discovered node, returning `false` if it was already discovered,
+    /// or can't be remembered.
+    fn discover(&mut self, node: &Node) -> Result<bool, io::Error> {
+        if self.discovered.contains(node.id()) {
+            return Ok(false);
+        }
+
+        let Some(spill) = &mut self.spill else {
+            return Ok(self.discovered.add(node.clone()));
+        };
+
+        if spill.contains(node.id())? {
+            return Ok(false);
+        }
+        if self.discovered.size() < self.capacity && self.discovered.add(node.clone()) {
+            return Ok(true);
+        }
+
+        spill.insert(node)
+    }
+
+    /// A query to a random target, seeded with the closest discovered nodes,
+    /// with a [compact](IterativeQuery::with_compact_visited) set of visited nodes.
+    fn next_query(&self) -> IterativeQuery {
+        let target = Id::random();
+
+        let seeds = if self.discovered.is_empty() {
+            self.seeds.clone()
+        } else {
+            self.discovered.closest(target).to_vec()
+        };
+
+        self.own_addresses.iter().fold(
+            IterativeQuery::new_with_seed(
+                self.requester_id,
+                target,
+                GetRequestSpecific::FindNode(FindNodeRequestArguments { target }),
+                self.deadline,
+                seeds,
+            )
+            .with_compact_visited(),
+            |query, address| query.with_own_address(*address),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::channel;
+
+    use crate::common::{FindNodeResponseArguments, MessageType, ResponseSpecific};
+    use crate::rpc::DEFAULT_QUERY_DEADLINE;
+
+    use super::*;
+
+    fn find_node_response(responder: &Node, nodes: &[Node]) -> Message {
+        Message {
+            transaction_id: 0,
+            version: None,
+            requester_ip: None,
+            read_only: false,
+            message_type: MessageType::Response(ResponseSpecific::FindNode(
+                FindNodeResponseArguments {
+                    responder_id: *responder.id(),
+                    nodes: nodes.into(),
+                },
+            )),
+        }
+    }
+
+    #[test]
+    fn never_done() {
+        let (tx, rx) = channel();
+        let mut socket = KrpcSocket::client().unwrap();
+
+        let seed = Node::new(Id::random(), SocketAddr::from(([127, 0, 0, 1], 1)));
+        let mut crawl = CrawlQuery::new(
+            Id::random(),
+            vec![seed.clone()],
+            DEFAULT_QUERY_DEADLINE,
+            DEFAULT_CRAWL_CAPACITY,
+            tx,
+        );
+
+        assert_eq!(crawl.tick(&mut socket), TickResult::InProgress);
+
+        let tids = (0..=u16::MAX)
+            .filter(|tid| crawl.inflight(*tid))
+            .collect::<Vec<_>>();
+        assert!(!tids.is_empty());
+
+        for tid in &tids {
+            crawl.add_response(*tid, &seed, &find_node_response(&seed, &[]));
+            socket.cancel(*tid);
+        }
+
+        // Sent once.
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![seed.clone()]);
+
+        // The query converged, and the next one starts from the discovered nodes.
+        assert_eq!(crawl.tick(&mut socket), TickResult::InProgress);
+        assert_eq!(crawl.rounds(), 1);
+        assert!(!crawl.is_done());
+
+        crawl.cancel(&mut socket);
+        assert!(crawl.is_done());
+        assert_eq!(crawl.tick(&mut socket), TickResult::Cancelled);
+    }
+
+    #[test]
+    fn receiver_dropped() {
+        let (tx, rx) = channel();
+        let mut socket = KrpcSocket::client().unwrap();
+
+        let mut crawl = CrawlQuery::new(
+            Id::random(),
+            Vec::new(),
+            DEFAULT_QUERY_DEADLINE,
+            DEFAULT_CRAWL_CAPACITY,
+            tx,
+        );
+        drop(rx);
+
+        let node = Node::new(Id::random(), SocketAddr::from(([127, 0, 0, 1], 1)));
+        crawl.add_response(0, &node, &find_node_response(&node, &[]));
+
+        assert!(crawl.is_done());
+        assert_eq!(crawl.tick(&mut socket), TickResult::Cancelled);
+    }
+    #[test]
+    fn spill() {
+        let (tx, rx) = channel();
+        let path = std::env::temp_dir().join(format!("mainline-crawl-spill-{}", Id::random()));
+
+        let mut crawl = CrawlQuery::new(Id::random(), Vec::new(), DEFAULT_QUERY_DEADLINE, 2, tx)
+            .with_spill(SpillStore::create(&path, 100, 0.01).unwrap());
+
+        let nodes = (1..=5).map(Node::unique).collect::<Vec<_>>();
+        for _ in 0..2 {
+            for node in &nodes {
+                crawl.add_response(0, node, &find_node_response(node, &[]));
+            }
+        }
+
+        let spilled = crawl.spill.as_ref().unwrap().len();
+        std::fs::remove_file(&path).unwrap();
+
+        // Each sent once, though only two fit in memory.
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), nodes);
+        assert_eq!(crawl.discovered.size(), 2);
+        assert_eq!(spilled, 3);
+    }
+}
@@ -0,0 +1,260 @@
+//! On-disk store of discovered nodes, for crawls that outgrow memory.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::common::{Id, Node, ID_SIZE, NODE_BYTE_SIZE};
+
+/// Append-only file of the compact node infos (26 bytes each) of discovered nodes,
+/// guarded by an in-memory bloom filter of their Ids.
+///
+/// Checking whether an Id is stored only reads the file if the bloom filter
+/// matches it, which is always the case for stored Ids, and for a fraction of
+/// other Ids (its false positive rate). False positives never make an unknown
+/// node look stored, they only cost reading the records sharing the first two
+/// bytes of its Id, found through an index of 4 bytes per stored node.
+///
+/// The false positive rate grows once more nodes than expected are stored,
+/// for example from 1% to ~5% at twice the expected nodes,
+/// while the filter takes `-ln(rate) / ln(2)^2` bits (~1.2 bytes at 1%) per expected node.
+///
+/// Ipv6 nodes have no compact node info, and are never stored.
+#[derive(Debug)]
+pub struct SpillStore {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    filter: IdFilter,
+    /// Record numbers in the file, by the first two bytes of their Id.
+    index: Vec<Vec<u32>>,
+    len: usize,
+}
+
+impl SpillStore {
+    /// Create a store at `path`, truncating any existing file, with a bloom filter
+    /// sized for `expected_nodes` at the given `false_positive_rate`.
+    ///
+    /// For example, 10 million nodes at 1% take a ~12 MB filter.
+    ///
+    /// The file is left on disk once the store is dropped.
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        expected_nodes: usize,
+        false_positive_rate: f64,
+    ) -> Result<Self, io::Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        file.set_len(0)?;
+
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            writer: BufWriter::new(file),
+            filter: IdFilter::new(expected_nodes, false_positive_rate),
+            index: vec![Vec::new(); 1 << 16],
+            len: 0,
+        })
+    }
+
+    // === Getters ===
+
+    /// Returns the path of the file nodes are appended to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the number of stored nodes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if no nodes are stored yet.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // === Public Methods ===
+
+    /// Append a node to the file, returning `false` if it is an Ipv6 node.
+    ///
+    /// Doesn't check whether the node is already stored, see [Self::contains].
+    pub fn insert(&mut self, node: &Node) -> Result<bool, io::Error> {
+        let Some(compact) = node.to_compact() else {
+            return Ok(false);
+        };
+
+        self.writer.write_all(&compact)?;
+        self.filter.insert(node.id());
+        self.index[prefix(node.id())].push(self.len as u32);
+        self.len += 1;
+
+        Ok(true)
+    }
+
+    /// Returns `true` if a node with this Id is stored.
+    pub fn contains(&mut self, id: &Id) -> Result<bool, io::Error> {
+        if !self.filter.contains(id) {
+            return Ok(false);
+        }
+
+        self.writer.flush()?;
+
+        let mut file = self.writer.get_ref();
+        let mut record = [0; ID_SIZE];
+
+        for number in &self.index[prefix(id)] {
+            file.seek(SeekFrom::Start(*number as u64 * NODE_BYTE_SIZE as u64))?;
+            file.read_exact(&mut record)?;
+
+            if record == *id.as_bytes() {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+fn prefix(id: &Id) -> usize {
+    u16::from_be_bytes([id.as_bytes()[0], id.as_bytes()[1]]) as usize
+}
+
+/// Bloom filter of Ids, hashed with their own bytes since Ids are expected to be
+/// uniformly random. Nodes choosing their Ids to collide can only degrade the
+/// false positive rate, costing more file reads.
+#[derive(Debug)]
+struct IdFilter {
+    bits: Vec<u64>,
+    hashes: u64,
+}
+
+impl IdFilter {
+    fn new(expected: usize, false_positive_rate: f64) -> Self {
+        let rate = false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+
+        let bits = ((expected.max(1) as f64) * -rate.ln() / (ln2 * ln2)).ceil() as usize;
+        let hashes = ((bits as f64 / expected.max(1) as f64) * ln2).round() as u64;
+
+        Self {
+            bits: vec![0; bits.div_ceil(64).max(1)],
+            hashes: hashes.max(1),
+        }
+    }
+
+    fn insert(&mut self, id: &Id) {
+        for index in self.indices(id).collect::<Vec<_>>() {
+            self.bits[index / 64] |= 1 << (index % 64);
+        }
+    }
+
+    fn contains(&self, id: &Id) -> bool {
+        self.indices(id)
+            .all(|index| self.bits[index / 64] & (1 << (index % 64)) != 0)
+    }
+
+    /// Bit indices of an Id, with double hashing over the first 16 bytes of the Id.
+    fn indices(&self, id: &Id) -> impl Iterator<Item = usize> + '_ {
+        let bytes = id.as_bytes();
+        let h1 = u64::from_le_bytes(bytes[..8].try_into().expect("infallible"));
+        let h2 = u64::from_le_bytes(bytes[8..16].try_into().expect("infallible")) | 1;
+        let len = (self.bits.len() * 64) as u64;
+
+        (0..self.hashes).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddrV4;
+    use std::time::Instant;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mainline-spill-{name}-{}", Id::random()))
+    }
+
+    #[test]
+    fn insert_contains() {
+        let path = temp_path("insert");
+        let mut store = SpillStore::create(&path, 1000, 0.01).unwrap();
+
+        let nodes = (0..100).map(Node::unique).collect::<Vec<_>>();
+        for node in &nodes {
+            assert!(store.insert(node).unwrap());
+        }
+        let v6 = Node::new(Id::random(), "[2001:4860::1]:6881".parse().unwrap());
+        assert!(!store.insert(&v6).unwrap());
+
+        assert_eq!(store.len(), 100);
+        assert!(nodes.iter().all(|node| store.contains(node.id()).unwrap()));
+        assert!(!store.contains(v6.id()).unwrap());
+        assert!(!store.contains(&Id::random()).unwrap());
+
+        // The file holds the compact node infos, in insertion order.
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(bytes.len(), 100 * NODE_BYTE_SIZE);
+        assert_eq!(
+            Node::from_compact(&bytes[..NODE_BYTE_SIZE]).unwrap().id(),
+            nodes[0].id()
+        );
+    }
+
+    #[test]
+    fn false_positive_rate() {
+        let mut filter = IdFilter::new(10_000, 0.01);
+
+        for _ in 0..10_000 {
+            filter.insert(&Id::random());
+        }
+
+        let false_positives = (0..10_000)
+            .filter(|_| filter.contains(&Id::random()))
+            .count();
+
+        assert!(false_positives < 300, "{false_positives}");
+    }
+
+    /// Run with `cargo test --release -- --ignored spill_millions --nocapture`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn spill_millions() {
+        let path = temp_path("bench");
+        let count = 3_000_000;
+        let mut store = SpillStore::create(&path, count, 0.01).unwrap();
+
+        let start = Instant::now();
+        let mut rediscovered = Vec::new();
+        for i in 0..count {
+            let address = SocketAddrV4::new((i as u32).into(), 6881);
+            let node = Node::new(Id::random(), address.into());
+
+            // Checked before inserting, like a crawl discovering new nodes.
+            assert!(!store.contains(node.id()).unwrap());
+            store.insert(&node).unwrap();
+
+            if i % 1000 == 0 {
+                rediscovered.push(*node.id());
+            }
+        }
+        let inserted = start.elapsed();
+
+        let start = Instant::now();
+        assert!(rediscovered.iter().all(|id| store.contains(id).unwrap()));
+        let found = start.elapsed();
+
+        std::fs::remove_file(&path).unwrap();
+
+        println!(
+            "Spilled {count} nodes in {inserted:?}, found {} stored nodes in {found:?}, \
+            with a filter of {} MB",
+            rediscovered.len(),
+            store.filter.bits.len() * 8 / 1_000_000
+        );
+    }
+}
@@ -1,18 +1,24 @@
-use std::net::SocketAddrV4;
+use std::collections::HashMap;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
 
-use crate::Id;
+use crate::{Id, Node};
 
-use super::Rpc;
+use super::{
+    iterative_query::IterativeQuery, LookupMetrics, QueryEvent, QueryState, Rpc, SocketMetrics,
+};
 
 /// Information and statistics about this mainline node.
 #[derive(Debug, Clone)]
 pub struct Info {
     id: Id,
     local_addr: SocketAddrV4,
+    local_addr6: Option<SocketAddrV6>,
     public_address: Option<SocketAddrV4>,
     firewalled: bool,
     dht_size_estimate: (usize, f64),
     server_mode: bool,
+    socket_metrics: SocketMetrics,
+    lookup_metrics: LookupMetrics,
 }
 
 impl Info {
@@ -24,6 +30,11 @@ impl Info {
     pub fn local_addr(&self) -> SocketAddrV4 {
         self.local_addr
     }
+    /// Local UDP Ipv6 socket address that this node is listening on,
+    /// if it was configured with [crate::DhtBuilder::bind_address_v6].
+    pub fn local_addr6(&self) -> Option<SocketAddrV6> {
+        self.local_addr6
+    }
     /// Returns the best guess for this node's Public address.
     ///
     /// If [crate::DhtBuilder::public_ip] was set, this is what will be returned
@@ -54,6 +65,16 @@ impl Info {
     pub fn dht_size_estimate(&self) -> (usize, f64) {
         self.dht_size_estimate
     }
+
+    /// Counters of messages sent and received by this node's socket.
+    pub fn socket_metrics(&self) -> SocketMetrics {
+        self.socket_metrics
+    }
+
+    /// Histograms of the end-to-end latencies of this node's lookups.
+    pub fn lookup_metrics(&self) -> LookupMetrics {
+        self.lookup_metrics
+    }
 }
 
 impl From<&Rpc> for Info {
@@ -61,10 +82,91 @@ impl From<&Rpc> for Info {
         Self {
             id: *rpc.id(),
             local_addr: rpc.local_addr(),
+            local_addr6: rpc.local_addr6(),
             dht_size_estimate: rpc.dht_size_estimate(),
             public_address: rpc.public_address(),
             firewalled: rpc.firewalled(),
             server_mode: rpc.server_mode(),
+            socket_metrics: rpc.socket_metrics(),
+            lookup_metrics: rpc.lookup_metrics(),
+        }
+    }
+}
+
+/// Snapshot of an inflight query, see [crate::Dht::query_info].
+#[derive(Debug, Clone)]
+pub struct QueryInfo {
+    target: Id,
+    state: QueryState,
+    client_versions: HashMap<String, usize>,
+    peers: Vec<SocketAddr>,
+    found_peers: bool,
+    peer_responses: usize,
+    responding_nodes: Box<[Node]>,
+    trace: Vec<QueryEvent>,
+}
+
+impl QueryInfo {
+    /// The query's target [Id]
+    pub fn target(&self) -> &Id {
+        &self.target
+    }
+
+    /// Lifecycle state of the query, as of its last tick.
+    pub fn state(&self) -> QueryState {
+        self.state
+    }
+
+    /// Number of responding nodes per client version, formatted as the client's
+    /// two letters and its version in hex, like `LT0102`, or `unknown`
+    /// if a node didn't send a valid version.
+    pub fn client_versions(&self) -> &HashMap<String, usize> {
+        &self.client_versions
+    }
+
+    /// Unique peers found so far by a `get_peers` query, the most recently received first.
+    pub fn peers(&self) -> &[SocketAddr] {
+        &self.peers
+    }
+
+    /// Returns `true` if any node responded to this `get_peers` query with values,
+    /// even if they were all duplicates, as opposed to only ever receiving nodes.
+    pub fn found_peers(&self) -> bool {
+        self.found_peers
+    }
+
+    /// Number of `get_peers` responses carrying values received so far.
+    pub fn peer_responses(&self) -> usize {
+        self.peer_responses
+    }
+
+    /// Nodes that responded to this query, with the Id from their response and the
+    /// last time they did, as opposed to the Ids they were advertised with by others.
+    ///
+    /// Useful to see how many Ids answered from the same ip, for example.
+    pub fn responding_nodes(&self) -> &[Node] {
+        &self.responding_nodes
+    }
+
+    /// Events the query recorded since its previous snapshot, oldest first,
+    /// or nothing if [crate::DhtBuilder::trace_queries] isn't enabled.
+    pub fn trace(&self) -> &[QueryEvent] {
+        &self.trace
+    }
+}
+
+impl QueryInfo {
+    /// Snapshot `query`, taking the events it recorded so far.
+    pub(crate) fn from_query(query: &mut IterativeQuery) -> Self {
+        Self {
+            target: query.target(),
+            state: query.state(),
+            client_versions: query.client_versions(),
+            peers: query.peers_sorted(),
+            found_peers: query.found_peers(),
+            peer_responses: query.peer_responses(),
+            responding_nodes: query.responding_nodes().cloned().collect(),
+            trace: query.take_trace(),
         }
     }
 }
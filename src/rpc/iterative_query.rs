@@ -2,29 +2,259 @@
 
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::net::SocketAddrV4;
+use std::fmt::{self, Debug, Formatter};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::{mpsc::Sender, Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use tracing::{debug, trace};
+use tracing::{debug, debug_span, trace, Span};
 
-use super::{socket::KrpcSocket, ClosestNodes};
-use crate::common::{FindNodeRequestArguments, GetPeersRequestArguments, GetValueRequestArguments};
+use super::{
+    socket::{KrpcSocket, SocketError},
+    ClosestNodes,
+};
+use crate::common::{
+    is_bogon, BloomFilter, Clock, ErrorSpecific, FindNodeRequestArguments,
+    GetPeersRequestArguments, GetValueRequestArguments, Message, MessageType, ResponseSpecific,
+    Rng, SampleInfohashesRequestArguments, SystemClock, SystemRng, Want,
+};
 use crate::{
-    common::{Id, Node, RequestSpecific, RequestTypeSpecific, MAX_BUCKET_SIZE_K},
+    common::{
+        Id, MutableItem, Node, RequestSpecific, RequestTypeSpecific, RoutingTable,
+        MAX_BUCKET_SIZE_K,
+    },
     rpc::Response,
 };
 
+/// Default maximum duration an [IterativeQuery] can run, before it is considered timed out.
+pub const DEFAULT_QUERY_DEADLINE: Duration = Duration::from_secs(60);
+
+/// Default maximum number of concurrent visits (Kademlia's alpha) for an [IterativeQuery].
+pub const DEFAULT_ALPHA: usize = 3;
+
+/// Default number of times an [IterativeQuery] retransmits a timed out request
+/// to the same node before giving up on it.
+pub const DEFAULT_MAX_RETRIES: u8 = 2;
+
+/// Longest token accepted from a `get_peers` or `get` response.
+///
+/// Tokens are opaque, commonly 4 to 20 bytes, so a longer (or empty) token
+/// comes from a broken or malicious node, that shouldn't be stored at.
+const MAX_TOKEN_LENGTH: usize = 32;
+
+/// Customizes how queries handle responses, without changing their
+/// iterative traversal of the closest nodes, see [crate::DhtBuilder::query_strategy].
+pub trait QueryStrategy: std::fmt::Debug + Send {
+    /// Handle a response from a visited node, and return the closer nodes
+    /// it contains to be added as candidates.
+    fn on_response(&mut self, node: &Node, message: &Message) -> Vec<Node>;
+
+    /// Return `false` to drop a response matching the query's request type before
+    /// it is handled, as if it didn't match, so its sender isn't visited again.
+    ///
+    /// Useful for application specific filtering, like only accepting responses
+    /// with a plausible client version. Accepts every response by default.
+    fn accept(&mut self, _node: &Node, _message: &Message) -> bool {
+        true
+    }
+
+    /// Clone this strategy for every new query.
+    fn clone_box(&self) -> Box<dyn QueryStrategy>;
+}
+
+impl Clone for Box<dyn QueryStrategy> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+/// Called once with the closest nodes and the peers found so far by a query
+/// that hits its deadline, see [IterativeQuery::with_on_timeout].
+pub(crate) type OnTimeout = Box<dyn FnOnce(&[Node], &[SocketAddr]) + Send>;
+
+/// Default [QueryStrategy], following the closer nodes in any response,
+/// except the responder itself.
+#[derive(Debug, Clone)]
+pub struct ClosestNodesStrategy;
+
+impl QueryStrategy for ClosestNodesStrategy {
+    fn on_response(&mut self, node: &Node, message: &Message) -> Vec<Node> {
+        message
+            .get_closer_nodes()
+            .unwrap_or_default()
+            .iter()
+            // A node can't be closer than itself.
+            .filter(|closer| !closer.same_address(node) && closer.id() != node.id())
+            .cloned()
+            .collect()
+    }
+
+    fn clone_box(&self) -> Box<dyn QueryStrategy> {
+        Box::new(self.clone())
+    }
+}
+
 /// An iterative process of concurrently sending a request to the closest known nodes to
 /// the target, updating the routing table with closer nodes discovered in the responses, and
 /// repeating this process until no closer nodes (that aren't already queried) are found.
-#[derive(Debug)]
 pub(crate) struct IterativeQuery {
-    pub request: RequestSpecific,
+    request: RequestSpecific,
+    strategy: Box<dyn QueryStrategy>,
     closest: ClosestNodes,
+    closest_v6: ClosestNodes,
     responders: ClosestNodes,
-    inflight_requests: Vec<u16>,
-    visited: HashSet<SocketAddrV4>,
+    /// Tokens received from each responding node, to store data at it later.
+    tokens: HashMap<SocketAddr, Box<[u8]>>,
+    /// Transaction ids of all requests sent by this query, for fast lookup by [Self::inflight].
+    inflight_requests: HashSet<u16>,
+    /// Transaction ids of the main request sent to each visited node.
+    inflight_visits: Vec<(u16, SocketAddr)>,
+    alpha: usize,
+    /// Visit nodes that responded to us before others among the closest nodes.
+    prefer_verified: bool,
+    /// Number of retransmissions per visited address.
+    retries: HashMap<SocketAddr, u8>,
+    max_retries: u8,
+    /// Timed out visits waiting for their backoff before being retransmitted.
+    backing_off: Vec<(SocketAddr, Instant)>,
+    /// Fraction of retransmission backoffs they are randomly shifted by.
+    jitter: f64,
+    rng: Arc<dyn Rng>,
+    /// Number of ticks that sent requests, see [Self::with_max_iterations].
+    iterations: usize,
+    max_iterations: Option<usize>,
+    /// Number of requests sent, see [Self::with_max_requests].
+    requests: usize,
+    max_requests: Option<usize>,
+    /// Leading bits a responder must share with the target to finish early,
+    /// see [Self::with_closeness_floor].
+    closeness_floor: Option<u8>,
+    visited: VisitedSet,
+    /// Visited nodes that responded, including with an error.
+    responded: HashSet<SocketAddr>,
+    /// Most recent node Id each address responded with, and when.
+    responding_nodes: HashMap<SocketAddr, Node>,
+    /// Visited nodes that never responded, even after retries, or that can't be sent to,
+    /// and are skipped when picking the closest nodes, so the query backtracks to the
+    /// next closest ones.
+    unresponsive: HashSet<SocketAddr>,
+    /// Addresses of this node, that are never added as candidates or visited.
+    own_addresses: Vec<SocketAddr>,
+    /// Local port of the ephemeral socket to send requests from, see [Self::with_source_port].
+    source_port: Option<u16>,
+    /// Ignore candidates at [bogon](crate::common::is_bogon) addresses.
+    bogon_filter: bool,
+    /// Ids of visited candidates, to avoid visiting a node again at a new address.
+    visited_ids: HashSet<Id>,
     responses: Vec<Response>,
-    public_address_votes: HashMap<SocketAddrV4, u16>,
+    responses_count: usize,
+    mismatched: usize,
+    rejected: usize,
+    /// Client version of each responding node.
+    versions: HashMap<SocketAddr, Option<[u8; 4]>>,
+    errors: Vec<(SocketAddr, ErrorSpecific)>,
+    peers: HashSet<SocketAddr>,
+    /// Unique peers in the order they were received, preserving each responder's ordering.
+    peers_order: Vec<SocketAddr>,
+    /// Number of `get_peers` responses carrying values.
+    peer_responses: usize,
+    max_peers: Option<usize>,
+    /// Number of peers after which a `get_peers` query sends `find_node` requests instead.
+    find_node_after: Option<usize>,
+    /// Senders for newly found peers, dropped once the query is done.
+    peers_tx: Vec<Sender<SocketAddr>>,
+    samples: HashSet<Id>,
+    /// Unique info hashes from `sample_infohashes` responses, in the order they were received.
+    samples_order: Vec<Id>,
+    /// Merged bloom filters of seeders and peers from scrape responses (BEP_0033).
+    bf_seeders: BloomFilter,
+    bf_peers: BloomFilter,
+    started_at: Instant,
+    deadline: Duration,
+    state: QueryState,
+    clock: Arc<dyn Clock>,
+    /// Parent of the events of this query, to follow a lookup across ticks.
+    span: Span,
+    /// Decisions recorded since the last [Self::take_trace], if enabled by [Self::with_trace].
+    trace: Option<Vec<QueryEvent>>,
+    /// The node's routing table, shared with the other queries, see [Self::with_routing_table].
+    routing_table: Option<Arc<Mutex<RoutingTable>>>,
+    /// Taken and called when this query times out, see [Self::with_on_timeout].
+    on_timeout: Option<OnTimeout>,
+}
+
+/// Progress metrics of an [IterativeQuery].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueryStats {
+    /// Number of visited nodes.
+    pub visited: usize,
+    /// Number of visits still waiting for a response, as of the last tick.
+    pub inflight: usize,
+    /// Number of known closest nodes (Ipv4 and Ipv6).
+    pub closest_known: usize,
+    /// Number of responses received.
+    pub responses: usize,
+    /// Number of responses rejected for not matching the request type,
+    /// see [IterativeQuery::add_response].
+    pub mismatched: usize,
+    /// Number of responses dropped by the query's [QueryStrategy::accept].
+    pub rejected: usize,
+}
+
+/// The result of calling [IterativeQuery::tick].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickResult {
+    /// Query still has inflight requests.
+    InProgress,
+    /// No more inflight requests.
+    Done,
+    /// Query exceeded its deadline, and dropped its remaining inflight requests.
+    TimedOut,
+    /// Query was stopped by [IterativeQuery::cancel].
+    Cancelled,
+    /// Query reached its [maximum iterations](IterativeQuery::with_max_iterations)
+    /// before converging, and has no more inflight requests.
+    IterationsExhausted,
+    /// Query sent its [maximum requests](IterativeQuery::with_max_requests)
+    /// before converging, and has no more inflight requests.
+    RequestsExhausted,
+    /// Query found its [maximum peers](IterativeQuery::with_max_peers),
+    /// and dropped its remaining inflight requests.
+    MaxPeersReached,
+    /// Query got a response from a node within its
+    /// [closeness floor](IterativeQuery::with_closeness_floor) before converging,
+    /// and dropped its remaining inflight requests.
+    CloseEnough,
+    /// Query had no candidates to send any request to, for example with an empty
+    /// routing table and no reachable bootstrap nodes, so it found nothing without
+    /// asking anyone.
+    NoCandidates,
+}
+
+/// Lifecycle state of a query, see [QueryInfo::state](super::QueryInfo::state).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryState {
+    /// Created, but didn't send any request yet.
+    New,
+    /// Sent requests, and isn't done yet, even if none are inflight at the moment,
+    /// for example between retransmissions.
+    Running,
+    /// Finished, either because it found no closer nodes to visit,
+    /// or because it reached one of its limits, like its maximum iterations.
+    Converged,
+    /// Exceeded its deadline.
+    TimedOut,
+    /// Cancelled before it finished.
+    Cancelled,
+}
+
+impl QueryState {
+    /// Returns `true` if the query converged (or otherwise finished), timed out,
+    /// or was cancelled, as opposed to not being started yet, or still running
+    /// even if it has no requests inflight at the moment.
+    pub fn is_done(&self) -> bool {
+        matches!(self, Self::Converged | Self::TimedOut | Self::Cancelled)
+    }
 }
 
 #[derive(Debug)]
@@ -32,6 +262,7 @@ pub enum GetRequestSpecific {
     FindNode(FindNodeRequestArguments),
     GetPeers(GetPeersRequestArguments),
     GetValue(GetValueRequestArguments),
+    SampleInfohashes(SampleInfohashesRequestArguments),
 }
 
 impl GetRequestSpecific {
@@ -40,45 +271,362 @@ impl GetRequestSpecific {
             GetRequestSpecific::FindNode(args) => &args.target,
             GetRequestSpecific::GetPeers(args) => &args.info_hash,
             GetRequestSpecific::GetValue(args) => &args.target,
+            GetRequestSpecific::SampleInfohashes(args) => &args.target,
         }
     }
 }
 
 impl IterativeQuery {
-    pub fn new(requester_id: Id, target: Id, request: GetRequestSpecific) -> Self {
+    pub fn new(
+        requester_id: Id,
+        target: Id,
+        request: GetRequestSpecific,
+        deadline: Duration,
+    ) -> Self {
         let request_type = match request {
             GetRequestSpecific::FindNode(s) => RequestTypeSpecific::FindNode(s),
             GetRequestSpecific::GetPeers(s) => RequestTypeSpecific::GetPeers(s),
             GetRequestSpecific::GetValue(s) => RequestTypeSpecific::GetValue(s),
+            GetRequestSpecific::SampleInfohashes(s) => RequestTypeSpecific::SampleInfohashes(s),
         };
 
-        trace!(?target, ?request_type, "New Query");
+        let span = debug_span!("query", %target);
+        span.in_scope(|| trace!(?request_type, "New Query"));
 
         Self {
             request: RequestSpecific {
                 requester_id,
                 request_type,
+                want: vec![Want::N4],
             },
+            strategy: Box::new(ClosestNodesStrategy),
 
             closest: ClosestNodes::new(target),
+            closest_v6: ClosestNodes::new(target),
             responders: ClosestNodes::new(target),
+            tokens: HashMap::new(),
 
-            inflight_requests: Vec::new(),
-            visited: HashSet::new(),
+            inflight_requests: HashSet::new(),
+            inflight_visits: Vec::new(),
+            alpha: DEFAULT_ALPHA,
+            prefer_verified: true,
+            retries: HashMap::new(),
+            max_retries: DEFAULT_MAX_RETRIES,
+            backing_off: Vec::new(),
+            jitter: 0.0,
+            rng: Arc::new(SystemRng),
+            iterations: 0,
+            max_iterations: None,
+            requests: 0,
+            max_requests: None,
+            closeness_floor: None,
+            visited: VisitedSet::default(),
+            responded: HashSet::new(),
+            responding_nodes: HashMap::new(),
+            unresponsive: HashSet::new(),
+            own_addresses: Vec::new(),
+            source_port: None,
+            bogon_filter: false,
+            visited_ids: HashSet::new(),
 
             responses: Vec::new(),
+            responses_count: 0,
+            mismatched: 0,
+            rejected: 0,
+            versions: HashMap::new(),
+            errors: Vec::new(),
+            peers: HashSet::new(),
+            peers_order: Vec::new(),
+            peer_responses: 0,
+            max_peers: None,
+            find_node_after: None,
+            peers_tx: Vec::new(),
+            samples: HashSet::new(),
+            samples_order: Vec::new(),
+            bf_seeders: BloomFilter::new(),
+            bf_peers: BloomFilter::new(),
+
+            started_at: Instant::now(),
+            deadline,
+            state: QueryState::New,
+            clock: Arc::new(SystemClock),
+            span,
+            trace: None,
+            routing_table: None,
+            on_timeout: None,
+        }
+    }
+
+    /// Create a query with `seed_nodes` as its initial candidates.
+    ///
+    /// Candidates are ordered by distance to the target regardless of the order
+    /// they are given in, so the same seeds always lead to the same first visits.
+    pub fn new_with_seed(
+        requester_id: Id,
+        target: Id,
+        request: GetRequestSpecific,
+        deadline: Duration,
+        seed_nodes: Vec<Node>,
+    ) -> Self {
+        let mut query = Self::new(requester_id, target, request, deadline);
+
+        for node in seed_nodes {
+            query.add_candidate(node);
+        }
+
+        query
+    }
+
+    /// Set the maximum number of concurrent visits to closest nodes.
+    ///
+    /// Defaults to [DEFAULT_ALPHA]
+    pub fn with_alpha(mut self, alpha: usize) -> Self {
+        self.alpha = alpha.max(1);
+
+        self
+    }
+
+    /// Set the number of times a timed out request is retransmitted
+    /// to the same node, before considering it dead for this query.
+    ///
+    /// Defaults to [DEFAULT_MAX_RETRIES]
+    pub fn with_max_retries(mut self, max_retries: u8) -> Self {
+        self.max_retries = max_retries;
+
+        self
+    }
+
+    /// Set the fraction of retransmission backoffs they are randomly shifted by,
+    /// see [crate::DEFAULT_JITTER].
+    ///
+    /// Defaults to 0, where retransmissions back off exactly.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter;
+
+        self
+    }
+
+    /// Set the maximum number of iterations (hops) that send requests to closer nodes,
+    /// after which the query waits for its inflight requests and finishes with
+    /// whatever it found, returning [TickResult::IterationsExhausted].
+    ///
+    /// Caps the number of requests of a query that fails to converge,
+    /// for example because of adversarial nodes.
+    ///
+    /// Defaults to None, where only the query's deadline limits it.
+    pub fn with_max_iterations(mut self, max_iterations: usize) -> Self {
+        self.max_iterations = Some(max_iterations);
+
+        self
+    }
+
+    /// Set the maximum number of requests this query sends, including retransmissions,
+    /// after which the query waits for its inflight requests and finishes with
+    /// whatever it found, returning [TickResult::RequestsExhausted].
+    ///
+    /// Caps the traffic of a single query regardless of how fast the network is,
+    /// which the deadline alone doesn't.
+    ///
+    /// Defaults to None, where only the query's deadline limits it.
+    pub fn with_max_requests(mut self, max_requests: usize) -> Self {
+        self.max_requests = Some(max_requests);
+
+        self
+    }
 
-            public_address_votes: HashMap::new(),
+    /// Finish the query, returning [TickResult::CloseEnough], as soon as a node sharing
+    /// at least `matching_bits` leading bits with the target responded, even if
+    /// closer nodes could still be found.
+    ///
+    /// Trades finding the closest nodes for latency, for lookups where any node
+    /// close enough to the target will do.
+    ///
+    /// Defaults to None, where the query runs until it converges.
+    pub fn with_closeness_floor(mut self, matching_bits: u8) -> Self {
+        self.closeness_floor = Some(matching_bits);
+
+        self
+    }
+
+    /// Send `find_node` requests instead of `get_peers` requests once a `get_peers`
+    /// query found `peers` unique peers, to finish converging to the closest nodes
+    /// without asking any more nodes to look up their peer stores.
+    ///
+    /// Nodes visited after the switch don't give tokens, so they can't be
+    /// announced to, unless they were visited with `get_peers` before.
+    ///
+    /// Defaults to None, where `get_peers` requests are sent throughout the query.
+    pub fn with_find_node_after(mut self, peers: usize) -> Self {
+        self.find_node_after = Some(peers);
+
+        self
+    }
+
+    /// Record every decision of this query, like adding, ignoring or visiting a candidate,
+    /// as [QueryEvent]s to be collected with [Self::take_trace].
+    ///
+    /// Unlike `tracing` events, these can be inspected by the caller, for example to
+    /// study how lookups converge. Defaults to off, where nothing is recorded.
+    pub fn with_trace(mut self) -> Self {
+        self.trace = Some(Vec::new());
+
+        self
+    }
+
+    /// Call `on_timeout` with the closest nodes and the peers found so far, once,
+    /// when this query hits its deadline, as it returns [TickResult::TimedOut].
+    ///
+    /// Not called if the query completes, or is cancelled, before its deadline.
+    pub fn with_on_timeout(
+        mut self,
+        on_timeout: impl FnOnce(&[Node], &[SocketAddr]) + Send + 'static,
+    ) -> Self {
+        self.on_timeout = Some(Box::new(on_timeout));
+
+        self
+    }
+
+    /// Set the number of unique peers after which a `get_peers` query stops
+    /// visiting nodes and finishes, returning [TickResult::MaxPeersReached].
+    ///
+    /// Candidates are visited closest first, so the cap is filled by the peers
+    /// of the closest responders so far. Peers beyond the cap are dropped.
+    ///
+    /// Defaults to None, where all the peers found are kept.
+    pub fn with_max_peers(mut self, max_peers: usize) -> Self {
+        self.max_peers = Some(max_peers);
+
+        self
+    }
+
+    /// Seed this query with the closest nodes to its target from `table`,
+    /// usually the node's own routing table shared with every other query,
+    /// and add the nodes responding to this query back to it.
+    ///
+    /// Responders are only added if their bucket isn't full, or already contains them,
+    /// so evicting nodes is left to the owner of the table.
+    pub fn with_routing_table(mut self, table: Arc<Mutex<RoutingTable>>) -> Self {
+        let closest = table.lock().expect("poisoned lock").closest(self.target());
+
+        for node in closest {
+            self.add_candidate(node);
         }
+        self.routing_table = Some(table);
+
+        self
+    }
+
+    /// Ignore candidates at private, loopback, and other reserved addresses,
+    /// while still visiting such addresses explicitly passed to [Self::visit].
+    ///
+    /// Defaults to false
+    pub fn with_bogon_filter(mut self, enabled: bool) -> Self {
+        self.bogon_filter = enabled;
+
+        self
+    }
+
+    /// Send all requests from the [ephemeral socket](KrpcSocket::open_ephemeral)
+    /// bound to this local port, instead of the shared socket.
+    pub fn with_source_port(mut self, port: u16) -> Self {
+        self.source_port = Some(port);
+
+        self
+    }
+
+    /// Add an address of this node, so the query never visits itself,
+    /// for example if it is listed in responses by its public address.
+    ///
+    /// An unspecified ip (`0.0.0.0` or `::`) matches the loopback address.
+    pub fn with_own_address(mut self, address: SocketAddr) -> Self {
+        self.own_addresses.push(address);
+
+        self
+    }
+
+    /// Set whether to also ask for Ipv6 nodes in responses, to visit them
+    /// from the Ipv6 socket, see [KrpcSocket::can_send_to].
+    ///
+    /// Defaults to false, where only Ipv4 nodes are asked for.
+    pub fn with_ipv6(mut self, enabled: bool) -> Self {
+        self.request.want = if enabled {
+            vec![Want::N4, Want::N6]
+        } else {
+            vec![Want::N4]
+        };
+
+        self
+    }
+
+    /// Store visited Ipv4 addresses packed in 8 bytes each, instead of a [SocketAddr] each,
+    /// to save memory in queries visiting a very large number of nodes, like crawls.
+    pub fn with_compact_visited(mut self) -> Self {
+        self.visited = VisitedSet::compact(self.visited);
+
+        self
+    }
+
+    /// Set whether to spend the `alpha` visits on nodes we got a response from
+    /// before (with a known [Node::rtt]) first, among the closest `k` nodes,
+    /// instead of strictly by distance to the target.
+    ///
+    /// Defaults to true
+    pub fn with_prefer_verified(mut self, prefer_verified: bool) -> Self {
+        self.prefer_verified = prefer_verified;
+
+        self
+    }
+
+    /// Use this [Clock] for the query's deadline instead of the system clock,
+    /// starting the query at its current time.
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.started_at = clock.now();
+        self.clock = clock;
+
+        self
+    }
+
+    /// Use this [Rng] to jitter retransmission backoffs.
+    #[cfg(test)]
+    pub fn with_rng(mut self, rng: Arc<dyn Rng>) -> Self {
+        self.rng = rng;
+
+        self
+    }
+
+    /// Set the [QueryStrategy] handling this query's responses.
+    ///
+    /// Defaults to [ClosestNodesStrategy]
+    pub fn with_strategy(mut self, strategy: Box<dyn QueryStrategy>) -> Self {
+        self.strategy = strategy;
+
+        self
     }
 
     // === Getters ===
 
+    /// Returns the target of this query.
     pub fn target(&self) -> Id {
         self.responders.target()
     }
 
-    /// Closest nodes according to other nodes.
+    /// Returns the request of this query, sent to every visited node,
+    /// unless it [switched to `find_node`](Self::with_find_node_after).
+    pub fn request(&self) -> &RequestSpecific {
+        &self.request
+    }
+
+    /// Returns `true` if this `get_peers` query found enough peers to send
+    /// `find_node` requests instead, see [Self::with_find_node_after].
+    pub fn switched_to_find_node(&self) -> bool {
+        matches!(self.request.request_type, RequestTypeSpecific::GetPeers(_))
+            && self
+                .find_node_after
+                .is_some_and(|after| self.peers_order.len() >= after)
+    }
+
+    /// Closest Ipv4 nodes according to other nodes.
     pub fn closest(&self) -> &ClosestNodes {
         &self.closest
     }
@@ -92,18 +640,167 @@ impl IterativeQuery {
         &self.responses
     }
 
-    pub fn best_address(&self) -> Option<SocketAddrV4> {
-        let mut max = 0_u16;
-        let mut best_addr = None;
+    /// Returns the valid mutable item with the highest `seq` received so far, if any.
+    pub fn most_recent_mutable(&self) -> Option<&MutableItem> {
+        self.responses
+            .iter()
+            .filter_map(|response| match response {
+                Response::Mutable(item) => Some(item),
+                _ => None,
+            })
+            .max_by_key(|item| item.seq())
+    }
+
+    /// Unique peers found so far by a `get_peers` query, in the order they were received.
+    #[cfg(test)]
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.peers_order.clone()
+    }
 
-        for (addr, count) in self.public_address_votes.iter() {
-            if *count > max {
-                max = *count;
-                best_addr = Some(*addr);
-            };
+    /// Unique peers found so far by a `get_peers` query, the most recently received first.
+    pub fn peers_sorted(&self) -> Vec<SocketAddr> {
+        self.peers_order.iter().rev().copied().collect()
+    }
+
+    /// Unique peers found so far by a `get_peers` query, without copying them,
+    /// for membership checks in swarms with many peers.
+    pub fn peers_unordered(&self) -> &HashSet<SocketAddr> {
+        &self.peers
+    }
+
+    /// Unique info hashes sampled by a `sample_infohashes` query, in the order they were received.
+    pub fn samples(&self) -> &[Id] {
+        &self.samples_order
+    }
+
+    /// Nodes that responded to this query, with the Id from their response and the
+    /// last time they did, as opposed to the Ids they were advertised with by others.
+    ///
+    /// Useful to see how many Ids answered from the same ip, for example.
+    pub fn responding_nodes(&self) -> impl Iterator<Item = &Node> {
+        self.responding_nodes.values()
+    }
+
+    /// Returns `true` if any node responded to this `get_peers` query with values,
+    /// even if they were all duplicates, as opposed to only ever receiving nodes.
+    pub fn found_peers(&self) -> bool {
+        self.peer_responses > 0
+    }
+
+    /// Returns `true` if this query found its [maximum peers](Self::with_max_peers).
+    pub fn max_peers_reached(&self) -> bool {
+        self.max_peers
+            .is_some_and(|max| self.peers_order.len() >= max)
+    }
+
+    /// Number of `get_peers` responses carrying values received so far.
+    pub fn peer_responses(&self) -> usize {
+        self.peer_responses
+    }
+
+    /// Estimated number of seeders in the swarm, from the merged
+    /// scrape bloom filters received so far.
+    pub fn estimated_seeders(&self) -> usize {
+        self.bf_seeders.estimate()
+    }
+
+    /// Estimated number of peers (non seeders) in the swarm, from the merged
+    /// scrape bloom filters received so far.
+    pub fn estimated_peers(&self) -> usize {
+        self.bf_peers.estimate()
+    }
+
+    /// Returns the number of responding nodes per client version,
+    /// formatted as the client's two letters and its version in hex, like `LT0102`,
+    /// or `unknown` if a node didn't send a valid version.
+    pub fn client_versions(&self) -> HashMap<String, usize> {
+        let mut histogram = HashMap::new();
+
+        for version in self.versions.values() {
+            *histogram.entry(format_version(*version)).or_default() += 1;
+        }
+
+        histogram
+    }
+
+    /// Error responses received from visited nodes.
+    pub fn errors(&self) -> &[(SocketAddr, ErrorSpecific)] {
+        &self.errors
+    }
+
+    /// Returns the local port of the ephemeral socket this query sends requests from, if any.
+    pub fn source_port(&self) -> Option<u16> {
+        self.source_port
+    }
+
+    /// Returns `true` if this query was stopped by [Self::cancel],
+    /// rather than done or timed out.
+    pub fn is_cancelled(&self) -> bool {
+        self.state == QueryState::Cancelled
+    }
+
+    /// Returns the lifecycle state of this query, as of its last [Self::tick].
+    pub fn state(&self) -> QueryState {
+        self.state
+    }
+
+    /// Returns how long each of this query's requests still inflight in the socket
+    /// has been waiting for a response, oldest first.
+    pub fn inflight_ages(&self, socket: &KrpcSocket) -> Vec<(u16, Duration)> {
+        let mut ages = self
+            .inflight_requests
+            .iter()
+            .filter_map(|tid| socket.request_age(*tid).map(|age| (*tid, age)))
+            .collect::<Vec<_>>();
+        ages.sort_by_key(|(_, age)| std::cmp::Reverse(*age));
+
+        ages
+    }
+
+    /// Time since this query was created.
+    pub fn elapsed(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.started_at)
+    }
+
+    /// Returns the events recorded since the last call, oldest first,
+    /// or nothing if [Self::with_trace] isn't enabled.
+    pub fn take_trace(&mut self) -> Vec<QueryEvent> {
+        self.trace.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Returns the progress metrics of this query.
+    pub fn stats(&self) -> QueryStats {
+        QueryStats {
+            visited: self.visited.len(),
+            inflight: self.inflight_visits.len(),
+            closest_known: self.closest.len() + self.closest_v6.len(),
+            responses: self.responses_count,
+            mismatched: self.mismatched,
+            rejected: self.rejected,
         }
+    }
+
+    /// Returns the k closest candidates still considered for visiting, closest first,
+    /// each paired with whether it was visited, for example to see why a query
+    /// isn't converging.
+    pub fn frontier(&self) -> Vec<(Node, bool)> {
+        let mut frontier = self
+            .live_closest()
+            .take(MAX_BUCKET_SIZE_K)
+            .chain(self.live_closest_v6().take(MAX_BUCKET_SIZE_K))
+            .collect::<Vec<_>>();
+        frontier.sort_by_key(|node| self.visit_order(node));
+
+        frontier
+            .into_iter()
+            .take(MAX_BUCKET_SIZE_K)
+            .map(|node| {
+                let visited =
+                    self.visited.contains(&node.address()) || self.visited_ids.contains(node.id());
 
-        best_addr
+                (node.clone(), visited)
+            })
+            .collect()
     }
 
     // === Public Methods ===
@@ -113,93 +810,2853 @@ impl IterativeQuery {
         self.visit_closest(socket);
     }
 
-    /// Add a candidate node to query on next tick if it is among the closest nodes.
-    pub fn add_candidate(&mut self, node: Node) {
-        // ready for a ipv6 routing table?
-        self.closest.add(node);
-    }
+    /// Stop this query, dropping its inflight requests from the socket,
+    /// so every subsequent [Self::tick] returns [TickResult::Cancelled].
+    pub fn cancel(&mut self, socket: &mut KrpcSocket) {
+        debug!(id=?self.target(), stats = ?self.stats(), "Query cancelled");
+
+        for tid in self.inflight_requests.drain() {
+            socket.cancel(tid);
+        }
+        self.inflight_visits.clear();
+        self.visited.clear();
+        self.peers_tx.clear();
 
-    /// Add a vote for this node's address.
-    pub fn add_address_vote(&mut self, address: SocketAddrV4) {
-        self.public_address_votes
-            .entry(address)
-            .and_modify(|counter| *counter += 1)
-            .or_insert(1);
+        self.state = QueryState::Cancelled;
     }
 
-    /// Visit explicitly given addresses, and add them to the visited set.
-    /// only used from the Rpc when calling bootstrapping nodes.
-    pub fn visit(&mut self, socket: &mut KrpcSocket, address: SocketAddrV4) {
-        let tid = socket.request(address, self.request.clone());
-        self.inflight_requests.push(tid);
+    /// Move this query to a new socket, for example after [rebinding](KrpcSocket::rebind)
+    /// to another network interface, and resume visiting the closest nodes through it.
+    ///
+    /// Transaction ids of the old socket mean nothing to the new one, so inflight
+    /// requests and pending retransmissions are dropped, and the nodes that didn't
+    /// answer them become candidates again. The closest nodes, and the visited nodes
+    /// that already answered, are kept.
+    ///
+    /// Requests are sent from the new socket's shared port, even if this query
+    /// used an [ephemeral port](Self::with_source_port) of the old socket.
+    pub fn rebind(&mut self, socket: &mut KrpcSocket) {
+        debug!(id=?self.target(), stats = ?self.stats(), "Query moved to a new socket");
 
-        let tid = socket.request(
-            address,
-            RequestSpecific {
-                requester_id: Id::random(),
-                request_type: RequestTypeSpecific::Ping,
-            },
-        );
-        self.inflight_requests.push(tid);
+        self.inflight_requests.clear();
 
-        self.visited.insert(address);
-    }
+        let unanswered = self
+            .inflight_visits
+            .drain(..)
+            .map(|(_, address)| address)
+            .chain(self.backing_off.drain(..).map(|(address, _)| address))
+            .collect::<Vec<_>>();
 
-    /// Return true if a response (by transaction_id) is expected by this query.
-    pub fn inflight(&self, tid: u16) -> bool {
-        self.inflight_requests.contains(&tid)
+        for address in unanswered {
+            self.visited.remove(&address);
+
+            if let Some(node) = self
+                .closest
+                .nodes()
+                .iter()
+                .chain(self.closest_v6.nodes())
+                .find(|node| node.address() == address)
+            {
+                self.visited_ids.remove(node.id());
+            }
+        }
+
+        self.source_port = None;
+        self.visit_closest(socket);
     }
 
-    /// Add a node that responded with a token as a probable storage node.
-    pub fn add_responding_node(&mut self, node: Node) {
-        self.responders.add(node)
+    /// Add nodes known to be close to the target, for example from a previous
+    /// session, as the first candidates to visit.
+    ///
+    /// Meant to be called before the first [Self::tick]. Seeds at bogon addresses
+    /// are skipped if [Self::with_bogon_filter] is enabled.
+    ///
+    /// Returns the number of seeds added.
+    pub fn add_seeds(&mut self, nodes: &[Node]) -> usize {
+        let before = self.closest.len() + self.closest_v6.len();
+
+        for node in nodes {
+            self.add_candidate(node.clone());
+        }
+
+        self.closest.len() + self.closest_v6.len() - before
     }
 
-    /// Store received response.
-    pub fn response(&mut self, from: SocketAddrV4, response: Response) {
-        let target = self.target();
+    /// Add a candidate node to query on next tick if it is among the closest nodes.
+    pub fn add_candidate(&mut self, node: Node) {
+        let address = node.address();
 
-        debug!(?target, ?response, ?from, "Query got response");
+        if self.is_own_address(address) {
+            self.record(QueryEvent::Ignored {
+                address,
+                reason: IgnoreReason::OwnAddress,
+            });
 
-        self.responses.push(response.to_owned());
+            return;
+        }
+        if *node.id() == self.request.requester_id {
+            // Listed with our own Id, possibly at an address we don't know is ours.
+            trace!(?address, "Ignoring candidate with our own Id");
+            self.record(QueryEvent::Ignored {
+                address,
+                reason: IgnoreReason::OwnId,
+            });
+
+            return;
+        }
+        if self.bogon_filter && is_bogon(&address) {
+            trace!(?address, "Ignoring candidate at a bogon address");
+            self.record(QueryEvent::Ignored {
+                address,
+                reason: IgnoreReason::Bogon,
+            });
+
+            return;
+        }
+
+        self.record(QueryEvent::Added {
+            address,
+            distance: self.target().distance(node.id()),
+        });
+
+        if address.is_ipv6() {
+            self.closest_v6.add(node);
+        } else {
+            self.closest.add(node);
+        }
     }
 
-    /// Query closest nodes for this query's target and message.
+    /// Handle a response to one of this query's requests (by transaction_id)
+    /// with this query's [QueryStrategy], and add the candidates it returns,
+    /// except this node itself.
     ///
-    /// Returns true if it is done.
-    pub fn tick(&mut self, socket: &mut KrpcSocket) -> bool {
-        // Visit closest nodes
-        self.visit_closest(socket);
+    /// Returns `false`, without adding any candidates, if the response doesn't
+    /// [answer](crate::common::ResponseSpecific::answers) this query's request type, in which case
+    /// the node is considered unresponsive for the rest of the query.
+    pub fn add_response(&mut self, tid: u16, node: &Node, message: &Message) -> bool {
+        if let MessageType::Response(response) = &message.message_type {
+            let switched =
+                self.switched_to_find_node() && matches!(response, ResponseSpecific::FindNode(_));
 
-        // If no more inflight_requests are inflight in the socket (not timed out),
-        // then the query is done.
-        let done = !self
-            .inflight_requests
-            .iter()
-            .any(|&tid| socket.inflight(&tid));
+            if !response.answers(&self.request.request_type) && !switched {
+                self.mismatched += 1;
+                self.reject_response(tid);
 
-        if done {
-            debug!(id=?self.target(), closest = ?self.closest.len(), visited = ?self.visited.len(), responders = ?self.responders.len(), "Done query");
-        };
+                return false;
+            }
+        }
+
+        if !self.strategy.accept(node, message) {
+            trace!(?node, "Strategy rejected response");
+
+            self.rejected += 1;
+            self.reject_response(tid);
+
+            return false;
+        }
 
-        done
+        self.versions.insert(node.address(), node.version());
+        self.learn(node);
+        self.responding_nodes.insert(
+            node.address(),
+            match self.responding_nodes.get(&node.address()) {
+                Some(previous) if previous.id() == node.id() => {
+                    node.clone().refreshed_from(previous)
+                }
+                _ => node.clone(),
+            },
+        );
+
+        let requester_id = self.request.requester_id;
+        let mut candidates = self.strategy.on_response(node, message);
+        candidates.retain(|candidate| *candidate.id() != requester_id);
+
+        self.add_candidates(tid, &candidates);
+
+        true
     }
 
-    // === Private Methods ===
+    /// Count a response to one of this query's requests (by transaction_id),
+    /// and add the closer nodes it contains as candidates.
+    pub fn add_candidates(&mut self, tid: u16, nodes: &[Node]) {
+        self.add_candidates_batch(&[(tid, nodes.to_vec())]);
+    }
 
-    /// Visit the closest candidates and remove them as candidates
-    fn visit_closest(&mut self, socket: &mut KrpcSocket) {
-        let to_visit = self
-            .closest
-            .nodes()
-            .iter()
-            .take(MAX_BUCKET_SIZE_K)
-            .filter(|node| !self.visited.contains(&node.address()))
-            .map(|node| node.address())
-            .collect::<Vec<_>>();
+    /// Same as [Self::add_candidates] for many responses at once,
+    /// for example all the responses received between two ticks.
+    pub fn add_candidates_batch(&mut self, responses: &[(u16, Vec<Node>)]) {
+        let _span = self.span.clone().entered();
+
+        for (tid, nodes) in responses {
+            trace!(tid, candidates = nodes.len(), "Query got closer nodes");
 
-        for address in to_visit {
-            self.visit(socket, address);
+            self.responses_count += 1;
+            self.settle_visit(*tid);
+            self.record(QueryEvent::Responded {
+                tid: *tid,
+                closer: nodes.len(),
+            });
+
+            for node in nodes {
+                self.add_candidate(node.clone());
+            }
+        }
+    }
+
+    /// Handle an error response to one of this query's requests, so we stop waiting for it,
+    /// and record the error against the responding node.
+    pub fn handle_error(&mut self, tid: u16, from: SocketAddr, error: ErrorSpecific) {
+        debug!(target = ?self.target(), ?from, ?error, "Query got error response");
+
+        self.inflight_requests.remove(&tid);
+        self.settle_visit(tid);
+
+        self.errors.push((from, error));
+    }
+
+    /// Merge the bloom filters of a scrape response into this query's filters.
+    ///
+    /// Returns `false` if the response had no bloom filters.
+    pub fn add_scrape(
+        &mut self,
+        bf_seeders: Option<&BloomFilter>,
+        bf_peers: Option<&BloomFilter>,
+    ) -> bool {
+        if let Some(filter) = bf_seeders {
+            self.bf_seeders.merge(filter);
+        }
+        if let Some(filter) = bf_peers {
+            self.bf_peers.merge(filter);
+        }
+
+        bf_seeders.is_some() || bf_peers.is_some()
+    }
+
+    /// Visit explicitly given addresses, and add them to the visited set.
+    /// only used from the Rpc when calling bootstrapping nodes.
+    ///
+    /// Returns [VisitOutcome::Sent] unless the socket refused to send the request.
+    pub fn visit(&mut self, socket: &mut KrpcSocket, address: SocketAddr) -> VisitOutcome {
+        let outcome = self.send_visit(socket, address);
+
+        self.record(match outcome {
+            VisitOutcome::Sent(tid) => QueryEvent::Visited { address, tid },
+            outcome => QueryEvent::Skipped { address, outcome },
+        });
+
+        outcome
+    }
+
+    fn send_visit(&mut self, socket: &mut KrpcSocket, address: SocketAddr) -> VisitOutcome {
+        let _span = self.span.clone().entered();
+
+        if self.is_own_address(address) {
+            return VisitOutcome::SkippedOwnAddress;
+        }
+        if self
+            .inflight_visits
+            .iter()
+            .any(|(_, inflight)| *inflight == address)
+        {
+            // Listed more than once, for example in both the bootstrapping and extra nodes.
+            return VisitOutcome::SkippedInflight;
+        }
+        if self.requests_exhausted() {
+            return VisitOutcome::RequestsExhausted;
+        }
+
+        let request = if self.switched_to_find_node() {
+            RequestSpecific {
+                request_type: RequestTypeSpecific::FindNode(FindNodeRequestArguments {
+                    target: self.target(),
+                }),
+                ..self.request.clone()
+            }
+        } else {
+            self.request.clone()
+        };
+
+        let tid = match socket.request_via(self.source_port, address, request) {
+            Ok(tid) => tid,
+            Err(error) => {
+                // Not marked as visited, so it can be visited again on a later tick.
+                debug!(?error, ?address, "Deferring visit");
+
+                return match error {
+                    SocketError::RateLimited => VisitOutcome::RateLimited,
+                    error => VisitOutcome::Error(error),
+                };
+            }
+        };
+        trace!(tid, ?address, "Query sent request");
+
+        self.inflight_requests.insert(tid);
+        self.inflight_visits.push((tid, address));
+        self.visited.insert(address);
+        self.requests += 1;
+
+        if self.state == QueryState::New {
+            self.state = QueryState::Running;
+        }
+
+        if self.requests_exhausted() {
+            return VisitOutcome::Sent(tid);
+        }
+
+        if let Ok(tid) = socket.request_via(
+            self.source_port,
+            address,
+            RequestSpecific::ping(Id::random()),
+        ) {
+            self.inflight_requests.insert(tid);
+            self.requests += 1;
+        }
+
+        VisitOutcome::Sent(tid)
+    }
+
+    /// Returns the transaction ids of this query's requests still waiting for a response.
+    pub fn inflight_requests(&self) -> impl Iterator<Item = u16> + '_ {
+        self.inflight_requests.iter().copied()
+    }
+
+    /// Return true if a response (by transaction_id) is expected by this query.
+    pub fn inflight(&self, tid: u16) -> bool {
+        self.inflight_requests.contains(&tid)
+    }
+
+    /// Add a node that responded with a token as a probable storage node.
+    ///
+    /// Nodes with an empty or longer than [MAX_TOKEN_LENGTH] token are ignored.
+    pub fn add_responding_node(&mut self, node: Node) {
+        if let Some(token) = node.token() {
+            if token.is_empty() || token.len() > MAX_TOKEN_LENGTH {
+                debug!(
+                    ?node,
+                    len = token.len(),
+                    "Ignoring node with an invalid token"
+                );
+                return;
+            }
+
+            self.tokens.insert(node.address(), token);
+        }
+
+        self.responders.add(node)
+    }
+
+    /// Returns this node with the most recent token received from its address,
+    /// if any, so data can be stored at it.
+    pub fn attach_token(&self, node: &Node) -> Node {
+        match self.tokens.get(&node.address()) {
+            Some(token) => node.clone().with_token(Some(token.clone())),
+            None => node.clone(),
+        }
+    }
+
+    /// Add info hashes from a `sample_infohashes` response to the union of samples,
+    /// and return the ones not seen before.
+    pub fn add_samples(&mut self, samples: &[Id]) -> Vec<Id> {
+        let new_samples = samples
+            .iter()
+            .filter(|sample| self.samples.insert(**sample))
+            .copied()
+            .collect::<Vec<_>>();
+
+        self.samples_order.extend_from_slice(&new_samples);
+
+        new_samples
+    }
+
+    /// Never visit this address, for example a node that asked not to be
+    /// sampled again before its `sample_infohashes` interval elapsed.
+    ///
+    /// Unlike [Self::with_own_address], the address is still visited if it is
+    /// explicitly passed to [Self::visit].
+    pub fn skip(&mut self, address: SocketAddr) {
+        self.visited.insert(address);
+    }
+
+    /// Send the peers found so far on `tx`, then every newly found peer as soon as
+    /// it is discovered, instead of waiting for the query to be done.
+    ///
+    /// The sender is dropped, closing the channel, once the query is done.
+    pub fn stream_peers(&mut self, tx: Sender<SocketAddr>) {
+        if self.peers_order.iter().all(|peer| tx.send(*peer).is_ok()) {
+            self.peers_tx.push(tx);
+        }
+    }
+
+    /// Add peers from a `get_peers` response's values.
+    pub fn add_peers(&mut self, peers: &[SocketAddrV4]) {
+        if !peers.is_empty() {
+            self.peer_responses += 1;
+        }
+
+        for peer in peers {
+            if self.max_peers_reached() {
+                break;
+            }
+
+            let peer = SocketAddr::from(*peer);
+
+            if self.peers.insert(peer) {
+                self.peers_order.push(peer);
+
+                // Stop streaming to receivers that are gone.
+                self.peers_tx.retain(|tx| tx.send(peer).is_ok());
+            }
+        }
+    }
+
+    /// Store received response.
+    pub fn response(&mut self, from: SocketAddr, response: Response) {
+        let target = self.target();
+
+        debug!(?target, ?response, ?from, "Query got response");
+
+        self.responses.push(response.to_owned());
+    }
+
+    /// Query closest nodes for this query's target and message.
+    ///
+    /// Returns [TickResult::Done] if there are no more inflight requests,
+    /// [TickResult::TimedOut] if the query's deadline has passed,
+    /// [TickResult::Cancelled] if the query was cancelled, or
+    /// [TickResult::IterationsExhausted] if it is done after its maximum iterations,
+    /// [TickResult::RequestsExhausted] if it is done after its maximum requests,
+    /// [TickResult::MaxPeersReached] if it found its maximum peers, or
+    /// [TickResult::CloseEnough] if a node within its closeness floor responded.
+    ///
+    /// Updates the query's [state](Self::state) accordingly.
+    pub fn tick(&mut self, socket: &mut KrpcSocket) -> TickResult {
+        let result = self.tick_inner(socket);
+
+        self.state = match result {
+            TickResult::InProgress => QueryState::Running,
+            TickResult::TimedOut => QueryState::TimedOut,
+            TickResult::Cancelled => QueryState::Cancelled,
+            _ => QueryState::Converged,
+        };
+
+        result
+    }
+
+    // === Private Methods ===
+
+    fn tick_inner(&mut self, socket: &mut KrpcSocket) -> TickResult {
+        let _span = self.span.clone().entered();
+
+        if self.state == QueryState::Cancelled {
+            return TickResult::Cancelled;
+        }
+
+        if self.elapsed() >= self.deadline {
+            debug!(id=?self.target(), deadline = ?self.deadline, stats = ?self.stats(), responders = ?self.responders.len(), inflight_ages = ?self.inflight_ages(socket), "Query timed out");
+
+            self.abandon_inflight();
+
+            if let Some(on_timeout) = self.on_timeout.take() {
+                on_timeout(self.closest.nodes(), &self.peers_order);
+            }
+
+            return TickResult::TimedOut;
+        }
+
+        if self.max_peers_reached() {
+            self.abandon_inflight();
+
+            debug!(id=?self.target(), peers = self.peers_order.len(), stats = ?self.stats(), "Query found its maximum peers");
+
+            return TickResult::MaxPeersReached;
+        }
+
+        if self.close_enough() {
+            self.abandon_inflight();
+
+            debug!(id=?self.target(), stats = ?self.stats(), responders = ?self.responders.len(), "Query found a node within its closeness floor");
+
+            return TickResult::CloseEnough;
+        }
+
+        // Visit closest nodes
+        self.visit_closest(socket);
+
+        if self.converged() {
+            self.abandon_inflight();
+
+            debug!(id=?self.target(), stats = ?self.stats(), responders = ?self.responders.len(), "Query converged");
+
+            return TickResult::Done;
+        }
+
+        // If no more inflight_requests are inflight in the socket (not timed out),
+        // then the query is done.
+        let done = self.backing_off.is_empty()
+            && !self
+                .inflight_requests
+                .iter()
+                .any(|&tid| socket.inflight(&tid));
+
+        if done {
+            self.abandon_inflight();
+
+            if self.iterations_exhausted() {
+                debug!(id=?self.target(), iterations = self.iterations, stats = ?self.stats(), responders = ?self.responders.len(), "Query exhausted its iterations");
+
+                return TickResult::IterationsExhausted;
+            }
+
+            if self.requests_exhausted() {
+                debug!(id=?self.target(), requests = self.requests, stats = ?self.stats(), responders = ?self.responders.len(), "Query exhausted its requests");
+
+                return TickResult::RequestsExhausted;
+            }
+
+            if self.requests == 0 {
+                debug!(id=?self.target(), "Query had no candidates to visit");
+
+                return TickResult::NoCandidates;
+            }
+
+            debug!(id=?self.target(), stats = ?self.stats(), responders = ?self.responders.len(), "Done query");
+
+            TickResult::Done
+        } else {
+            TickResult::InProgress
+        }
+    }
+
+    /// Forget the requests still inflight, pending retransmissions and peer streams,
+    /// once this query reached a terminal state.
+    fn abandon_inflight(&mut self) {
+        self.inflight_requests.clear();
+        self.inflight_visits.clear();
+        self.backing_off.clear();
+        self.peers_tx.clear();
+    }
+
+    /// Add a responder to the [shared routing table](Self::with_routing_table), if any.
+    fn learn(&self, node: &Node) {
+        let Some(table) = &self.routing_table else {
+            return;
+        };
+
+        if self.bogon_filter && is_bogon(&node.address()) {
+            return;
+        }
+
+        let mut table = table.lock().expect("poisoned lock");
+
+        if table.oldest_if_full(node).is_none() {
+            table.add(node.clone());
+        }
+    }
+
+    fn is_own_address(&self, address: SocketAddr) -> bool {
+        self.own_addresses.iter().any(|own| {
+            own.port() == address.port()
+                && (own.ip() == address.ip()
+                    || (own.ip().is_unspecified()
+                        && own.is_ipv4() == address.is_ipv4()
+                        && address.ip().is_loopback()))
+        })
+    }
+
+    fn record(&mut self, event: QueryEvent) {
+        if let Some(trace) = &mut self.trace {
+            trace.push(event);
+        }
+    }
+
+    fn iterations_exhausted(&self) -> bool {
+        self.max_iterations
+            .is_some_and(|max| self.iterations >= max)
+    }
+
+    fn requests_exhausted(&self) -> bool {
+        self.max_requests.is_some_and(|max| self.requests >= max)
+    }
+
+    /// Stop waiting for a visit that got a response (by transaction_id).
+    fn settle_visit(&mut self, tid: u16) {
+        if let Some(index) = self
+            .inflight_visits
+            .iter()
+            .position(|(inflight, _)| *inflight == tid)
+        {
+            let (_, address) = self.inflight_visits.remove(index);
+            self.responded.insert(address);
+        }
+    }
+
+    /// Stop waiting for a response that doesn't match this query's request type,
+    /// or is rejected by its strategy, and never visit its sender again.
+    fn reject_response(&mut self, tid: u16) {
+        self.inflight_requests.remove(&tid);
+
+        if let Some(index) = self
+            .inflight_visits
+            .iter()
+            .position(|(inflight, _)| *inflight == tid)
+        {
+            let (_, address) = self.inflight_visits.remove(index);
+            debug!(?address, request = ?self.request.request_type, "Rejected response");
+
+            self.unresponsive.insert(address);
+        }
+    }
+
+    /// Returns true if all of the closest `k` known nodes of each address family
+    /// (or all of them if fewer) have responded, so no closer nodes are left to discover.
+    fn converged(&self) -> bool {
+        let mut frontier = self
+            .live_closest()
+            .take(MAX_BUCKET_SIZE_K)
+            .chain(self.live_closest_v6().take(MAX_BUCKET_SIZE_K))
+            .peekable();
+
+        frontier.peek().is_some() && frontier.all(|node| self.responded.contains(&node.address()))
+    }
+
+    /// Returns true if the closest node that responded, of either address family,
+    /// shares at least [Self::with_closeness_floor] leading bits with the target.
+    fn close_enough(&self) -> bool {
+        let Some(floor) = self.closeness_floor else {
+            return false;
+        };
+
+        self.closest
+            .nodes()
+            .iter()
+            .chain(self.closest_v6.nodes())
+            .filter(|node| self.responded.contains(&node.address()))
+            .any(|node| self.target().xor(node.id()).leading_zeros() >= floor)
+    }
+
+    /// Closest Ipv4 nodes, skipping the unresponsive ones.
+    fn live_closest(&self) -> impl Iterator<Item = &Node> {
+        self.closest
+            .nodes()
+            .iter()
+            .filter(|node| !self.unresponsive.contains(&node.address()))
+    }
+
+    /// Orders candidates of both address families like [ClosestNodes] does,
+    /// secure nodes first, then by distance to the target.
+    fn visit_order(&self, node: &Node) -> (bool, Id) {
+        (!node.is_secure(), self.target().xor(node.id()))
+    }
+
+    /// Closest Ipv6 nodes, skipping the unresponsive ones.
+    fn live_closest_v6(&self) -> impl Iterator<Item = &Node> {
+        self.closest_v6
+            .nodes()
+            .iter()
+            .filter(|node| !self.unresponsive.contains(&node.address()))
+    }
+
+    /// Visit the closest unvisited candidates, keeping at most `alpha` visits inflight,
+    /// and retransmit timed out visits up to `max_retries` times.
+    ///
+    /// Counts an iteration if any request was sent, and sends none
+    /// once the maximum iterations or requests are exhausted.
+    fn visit_closest(&mut self, socket: &mut KrpcSocket) {
+        if self.iterations_exhausted() || self.requests_exhausted() {
+            self.backing_off.clear();
+
+            return;
+        }
+
+        let sent = self.inflight_requests.len();
+        self.visit_closest_nodes(socket);
+
+        if self.inflight_requests.len() > sent {
+            self.iterations += 1;
+        }
+    }
+
+    fn visit_closest_nodes(&mut self, socket: &mut KrpcSocket) {
+        // Visits that got a response are already removed, the rest timed out.
+        let mut timed_out = Vec::new();
+        self.inflight_visits.retain(|(tid, address)| {
+            if socket.inflight(tid) {
+                true
+            } else {
+                timed_out.push(*address);
+                false
+            }
+        });
+
+        let now = self.clock.now();
+        let timeout = socket.current_timeout();
+
+        for address in timed_out {
+            let retries = self.retries.get(&address).copied().unwrap_or_default();
+
+            if retries >= self.max_retries {
+                trace!(?address, retries, "Giving up on unresponsive node");
+                self.unresponsive.insert(address);
+                continue;
+            }
+
+            // Back off exponentially, so retransmissions are 1, 2, 4... timeouts apart.
+            let backoff = timeout.saturating_mul(2_u32.saturating_pow(retries.into()) - 1);
+
+            self.backing_off
+                .push((address, now + self.rng.jitter(backoff, self.jitter)));
+        }
+
+        let mut available = self.alpha.saturating_sub(self.inflight_visits.len());
+        let mut index = 0;
+
+        while available > 0 && index < self.backing_off.len() {
+            let (address, retransmit_at) = self.backing_off[index];
+
+            if retransmit_at > now {
+                index += 1;
+                continue;
+            }
+
+            self.backing_off.remove(index);
+
+            let retries = self.retries.get(&address).copied().unwrap_or_default();
+            trace!(?address, retries, "Retransmitting timed out request");
+
+            match self.visit(socket, address) {
+                VisitOutcome::Sent(_) => {}
+                VisitOutcome::RateLimited => {
+                    self.backing_off.insert(index, (address, retransmit_at));
+                    break;
+                }
+                VisitOutcome::Error(SocketError::NodeBusy) => {
+                    // Retransmit once other queries' requests to it are done.
+                    self.backing_off.insert(index, (address, retransmit_at));
+                    index += 1;
+                    continue;
+                }
+                _ => break,
+            }
+
+            self.retries.insert(address, retries + 1);
+            available -= 1;
+        }
+
+        if available == 0 {
+            return;
+        }
+
+        let mut to_visit = self
+            .live_closest()
+            .take(MAX_BUCKET_SIZE_K)
+            .chain(self.live_closest_v6().take(MAX_BUCKET_SIZE_K))
+            .filter(|node| {
+                !self.visited.contains(&node.address()) && !self.visited_ids.contains(node.id())
+            })
+            .collect::<Vec<_>>();
+        to_visit.sort_by_key(|node| self.visit_order(node));
+        let mut to_visit = to_visit
+            .into_iter()
+            .map(|node| (*node.id(), node.address(), node.rtt().is_some()))
+            .collect::<Vec<_>>();
+
+        if self.prefer_verified {
+            // Stable, so nodes are still ordered by distance within each group.
+            to_visit.sort_by_key(|(_, _, verified)| !verified);
+        }
+
+        for (id, address, _) in to_visit {
+            if available == 0 {
+                break;
+            }
+
+            let outcome = self.visit_candidate(socket, id, address);
+            trace!(?address, ?outcome, "Visited candidate");
+
+            match outcome {
+                VisitOutcome::Sent(_) => available -= 1,
+                VisitOutcome::SkippedVisited
+                | VisitOutcome::SkippedInflight
+                | VisitOutcome::SkippedIpv6
+                | VisitOutcome::SkippedOwnAddress
+                | VisitOutcome::Error(SocketError::Blacklisted) => {}
+                // Not visited, so it is tried again on the next tick, visit other nodes meanwhile.
+                VisitOutcome::Error(SocketError::NodeBusy) => {}
+                // Try again on the next tick.
+                VisitOutcome::RateLimited | VisitOutcome::Error(_) => break,
+                VisitOutcome::RequestsExhausted => break,
+            }
+        }
+    }
+
+    /// Visit a candidate from the closest nodes, unless it can't or shouldn't be visited.
+    pub(crate) fn visit_candidate(
+        &mut self,
+        socket: &mut KrpcSocket,
+        id: Id,
+        address: SocketAddr,
+    ) -> VisitOutcome {
+        let skipped = |query: &mut Self, outcome| {
+            query.record(QueryEvent::Skipped { address, outcome });
+
+            outcome
+        };
+
+        if !socket.can_send_to(&address, self.source_port) {
+            // No Ipv6 socket, or sending from an ephemeral Ipv4 one.
+            self.visited.insert(address);
+            self.unresponsive.insert(address);
+
+            return skipped(self, VisitOutcome::SkippedIpv6);
+        }
+
+        if self.visited.contains(&address) || self.visited_ids.contains(&id) {
+            // Possibly the same Id listed at more than one address.
+            return skipped(self, VisitOutcome::SkippedVisited);
+        }
+        if socket.is_blacklisted(&address) {
+            self.visited.insert(address);
+
+            return skipped(self, VisitOutcome::Error(SocketError::Blacklisted));
+        }
+
+        let outcome = self.visit(socket, address);
+        if outcome.is_sent() {
+            self.visited_ids.insert(id);
+        }
+
+        outcome
+    }
+}
+
+impl Debug for IterativeQuery {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IterativeQuery")
+            .field("request", &self.request)
+            .field("stats", &self.stats())
+            .field("frontier", &self.frontier())
+            .field("responders", &self.responders.len())
+            .field("inflight_visits", &self.inflight_visits)
+            .field("backing_off", &self.backing_off)
+            .field("iterations", &self.iterations)
+            .field("requests", &self.requests)
+            .field("peers", &self.peers_order.len())
+            .field("samples", &self.samples().len())
+            .field("errors", &self.errors)
+            .field("elapsed", &self.elapsed())
+            .field("deadline", &self.deadline)
+            .field("state", &self.state)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Clone for IterativeQuery {
+    /// Snapshot this query, for example to run it forward with different policies.
+    ///
+    /// Inflight requests are dropped from the clone, since their transaction ids
+    /// belong to the socket the original query sent them through, and responses to
+    /// them are only delivered to the original. Their nodes are still considered
+    /// visited by the clone, which sends its own requests once ticked.
+    ///
+    /// The clone doesn't stream peers to the original's [Self::stream_peers] channels,
+    /// but writes its responders to the same [shared routing table](Self::with_routing_table),
+    /// and doesn't call the original's [Self::with_on_timeout] callback.
+    fn clone(&self) -> Self {
+        Self {
+            request: self.request.clone(),
+            strategy: self.strategy.clone_box(),
+            closest: self.closest.clone(),
+            closest_v6: self.closest_v6.clone(),
+            responders: self.responders.clone(),
+            tokens: self.tokens.clone(),
+            inflight_requests: HashSet::new(),
+            inflight_visits: Vec::new(),
+            alpha: self.alpha,
+            prefer_verified: self.prefer_verified,
+            retries: self.retries.clone(),
+            max_retries: self.max_retries,
+            backing_off: self.backing_off.clone(),
+            jitter: self.jitter,
+            rng: self.rng.clone(),
+            iterations: self.iterations,
+            max_iterations: self.max_iterations,
+            requests: self.requests,
+            max_requests: self.max_requests,
+            closeness_floor: self.closeness_floor,
+            visited: self.visited.clone(),
+            responded: self.responded.clone(),
+            responding_nodes: self.responding_nodes.clone(),
+            unresponsive: self.unresponsive.clone(),
+            own_addresses: self.own_addresses.clone(),
+            source_port: self.source_port,
+            bogon_filter: self.bogon_filter,
+            visited_ids: self.visited_ids.clone(),
+            responses: self.responses.clone(),
+            responses_count: self.responses_count,
+            mismatched: self.mismatched,
+            rejected: self.rejected,
+            versions: self.versions.clone(),
+            errors: self.errors.clone(),
+            peers: self.peers.clone(),
+            peers_order: self.peers_order.clone(),
+            peer_responses: self.peer_responses,
+            max_peers: self.max_peers,
+            find_node_after: self.find_node_after,
+            peers_tx: Vec::new(),
+            samples: self.samples.clone(),
+            samples_order: self.samples_order.clone(),
+            bf_seeders: self.bf_seeders.clone(),
+            bf_peers: self.bf_peers.clone(),
+            started_at: self.started_at,
+            deadline: self.deadline,
+            state: self.state,
+            clock: self.clock.clone(),
+            span: self.span.clone(),
+            trace: self.trace.clone(),
+            routing_table: self.routing_table.clone(),
+            on_timeout: None,
+        }
+    }
+}
+
+/// Set of the addresses visited by an [IterativeQuery].
+#[derive(Debug, Clone)]
+enum VisitedSet {
+    Addresses(HashSet<SocketAddr>),
+    /// Ipv4 addresses packed as `ip << 16 | port`.
+    Compact {
+        v4: HashSet<u64>,
+        v6: HashSet<SocketAddr>,
+    },
+}
+
+impl Default for VisitedSet {
+    fn default() -> Self {
+        Self::Addresses(HashSet::new())
+    }
+}
+
+impl VisitedSet {
+    fn compact(visited: VisitedSet) -> Self {
+        match visited {
+            Self::Addresses(addresses) => {
+                let mut compact = Self::Compact {
+                    v4: HashSet::new(),
+                    v6: HashSet::new(),
+                };
+                for address in addresses {
+                    compact.insert(address);
+                }
+
+                compact
+            }
+            compact => compact,
+        }
+    }
+
+    fn insert(&mut self, address: SocketAddr) -> bool {
+        match (self, address) {
+            (Self::Addresses(addresses), _) => addresses.insert(address),
+            (Self::Compact { v4, .. }, SocketAddr::V4(address)) => v4.insert(pack(address)),
+            (Self::Compact { v6, .. }, SocketAddr::V6(_)) => v6.insert(address),
+        }
+    }
+
+    fn contains(&self, address: &SocketAddr) -> bool {
+        match (self, address) {
+            (Self::Addresses(addresses), _) => addresses.contains(address),
+            (Self::Compact { v4, .. }, SocketAddr::V4(address)) => v4.contains(&pack(*address)),
+            (Self::Compact { v6, .. }, SocketAddr::V6(_)) => v6.contains(address),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Self::Addresses(addresses) => addresses.len(),
+            Self::Compact { v4, v6 } => v4.len() + v6.len(),
+        }
+    }
+
+    #[cfg(test)]
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn remove(&mut self, address: &SocketAddr) -> bool {
+        match (self, address) {
+            (Self::Addresses(addresses), _) => addresses.remove(address),
+            (Self::Compact { v4, .. }, SocketAddr::V4(address)) => v4.remove(&pack(*address)),
+            (Self::Compact { v6, .. }, SocketAddr::V6(_)) => v6.remove(address),
+        }
+    }
+
+    fn clear(&mut self) {
+        match self {
+            Self::Addresses(addresses) => addresses.clear(),
+            Self::Compact { v4, v6 } => {
+                v4.clear();
+                v6.clear();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl PartialEq<HashSet<SocketAddr>> for VisitedSet {
+    fn eq(&self, other: &HashSet<SocketAddr>) -> bool {
+        self.len() == other.len() && other.iter().all(|address| self.contains(address))
+    }
+}
+
+fn pack(address: SocketAddrV4) -> u64 {
+    ((address.ip().to_bits() as u64) << 16) | address.port() as u64
+}
+
+fn format_version(version: Option<[u8; 4]>) -> String {
+    match version {
+        Some([a, b, major, minor]) if a.is_ascii_alphanumeric() && b.is_ascii_alphanumeric() => {
+            format!("{}{}{major:02x}{minor:02x}", a as char, b as char)
+        }
+        _ => "unknown".to_string(),
+    }
+}
+
+/// The result of trying to visit a node in a query, see [QueryEvent::Skipped].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VisitOutcome {
+    /// The request was sent with this transaction_id.
+    Sent(u16),
+    /// The address or the Id was already visited.
+    SkippedVisited,
+    /// A visit to this address is still waiting for a response.
+    SkippedInflight,
+    /// Ipv6 nodes can't be visited without an Ipv6 socket,
+    /// see [DhtBuilder::bind_address_v6](crate::DhtBuilder::bind_address_v6).
+    SkippedIpv6,
+    /// The address is one of this node's own addresses.
+    SkippedOwnAddress,
+    /// Sending the request would exceed the socket's requests per second,
+    /// the node can be visited on a later tick.
+    RateLimited,
+    /// The query already sent its
+    /// [maximum requests](crate::DhtBuilder::max_requests_per_query).
+    RequestsExhausted,
+    /// The socket refused to send the request.
+    Error(SocketError),
+}
+
+impl VisitOutcome {
+    /// Returns `true` if the request was sent.
+    pub fn is_sent(&self) -> bool {
+        matches!(self, VisitOutcome::Sent(_))
+    }
+}
+
+/// A decision of a query, recorded if [DhtBuilder::trace_queries](crate::DhtBuilder::trace_queries)
+/// is enabled, see [QueryInfo::trace](super::QueryInfo::trace).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryEvent {
+    /// A candidate was added, at this [Id::distance] from the target.
+    ///
+    /// It may still be dropped if it isn't among the closest nodes.
+    Added {
+        /// The candidate's address.
+        address: SocketAddr,
+        /// The candidate's distance from the target.
+        distance: u8,
+    },
+    /// A candidate was ignored before being added.
+    Ignored {
+        /// The candidate's address.
+        address: SocketAddr,
+        /// Why it was ignored.
+        reason: IgnoreReason,
+    },
+    /// A request was sent to this address.
+    Visited {
+        /// The visited node's address.
+        address: SocketAddr,
+        /// The request's transaction_id.
+        tid: u16,
+    },
+    /// A node wasn't visited, or not yet.
+    Skipped {
+        /// The skipped node's address.
+        address: SocketAddr,
+        /// Why it wasn't visited.
+        outcome: VisitOutcome,
+    },
+    /// A response was received with this many closer nodes.
+    Responded {
+        /// The transaction_id of the request it responds to.
+        tid: u16,
+        /// The number of closer nodes in the response.
+        closer: usize,
+    },
+}
+
+/// Why a candidate was ignored by a query, see [QueryEvent::Ignored].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IgnoreReason {
+    /// The address is one of this node's own addresses.
+    OwnAddress,
+    /// The node is listed with this node's own Id.
+    OwnId,
+    /// The address is private, loopback or otherwise reserved,
+    /// see [DhtBuilder::allow_private_addresses](crate::DhtBuilder::allow_private_addresses).
+    Bogon,
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::{FindNodeResponseArguments, MessageType, MockClock, MockRng};
+
+    use super::*;
+
+    fn find_node_query(deadline: Duration) -> IterativeQuery {
+        let target = Id::random();
+
+        IterativeQuery::new(
+            Id::random(),
+            target,
+            GetRequestSpecific::FindNode(FindNodeRequestArguments { target }),
+            deadline,
+        )
+    }
+
+    #[test]
+    fn done_without_candidates() {
+        let mut socket = KrpcSocket::client().unwrap();
+
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+        query.start(&mut socket);
+        assert_eq!(query.tick(&mut socket), TickResult::NoCandidates);
+
+        // Candidates that can't be visited don't count either.
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+        query.add_candidate(Node::new(
+            Id::random(),
+            "[2001:4860::1]:6881".parse().unwrap(),
+        ));
+        query.start(&mut socket);
+        assert_eq!(query.tick(&mut socket), TickResult::NoCandidates);
+    }
+
+    #[test]
+    fn timed_out() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(Duration::ZERO);
+
+        query.visit(&mut socket, SocketAddr::from(([127, 0, 0, 1], 1)));
+        assert_eq!(query.tick(&mut socket), TickResult::TimedOut);
+        assert!(query.inflight_requests.is_empty());
+        assert_eq!(query.stats().inflight, 0);
+        assert!(query.backing_off.is_empty());
+    }
+
+    #[test]
+    fn deadline() {
+        let clock = MockClock::new();
+        let mut socket = KrpcSocket::client()
+            .unwrap()
+            .with_clock(Arc::new(clock.clone()));
+        let mut query =
+            find_node_query(Duration::from_secs(10)).with_clock(Arc::new(clock.clone()));
+
+        query.visit(&mut socket, SocketAddr::from(([127, 0, 0, 1], 1)));
+        assert_eq!(query.tick(&mut socket), TickResult::InProgress);
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(query.tick(&mut socket), TickResult::TimedOut);
+    }
+
+    #[test]
+    fn on_timeout() {
+        let clock = MockClock::new();
+        let mut socket = KrpcSocket::client()
+            .unwrap()
+            .with_clock(Arc::new(clock.clone()));
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut query = find_node_query(Duration::from_secs(10))
+            .with_clock(Arc::new(clock.clone()))
+            .with_on_timeout(move |closest, peers| {
+                tx.send((closest.len(), peers.to_vec())).unwrap();
+            });
+
+        let peer = SocketAddrV4::new([1, 1, 1, 1].into(), 6881);
+        query.add_candidate(Node::unique(1));
+        query.add_peers(&[peer]);
+
+        assert_eq!(query.tick(&mut socket), TickResult::InProgress);
+        assert!(rx.try_recv().is_err());
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(query.tick(&mut socket), TickResult::TimedOut);
+        assert_eq!(rx.try_recv().unwrap(), (1, vec![peer.into()]));
+
+        // Only called once.
+        assert_eq!(query.tick(&mut socket), TickResult::TimedOut);
+        assert!(rx.try_recv().is_err());
+
+        // Not called when the query converges before its deadline.
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE).with_on_timeout(move |_, _| {
+            tx.send(()).unwrap();
+        });
+        query.start(&mut socket);
+        assert_eq!(query.tick(&mut socket), TickResult::NoCandidates);
+        assert_eq!(query.state(), QueryState::Converged);
+        drop(query);
+        assert!(rx.recv().is_err());
+
+        // Nor when it is cancelled, even after its deadline.
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut query = find_node_query(Duration::from_secs(10))
+            .with_clock(Arc::new(clock.clone()))
+            .with_on_timeout(move |_, _| {
+                tx.send(()).unwrap();
+            });
+        query.add_candidate(Node::unique(1));
+        assert_eq!(query.tick(&mut socket), TickResult::InProgress);
+        query.cancel(&mut socket);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(query.tick(&mut socket), TickResult::Cancelled);
+        drop(query);
+        assert!(rx.recv().is_err());
+    }
+
+    #[test]
+    fn rebind() {
+        let mut old = KrpcSocket::client().unwrap();
+        let mut new = KrpcSocket::client().unwrap();
+        // So the transaction ids of both sockets don't overlap.
+        for _ in 0..10 {
+            new.request(old.local_addr().into(), RequestSpecific::ping(Id::random()))
+                .unwrap();
+        }
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE).with_alpha(2);
+
+        for i in 1..=4 {
+            query.add_candidate(Node::unique(i));
+        }
+        query.start(&mut old);
+
+        let (responded_tid, responded) = query.inflight_visits[0];
+        let (_, waiting) = query.inflight_visits[1];
+        query.add_candidates(responded_tid, &[]);
+        let old_tids = query.inflight_requests.clone();
+
+        query.rebind(&mut new);
+
+        // Old transaction ids are dropped, and the nodes waiting for them visited again.
+        assert!(old_tids.is_disjoint(&query.inflight_requests));
+        assert!(query
+            .inflight_requests
+            .iter()
+            .all(|tid| new.request_age(*tid).is_some()));
+        assert!(query.backing_off.is_empty());
+
+        let visiting = query
+            .inflight_visits
+            .iter()
+            .map(|(_, address)| *address)
+            .collect::<Vec<_>>();
+        assert_eq!(visiting.len(), 2);
+        assert!(visiting.contains(&waiting));
+        assert!(!visiting.contains(&responded));
+        assert!(query.visited.contains(&responded));
+        assert_eq!(query.closest().len(), 4);
+    }
+
+    #[test]
+    fn visit_outcome() {
+        let mut socket = KrpcSocket::new(&crate::rpc::config::Config {
+            max_requests_per_sec: std::num::NonZeroU32::new(2),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+
+        let node = Node::unique(1);
+        assert!(matches!(
+            query.visit_candidate(&mut socket, *node.id(), node.address()),
+            VisitOutcome::Sent(_)
+        ));
+        assert_eq!(
+            query.visit_candidate(&mut socket, *node.id(), node.address()),
+            VisitOutcome::SkippedVisited
+        );
+        assert_eq!(
+            query.visit_candidate(&mut socket, Id::random(), "[::1]:6881".parse().unwrap()),
+            VisitOutcome::SkippedIpv6
+        );
+
+        // The visit and its ping used up the requests budget.
+        let node = Node::unique(2);
+        assert_eq!(
+            query.visit_candidate(&mut socket, *node.id(), node.address()),
+            VisitOutcome::RateLimited
+        );
+
+        socket.blacklist([0, 0, 0, 3].into());
+        let node = Node::unique(3);
+        assert_eq!(
+            query.visit_candidate(&mut socket, *node.id(), node.address()),
+            VisitOutcome::Error(SocketError::Blacklisted)
+        );
+    }
+
+    #[test]
+    fn client_versions() {
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+
+        let message = |version| Message {
+            transaction_id: 0,
+            version,
+            requester_ip: None,
+            read_only: false,
+            message_type: MessageType::Response(ResponseSpecific::Ping(
+                crate::common::PingResponseArguments {
+                    responder_id: Id::random(),
+                },
+            )),
+        };
+
+        for (i, version) in [
+            Some(*b"LT\x01\x02"),
+            Some(*b"LT\x01\x02"),
+            Some([82, 83, 0, 4]),
+            Some([0, 0, 0, 0]),
+            None,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let node = Node::unique(i).with_version(version);
+            // Counted once per node.
+            query.add_response(0, &node, &message(version));
+            query.add_response(1, &node, &message(version));
+        }
+
+        assert_eq!(
+            query.client_versions(),
+            HashMap::from([
+                ("LT0102".to_string(), 2),
+                ("RS0004".to_string(), 1),
+                ("unknown".to_string(), 2),
+            ])
+        );
+    }
+
+    #[test]
+    fn skip_own_address() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let public = SocketAddr::from(([1, 2, 3, 4], 6881));
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE)
+            .with_own_address(socket.local_addr().into())
+            .with_own_address(public);
+        let loopback = SocketAddr::from(([127, 0, 0, 1], socket.local_addr().port()));
+
+        query.add_candidate(Node::new(Id::random(), public));
+        query.add_candidate(Node::new(Id::random(), loopback));
+        assert!(query.closest().is_empty());
+
+        assert_eq!(
+            query.visit(&mut socket, loopback),
+            VisitOutcome::SkippedOwnAddress
+        );
+        query.start(&mut socket);
+        assert_eq!(query.stats().visited, 0);
+    }
+
+    #[test]
+    fn compact_visited() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+
+        let a = SocketAddr::from(([127, 0, 0, 1], 1));
+        let b = SocketAddr::from(([127, 0, 0, 1], 2));
+        query.visit(&mut socket, a);
+
+        let mut query = query.with_compact_visited();
+        query.visit(&mut socket, b);
+        query.visited.insert("[::1]:1".parse().unwrap());
+
+        assert!(matches!(query.visited, VisitedSet::Compact { .. }));
+        assert_eq!(
+            query.visited,
+            HashSet::from([a, b, "[::1]:1".parse().unwrap()])
+        );
+        assert!(!query
+            .visited
+            .contains(&SocketAddr::from(([127, 0, 0, 2], 1))));
+    }
+
+    #[test]
+    fn skip_inflight_address() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+        let address = SocketAddr::from(([127, 0, 0, 1], 1));
+
+        assert!(query.visit(&mut socket, address).is_sent());
+        assert_eq!(
+            query.visit(&mut socket, address),
+            VisitOutcome::SkippedInflight
+        );
+
+        // Same address listed under two Ids.
+        query.add_candidate(Node::new(Id::random(), address));
+        query.add_candidate(Node::new(Id::random(), address));
+        query.start(&mut socket);
+
+        assert_eq!(query.inflight_visits.len(), 1);
+        assert_eq!(query.stats().visited, 1);
+    }
+
+    #[test]
+    fn inflight_ages() {
+        let clock = MockClock::new();
+        let mut socket = KrpcSocket::client()
+            .unwrap()
+            .with_clock(Arc::new(clock.clone()));
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+
+        query.visit(&mut socket, SocketAddr::from(([127, 0, 0, 1], 1)));
+        clock.advance(Duration::from_millis(300));
+        query.visit(&mut socket, SocketAddr::from(([127, 0, 0, 2], 1)));
+        clock.advance(Duration::from_millis(100));
+
+        let ages = query
+            .inflight_ages(&socket)
+            .into_iter()
+            .map(|(_, age)| age)
+            .collect::<Vec<_>>();
+
+        // A visit and its ping per node.
+        assert_eq!(
+            ages,
+            [400, 400, 100, 100].map(Duration::from_millis).to_vec()
+        );
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE).with_alpha(1);
+
+        for i in 1..=3 {
+            query.add_candidate(Node::new(
+                Id::random(),
+                SocketAddrV4::new([127, 0, 0, i].into(), 1).into(),
+            ));
+        }
+
+        query.tick(&mut socket);
+        assert_eq!(query.stats().visited, 1);
+        assert_eq!(query.stats().inflight, 1);
+
+        let mut clone = query.clone();
+        assert_eq!(clone.stats().visited, 1);
+        assert!(clone.inflight_requests.is_empty());
+        assert!(clone.inflight_visits.is_empty());
+
+        // The clone visits the next closest node with its own transaction ids.
+        clone.tick(&mut socket);
+        assert_eq!(clone.stats().visited, 2);
+        assert!(clone
+            .inflight_requests
+            .is_disjoint(&query.inflight_requests));
+        assert_eq!(query.stats().visited, 1);
+    }
+
+    #[test]
+    fn max_iterations() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE)
+            .with_alpha(1)
+            .with_max_iterations(2);
+
+        for i in 1..=4 {
+            query.add_candidate(Node::new(
+                Id::random(),
+                SocketAddrV4::new([127, 0, 0, i].into(), 1).into(),
+            ));
+        }
+
+        // Responses without closer nodes.
+        let respond = |query: &mut IterativeQuery, socket: &mut KrpcSocket| {
+            for tid in query.inflight_requests.clone() {
+                query.add_candidates(tid, &[]);
+                socket.cancel(tid);
+            }
+        };
+
+        assert_eq!(query.tick(&mut socket), TickResult::InProgress);
+        respond(&mut query, &mut socket);
+        assert_eq!(query.tick(&mut socket), TickResult::InProgress);
+        respond(&mut query, &mut socket);
+
+        assert_eq!(query.tick(&mut socket), TickResult::IterationsExhausted);
+        assert_eq!(query.stats().visited, 2);
+    }
+
+    #[test]
+    fn seeds() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE).with_bogon_filter(true);
+
+        let a = Node::new(Id::random(), "1.1.1.1:6881".parse().unwrap());
+        let b = Node::new(Id::random(), "2.2.2.2:6881".parse().unwrap());
+
+        let added = query.add_seeds(&[
+            a.clone(),
+            b.clone(),
+            // Duplicate
+            a.clone(),
+            Node::new(Id::random(), "10.0.0.1:6881".parse().unwrap()),
+        ]);
+        assert_eq!(added, 2);
+
+        query.start(&mut socket);
+
+        assert!(query.visited.contains(&a.address()));
+        assert!(query.visited.contains(&b.address()));
+        assert_eq!(query.stats().visited, 2);
+    }
+
+    #[test]
+    fn shared_routing_table() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let table = Arc::new(Mutex::new(RoutingTable::new(Id::random())));
+
+        let known = Node::new(Id::random(), "1.1.1.1:6881".parse().unwrap());
+        table.lock().unwrap().add(known.clone());
+
+        // Seeded from the shared table.
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE).with_routing_table(table.clone());
+        assert_eq!(query.closest().nodes()[0].id(), known.id());
+
+        let address = SocketAddr::from(([127, 0, 0, 1], 1));
+        query.visit(&mut socket, address);
+        let tid = query.inflight_visits[0].0;
+        let responder = Node::new(Id::random(), address);
+        let response = Message {
+            transaction_id: tid,
+            version: None,
+            requester_ip: None,
+            read_only: false,
+            message_type: MessageType::Response(ResponseSpecific::FindNode(
+                FindNodeResponseArguments {
+                    responder_id: *responder.id(),
+                    nodes: [].into(),
+                },
+            )),
+        };
+        assert!(query.add_response(tid, &responder, &response));
+
+        // Responders are written back, for the next queries.
+        assert!(table.lock().unwrap().contains(responder.id()));
+        let next = find_node_query(DEFAULT_QUERY_DEADLINE).with_routing_table(table);
+        assert_eq!(next.closest().len(), 2);
+    }
+
+    #[test]
+    fn want() {
+        let query = find_node_query(DEFAULT_QUERY_DEADLINE);
+        assert_eq!(query.request.want, vec![Want::N4]);
+
+        let query = query.with_ipv6(true);
+        assert_eq!(query.request.want, vec![Want::N4, Want::N6]);
+    }
+
+    #[test]
+    fn visit_ipv6() {
+        let dual = || {
+            KrpcSocket::bind(
+                &crate::rpc::config::Config::default(),
+                "127.0.0.1:0".parse().unwrap(),
+                Some("[::1]:0".parse().unwrap()),
+            )
+            .unwrap()
+        };
+        let mut socket = dual();
+        let mut remote = dual();
+
+        let node = Node::new(Id::random(), remote.local_addr6().unwrap().into());
+
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE).with_ipv6(true);
+        query.add_candidate(node.clone());
+        query.start(&mut socket);
+
+        let (_, from) = loop {
+            if let Some(received) = remote.recv_from() {
+                break received;
+            }
+        };
+        assert_eq!(from, SocketAddr::from(socket.local_addr6().unwrap()));
+
+        let (tid, address) = query.inflight_visits[0];
+        assert_eq!(address, node.address());
+
+        query.add_candidates(tid, &[]);
+        assert_eq!(query.tick(&mut socket), TickResult::Done);
+    }
+
+    #[test]
+    fn query_span() {
+        // Disabled, so free, without a subscriber.
+        assert!(find_node_query(DEFAULT_QUERY_DEADLINE).span.is_disabled());
+
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_test_writer()
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let mut socket = KrpcSocket::client().unwrap();
+            let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+
+            let metadata = query.span.metadata().unwrap();
+            assert_eq!(metadata.name(), "query");
+            assert!(metadata.fields().field("target").is_some());
+
+            query.add_candidate(Node::new(Id::random(), "1.1.1.1:6881".parse().unwrap()));
+            query.tick(&mut socket);
+            assert!(!query.clone().span.is_disabled());
+        });
+    }
+
+    #[test]
+    fn frontier() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE).with_alpha(1);
+        let target = query.target();
+
+        let close = Node::new(
+            target.random_at_distance(100),
+            "1.1.1.1:6881".parse().unwrap(),
+        );
+        let far = Node::new(
+            target.random_at_distance(150),
+            "2.2.2.2:6881".parse().unwrap(),
+        );
+
+        query.add_candidate(far.clone());
+        query.add_candidate(close.clone());
+        assert_eq!(
+            query.frontier(),
+            vec![(close.clone(), false), (far.clone(), false)]
+        );
+
+        query.start(&mut socket);
+        assert_eq!(query.frontier(), vec![(close, true), (far, false)]);
+
+        assert!(format!("{query:?}").contains("frontier"));
+    }
+
+    #[test]
+    fn own_id_candidate() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let own_id = Id::random();
+        let target = Id::random();
+        let mut query = IterativeQuery::new(
+            own_id,
+            target,
+            GetRequestSpecific::FindNode(FindNodeRequestArguments { target }),
+            DEFAULT_QUERY_DEADLINE,
+        );
+
+        let VisitOutcome::Sent(tid) =
+            query.visit(&mut socket, SocketAddr::from(([1, 1, 1, 1], 6881)))
+        else {
+            panic!("visit not sent");
+        };
+
+        let phantom = Node::new(own_id, "2.2.2.2:6881".parse().unwrap());
+        let other = Node::new(Id::random(), "3.3.3.3:6881".parse().unwrap());
+
+        query.add_candidates(tid, &[phantom, other.clone()]);
+
+        assert_eq!(query.closest().nodes(), &[other]);
+    }
+
+    #[test]
+    fn bogon_filter() {
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE).with_bogon_filter(true);
+
+        let public = Node::new(Id::random(), "1.1.1.1:6881".parse().unwrap());
+        let private = Node::new(Id::random(), "192.168.1.1:6881".parse().unwrap());
+        let loopback = Node::new(Id::random(), "127.0.0.1:6881".parse().unwrap());
+
+        query.add_candidate(public.clone());
+        query.add_candidate(private.clone());
+        query.add_candidate(loopback.clone());
+
+        assert_eq!(query.closest().nodes(), &[public]);
+
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+        query.add_candidate(private);
+        query.add_candidate(loopback);
+
+        assert_eq!(query.closest().len(), 2);
+    }
+
+    #[test]
+    fn max_requests() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE).with_max_requests(3);
+
+        for i in 1..=4 {
+            query.add_candidate(Node::new(
+                Id::random(),
+                SocketAddrV4::new([127, 0, 0, i].into(), 1).into(),
+            ));
+        }
+
+        // Each visit also sends a ping, so the budget covers the first visit,
+        // its ping, and the second visit without its ping.
+        assert_eq!(query.tick(&mut socket), TickResult::InProgress);
+        assert_eq!(query.stats().visited, 2);
+        assert_eq!(query.requests, 3);
+
+        for tid in query.inflight_requests.clone() {
+            query.add_candidates(tid, &[]);
+            socket.cancel(tid);
+        }
+
+        assert_eq!(query.tick(&mut socket), TickResult::RequestsExhausted);
+        assert_eq!(query.stats().visited, 2);
+    }
+
+    #[test]
+    fn max_peers() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE).with_max_peers(3);
+
+        for i in 1..=4 {
+            query.add_candidate(Node::new(
+                Id::random(),
+                SocketAddrV4::new([127, 0, 0, i].into(), 1).into(),
+            ));
+        }
+
+        assert_eq!(query.tick(&mut socket), TickResult::InProgress);
+        let visited = query.stats().visited;
+
+        let peer = |i| SocketAddrV4::new([1, 1, 1, i].into(), 6881);
+
+        query.add_peers(&[peer(1), peer(2)]);
+        assert!(!query.max_peers_reached());
+
+        query.add_peers(&[peer(2), peer(3), peer(4)]);
+        assert!(query.max_peers_reached());
+        assert_eq!(
+            query.peers(),
+            vec![peer(1).into(), peer(2).into(), peer(3).into()]
+        );
+
+        assert_eq!(query.tick(&mut socket), TickResult::MaxPeersReached);
+        assert_eq!(query.stats().visited, visited);
+        assert!(query.inflight_requests.is_empty());
+    }
+
+    #[test]
+    fn samples() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+
+        let (a, b, c) = (Id::random(), Id::random(), Id::random());
+
+        assert_eq!(query.add_samples(&[a, b]), vec![a, b]);
+        assert_eq!(query.add_samples(&[b, c, a]), vec![c]);
+        assert_eq!(query.samples(), &[a, b, c]);
+
+        let skipped = SocketAddr::from(([127, 0, 0, 1], 1));
+        query.skip(skipped);
+        query.add_candidate(Node::new(Id::random(), skipped));
+
+        query.tick(&mut socket);
+        assert!(query.inflight_visits.is_empty());
+    }
+
+    #[test]
+    fn shut_down_socket() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+
+        query.add_candidate(Node::new(
+            Id::random(),
+            SocketAddr::from(([127, 0, 0, 1], 1)),
+        ));
+        query.tick(&mut socket);
+
+        socket.shutdown(Duration::ZERO);
+
+        query.add_candidate(Node::new(
+            Id::random(),
+            SocketAddr::from(([127, 0, 0, 2], 1)),
+        ));
+        assert_eq!(query.tick(&mut socket), TickResult::Done);
+    }
+
+    #[test]
+    fn find_node_after() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut before = KrpcSocket::server().unwrap();
+        let mut after = KrpcSocket::server().unwrap();
+
+        let target = Id::random();
+        let mut query = IterativeQuery::new(
+            Id::random(),
+            target,
+            GetRequestSpecific::GetPeers(GetPeersRequestArguments {
+                info_hash: target,
+                scrape: false,
+            }),
+            DEFAULT_QUERY_DEADLINE,
+        )
+        .with_find_node_after(1);
+
+        // Skip the probe pings.
+        let recv_request = |server: &mut KrpcSocket| loop {
+            if let Some((message, _)) = server.recv_from() {
+                if let MessageType::Request(request) = message.message_type {
+                    if request.request_type != RequestTypeSpecific::Ping {
+                        break request.request_type;
+                    }
+                }
+            }
+        };
+
+        let find_node_response = |tid, responder: &Node| Message {
+            transaction_id: tid,
+            version: None,
+            requester_ip: None,
+            read_only: false,
+            message_type: MessageType::Response(ResponseSpecific::FindNode(
+                FindNodeResponseArguments {
+                    responder_id: *responder.id(),
+                    nodes: [].into(),
+                },
+            )),
+        };
+
+        query.visit(&mut socket, before.local_addr().into());
+        assert!(matches!(
+            recv_request(&mut before),
+            RequestTypeSpecific::GetPeers(_)
+        ));
+
+        // find_node responses don't answer get_peers before the switch.
+        let tid = query.inflight_visits[0].0;
+        let responder = Node::new(Id::random(), before.local_addr().into());
+        assert!(!query.add_response(tid, &responder, &find_node_response(tid, &responder)));
+        assert_eq!(query.stats().mismatched, 1);
+
+        query.add_peers(&[SocketAddrV4::new([1, 1, 1, 1].into(), 6881)]);
+        assert!(query.switched_to_find_node());
+
+        query.visit(&mut socket, after.local_addr().into());
+        assert_eq!(
+            recv_request(&mut after),
+            RequestTypeSpecific::FindNode(FindNodeRequestArguments { target })
+        );
+
+        // find_node responses are accepted after the switch.
+        let tid = query.inflight_visits[0].0;
+        let responder = Node::new(Id::random(), after.local_addr().into());
+        assert!(query.add_response(tid, &responder, &find_node_response(tid, &responder)));
+        assert_eq!(query.stats().mismatched, 1);
+    }
+
+    #[test]
+    fn trace() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let own = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE)
+            .with_own_address(own)
+            .with_trace();
+
+        let node = Node::unique(1);
+        let closer = Node::unique(2);
+        query.add_candidate(Node::new(Id::random(), own));
+        query.add_candidate(node.clone());
+        query.start(&mut socket);
+
+        let SocketAddr::V4(address) = node.address() else {
+            unreachable!()
+        };
+        let tid = query.inflight_visits[0].0;
+        query.add_candidates(tid, std::slice::from_ref(&closer));
+        // Already visited.
+        query.visit_candidate(&mut socket, *node.id(), node.address());
+
+        assert_eq!(
+            query.take_trace(),
+            vec![
+                QueryEvent::Ignored {
+                    address: own,
+                    reason: IgnoreReason::OwnAddress
+                },
+                QueryEvent::Added {
+                    address: node.address(),
+                    distance: query.target().distance(node.id())
+                },
+                QueryEvent::Visited {
+                    address: address.into(),
+                    tid
+                },
+                QueryEvent::Responded { tid, closer: 1 },
+                QueryEvent::Added {
+                    address: closer.address(),
+                    distance: query.target().distance(closer.id())
+                },
+                QueryEvent::Skipped {
+                    address: node.address(),
+                    outcome: VisitOutcome::SkippedVisited
+                },
+            ]
+        );
+        assert!(query.take_trace().is_empty());
+
+        // Off by default
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+        query.add_candidate(node);
+        query.start(&mut socket);
+        assert!(query.take_trace().is_empty());
+    }
+
+    #[test]
+    fn lifecycle() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+
+        // Nothing inflight, but not done either.
+        assert_eq!(query.state(), QueryState::New);
+        assert!(!query.state().is_done());
+
+        query.add_candidate(Node::unique(1));
+        query.start(&mut socket);
+        assert_eq!(query.state(), QueryState::Running);
+
+        assert_eq!(query.tick(&mut socket), TickResult::InProgress);
+        assert_eq!(query.state(), QueryState::Running);
+        assert!(!query.state().is_done());
+
+        let tid = query.inflight_visits[0].0;
+        query.add_candidates(tid, &[]);
+
+        assert_eq!(query.tick(&mut socket), TickResult::Done);
+        assert_eq!(query.state(), QueryState::Converged);
+        assert!(query.state().is_done());
+
+        // Timed out
+        let mut query = find_node_query(Duration::ZERO);
+        query.add_candidate(Node::unique(1));
+        query.start(&mut socket);
+
+        assert_eq!(query.tick(&mut socket), TickResult::TimedOut);
+        assert_eq!(query.state(), QueryState::TimedOut);
+        assert!(query.state().is_done());
+
+        // Cancelled before starting
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+        query.cancel(&mut socket);
+        assert_eq!(query.state(), QueryState::Cancelled);
+        assert!(query.state().is_done());
+
+        query.add_candidate(Node::unique(1));
+        assert_eq!(query.tick(&mut socket), TickResult::Cancelled);
+        assert_eq!(query.state(), QueryState::Cancelled);
+    }
+
+    #[test]
+    fn cancel() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+
+        query.visit(&mut socket, SocketAddr::from(([127, 0, 0, 1], 1)));
+        let tids = query.inflight_requests.clone();
+        assert!(!tids.is_empty());
+
+        query.cancel(&mut socket);
+
+        assert!(query.is_cancelled());
+        assert!(query.visited.is_empty());
+        for tid in tids {
+            assert!(!socket.inflight(&tid));
+        }
+        assert_eq!(query.tick(&mut socket), TickResult::Cancelled);
+        assert_eq!(query.tick(&mut socket), TickResult::Cancelled);
+    }
+
+    #[test]
+    fn timed_out_keeps_closest() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let target = Id::random();
+        let seeds = vec![Node::unique(1), Node::unique(2)];
+        let mut query = IterativeQuery::new_with_seed(
+            Id::random(),
+            target,
+            GetRequestSpecific::FindNode(FindNodeRequestArguments { target }),
+            Duration::ZERO,
+            seeds,
+        );
+
+        assert_eq!(query.tick(&mut socket), TickResult::TimedOut);
+        // Partial results are still returned as the query's closest nodes.
+        assert_eq!(query.closest().len(), 2);
+    }
+
+    #[test]
+    fn attach_token() {
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+
+        let node = Node::unique(1);
+        assert_eq!(query.attach_token(&node).token(), None);
+
+        query.add_responding_node(Node::new_with_token(
+            *node.id(),
+            node.address(),
+            [1, 2, 3, 4].into(),
+        ));
+        query.add_responding_node(Node::new_with_token(
+            *node.id(),
+            node.address(),
+            [5, 6, 7, 8].into(),
+        ));
+
+        assert_eq!(query.attach_token(&node).token(), Some([5, 6, 7, 8].into()));
+        assert_eq!(query.attach_token(&Node::unique(2)).token(), None);
+    }
+
+    #[test]
+    fn invalid_token() {
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+
+        let empty = Node::new_with_token(Id::random(), Node::unique(1).address(), [].into());
+        let long = Node::new_with_token(
+            Id::random(),
+            Node::unique(2).address(),
+            vec![0; MAX_TOKEN_LENGTH + 1].into(),
+        );
+        let valid = Node::new_with_token(
+            Id::random(),
+            Node::unique(3).address(),
+            vec![0; MAX_TOKEN_LENGTH].into(),
+        );
+
+        for node in [&empty, &long, &valid] {
+            query.add_responding_node(node.clone());
+        }
+
+        // Only the node with a valid token is announced to.
+        let without_token = |node: &Node| Node::new(*node.id(), node.address());
+        assert_eq!(query.attach_token(&without_token(&empty)).token(), None);
+        assert_eq!(query.attach_token(&without_token(&long)).token(), None);
+        assert!(query.attach_token(&without_token(&valid)).token().is_some());
+        assert_eq!(query.responders.nodes(), &[valid]);
+    }
+
+    #[test]
+    fn unique_peers() {
+        let target = Id::random();
+        let mut query = IterativeQuery::new(
+            Id::random(),
+            target,
+            GetRequestSpecific::GetPeers(GetPeersRequestArguments {
+                info_hash: target,
+                scrape: false,
+            }),
+            DEFAULT_QUERY_DEADLINE,
+        );
+
+        let a = SocketAddrV4::new([127, 0, 0, 1].into(), 6881);
+        let b = SocketAddrV4::new([127, 0, 0, 2].into(), 6881);
+
+        query.add_peers(&[b, a]);
+        query.add_peers(&[b]);
+
+        // In the order they were received.
+        assert_eq!(query.peers(), vec![b.into(), a.into()]);
+    }
+
+    #[test]
+    fn peers_across_responders() {
+        let target = Id::random();
+        let mut query = IterativeQuery::new(
+            Id::random(),
+            target,
+            GetRequestSpecific::GetPeers(GetPeersRequestArguments {
+                info_hash: target,
+                scrape: false,
+            }),
+            DEFAULT_QUERY_DEADLINE,
+        );
+
+        let a = SocketAddrV4::new([127, 0, 0, 1].into(), 6881);
+        let b = SocketAddrV4::new([127, 0, 0, 2].into(), 6881);
+        let c = SocketAddrV4::new([127, 0, 0, 3].into(), 6881);
+
+        // Two responders with overlapping peers.
+        query.add_peers(&[a, b]);
+        query.add_peers(&[b, c, a]);
+
+        assert_eq!(query.peers(), vec![a.into(), b.into(), c.into()]);
+        assert_eq!(query.peers_sorted(), vec![c.into(), b.into(), a.into()]);
+        assert_eq!(query.peers_unordered().len(), 3);
+        assert!(query.peers_unordered().contains(&b.into()));
+    }
+
+    #[test]
+    fn found_peers() {
+        let target = Id::random();
+        let mut query = IterativeQuery::new(
+            Id::random(),
+            target,
+            GetRequestSpecific::GetPeers(GetPeersRequestArguments {
+                info_hash: target,
+                scrape: false,
+            }),
+            DEFAULT_QUERY_DEADLINE,
+        );
+
+        query.add_peers(&[]);
+        assert!(!query.found_peers());
+
+        let a = SocketAddrV4::new([127, 0, 0, 1].into(), 6881);
+
+        query.add_peers(&[a]);
+        // Duplicates still mean the swarm isn't empty.
+        query.add_peers(&[a]);
+
+        assert!(query.found_peers());
+        assert_eq!(query.peer_responses(), 2);
+        assert_eq!(query.peers().len(), 1);
+    }
+
+    #[test]
+    fn merge_scrapes() {
+        let target = Id::random();
+        let mut query = IterativeQuery::new(
+            Id::random(),
+            target,
+            GetRequestSpecific::GetPeers(GetPeersRequestArguments {
+                info_hash: target,
+                scrape: true,
+            }),
+            DEFAULT_QUERY_DEADLINE,
+        );
+
+        let mut seeders = BloomFilter::new();
+        let mut peers_a = BloomFilter::new();
+        let mut peers_b = BloomFilter::new();
+
+        for i in 0..20 {
+            seeders.insert([10, 0, 0, i].into());
+            peers_a.insert([10, 0, 1, i].into());
+            peers_b.insert([10, 0, 2, i].into());
+        }
+
+        assert!(query.add_scrape(Some(&seeders), Some(&peers_a)));
+        assert!(query.add_scrape(Some(&seeders), Some(&peers_b)));
+        // Responses without filters don't affect the estimate.
+        assert!(!query.add_scrape(None, None));
+
+        assert_eq!(query.estimated_seeders(), seeders.estimate());
+        assert!((36..=44).contains(&query.estimated_peers()));
+    }
+
+    #[test]
+    fn streaming_peers() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (late_tx, late_rx) = std::sync::mpsc::channel();
+
+        let target = Id::random();
+        let mut query = IterativeQuery::new(
+            Id::random(),
+            target,
+            GetRequestSpecific::GetPeers(GetPeersRequestArguments {
+                info_hash: target,
+                scrape: false,
+            }),
+            DEFAULT_QUERY_DEADLINE,
+        );
+        query.stream_peers(tx);
+
+        let a = SocketAddrV4::new([127, 0, 0, 1].into(), 1);
+        let b = SocketAddrV4::new([127, 0, 0, 1].into(), 2);
+        let c = SocketAddrV4::new([127, 0, 0, 1].into(), 3);
+
+        query.add_peers(&[a, b]);
+        query.add_peers(&[b]);
+
+        assert_eq!(rx.try_recv(), Ok(a.into()));
+        assert_eq!(rx.try_recv(), Ok(b.into()));
+        assert!(rx.try_recv().is_err());
+
+        // Late receivers get the peers found so far first.
+        query.stream_peers(late_tx);
+        query.add_peers(&[c]);
+
+        assert_eq!(rx.try_recv(), Ok(c.into()));
+        assert_eq!(
+            late_rx.try_iter().collect::<Vec<_>>(),
+            vec![a.into(), b.into(), c.into()]
+        );
+
+        assert_eq!(query.tick(&mut socket), TickResult::NoCandidates);
+        assert_eq!(
+            rx.try_recv(),
+            Err(std::sync::mpsc::TryRecvError::Disconnected)
+        );
+        assert_eq!(
+            late_rx.try_recv(),
+            Err(std::sync::mpsc::TryRecvError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn skip_visited_ids() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+
+        let id = Id::random();
+        query.add_candidate(Node::new(id, SocketAddr::from(([127, 0, 0, 1], 1))));
+        query.add_candidate(Node::new(id, SocketAddr::from(([127, 0, 0, 2], 1))));
+        query.start(&mut socket);
+
+        assert_eq!(query.stats().visited, 1);
+    }
+
+    #[test]
+    fn most_recent_mutable() {
+        let signer = crate::SigningKey::from_bytes(&[0; 32]);
+        let target = MutableItem::target_from_key(signer.verifying_key().as_bytes(), None);
+
+        let mut query = IterativeQuery::new(
+            Id::random(),
+            target,
+            GetRequestSpecific::GetValue(GetValueRequestArguments {
+                target,
+                seq: None,
+                salt: None,
+            }),
+            DEFAULT_QUERY_DEADLINE,
+        );
+        let from = SocketAddr::from(([127, 0, 0, 1], 1));
+
+        assert!(query.most_recent_mutable().is_none());
+
+        let newer = MutableItem::new(signer.clone(), b"newer", 2, None);
+
+        query.response(
+            from,
+            Response::Mutable(MutableItem::new(signer.clone(), b"older", 1, None)),
+        );
+        query.response(from, Response::Mutable(newer.clone()));
+
+        assert_eq!(query.most_recent_mutable(), Some(&newer));
+    }
+
+    #[test]
+    fn converged() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+
+        query.add_candidate(Node::unique(1));
+        query.add_candidate(Node::unique(2));
+        query.start(&mut socket);
+
+        assert_eq!(query.tick(&mut socket), TickResult::InProgress);
+
+        let tids = query
+            .inflight_visits
+            .iter()
+            .map(|(tid, _)| *tid)
+            .collect::<Vec<_>>();
+        for tid in tids {
+            query.add_candidates(tid, &[]);
+        }
+
+        // Both closest nodes responded without closer nodes,
+        // even though their pings are still inflight.
+        assert_eq!(query.tick(&mut socket), TickResult::Done);
+    }
+
+    #[test]
+    fn closeness_floor() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let target = Id::random();
+
+        let query = IterativeQuery::new(
+            Id::random(),
+            target,
+            GetRequestSpecific::FindNode(FindNodeRequestArguments { target }),
+            DEFAULT_QUERY_DEADLINE,
+        );
+        let mut exact = query.clone();
+        let mut query = query.with_closeness_floor(16);
+
+        // Shares exactly the first 16 bits with the target.
+        let near = Node::new(
+            target.random_at_distance(144),
+            SocketAddr::from(([1, 1, 1, 1], 6881)),
+        );
+
+        for query in [&mut query, &mut exact] {
+            query.add_candidate(near.clone());
+            for i in 2..10 {
+                query.add_candidate(Node::unique(i));
+            }
+            query.start(&mut socket);
+            assert_eq!(query.tick(&mut socket), TickResult::InProgress);
+
+            let tid = query
+                .inflight_visits
+                .iter()
+                .find(|(_, address)| *address == near.address())
+                .unwrap()
+                .0;
+            query.add_candidates(tid, &[]);
+        }
+
+        // Other visits are still inflight, and may return closer nodes.
+        assert_eq!(exact.tick(&mut socket), TickResult::InProgress);
+        assert_eq!(query.tick(&mut socket), TickResult::CloseEnough);
+        assert!(query.inflight_requests.is_empty());
+    }
+
+    #[test]
+    fn custom_strategy() {
+        /// Only follows the responders themselves, ignoring the nodes they return.
+        #[derive(Debug)]
+        struct RespondersOnly;
+
+        impl QueryStrategy for RespondersOnly {
+            fn on_response(&mut self, node: &Node, _message: &Message) -> Vec<Node> {
+                vec![node.clone()]
+            }
+
+            fn clone_box(&self) -> Box<dyn QueryStrategy> {
+                Box::new(RespondersOnly)
+            }
+        }
+
+        let mut query =
+            find_node_query(DEFAULT_QUERY_DEADLINE).with_strategy(Box::new(RespondersOnly));
+
+        let responder = Node::unique(1);
+        let message = Message {
+            transaction_id: 0,
+            version: None,
+            requester_ip: None,
+            read_only: false,
+            message_type: MessageType::Response(ResponseSpecific::FindNode(
+                FindNodeResponseArguments {
+                    responder_id: *responder.id(),
+                    nodes: [Node::unique(2)].into(),
+                },
+            )),
+        };
+
+        query.add_response(0, &responder, &message);
+
+        assert_eq!(query.closest().nodes(), &[responder]);
+    }
+
+    #[test]
+    fn ignore_responder_as_closer_node() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+
+        let responder = Node::unique(1);
+        query.add_candidate(responder.clone());
+        query.start(&mut socket);
+        let tid = query.inflight_visits[0].0;
+
+        let querier = Node::new(query.request.requester_id, Node::unique(2).address());
+        let message = Message {
+            transaction_id: tid,
+            version: None,
+            requester_ip: None,
+            read_only: false,
+            message_type: MessageType::Response(ResponseSpecific::FindNode(
+                FindNodeResponseArguments {
+                    responder_id: *responder.id(),
+                    nodes: [
+                        // Same address with a new Id.
+                        Node::new(Id::random(), responder.address()),
+                        querier,
+                    ]
+                    .into(),
+                },
+            )),
+        };
+
+        assert!(ClosestNodesStrategy
+            .on_response(&responder, &message)
+            .iter()
+            .all(|node| !node.same_address(&responder)));
+
+        query.add_response(tid, &responder, &message);
+        query.tick(&mut socket);
+
+        assert_eq!(query.closest().nodes(), &[responder]);
+        assert_eq!(query.stats().visited, 1);
+    }
+
+    #[test]
+    fn responding_nodes() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+
+        let advertised = Node::unique(1);
+        query.add_candidate(advertised.clone());
+        query.start(&mut socket);
+        let tid = query.inflight_visits[0].0;
+
+        // Responds with another Id than the one it was advertised with.
+        let responder_id = Id::random();
+        let message = Message {
+            transaction_id: tid,
+            version: None,
+            requester_ip: None,
+            read_only: false,
+            message_type: MessageType::Response(ResponseSpecific::FindNode(
+                FindNodeResponseArguments {
+                    responder_id,
+                    nodes: [].into(),
+                },
+            )),
+        };
+
+        let first = Node::new(responder_id, advertised.address());
+        query.add_response(tid, &first, &message);
+
+        let again = Node::new(responder_id, advertised.address());
+        query.add_response(tid, &again, &message);
+
+        let responding = query.responding_nodes().collect::<Vec<_>>();
+        assert_eq!(responding.len(), 1);
+        assert_eq!(*responding[0].id(), responder_id);
+        assert_eq!(responding[0].address(), advertised.address());
+        assert!(responding[0].last_seen() >= first.last_seen());
+    }
+
+    #[test]
+    fn seeded() {
+        let mut socket = KrpcSocket::client().unwrap();
+
+        let target = Id::random();
+        let seeds = (0..10).map(Node::unique).collect::<Vec<_>>();
+
+        let mut query = IterativeQuery::new_with_seed(
+            Id::random(),
+            target,
+            GetRequestSpecific::FindNode(FindNodeRequestArguments { target }),
+            DEFAULT_QUERY_DEADLINE,
+            seeds.iter().rev().cloned().collect(),
+        );
+
+        assert_eq!(query.target(), target);
+        assert!(matches!(
+            query.request().request_type,
+            RequestTypeSpecific::FindNode(FindNodeRequestArguments { target: t }) if t == target
+        ));
+
+        let mut expected = seeds.clone();
+        expected.sort_by(|a, b| target.cmp_distance(a.id(), b.id()));
+
+        assert_eq!(query.closest().nodes(), &expected[..]);
+
+        query.tick(&mut socket);
+
+        let visited = query
+            .inflight_visits
+            .iter()
+            .map(|(_, address)| *address)
+            .collect::<Vec<_>>();
+        let closest = expected
+            .iter()
+            .take(DEFAULT_ALPHA)
+            .map(|node| node.address())
+            .collect::<Vec<_>>();
+
+        assert_eq!(visited, closest);
+    }
+
+    #[test]
+    fn backtrack_unresponsive_closest() {
+        let mut socket = KrpcSocket::client()
+            .unwrap()
+            .with_timeout(Duration::from_millis(10));
+
+        let target = Id::random();
+        let mut query = IterativeQuery::new(
+            Id::random(),
+            target,
+            GetRequestSpecific::FindNode(FindNodeRequestArguments { target }),
+            DEFAULT_QUERY_DEADLINE,
+        )
+        .with_max_retries(0);
+
+        // None of the closest k nodes will respond.
+        for i in 0..MAX_BUCKET_SIZE_K {
+            query.add_candidate(Node::new(
+                target.random_at_distance(100 + i as u8),
+                SocketAddr::from(([127, 0, 0, 1], 1 + i as u16)),
+            ));
+        }
+        let farther = Node::new(
+            target.random_at_distance(159),
+            SocketAddr::from(([127, 0, 0, 2], 1)),
+        );
+        query.add_candidate(farther.clone());
+
+        for _ in 0..20 {
+            query.tick(&mut socket);
+
+            if query.visited.contains(&farther.address()) {
+                break;
+            }
+
+            std::thread::sleep(Duration::from_millis(20));
+            // Reap timed out requests.
+            socket.recv_from();
+        }
+
+        assert!(query.visited.contains(&farther.address()));
+    }
+
+    #[test]
+    fn add_candidates_batch() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+
+        query.visit(&mut socket, SocketAddr::from(([127, 0, 0, 1], 1)));
+        query.visit(&mut socket, SocketAddr::from(([127, 0, 0, 2], 1)));
+        let responses = query
+            .inflight_visits
+            .iter()
+            .enumerate()
+            .map(|(i, (tid, _))| (*tid, vec![Node::unique(10 + i)]))
+            .collect::<Vec<_>>();
+
+        query.add_candidates_batch(&responses);
+
+        assert!(query.inflight_visits.is_empty());
+        assert_eq!(query.stats().responses, 2);
+        assert_eq!(query.closest().len(), 2);
+    }
+
+    #[test]
+    fn stats() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE).with_alpha(2);
+
+        query.add_candidates(0, &[Node::unique(1), Node::unique(2), Node::unique(3)]);
+        query.start(&mut socket);
+
+        assert_eq!(
+            query.stats(),
+            QueryStats {
+                visited: 2,
+                inflight: 2,
+                closest_known: 3,
+                responses: 1,
+                mismatched: 0,
+                rejected: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn max_inflight_per_node() {
+        let mut socket = KrpcSocket::new(&crate::rpc::config::Config {
+            max_inflight_per_node: std::num::NonZeroUsize::new(1),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let shared = Node::unique(1);
+        let other = Node::unique(2);
+
+        let mut first = find_node_query(DEFAULT_QUERY_DEADLINE);
+        first.add_candidate(shared.clone());
+        let mut second = find_node_query(DEFAULT_QUERY_DEADLINE);
+        second.add_candidate(shared.clone());
+        second.add_candidate(other.clone());
+
+        first.tick(&mut socket);
+        second.tick(&mut socket);
+
+        let visited = |query: &IterativeQuery| {
+            query
+                .inflight_visits
+                .iter()
+                .map(|(_, address)| *address)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(visited(&first), vec![shared.address()]);
+        // Falls back to the other node, and visits the shared node later.
+        assert_eq!(visited(&second), vec![other.address()]);
+        assert!(!second.visited.contains(&shared.address()));
+    }
+
+    #[test]
+    fn mismatched_response() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let target = Id::random();
+        let mut query = IterativeQuery::new(
+            Id::random(),
+            target,
+            GetRequestSpecific::GetPeers(GetPeersRequestArguments {
+                info_hash: target,
+                scrape: false,
+            }),
+            DEFAULT_QUERY_DEADLINE,
+        );
+
+        let responder = Node::unique(1);
+        query.add_candidate(responder.clone());
+        query.start(&mut socket);
+        let tid = query.inflight_visits[0].0;
+
+        // No token, so it can't be a response to get_peers.
+        let message = Message {
+            transaction_id: tid,
+            version: None,
+            requester_ip: None,
+            read_only: false,
+            message_type: MessageType::Response(ResponseSpecific::FindNode(
+                FindNodeResponseArguments {
+                    responder_id: *responder.id(),
+                    nodes: [Node::unique(2)].into(),
+                },
+            )),
+        };
+
+        assert!(!query.add_response(tid, &responder, &message));
+
+        assert_eq!(query.stats().mismatched, 1);
+        assert_eq!(query.stats().responses, 0);
+        assert!(query.inflight_visits.is_empty());
+        assert!(query.responding_nodes().next().is_none());
+        // Neither the advertised node, nor the responder, are candidates anymore.
+        assert!(query.frontier().is_empty());
+    }
+
+    #[test]
+    fn strategy_rejects_responses() {
+        #[derive(Debug)]
+        struct RejectAll;
+
+        impl QueryStrategy for RejectAll {
+            fn on_response(&mut self, _node: &Node, _message: &Message) -> Vec<Node> {
+                unreachable!("rejected responses aren't handled")
+            }
+
+            fn accept(&mut self, _node: &Node, _message: &Message) -> bool {
+                false
+            }
+
+            fn clone_box(&self) -> Box<dyn QueryStrategy> {
+                Box::new(RejectAll)
+            }
+        }
+
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE).with_strategy(Box::new(RejectAll));
+
+        let responders = [Node::unique(1), Node::unique(2)];
+        for responder in &responders {
+            query.add_candidate(responder.clone());
+        }
+        query.start(&mut socket);
+
+        for (tid, address) in query.inflight_visits.clone() {
+            let responder = responders
+                .iter()
+                .find(|node| node.address() == address)
+                .unwrap();
+
+            let message = Message {
+                transaction_id: tid,
+                version: None,
+                requester_ip: None,
+                read_only: false,
+                message_type: MessageType::Response(ResponseSpecific::FindNode(
+                    FindNodeResponseArguments {
+                        responder_id: *responder.id(),
+                        nodes: [Node::unique(3)].into(),
+                    },
+                )),
+            };
+
+            assert!(!query.add_response(tid, responder, &message));
+        }
+
+        assert_eq!(query.stats().rejected, 2);
+        assert_eq!(query.stats().responses, 0);
+        assert!(query.responding_nodes().next().is_none());
+        assert!(query.frontier().is_empty());
+    }
+
+    #[test]
+    fn handle_error() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE);
+
+        let address = SocketAddr::from(([127, 0, 0, 1], 1));
+        query.visit(&mut socket, address);
+
+        let (tid, _) = query.inflight_visits[0];
+        assert!(query.inflight(tid));
+
+        let error = ErrorSpecific {
+            code: 203,
+            description: "Protocol Error".to_string(),
+        };
+        query.handle_error(tid, address, error.clone());
+
+        assert!(!query.inflight(tid));
+        assert!(query.inflight_visits.is_empty());
+        assert_eq!(query.errors(), &[(address, error)]);
+    }
+
+    #[test]
+    fn retransmit_timed_out_requests() {
+        let mut socket = KrpcSocket::client().unwrap().with_timeout(Duration::ZERO);
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE).with_max_retries(2);
+
+        let address = SocketAddr::from(([127, 0, 0, 1], 1));
+        query.visit(&mut socket, address);
+
+        for _ in 0..10 {
+            // Cleanup timed out requests.
+            socket.recv_from();
+            query.tick(&mut socket);
+        }
+
+        assert_eq!(query.retries.get(&address), Some(&2));
+        assert!(query.inflight_visits.is_empty());
+    }
+
+    #[test]
+    fn retransmit_backoff() {
+        let mut socket = KrpcSocket::client()
+            .unwrap()
+            .with_timeout(Duration::from_millis(10));
+        let clock = MockClock::new();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE)
+            .with_max_retries(2)
+            .with_clock(Arc::new(clock.clone()))
+            .with_jitter(0.5)
+            .with_rng(Arc::new(MockRng(1.0)));
+
+        let address = SocketAddr::from(([127, 0, 0, 1], 1));
+        query.visit(&mut socket, address);
+
+        let mut time_out = |query: &mut IterativeQuery| {
+            std::thread::sleep(Duration::from_millis(20));
+            // Cleanup timed out requests.
+            socket.recv_from();
+            query.tick(&mut socket)
+        };
+
+        // The first retransmission is immediate.
+        time_out(&mut query);
+        assert_eq!(query.retries.get(&address), Some(&1));
+
+        // The second waits for a whole timeout, plus up to half of it as jitter.
+        assert_eq!(time_out(&mut query), TickResult::InProgress);
+        assert_eq!(query.retries.get(&address), Some(&1));
+        assert_eq!(query.backing_off.len(), 1);
+
+        clock.advance(Duration::from_millis(10));
+        query.tick(&mut socket);
+        assert_eq!(query.retries.get(&address), Some(&1));
+
+        clock.advance(Duration::from_millis(5));
+        query.tick(&mut socket);
+        assert_eq!(query.retries.get(&address), Some(&2));
+        assert!(query.backing_off.is_empty());
+    }
+
+    #[test]
+    fn alpha() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut query = find_node_query(DEFAULT_QUERY_DEADLINE).with_alpha(2);
+
+        for i in 1..10 {
+            query.add_candidate(Node::unique(i));
+        }
+
+        query.start(&mut socket);
+        assert_eq!(query.visited.len(), 2);
+
+        // No slots until inflight visits time out or get responses.
+        query.tick(&mut socket);
+        assert_eq!(query.visited.len(), 2);
+
+        // Closest candidates are visited first.
+        let closest = query
+            .closest()
+            .nodes()
+            .iter()
+            .take(2)
+            .map(|node| node.address())
+            .collect::<HashSet<_>>();
+        assert_eq!(query.visited, closest);
+    }
+
+    #[test]
+    fn prefer_verified() {
+        let target = Id::random();
+        let unverified = (0..5)
+            .map(|i| {
+                Node::new(
+                    target.random_at_distance(100 + i),
+                    SocketAddrV4::new([127, 0, 0, 1 + i].into(), 1).into(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let verified = Node::new(
+            target.random_at_distance(150),
+            SocketAddr::from(([127, 0, 1, 1], 1)),
+        )
+        .with_rtt(Some(Duration::from_millis(50)));
+
+        for prefer_verified in [true, false] {
+            let mut socket = KrpcSocket::client().unwrap();
+            let mut query = IterativeQuery::new(
+                Id::random(),
+                target,
+                GetRequestSpecific::FindNode(FindNodeRequestArguments { target }),
+                DEFAULT_QUERY_DEADLINE,
+            )
+            .with_alpha(1)
+            .with_prefer_verified(prefer_verified);
+
+            for node in unverified.iter().chain([&verified]) {
+                query.add_candidate(node.clone());
+            }
+
+            query.start(&mut socket);
+
+            let expected = if prefer_verified {
+                &verified
+            } else {
+                &unverified[0]
+            };
+            assert_eq!(
+                query.visited,
+                HashSet::from([expected.address()]),
+                "prefer_verified: {prefer_verified}"
+            );
         }
     }
 }
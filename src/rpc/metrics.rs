@@ -0,0 +1,126 @@
+//! Histograms of lookup latencies.
+
+use std::time::Duration;
+
+/// Upper bounds of the [LatencyHistogram] buckets, the last bucket catches everything above.
+pub const LATENCY_BUCKETS: [Duration; 10] = [
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_millis(2500),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+    Duration::from_secs(30),
+    Duration::from_secs(60),
+];
+
+/// Fixed buckets histogram of durations, in the style of Prometheus histograms.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// Counts per bucket of [LATENCY_BUCKETS], plus one for durations above all of them.
+    counts: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: Duration,
+}
+
+impl LatencyHistogram {
+    /// Record one observed duration.
+    pub fn record(&mut self, duration: Duration) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|upper_bound| duration <= *upper_bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+
+        self.counts[bucket] += 1;
+        self.sum += duration;
+    }
+
+    /// Number of recorded durations.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Sum of recorded durations.
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// Cumulative counts of recorded durations less than or equal to each of
+    /// [LATENCY_BUCKETS], like Prometheus `le` buckets.
+    ///
+    /// Durations above the last bucket are only counted in [Self::count].
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        LATENCY_BUCKETS
+            .iter()
+            .zip(self.counts.iter().scan(0, |cumulative, count| {
+                *cumulative += count;
+
+                Some(*cumulative)
+            }))
+            .map(|(upper_bound, count)| (*upper_bound, count))
+    }
+
+    /// Upper bound of the bucket containing the `quantile` (clamped to `[0, 1]`)
+    /// of recorded durations, for example `0.99` for the p99.
+    ///
+    /// Returns `None` if nothing was recorded, or [Duration::MAX] if the
+    /// quantile is above the last bucket.
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+
+        let rank = ((quantile.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+
+        Some(
+            self.buckets()
+                .find(|(_, cumulative)| *cumulative >= rank)
+                .map(|(upper_bound, _)| upper_bound)
+                .unwrap_or(Duration::MAX),
+        )
+    }
+}
+
+/// End-to-end latencies of the lookups run by an [Rpc](crate::rpc::Rpc),
+/// from creating a query until it is done.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LookupMetrics {
+    /// Latencies of `find_node` lookups, including routing table refreshes.
+    pub find_node: LatencyHistogram,
+    /// Latencies of `get_peers` lookups.
+    pub get_peers: LatencyHistogram,
+    /// Latencies of `get` lookups of immutable and mutable items.
+    pub get_value: LatencyHistogram,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn histogram() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.quantile(0.5), None);
+
+        for millis in [10, 40, 75, 300, 900, 120_000] {
+            histogram.record(Duration::from_millis(millis));
+        }
+
+        assert_eq!(histogram.count(), 6);
+        assert_eq!(histogram.sum(), Duration::from_millis(121_325));
+
+        let buckets = histogram.buckets().collect::<Vec<_>>();
+        assert_eq!(buckets.len(), LATENCY_BUCKETS.len());
+        assert_eq!(buckets[0], (Duration::from_millis(50), 2));
+        assert_eq!(buckets[1], (Duration::from_millis(100), 3));
+        assert_eq!(buckets[4], (Duration::from_secs(1), 5));
+        assert_eq!(buckets[9], (Duration::from_secs(60), 5));
+
+        assert_eq!(histogram.quantile(0.0), Some(Duration::from_millis(50)));
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_millis(100)));
+        assert_eq!(histogram.quantile(0.8), Some(Duration::from_secs(1)));
+        assert_eq!(histogram.quantile(0.99), Some(Duration::MAX));
+    }
+}
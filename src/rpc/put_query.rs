@@ -1,4 +1,4 @@
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 
 use crate::{
     common::{
@@ -35,6 +35,10 @@ impl PutQuery {
         }
     }
 
+    /// Send the put request to `closest_nodes` and the extra nodes, each with the
+    /// token it returned to the lookup, see [super::IterativeQuery::attach_token].
+    ///
+    /// Nodes without a token are skipped, as they would reject the request.
     pub fn start(
         &mut self,
         socket: &mut KrpcSocket,
@@ -56,20 +60,26 @@ impl PutQuery {
         }
 
         for node in closest_nodes.iter().chain(self.extra_nodes.iter()) {
-            // Set correct values to the request placeholders
-            if let Some(token) = node.token() {
-                let tid = socket.request(
-                    node.address(),
-                    RequestSpecific {
-                        requester_id: Id::random(),
-                        request_type: RequestTypeSpecific::Put(PutRequest {
-                            token,
-                            put_request_type: self.request.clone(),
-                        }),
-                    },
-                );
+            let Some(token) = node.token() else {
+                warn!(?target, address = ?node.address(), "Skipping PutQuery request to a node without a token");
+                continue;
+            };
 
-                self.inflight_requests.push(tid);
+            // Set correct values to the request placeholders
+            let address = node.address();
+            match socket.request(
+                address,
+                RequestSpecific {
+                    requester_id: Id::random(),
+                    request_type: RequestTypeSpecific::Put(PutRequest {
+                        token,
+                        put_request_type: self.request.clone(),
+                    }),
+                    want: Vec::new(),
+                },
+            ) {
+                Ok(tid) => self.inflight_requests.push(tid),
+                Err(error) => debug!(?error, ?address, "Skipping PutQuery request"),
             }
         }
 
@@ -253,3 +263,45 @@ pub enum ConcurrencyError {
     #[error("CAS check failed, try reading most recent item before writing again.")]
     CasFailed,
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddrV4;
+
+    use crate::common::AnnouncePeerRequestArguments;
+
+    use super::*;
+
+    #[test]
+    fn announce_skips_nodes_without_token() {
+        let mut socket = KrpcSocket::client().unwrap();
+
+        let info_hash = Id::random();
+        let mut query = PutQuery::new(
+            info_hash,
+            PutRequestSpecific::AnnouncePeer(AnnouncePeerRequestArguments {
+                info_hash,
+                port: 6881,
+                implied_port: None,
+            }),
+            None,
+        );
+
+        let closest_nodes = [
+            Node::new_with_token(
+                Id::random(),
+                SocketAddrV4::new([127, 0, 0, 1].into(), 1).into(),
+                [1, 2, 3, 4].into(),
+            ),
+            Node::new(
+                Id::random(),
+                SocketAddrV4::new([127, 0, 0, 2].into(), 1).into(),
+            ),
+        ];
+
+        query.start(&mut socket, &closest_nodes).unwrap();
+
+        assert!(query.started());
+        assert_eq!(query.inflight_requests.len(), 1);
+    }
+}
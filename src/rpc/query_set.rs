@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+
+use crate::Id;
+
+use super::iterative_query::{IterativeQuery, TickResult};
+use super::socket::KrpcSocket;
+
+#[derive(Debug, Default)]
+/// The inflight [IterativeQuery]s, at most one per target, sharing one [KrpcSocket].
+///
+/// Responses are routed to the query that sent their transaction id through an
+/// index, instead of asking every query whether it is waiting for it.
+pub(crate) struct QuerySet {
+    queries: HashMap<Id, IterativeQuery>,
+    /// Target of the query that sent each inflight transaction id.
+    owners: HashMap<u16, Id>,
+}
+
+impl QuerySet {
+    /// Create an empty [QuerySet].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // === Getters ===
+
+    /// Returns the number of inflight queries.
+    pub fn len(&self) -> usize {
+        self.queries.len()
+    }
+
+    /// Returns `true` if there is an inflight query to this target.
+    pub fn contains(&self, target: &Id) -> bool {
+        self.queries.contains_key(target)
+    }
+
+    /// Returns the inflight query to this target, if any.
+    pub fn get(&self, target: &Id) -> Option<&IterativeQuery> {
+        self.queries.get(target)
+    }
+
+    /// Returns the inflight query to this target, if any.
+    pub fn get_mut(&mut self, target: &Id) -> Option<&mut IterativeQuery> {
+        self.queries.get_mut(target)
+    }
+
+    /// Returns `true` if a response with this transaction id is expected by any query.
+    pub fn owns(&self, tid: u16) -> bool {
+        self.owners
+            .get(&tid)
+            .and_then(|target| self.queries.get(target))
+            .is_some_and(|query| query.inflight(tid))
+    }
+
+    /// Returns the query that sent this transaction id and still expects its response, if any.
+    pub fn owner_mut(&mut self, tid: u16) -> Option<&mut IterativeQuery> {
+        self.owners
+            .get(&tid)
+            .and_then(|target| self.queries.get_mut(target))
+            .filter(|query| query.inflight(tid))
+    }
+
+    // === Public Methods ===
+
+    /// Add a query, replacing the inflight query to the same target, if any.
+    pub fn insert(&mut self, query: IterativeQuery) {
+        let target = query.target();
+
+        self.owners
+            .extend(query.inflight_requests().map(|tid| (tid, target)));
+        self.queries.insert(target, query);
+    }
+
+    /// Remove the query to this target, returning it if it was inflight.
+    pub fn remove(&mut self, target: &Id) -> Option<IterativeQuery> {
+        let query = self.queries.remove(target)?;
+
+        for tid in query.inflight_requests() {
+            self.owners.remove(&tid);
+        }
+
+        Some(query)
+    }
+
+    /// Move every query to `socket`, see [IterativeQuery::rebind].
+    pub fn rebind(&mut self, socket: &mut KrpcSocket) {
+        self.owners.clear();
+
+        for (target, query) in self.queries.iter_mut() {
+            query.rebind(socket);
+
+            self.owners
+                .extend(query.inflight_requests().map(|tid| (tid, *target)));
+        }
+    }
+
+    /// Tick every query, see [IterativeQuery::tick], and return the targets
+    /// of the queries that are done, with the reason why.
+    ///
+    /// Done queries are kept until [removed](Self::remove).
+    pub fn tick(&mut self, socket: &mut KrpcSocket) -> Vec<(Id, TickResult)> {
+        let mut done = Vec::new();
+
+        // Transaction ids sent by this tick replace the timed out ones.
+        self.owners.clear();
+
+        for (target, query) in self.queries.iter_mut() {
+            let result = query.tick(socket);
+
+            if result != TickResult::InProgress {
+                done.push((*target, result));
+            }
+
+            self.owners
+                .extend(query.inflight_requests().map(|tid| (tid, *target)));
+        }
+
+        done
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use crate::common::{FindNodeRequestArguments, Node};
+    use crate::rpc::{GetRequestSpecific, VisitOutcome, DEFAULT_QUERY_DEADLINE};
+
+    use super::*;
+
+    fn find_node_query() -> IterativeQuery {
+        let target = Id::random();
+
+        IterativeQuery::new(
+            Id::random(),
+            target,
+            GetRequestSpecific::FindNode(FindNodeRequestArguments { target }),
+            DEFAULT_QUERY_DEADLINE,
+        )
+    }
+
+    #[test]
+    fn route_by_tid() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let mut queries = QuerySet::new();
+
+        let mut first = find_node_query();
+        let first_target = first.target();
+        let VisitOutcome::Sent(first_tid) =
+            first.visit(&mut socket, SocketAddr::from(([127, 0, 0, 1], 1)))
+        else {
+            panic!("should send the request");
+        };
+        queries.insert(first);
+
+        let mut second = find_node_query();
+        let second_target = second.target();
+        second.add_candidate(Node::new(
+            Id::random(),
+            SocketAddr::from(([127, 0, 0, 2], 1)),
+        ));
+        queries.insert(second);
+
+        assert_eq!(queries.len(), 2);
+        assert_eq!(queries.owner_mut(first_tid).unwrap().target(), first_target);
+
+        // The second query sends its request on its first tick.
+        assert!(queries.tick(&mut socket).is_empty());
+        let second_tid = queries
+            .get(&second_target)
+            .unwrap()
+            .inflight_requests()
+            .next()
+            .unwrap();
+        assert_eq!(
+            queries.owner_mut(second_tid).unwrap().target(),
+            second_target
+        );
+        assert_eq!(queries.owner_mut(first_tid).unwrap().target(), first_target);
+
+        // Cancelled queries don't own their transaction ids anymore.
+        queries.get_mut(&first_target).unwrap().cancel(&mut socket);
+        assert!(!queries.owns(first_tid));
+        assert_eq!(
+            queries.tick(&mut socket),
+            vec![(first_target, TickResult::Cancelled)]
+        );
+
+        assert!(queries.remove(&first_target).is_some());
+        assert!(!queries.contains(&first_target));
+        assert!(queries.owns(second_tid));
+    }
+}
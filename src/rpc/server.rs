@@ -15,7 +15,8 @@ use crate::common::{
     GetPeersRequestArguments, GetPeersResponseArguments, GetValueRequestArguments, Id, MutableItem,
     NoMoreRecentValueResponseArguments, NoValuesResponseArguments, PingResponseArguments,
     PutImmutableRequestArguments, PutMutableRequestArguments, PutRequest, PutRequestSpecific,
-    RequestTypeSpecific, ResponseSpecific, RoutingTable,
+    RequestTypeSpecific, ResponseSpecific, RoutingTable, SampleInfohashesRequestArguments,
+    SampleInfohashesResponseArguments,
 };
 
 use peers::PeersStore;
@@ -29,6 +30,19 @@ pub const MAX_INFO_HASHES: usize = 2000;
 pub const MAX_PEERS: usize = 500;
 /// Default maximum number of Immutable and Mutable items to store.
 pub const MAX_VALUES: usize = 1000;
+/// Default maximum number of nodes returned in a response, as recommended by
+/// [BEP_0005](https://www.bittorrent.org/beps/bep_0005.html).
+pub const DEFAULT_MAX_RESPONSE_NODES: usize = 8;
+/// Upper bound of [ServerSettings::max_response_nodes], keeping the compact nodes
+/// of a response (26 bytes each) around 1000 bytes, so the whole packet stays
+/// under ~1400 bytes and avoids UDP fragmentation.
+pub const MAX_RESPONSE_NODES: usize = 40;
+/// Maximum number of info hashes sampled in a `sample_infohashes` response,
+/// 20 bytes each on top of the closest nodes.
+pub const MAX_SAMPLES: usize = 20;
+/// Seconds requesters are asked to wait before sampling this node again,
+/// which is the maximum allowed by [BEP_0051](https://www.bittorrent.org/beps/bep_0051.html).
+pub const SAMPLE_INTERVAL: u32 = 6 * 60 * 60;
 
 /// A trait for filtering incoming requests to a DHT node and
 /// decide whether to allow handling it or rate limit or ban
@@ -66,6 +80,8 @@ pub struct Server {
     mutable_values: LruCache<Id, MutableItem>,
     /// Filter requests before handling them.
     filter: Box<dyn RequestFilter>,
+    /// Maximum number of closest nodes returned in responses.
+    max_response_nodes: usize,
 }
 
 impl Default for Server {
@@ -93,6 +109,11 @@ pub struct ServerSettings {
     ///
     /// Defaults to [MAX_VALUES]
     pub max_mutable_values: usize,
+    /// Maximum number of closest nodes to return in responses, clamped
+    /// between 1 and [MAX_RESPONSE_NODES].
+    ///
+    /// Defaults to [DEFAULT_MAX_RESPONSE_NODES]
+    pub max_response_nodes: usize,
     /// Filter requests before handling them.
     ///
     /// Defaults to a function that always returns true.
@@ -106,6 +127,7 @@ impl Default for ServerSettings {
             max_peers_per_info_hash: MAX_PEERS,
             max_mutable_values: MAX_VALUES,
             max_immutable_values: MAX_VALUES,
+            max_response_nodes: DEFAULT_MAX_RESPONSE_NODES,
 
             filter: Box::new(DefaultFilter),
         }
@@ -136,6 +158,7 @@ impl Server {
                     .unwrap_or(NonZeroUsize::new(MAX_VALUES).expect("MAX_VALUES is NonZeroUsize")),
             ),
             filter: settings.filter,
+            max_response_nodes: settings.max_response_nodes.clamp(1, MAX_RESPONSE_NODES),
         }
     }
 
@@ -168,7 +191,7 @@ impl Server {
             RequestTypeSpecific::FindNode(FindNodeRequestArguments { target, .. }) => {
                 MessageType::Response(ResponseSpecific::FindNode(FindNodeResponseArguments {
                     responder_id: *routing_table.id(),
-                    nodes: routing_table.closest(target),
+                    nodes: routing_table.closest_n(target, self.max_response_nodes),
                 }))
             }
             RequestTypeSpecific::GetPeers(GetPeersRequestArguments { info_hash, .. }) => {
@@ -176,16 +199,33 @@ impl Server {
                     Some(peers) => ResponseSpecific::GetPeers(GetPeersResponseArguments {
                         responder_id: *routing_table.id(),
                         token: self.tokens.generate_token(from).into(),
-                        nodes: Some(routing_table.closest(info_hash)),
+                        nodes: Some(routing_table.closest_n(info_hash, self.max_response_nodes)),
                         values: peers,
+                        bf_seeders: None,
+                        bf_peers: None,
                     }),
                     None => ResponseSpecific::NoValues(NoValuesResponseArguments {
                         responder_id: *routing_table.id(),
                         token: self.tokens.generate_token(from).into(),
-                        nodes: Some(routing_table.closest(info_hash)),
+                        nodes: Some(routing_table.closest_n(info_hash, self.max_response_nodes)),
+                        bf_seeders: None,
+                        bf_peers: None,
                     }),
                 })
             }
+            RequestTypeSpecific::SampleInfohashes(SampleInfohashesRequestArguments { target }) => {
+                let (samples, num) = self.peers.sample_info_hashes(MAX_SAMPLES);
+
+                MessageType::Response(ResponseSpecific::SampleInfohashes(
+                    SampleInfohashesResponseArguments {
+                        responder_id: *routing_table.id(),
+                        interval: SAMPLE_INTERVAL,
+                        nodes: Some(routing_table.closest_n(target, self.max_response_nodes)),
+                        num: num.try_into().unwrap_or(u32::MAX),
+                        samples: samples.into(),
+                    },
+                ))
+            }
             RequestTypeSpecific::GetValue(GetValueRequestArguments { target, seq, .. }) => {
                 if seq.is_some() {
                     MessageType::Response(self.handle_get_mutable(routing_table, from, target, seq))
@@ -194,7 +234,7 @@ impl Server {
                         GetImmutableResponseArguments {
                             responder_id: *routing_table.id(),
                             token: self.tokens.generate_token(from).into(),
-                            nodes: Some(routing_table.closest(target)),
+                            nodes: Some(routing_table.closest_n(target, self.max_response_nodes)),
                             v: v.clone(),
                         },
                     ))
@@ -396,14 +436,14 @@ impl Server {
                         ResponseSpecific::NoMoreRecentValue(NoMoreRecentValueResponseArguments {
                             responder_id: *routing_table.id(),
                             token: self.tokens.generate_token(from).into(),
-                            nodes: Some(routing_table.closest(target)),
+                            nodes: Some(routing_table.closest_n(target, self.max_response_nodes)),
                             seq: item.seq(),
                         })
                     }
                     _ => ResponseSpecific::GetMutable(GetMutableResponseArguments {
                         responder_id: *routing_table.id(),
                         token: self.tokens.generate_token(from).into(),
-                        nodes: Some(routing_table.closest(target)),
+                        nodes: Some(routing_table.closest_n(target, self.max_response_nodes)),
                         v: item.value().into(),
                         k: *item.key(),
                         seq: item.seq(),
@@ -414,8 +454,73 @@ impl Server {
             None => ResponseSpecific::NoValues(NoValuesResponseArguments {
                 responder_id: *routing_table.id(),
                 token: self.tokens.generate_token(from).into(),
-                nodes: Some(routing_table.closest(target)),
+                nodes: Some(routing_table.closest_n(target, self.max_response_nodes)),
+                bf_seeders: None,
+                bf_peers: None,
             }),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use crate::common::{Message, Node};
+
+    use super::*;
+
+    fn find_node_response(server: &mut Server, routing_table: &RoutingTable) -> Message {
+        let message_type = server
+            .handle_request(
+                routing_table,
+                SocketAddrV4::new([127, 0, 0, 1].into(), 6881),
+                RequestSpecific {
+                    requester_id: Id::random(),
+                    request_type: RequestTypeSpecific::FindNode(FindNodeRequestArguments {
+                        target: Id::random(),
+                    }),
+                    want: Vec::new(),
+                },
+            )
+            .unwrap();
+
+        Message {
+            transaction_id: 0,
+            version: None,
+            requester_ip: None,
+            read_only: false,
+            message_type,
+        }
+    }
+
+    #[test]
+    fn max_response_nodes() {
+        let mut routing_table = RoutingTable::new(Id::random());
+        for i in 0..500 {
+            routing_table.add(Node::new(
+                Id::random(),
+                SocketAddr::from(([10, (i >> 8) as u8, i as u8, 1], 6881)),
+            ));
+        }
+        assert!(routing_table.size() > MAX_RESPONSE_NODES);
+
+        let mut server = Server::default();
+        let response = find_node_response(&mut server, &routing_table);
+        assert_eq!(
+            response.get_closer_nodes().unwrap().len(),
+            DEFAULT_MAX_RESPONSE_NODES
+        );
+
+        let mut server = Server::new(ServerSettings {
+            max_response_nodes: usize::MAX,
+            ..Default::default()
+        });
+        let response = find_node_response(&mut server, &routing_table);
+        assert_eq!(
+            response.get_closer_nodes().unwrap().len(),
+            MAX_RESPONSE_NODES
+        );
+        assert!(response.to_bytes().unwrap().len() < 1400);
+    }
+}
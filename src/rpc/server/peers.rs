@@ -1,6 +1,11 @@
 //! Manage announced peers for info_hashes
 
-use std::{net::SocketAddrV4, num::NonZeroUsize};
+use std::{
+    cmp::Reverse,
+    net::SocketAddrV4,
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
 
 use crate::common::Id;
 
@@ -9,13 +14,17 @@ use lru::LruCache;
 
 const CHANCE_SCALE: f32 = 2.0 * (1u32 << 31) as f32;
 
+/// Duration after which an announced peer expires, unless it announces again.
+pub const PEERS_TTL: Duration = Duration::from_secs(15 * 60);
+
 #[derive(Debug, Clone)]
 /// An LRU cache of "Peers" per info hashes.
 ///
 /// Read [BEP_0005](https://www.bittorrent.org/beps/bep_0005.html) for more information.
 pub struct PeersStore {
-    info_hashes: LruCache<Id, LruCache<Id, SocketAddrV4>>,
+    info_hashes: LruCache<Id, LruCache<Id, (SocketAddrV4, Instant)>>,
     max_peers: NonZeroUsize,
+    ttl: Duration,
 }
 
 impl PeersStore {
@@ -24,22 +33,29 @@ impl PeersStore {
         Self {
             info_hashes: LruCache::new(max_info_hashes),
             max_peers,
+            ttl: PEERS_TTL,
         }
     }
 
     /// Add a peer for an info hash.
     pub fn add_peer(&mut self, info_hash: Id, peer: (&Id, SocketAddrV4)) {
+        let entry = (peer.1, Instant::now());
+
         if let Some(info_hash_lru) = self.info_hashes.get_mut(&info_hash) {
-            info_hash_lru.put(*peer.0, peer.1);
+            info_hash_lru.put(*peer.0, entry);
         } else {
             let mut info_hash_lru = LruCache::new(self.max_peers);
-            info_hash_lru.put(*peer.0, peer.1);
+            info_hash_lru.put(*peer.0, entry);
             self.info_hashes.put(info_hash, info_hash_lru);
         };
     }
 
-    /// Returns a random set of peers per an info hash.
+    /// Returns a random set of peers per an info hash, most recently announced first.
+    ///
+    /// Expired peers are removed first.
     pub fn get_random_peers(&mut self, info_hash: &Id) -> Option<Vec<SocketAddrV4>> {
+        self.remove_expired(info_hash);
+
         if let Some(info_hash_lru) = self.info_hashes.get(info_hash) {
             let size = info_hash_lru.len();
             let target_size = 20;
@@ -48,12 +64,9 @@ impl PeersStore {
                 return None;
             }
             if size < target_size {
-                return Some(
-                    info_hash_lru
-                        .iter()
-                        .map(|n| n.1.to_owned())
-                        .collect::<Vec<_>>(),
-                );
+                return Some(newest_first(
+                    info_hash_lru.iter().map(|(_, entry)| *entry).collect(),
+                ));
             }
 
             let mut results = Vec::with_capacity(20);
@@ -61,7 +74,7 @@ impl PeersStore {
             let mut chunk = vec![0_u8; info_hash_lru.iter().len() * 4];
             getrandom(chunk.as_mut_slice()).expect("getrandom");
 
-            for (index, (_, addr)) in info_hash_lru.iter().enumerate() {
+            for (index, (_, entry)) in info_hash_lru.iter().enumerate() {
                 // Calculate the chance of adding the current item based on remaining items and slots
                 let remaining_slots = target_size - results.len();
                 let remaining_items = info_hash_lru.len() - index;
@@ -74,18 +87,73 @@ impl PeersStore {
 
                 // Randomly decide to add the item based on the current chance
                 if rand_int < current_chance {
-                    results.push(*addr);
+                    results.push(*entry);
                     if results.len() == target_size {
                         break;
                     }
                 }
             }
 
-            return Some(results);
+            return Some(newest_first(results));
         }
 
         None
     }
+
+    /// Returns up to `max` random info hashes this store has peers for,
+    /// and the total number of info hashes stored.
+    ///
+    /// Read [BEP_0051](https://www.bittorrent.org/beps/bep_0051.html) for more information.
+    pub fn sample_info_hashes(&self, max: usize) -> (Vec<Id>, usize) {
+        let mut info_hashes = self
+            .info_hashes
+            .iter()
+            .map(|(info_hash, _)| *info_hash)
+            .collect::<Vec<_>>();
+        let num = info_hashes.len();
+
+        if num > max {
+            let mut chunk = vec![0_u8; max * 4];
+            getrandom(chunk.as_mut_slice()).expect("getrandom");
+
+            // Partial Fisher-Yates shuffle of the first `max` info hashes.
+            for (i, bytes) in chunk.chunks_exact(4).enumerate() {
+                let rand_int = u32::from_le_bytes(bytes.try_into().expect("infallible")) as usize;
+
+                info_hashes.swap(i, i + rand_int % (num - i));
+            }
+
+            info_hashes.truncate(max);
+        }
+
+        (info_hashes, num)
+    }
+
+    fn remove_expired(&mut self, info_hash: &Id) {
+        if let Some(info_hash_lru) = self.info_hashes.peek_mut(info_hash) {
+            let expired = info_hash_lru
+                .iter()
+                .filter(|(_, (_, announced_at))| announced_at.elapsed() > self.ttl)
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>();
+
+            for id in expired {
+                info_hash_lru.pop(&id);
+            }
+
+            if info_hash_lru.is_empty() {
+                self.info_hashes.pop(info_hash);
+            }
+        }
+    }
+}
+
+/// Sort peers by their announce time, most recent first.
+fn newest_first(mut peers: Vec<(SocketAddrV4, Instant)>) -> Vec<SocketAddrV4> {
+    // Stable, so peers announced at the same instant keep their LRU order.
+    peers.sort_by_key(|(_, announced_at)| Reverse(*announced_at));
+
+    peers.into_iter().map(|(address, _)| address).collect()
 }
 
 #[cfg(test)]
@@ -152,6 +220,38 @@ mod test {
         );
     }
 
+    #[test]
+    fn sample_info_hashes() {
+        let mut store = PeersStore::new(
+            NonZeroUsize::new(100).unwrap(),
+            NonZeroUsize::new(1).unwrap(),
+        );
+        assert_eq!(store.sample_info_hashes(20), (vec![], 0));
+
+        let info_hashes = (0..50).map(|_| Id::random()).collect::<Vec<_>>();
+        for info_hash in &info_hashes {
+            store.add_peer(
+                *info_hash,
+                (info_hash, SocketAddrV4::new([127, 0, 1, 1].into(), 1)),
+            );
+        }
+
+        let (samples, num) = store.sample_info_hashes(20);
+        assert_eq!(num, 50);
+        assert_eq!(samples.len(), 20);
+        assert!(samples.iter().all(|sample| info_hashes.contains(sample)));
+        assert_eq!(
+            samples
+                .iter()
+                .collect::<std::collections::HashSet<_>>()
+                .len(),
+            20
+        );
+
+        let (samples, num) = store.sample_info_hashes(100);
+        assert_eq!((samples.len(), num), (50, 50));
+    }
+
     #[test]
     fn random_peers_subset() {
         let mut store = PeersStore::new(
@@ -174,4 +274,71 @@ mod test {
 
         assert_eq!(sample.len(), 20);
     }
+
+    #[test]
+    fn newest_peers_first() {
+        let mut store = PeersStore::new(
+            NonZeroUsize::new(1).unwrap(),
+            NonZeroUsize::new(200).unwrap(),
+        );
+
+        let info_hash = Id::random();
+        let ids = (0..100).map(|_| Id::random()).collect::<Vec<_>>();
+
+        for (i, id) in ids.iter().enumerate() {
+            store.add_peer(
+                info_hash,
+                (id, SocketAddrV4::new([127, 0, 1, i as u8].into(), 0)),
+            );
+        }
+        // Announcing again makes a peer the most recent.
+        store.add_peer(
+            info_hash,
+            (&ids[0], SocketAddrV4::new([127, 0, 1, 0].into(), 0)),
+        );
+
+        let recency = |peer: &SocketAddrV4| match peer.ip().octets()[3] {
+            0 => 100,
+            i => i,
+        };
+
+        for _ in 0..10 {
+            let peers = store.get_random_peers(&info_hash).unwrap();
+
+            assert!(peers
+                .windows(2)
+                .all(|pair| recency(&pair[0]) > recency(&pair[1])));
+        }
+    }
+
+    #[test]
+    fn expired_peers() {
+        let mut store = PeersStore::new(
+            NonZeroUsize::new(1).unwrap(),
+            NonZeroUsize::new(100).unwrap(),
+        );
+        store.ttl = Duration::from_millis(10);
+
+        let info_hash = Id::random();
+
+        store.add_peer(
+            info_hash,
+            (&Id::random(), SocketAddrV4::new([127, 0, 1, 1].into(), 0)),
+        );
+        std::thread::sleep(Duration::from_millis(20));
+        store.add_peer(
+            info_hash,
+            (&Id::random(), SocketAddrV4::new([127, 0, 1, 2].into(), 0)),
+        );
+
+        assert_eq!(
+            store.get_random_peers(&info_hash),
+            Some([SocketAddrV4::new([127, 0, 1, 2].into(), 0)].into())
+        );
+
+        std::thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(store.get_random_peers(&info_hash), None);
+        assert!(store.info_hashes.is_empty());
+    }
 }
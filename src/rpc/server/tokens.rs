@@ -1,27 +1,39 @@
 //! Manage tokens for remote client IPs.
 
-use crc::{Crc, CRC_32_ISCSI};
 use getrandom::getrandom;
+use sha1_smol::Sha1;
 use std::{
     fmt::{self, Debug, Formatter},
     net::SocketAddrV4,
+    sync::Arc,
     time::Instant,
 };
 
 use tracing::trace;
 
+use crate::common::{Clock, SystemClock};
+
 const SECRET_SIZE: usize = 20;
-const TOKEN_SIZE: usize = 4;
-const CASTAGNOLI: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+/// One byte of nonce, followed by the first 7 bytes of the SHA-1 of the secret, ip and nonce.
+const TOKEN_SIZE: usize = 8;
 
 /// Tokens generator.
 ///
+/// Each token starts with a nonce, incremented on every [rotation](Tokens::rotate),
+/// followed by a hash of the current secret, the requester's ip and the nonce,
+/// so it is only valid from the same ip, until the secret is rotated twice.
+/// Unlike a checksum, the hash doesn't let a token issued to one ip be turned
+/// into a valid token for another ip.
+///
 /// Read [BEP_0005](https://www.bittorrent.org/beps/bep_0005.html) for more information.
 #[derive(Clone)]
 pub struct Tokens {
     prev_secret: [u8; SECRET_SIZE],
     curr_secret: [u8; SECRET_SIZE],
+    /// Nonce of tokens generated with the current secret, the previous secret's is one less.
+    nonce: u8,
     last_updated: Instant,
+    clock: Arc<dyn Clock>,
 }
 
 impl Debug for Tokens {
@@ -33,10 +45,17 @@ impl Debug for Tokens {
 impl Tokens {
     /// Create a Tokens generator.
     pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+
+    /// Create a Tokens generator rotating its secrets by this [Clock].
+    pub(crate) fn with_clock(clock: Arc<dyn Clock>) -> Self {
         Tokens {
             prev_secret: random(),
             curr_secret: random(),
-            last_updated: Instant::now(),
+            nonce: 0,
+            last_updated: clock.now(),
+            clock,
         }
     }
 
@@ -44,15 +63,28 @@ impl Tokens {
 
     /// Returns `true` if the current secret needs to be updated after an interval.
     pub fn should_update(&self) -> bool {
-        self.last_updated.elapsed() > crate::common::TOKEN_ROTATE_INTERVAL
+        self.clock
+            .now()
+            .saturating_duration_since(self.last_updated)
+            > crate::common::TOKEN_ROTATE_INTERVAL
     }
 
-    /// Validate that the token was generated within the past 10 minutes
+    /// Validate that the token was generated for this address's ip, with the
+    /// current or previous nonce, so within the past 10 minutes.
     pub fn validate(&mut self, address: SocketAddrV4, token: &[u8]) -> bool {
-        let prev = self.internal_generate_token(address, self.prev_secret);
-        let curr = self.internal_generate_token(address, self.curr_secret);
-
-        token == curr || token == prev
+        let Some(&nonce) = token.first() else {
+            return false;
+        };
+
+        let secret = if nonce == self.nonce {
+            self.curr_secret
+        } else if nonce == self.nonce.wrapping_sub(1) {
+            self.prev_secret
+        } else {
+            return false;
+        };
+
+        token == self.internal_generate_token(address, secret, nonce)
     }
 
     /// Rotate the tokens secret.
@@ -61,13 +93,14 @@ impl Tokens {
 
         self.prev_secret = self.curr_secret;
         self.curr_secret = random();
+        self.nonce = self.nonce.wrapping_add(1);
 
-        self.last_updated = Instant::now();
+        self.last_updated = self.clock.now();
     }
 
     /// Generates a new token for a remote peer.
-    pub fn generate_token(&mut self, address: SocketAddrV4) -> [u8; 4] {
-        self.internal_generate_token(address, self.curr_secret)
+    pub fn generate_token(&mut self, address: SocketAddrV4) -> [u8; TOKEN_SIZE] {
+        self.internal_generate_token(address, self.curr_secret, self.nonce)
     }
 
     // === Private Methods ===
@@ -76,17 +109,19 @@ impl Tokens {
         &mut self,
         address: SocketAddrV4,
         secret: [u8; SECRET_SIZE],
+        nonce: u8,
     ) -> [u8; TOKEN_SIZE] {
-        let mut digest = CASTAGNOLI.digest();
+        let mut hasher = Sha1::new();
 
-        let octets: Box<[u8]> = address.ip().octets().into();
+        hasher.update(&secret);
+        hasher.update(&address.ip().octets());
+        hasher.update(&[nonce]);
 
-        digest.update(&octets);
-        digest.update(&secret);
+        let mut token = [0; TOKEN_SIZE];
+        token[0] = nonce;
+        token[1..].copy_from_slice(&hasher.digest().bytes()[..TOKEN_SIZE - 1]);
 
-        let checksum = digest.finalize();
-
-        checksum.to_be_bytes()
+        token
     }
 }
 
@@ -105,6 +140,9 @@ fn random() -> [u8; SECRET_SIZE] {
 
 #[cfg(test)]
 mod test {
+    use std::time::Duration;
+
+    use crate::common::MockClock;
 
     use super::*;
 
@@ -117,4 +155,70 @@ mod test {
 
         assert!(tokens.validate(address, &token))
     }
+
+    #[test]
+    fn expired_tokens() {
+        let mut tokens = Tokens::new();
+
+        let address = SocketAddrV4::new([127, 0, 0, 1].into(), 6881);
+        let token = tokens.generate_token(address);
+
+        // Previous secret is still accepted.
+        tokens.rotate();
+        assert!(tokens.validate(address, &token));
+
+        tokens.rotate();
+        assert!(!tokens.validate(address, &token));
+    }
+
+    #[test]
+    fn should_update() {
+        let clock = MockClock::new();
+        let mut tokens = Tokens::with_clock(Arc::new(clock.clone()));
+
+        assert!(!tokens.should_update());
+
+        clock.advance(crate::common::TOKEN_ROTATE_INTERVAL + Duration::from_secs(1));
+        assert!(tokens.should_update());
+
+        tokens.rotate();
+        assert!(!tokens.should_update());
+    }
+
+    #[test]
+    fn mismatched_ip() {
+        let mut tokens = Tokens::new();
+
+        let address = SocketAddrV4::new([127, 0, 0, 1].into(), 6881);
+        let token = tokens.generate_token(address);
+
+        // Tokens are bound to the ip, not the port.
+        assert!(tokens.validate(SocketAddrV4::new([127, 0, 0, 1].into(), 1), &token));
+        assert!(!tokens.validate(SocketAddrV4::new([127, 0, 0, 2].into(), 6881), &token));
+    }
+
+    #[test]
+    fn replayed_tokens() {
+        let mut tokens = Tokens::new();
+
+        let a = SocketAddrV4::new([1, 1, 1, 1].into(), 6881);
+        let b = SocketAddrV4::new([2, 2, 2, 2].into(), 6881);
+        let token = tokens.generate_token(a);
+
+        // Replayed from another ip.
+        assert!(!tokens.validate(b, &token));
+
+        // With a nonce that isn't current or previous, even for the right ip.
+        let mut future = token;
+        future[0] = future[0].wrapping_add(1);
+        assert!(!tokens.validate(a, &future));
+
+        // Long after issuance, once its nonce expired.
+        tokens.rotate();
+        tokens.rotate();
+        assert_eq!(token[0], tokens.nonce.wrapping_sub(2));
+        assert!(!tokens.validate(a, &token));
+
+        assert!(!tokens.validate(a, &[]));
+    }
 }
@@ -1,11 +1,18 @@
 //! UDP socket layer managing incoming/outgoing requests and responses.
 
+use lru::LruCache;
 use std::cmp::Ordering;
-use std::net::{SocketAddr, SocketAddrV4, UdpSocket};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tracing::{debug, error, trace};
+use tracing::{debug, trace, warn};
 
-use crate::common::{ErrorSpecific, Message, MessageType, RequestSpecific, ResponseSpecific};
+use crate::common::{
+    Clock, ErrorSpecific, Id, Message, MessageType, RequestSpecific, RequestTypeSpecific,
+    ResponseSpecific, SystemClock,
+};
 
 use super::config::Config;
 
@@ -16,17 +23,73 @@ pub const DEFAULT_PORT: u16 = 6881;
 /// Default request timeout before abandoning an inflight request to a non-responding node.
 pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_millis(2000); // 2 seconds
 pub const READ_TIMEOUT: Duration = Duration::from_millis(10);
+/// Default duration a request is remembered after its response, to recognize duplicates of that response.
+pub const DEFAULT_DUPLICATE_RESPONSE_WINDOW: Duration = Duration::from_secs(10);
+/// Default size of the OS receive buffer requested for the socket, see [crate::DhtBuilder::recv_buffer_size].
+pub const DEFAULT_RECV_BUFFER_SIZE: usize = 2 * 1024 * 1024;
+/// Default maximum size of a sent datagram, under common path MTUs so it isn't fragmented,
+/// see [crate::DhtBuilder::max_datagram_size].
+pub const DEFAULT_MAX_DATAGRAM_SIZE: usize = 1400;
+/// Maximum number of completed requests to recognize duplicate responses to.
+const COMPLETED_REQUESTS_CACHE_SIZE: usize = 1024;
+/// Maximum number of addresses to count protocol errors for.
+const PROTOCOL_ERRORS_CACHE_SIZE: usize = 1024;
+/// Maximum number of addresses to remember the first seen node Id of.
+const NODE_IDS_CACHE_SIZE: usize = 4096;
+/// Duration after which a node at a known address may respond with a new Id,
+/// for example after regenerating it for a new public ip.
+const NODE_ID_TTL: Duration = Duration::from_secs(15 * 60);
+/// Number of most recent external address reports to vote over.
+const EXTERNAL_ADDRESS_VOTES: usize = 20;
+/// Minimum number of agreeing reports before trusting an external address.
+const MIN_EXTERNAL_ADDRESS_VOTES: usize = 3;
+/// Lower bound of the adaptive request timeout, see [KrpcSocket::current_timeout].
+const MIN_REQUEST_TIMEOUT: Duration = Duration::from_millis(500);
 
 /// A UdpSocket wrapper that formats and correlates DHT requests and responses.
 #[derive(Debug)]
 pub struct KrpcSocket {
     next_tid: u16,
     socket: UdpSocket,
+    /// Optional Ipv6 socket, sending and receiving the messages of Ipv6 addresses.
+    socket6: Option<UdpSocket>,
+    /// Sockets owned by single queries, by their local port, see [Self::with_ephemeral_ports].
+    ephemeral: Vec<(u16, UdpSocket)>,
+    max_ephemeral: usize,
     pub(crate) server_mode: bool,
+    /// Maximum request timeout, see [Self::current_timeout].
     request_timeout: Duration,
+    rtt_estimator: RttEstimator,
     /// We don't need a HashMap, since we know the capacity is `65536` requests.
     /// Requests are also ordered by their transaction_id and thus sent_at, so lookup is fast.
     inflight_requests: Vec<InflightRequest>,
+    rate_limiter: Option<RateLimiter>,
+    /// See [Config::max_inflight_per_node].
+    max_inflight_per_node: Option<NonZeroUsize>,
+    /// See [Config::max_datagram_size].
+    max_datagram_size: usize,
+    /// Round trip time of the response last returned from [Self::recv_from].
+    last_rtt: Option<Duration>,
+    /// Ips we neither send requests to, nor accept messages from.
+    blacklist: HashSet<IpAddr>,
+    /// Count of malformed messages received per ip, when auto banning is enabled.
+    protocol_errors: Option<(LruCache<IpAddr, u32>, NonZeroU32)>,
+    /// First seen node Id, and when it was seen, of each address that responded to us.
+    node_ids: LruCache<SocketAddr, (Id, Instant)>,
+    /// Address and completion time of recently answered requests, by transaction_id.
+    completed_requests: LruCache<u16, (SocketAddr, Instant)>,
+    /// See [Config::duplicate_response_window].
+    duplicate_response_window: Duration,
+    /// Our address as reported in the `ip` field of the most recent responses.
+    external_address_votes: VecDeque<SocketAddrV4>,
+    metrics: SocketMetrics,
+    /// Whether to drop messages with malformed optional fields, see [Self::with_strict].
+    strict: bool,
+    /// Whether to share inflight requests, see [Self::with_coalescing].
+    coalescing: bool,
+    /// Whether [Self::shutdown] was called.
+    shut_down: bool,
+    clock: Arc<dyn Clock>,
 
     local_addr: SocketAddrV4,
 }
@@ -34,37 +97,98 @@ pub struct KrpcSocket {
 #[derive(Debug)]
 pub struct InflightRequest {
     tid: u16,
-    to: SocketAddrV4,
+    to: SocketAddr,
     sent_at: Instant,
+    /// Local port of the ephemeral socket this request was sent from, if any.
+    via: Option<u16>,
+    /// The request itself, kept to coalesce identical requests, see [KrpcSocket::with_coalescing].
+    request: Option<RequestSpecific>,
 }
 
 impl KrpcSocket {
     pub(crate) fn new(config: &Config) -> Result<Self, std::io::Error> {
-        let request_timeout = config.request_timeout;
-        let port = config.port;
+        let ip = config.bind_ip.unwrap_or(Ipv4Addr::UNSPECIFIED);
+        let v6 = config.bind_address_v6.map(SocketAddr::from);
 
-        let socket = if let Some(port) = port {
-            UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], port)))?
+        if let Some(port) = config.port {
+            Self::bind(config, (ip, port).into(), v6)
         } else {
-            match UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT))) {
+            match Self::bind(config, (ip, DEFAULT_PORT).into(), v6) {
                 Ok(socket) => Ok(socket),
-                Err(_) => UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))),
-            }?
-        };
+                Err(_) => Self::bind(config, (ip, 0).into(), v6),
+            }
+        }
+    }
+
+    /// Bind the Ipv4 socket to `v4`, and optionally an Ipv6 socket to `v6`.
+    ///
+    /// Returns an [std::io::ErrorKind::InvalidInput] error if either address
+    /// is not of the expected family.
+    pub(crate) fn bind(
+        config: &Config,
+        v4: SocketAddr,
+        v6: Option<SocketAddr>,
+    ) -> Result<Self, std::io::Error> {
+        if !v4.is_ipv4() || v6.is_some_and(|v6| !v6.is_ipv6()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "KrpcSocket expects an Ipv4 and an optional Ipv6 bind address",
+            ));
+        }
+
+        let socket = UdpSocket::bind(v4)?;
 
         let local_addr = match socket.local_addr()? {
             SocketAddr::V4(addr) => addr,
-            SocketAddr::V6(_) => unimplemented!("KrpcSocket does not support Ipv6"),
+            SocketAddr::V6(_) => unreachable!("bound to an Ipv4 address"),
         };
 
         socket.set_read_timeout(Some(READ_TIMEOUT))?;
 
+        // Polled before the Ipv4 socket, which blocks for the read timeout instead.
+        let socket6 = v6
+            .map(|address| {
+                let socket = UdpSocket::bind(address)?;
+                socket.set_nonblocking(true)?;
+
+                Ok::<_, std::io::Error>(socket)
+            })
+            .transpose()?;
+
         Ok(Self {
             socket,
+            socket6,
+            ephemeral: Vec::new(),
+            max_ephemeral: 0,
             next_tid: 0,
-            server_mode: config.server_mode,
-            request_timeout,
+            server_mode: config.server_mode && !config.read_only,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            rtt_estimator: RttEstimator::default(),
             inflight_requests: Vec::with_capacity(u16::MAX as usize),
+            rate_limiter: config.max_requests_per_sec.map(RateLimiter::new),
+            max_inflight_per_node: config.max_inflight_per_node,
+            max_datagram_size: config.max_datagram_size,
+            last_rtt: None,
+            blacklist: HashSet::new(),
+            protocol_errors: config.max_protocol_errors.map(|max| {
+                (
+                    LruCache::new(
+                        NonZeroUsize::new(PROTOCOL_ERRORS_CACHE_SIZE).expect("infallible"),
+                    ),
+                    max,
+                )
+            }),
+            node_ids: LruCache::new(NonZeroUsize::new(NODE_IDS_CACHE_SIZE).expect("infallible")),
+            completed_requests: LruCache::new(
+                NonZeroUsize::new(COMPLETED_REQUESTS_CACHE_SIZE).expect("infallible"),
+            ),
+            duplicate_response_window: config.duplicate_response_window,
+            external_address_votes: VecDeque::with_capacity(EXTERNAL_ADDRESS_VOTES),
+            metrics: SocketMetrics::default(),
+            strict: false,
+            coalescing: false,
+            shut_down: false,
+            clock: Arc::new(SystemClock),
 
             local_addr,
         })
@@ -83,6 +207,94 @@ impl KrpcSocket {
         Self::new(&Config::default())
     }
 
+    /// Allow up to `max` queries to send their requests from their own
+    /// [ephemeral socket](Self::open_ephemeral), instead of sharing this socket,
+    /// so the nodes they visit can't link them to each other or to this node.
+    ///
+    /// Each ephemeral socket costs a file descriptor for the lifetime of its query.
+    ///
+    /// Defaults to 0, where all requests are sent from this socket.
+    pub(crate) fn with_ephemeral_ports(mut self, max: usize) -> Self {
+        self.max_ephemeral = max;
+
+        self
+    }
+
+    /// Consider an inflight request timed out, and reap it from the inflight requests
+    /// on the next [Self::recv_from], once it waited `timeout` for a response.
+    ///
+    /// Shorter timeouts speed up lookups on lossy networks, at the cost of more
+    /// retransmissions. The effective timeout adapts to the round trip times of
+    /// received responses, but is never longer than `timeout`, see [Self::current_timeout].
+    ///
+    /// Defaults to [DEFAULT_REQUEST_TIMEOUT], see [Config::request_timeout].
+    pub(crate) fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+
+        self
+    }
+
+    /// Drop any received message that isn't strictly valid according to
+    /// [BEP_0005](https://www.bittorrent.org/beps/bep_0005.html), counting it
+    /// as [SocketMetrics::dropped_malformed].
+    ///
+    /// Defaults to false, where malformed optional fields are ignored,
+    /// see [Message::from_bytes_lenient].
+    pub(crate) fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+
+        self
+    }
+
+    /// Return the transaction_id of an identical request still inflight to the same address
+    /// (from the same local port) instead of sending another one, counting it as
+    /// [SocketMetrics::requests_coalesced].
+    ///
+    /// Whoever handles the single response must deliver it to everyone sharing its
+    /// transaction_id. Pings are never coalesced, since each is expected to
+    /// measure its own round trip.
+    ///
+    /// Defaults to false, see [Config::coalesce_requests].
+    pub(crate) fn with_coalescing(mut self, coalescing: bool) -> Self {
+        self.coalescing = coalescing;
+
+        self
+    }
+
+    /// Ask the OS for a receive buffer (`SO_RCVBUF`) of `bytes` for this socket,
+    /// so bursts of responses on a busy node wait in the buffer instead of
+    /// being silently dropped, stalling the queries waiting for them.
+    ///
+    /// The OS may not honor the exact size: Linux doubles the requested size
+    /// for its own bookkeeping, and caps it at `net.core.rmem_max`
+    /// (212992 bytes by default), which has to be raised to benefit from larger
+    /// buffers. See [Self::recv_buffer_size] for the effective size.
+    ///
+    /// Failing to set the size is logged and otherwise ignored, and is not
+    /// supported on non unix platforms.
+    pub(crate) fn with_recv_buffer(self, bytes: usize) -> Self {
+        match set_recv_buffer_size(&self.socket, bytes) {
+            Ok(()) => {
+                debug!(
+                    requested = bytes,
+                    effective = ?self.recv_buffer_size(),
+                    "Set socket receive buffer size"
+                );
+            }
+            Err(error) => warn!(?error, bytes, "Could not set socket receive buffer size"),
+        }
+
+        self
+    }
+
+    /// Use this [Clock] to time requests instead of the system clock.
+    #[cfg(test)]
+    pub(crate) fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+
+        self
+    }
+
     // === Getters ===
 
     /// Returns the address the server is listening to.
@@ -91,82 +303,397 @@ impl KrpcSocket {
         self.local_addr
     }
 
+    /// Returns the effective size of the OS receive buffer of this socket,
+    /// or `None` if it can't be read on this platform.
+    pub fn recv_buffer_size(&self) -> Option<usize> {
+        recv_buffer_size(&self.socket).ok()
+    }
+
+    /// Returns the address the Ipv6 socket is bound to, if any.
+    pub fn local_addr6(&self) -> Option<SocketAddrV6> {
+        match self.socket6.as_ref()?.local_addr().ok()? {
+            SocketAddr::V6(address) => Some(address),
+            SocketAddr::V4(_) => None,
+        }
+    }
+
+    /// Returns the round trip time of the response last returned from [Self::recv_from],
+    /// or None if the last message was not a response to one of our requests.
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.last_rtt
+    }
+
+    /// Returns the duration after which an inflight request is considered timed out.
+    ///
+    /// Estimated from the round trip times of recent responses as `srtt + 4 * rttvar`
+    /// (like TCP's retransmission timeout), clamped between [MIN_REQUEST_TIMEOUT]
+    /// and the configured request timeout, which is also used until a response is received.
+    pub fn current_timeout(&self) -> Duration {
+        match self.rtt_estimator.timeout() {
+            Some(timeout) => timeout.clamp(
+                MIN_REQUEST_TIMEOUT.min(self.request_timeout),
+                self.request_timeout,
+            ),
+            None => self.request_timeout,
+        }
+    }
+
+    /// Returns our external address, as reported by the majority of the most
+    /// recent responses (BEP_0042), or None if there is no such majority yet.
+    ///
+    /// Useful to learn the port mapped by a NAT.
+    pub fn external_address(&self) -> Option<SocketAddrV4> {
+        let mut votes: HashMap<SocketAddrV4, usize> = HashMap::new();
+
+        for address in &self.external_address_votes {
+            *votes.entry(*address).or_default() += 1;
+        }
+
+        votes
+            .into_iter()
+            .find(|(_, count)| {
+                *count >= MIN_EXTERNAL_ADDRESS_VOTES
+                    && *count * 2 > self.external_address_votes.len()
+            })
+            .map(|(address, _)| address)
+    }
+
+    /// Returns a snapshot of the counters of messages sent and received so far.
+    pub fn metrics(&self) -> SocketMetrics {
+        self.metrics
+    }
+
+    /// Returns true if requests to and messages from this address's ip are blocked.
+    pub fn is_blacklisted(&self, address: &SocketAddr) -> bool {
+        self.blacklist.contains(&address.ip())
+    }
+
+    /// Returns true if requests to this address can be sent from the `via` local port,
+    /// see [Self::request_via].
+    ///
+    /// Ipv6 addresses need an Ipv6 socket, and can't be sent to from
+    /// [ephemeral sockets](Self::open_ephemeral), which are all Ipv4.
+    pub fn can_send_to(&self, address: &SocketAddr, via: Option<u16>) -> bool {
+        address.is_ipv4() || (self.socket6.is_some() && via.is_none())
+    }
+
     // === Public Methods ===
 
+    /// Stop sending requests to, and accepting messages from, this ip.
+    pub fn blacklist(&mut self, ip: IpAddr) {
+        debug!(?ip, "Blacklisting ip");
+
+        self.blacklist.insert(ip);
+        self.inflight_requests
+            .retain(|request| request.to.ip() != ip);
+    }
+
+    /// Returns how long ago the request with this transaction_id was sent,
+    /// or None if it is not inflight anymore.
+    pub fn request_age(&self, transaction_id: u16) -> Option<Duration> {
+        let index = self.find_inflight(transaction_id).ok()?;
+
+        Some(
+            self.clock
+                .now()
+                .saturating_duration_since(self.inflight_requests[index].sent_at),
+        )
+    }
+
+    /// Stop waiting for a response to this transaction_id,
+    /// so a late response is ignored like an unexpected one.
+    pub fn cancel(&mut self, transaction_id: u16) {
+        if let Ok(index) = self.find_inflight(transaction_id) {
+            self.inflight_requests.remove(index);
+        }
+    }
+
     /// Returns true if this message's transaction_id is still inflight
     pub fn inflight(&self, transaction_id: &u16) -> bool {
+        self.find_inflight(*transaction_id).is_ok()
+    }
+
+    /// Bind a new ephemeral socket to send requests from with [Self::request_via],
+    /// and return its local port.
+    ///
+    /// Returns None if [the maximum](Self::with_ephemeral_ports) ephemeral sockets
+    /// are already open, or binding failed.
+    pub fn open_ephemeral(&mut self) -> Option<u16> {
+        if self.ephemeral.len() >= self.max_ephemeral {
+            return None;
+        }
+
+        let socket = UdpSocket::bind((*self.local_addr.ip(), 0))
+            .and_then(|socket| {
+                socket.set_nonblocking(true)?;
+                Ok(socket)
+            })
+            .map_err(|error| debug!(?error, "Failed to bind an ephemeral socket"))
+            .ok()?;
+        let port = socket.local_addr().ok()?.port();
+
+        self.ephemeral.push((port, socket));
+
+        Some(port)
+    }
+
+    /// Close the ephemeral socket bound to this local port,
+    /// and stop waiting for responses to the requests sent from it.
+    pub fn close_ephemeral(&mut self, port: u16) {
+        self.ephemeral.retain(|(local_port, _)| *local_port != port);
         self.inflight_requests
-            .binary_search_by(|request| request.tid.cmp(transaction_id))
-            .is_ok()
+            .retain(|request| request.via != Some(port));
+    }
+
+    /// Send a request to the given address, through the socket of its address family,
+    /// and return the transaction_id
+    ///
+    /// Returns an error if the next transaction_id is still inflight,
+    /// meaning all `65536` transaction ids are in use, if sending
+    /// this request would exceed the configured requests per second,
+    /// or if the address is Ipv6 and there is no Ipv6 socket.
+    pub fn request(
+        &mut self,
+        address: SocketAddr,
+        request: RequestSpecific,
+    ) -> Result<u16, SocketError> {
+        self.request_via(None, address, request)
     }
 
-    /// Send a request to the given address and return the transaction_id
-    pub fn request(&mut self, address: SocketAddrV4, request: RequestSpecific) -> u16 {
+    /// Same as [Self::request], but sent from the [ephemeral socket](Self::open_ephemeral)
+    /// bound to the `via` local port, if any, and only accepting its response on that socket.
+    ///
+    /// Ephemeral sockets are Ipv4, so Ipv6 addresses can only be sent to with no `via`,
+    /// see [Self::can_send_to].
+    pub fn request_via(
+        &mut self,
+        via: Option<u16>,
+        address: SocketAddr,
+        request: RequestSpecific,
+    ) -> Result<u16, SocketError> {
+        if self.shut_down {
+            return Err(SocketError::ShutDown);
+        }
+
+        if self.is_blacklisted(&address) {
+            return Err(SocketError::Blacklisted);
+        }
+
+        if !self.can_send_to(&address, via) {
+            return Err(SocketError::NoIpv6Socket);
+        }
+
+        let coalesce = self.coalescing && request.request_type != RequestTypeSpecific::Ping;
+
+        if coalesce {
+            if let Some(inflight) = self.inflight_requests.iter().find(|inflight| {
+                inflight.to == address
+                    && inflight.via == via
+                    && inflight.request.as_ref() == Some(&request)
+            }) {
+                trace!(?address, tid = inflight.tid, "Coalesced identical request");
+                self.metrics.requests_coalesced += 1;
+
+                return Ok(inflight.tid);
+            }
+        }
+
+        if let Some(max) = self.max_inflight_per_node {
+            if self.inflight_to(address) >= max.get() {
+                trace!(
+                    ?address,
+                    "Deferred request to a node with too many inflight requests"
+                );
+
+                return Err(SocketError::NodeBusy);
+            }
+        }
+
+        if let Some(rate_limiter) = &mut self.rate_limiter {
+            if !rate_limiter.try_acquire(self.clock.now()) {
+                trace!(?address, "Rate limited outgoing request");
+
+                return Err(SocketError::RateLimited);
+            }
+        }
+
+        if self.inflight(&self.next_tid) {
+            debug!(
+                inflight_requests = self.inflight_requests.len(),
+                "All transaction ids are inflight"
+            );
+
+            return Err(SocketError::TransactionIdsExhausted);
+        }
+
+        let kept = coalesce.then(|| request.clone());
         let message = self.request_message(request);
         trace!(context = "socket_message_sending", message = ?message);
 
+        if let Ok(bytes) = message.to_bytes() {
+            if bytes.len() > self.max_datagram_size {
+                debug!(
+                    ?address,
+                    size = bytes.len(),
+                    max = self.max_datagram_size,
+                    "Refusing to send an oversized request"
+                );
+
+                return Err(SocketError::Oversized);
+            }
+        }
+
+        // A reused transaction_id no longer identifies the old request's duplicates.
+        self.completed_requests.pop(&message.transaction_id);
+
         self.inflight_requests.push(InflightRequest {
             tid: message.transaction_id,
             to: address,
-            sent_at: Instant::now(),
+            sent_at: self.clock.now(),
+            via,
+            request: kept,
         });
 
         let tid = message.transaction_id;
-        let _ = self.send(address, message).map_err(|e| {
+        let result = match via {
+            Some(port) => self.send_via(port, address, message),
+            None => self.send(address, message),
+        };
+        let _ = result.map_err(|e| {
             debug!(?e, "Error sending request message");
         });
+        self.metrics.requests_sent += 1;
 
-        tid
+        Ok(tid)
     }
 
-    /// Send a response to the given address.
+    /// Send a response to the given address, through the socket of its address family.
     pub fn response(
         &mut self,
-        address: SocketAddrV4,
+        address: SocketAddr,
         transaction_id: u16,
         response: ResponseSpecific,
     ) {
-        let message =
+        let mut message =
             self.response_message(MessageType::Response(response), address, transaction_id);
+        self.fit_datagram(&mut message);
         trace!(context = "socket_message_sending", message = ?message);
         let _ = self.send(address, message).map_err(|e| {
             debug!(?e, "Error sending response message");
         });
     }
 
-    /// Send an error to the given address.
-    pub fn error(&mut self, address: SocketAddrV4, transaction_id: u16, error: ErrorSpecific) {
+    /// Send an error to the given address, through the socket of its address family.
+    pub fn error(&mut self, address: SocketAddr, transaction_id: u16, error: ErrorSpecific) {
         let message = self.response_message(MessageType::Error(error), address, transaction_id);
         let _ = self.send(address, message).map_err(|e| {
             debug!(?e, "Error sending error message");
         });
     }
 
-    /// Receives a single krpc message on the socket.
+    /// Bind the Ipv4 socket to a new address, for example after moving to another
+    /// network interface, keeping this socket's settings, blacklist and metrics.
+    ///
+    /// Ephemeral sockets were bound to the old ip, so they are closed along with the
+    /// requests sent from them. Other inflight requests time out as usual, since their
+    /// responses can't reach the new socket, and the receive buffer size is left to
+    /// the OS default.
+    pub fn rebind(&mut self, address: SocketAddrV4) -> Result<(), std::io::Error> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_read_timeout(Some(READ_TIMEOUT))?;
+
+        self.local_addr = match socket.local_addr()? {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!("bound to an Ipv4 address"),
+        };
+        self.socket = socket;
+
+        for (port, _) in std::mem::take(&mut self.ephemeral) {
+            self.inflight_requests
+                .retain(|request| request.via != Some(port));
+        }
+        // Reports of the old address don't apply to the new one.
+        self.external_address_votes.clear();
+
+        debug!(local_addr = ?self.local_addr, "Rebound socket");
+
+        Ok(())
+    }
+
+    /// Stop sending requests, and wait up to `timeout` for responses to
+    /// the inflight requests, returning them (including errors).
+    ///
+    /// Afterwards no requests are inflight, so queries ticked against this socket
+    /// finish instead of waiting for them, new requests fail with [SocketError::ShutDown],
+    /// and nothing is sent or received. The UDP socket is closed once this is dropped.
+    pub fn shutdown(&mut self, timeout: Duration) -> Vec<(Message, SocketAddr)> {
+        debug!(
+            inflight_requests = self.inflight_requests.len(),
+            ?timeout,
+            "Shutting down socket"
+        );
+
+        self.shut_down = true;
+
+        let started_at = self.clock.now();
+        let mut responses = Vec::new();
+
+        while !self.inflight_requests.is_empty()
+            && self.clock.now().saturating_duration_since(started_at) < timeout
+        {
+            if let Some((message, from)) = self.recv_from() {
+                if !matches!(message.message_type, MessageType::Request(_)) {
+                    responses.push((message, from));
+                }
+            }
+        }
+
+        self.metrics.timeouts += self.inflight_requests.len() as u64;
+        self.inflight_requests.clear();
+
+        responses
+    }
+
+    /// Receives a single krpc message on the socket, or the Ipv6 socket if any.
     /// On success, returns the dht message and the origin.
-    pub fn recv_from(&mut self) -> Option<(Message, SocketAddrV4)> {
+    pub fn recv_from(&mut self) -> Option<(Message, SocketAddr)> {
+        // Only receive responses to inflight requests while shutting down.
+        if self.shut_down && self.inflight_requests.is_empty() {
+            return None;
+        }
+
         let mut buf = [0u8; MTU];
+        self.last_rtt = None;
 
         // Cleanup timed-out transaction_ids.
         // Find the first timedout request, and delete all earlier requests.
+        let timeout = self.current_timeout();
+        let now = self.clock.now();
         match self.inflight_requests.binary_search_by(|request| {
-            if request.sent_at.elapsed() > self.request_timeout {
+            if now.saturating_duration_since(request.sent_at) > timeout {
                 Ordering::Less
             } else {
                 Ordering::Greater
             }
         }) {
-            Ok(index) => {
-                self.inflight_requests.drain(..index);
-            }
-            Err(index) => {
+            Ok(index) | Err(index) => {
                 self.inflight_requests.drain(..index);
+                self.metrics.timeouts += index as u64;
             }
         };
 
-        if let Ok((amt, SocketAddr::V4(from))) = self.socket.recv_from(&mut buf) {
+        if let Some((amt, from, via)) = self.receive(&mut buf) {
             let bytes = &buf[..amt];
 
+            if self.is_blacklisted(&from) {
+                trace!(
+                    context = "socket_validation",
+                    ?from,
+                    "Message from blacklisted ip"
+                );
+                return None;
+            }
+
             if from.port() == 0 {
                 trace!(
                     context = "socket_validation",
@@ -175,7 +702,13 @@ impl KrpcSocket {
                 return None;
             }
 
-            match Message::from_bytes(bytes) {
+            let message = if self.strict {
+                Message::from_bytes(bytes)
+            } else {
+                Message::from_bytes_lenient(bytes)
+            };
+
+            match message {
                 Ok(message) => {
                     // Parsed correctly.
                     let should_return = match message.message_type {
@@ -187,7 +720,8 @@ impl KrpcSocket {
                                 "Received request message"
                             );
 
-                            true
+                            // Ephemeral sockets only expect responses.
+                            via.is_none()
                         }
                         MessageType::Response(_) => {
                             trace!(
@@ -197,7 +731,18 @@ impl KrpcSocket {
                                 "Received response message"
                             );
 
-                            self.is_expected_response(&message, &from)
+                            let expected = self.is_expected_response(&message, &from, via)
+                                && self.has_consistent_id(&message, from);
+
+                            if expected {
+                                self.metrics.responses_received += 1;
+
+                                if let Some(address) = message.requester_ip {
+                                    self.add_external_address_vote(address);
+                                }
+                            }
+
+                            expected
                         }
                         MessageType::Error(_) => {
                             trace!(
@@ -207,7 +752,13 @@ impl KrpcSocket {
                                 "Received error message"
                             );
 
-                            self.is_expected_response(&message, &from)
+                            let expected = self.is_expected_response(&message, &from, via);
+
+                            if expected {
+                                self.metrics.errors_received += 1;
+                            }
+
+                            expected
                         }
                     };
 
@@ -217,6 +768,9 @@ impl KrpcSocket {
                 }
                 Err(error) => {
                     trace!(context = "socket_error", ?error, ?from, message = ?String::from_utf8_lossy(bytes), "Received invalid Bencode message.");
+
+                    self.metrics.dropped_malformed += 1;
+                    self.record_protocol_error(from.ip());
                 }
             };
         };
@@ -226,22 +780,105 @@ impl KrpcSocket {
 
     // === Private Methods ===
 
-    fn is_expected_response(&mut self, message: &Message, from: &SocketAddrV4) -> bool {
+    /// Count a protocol error from this ip, and blacklist it
+    /// if it exceeds the configured maximum.
+    fn record_protocol_error(&mut self, ip: IpAddr) {
+        if let Some((counts, max)) = &mut self.protocol_errors {
+            let count = counts.get_or_insert_mut(ip, || 0);
+            *count += 1;
+
+            if *count >= max.get() {
+                counts.pop(&ip);
+                self.blacklist(ip);
+            }
+        }
+    }
+
+    /// Returns false if the responder at this address claimed a different Id
+    /// than the one it first responded with, counting it as a protocol error.
+    ///
+    /// The first seen Id is kept until [NODE_ID_TTL] passed.
+    fn has_consistent_id(&mut self, message: &Message, from: SocketAddr) -> bool {
+        let Some(id) = message.get_author_id() else {
+            return true;
+        };
+        let now = self.clock.now();
+
+        match self.node_ids.get(&from) {
+            Some((first_seen, seen_at))
+                if *first_seen != id && now.saturating_duration_since(*seen_at) < NODE_ID_TTL =>
+            {
+                debug!(?from, ?first_seen, ?id, "Responder changed its node Id");
+
+                self.metrics.id_mismatches += 1;
+                self.record_protocol_error(from.ip());
+
+                false
+            }
+            Some((first_seen, _)) if *first_seen == id => true,
+            _ => {
+                self.node_ids.put(from, (id, now));
+
+                true
+            }
+        }
+    }
+
+    /// Receive a packet from any of the ephemeral sockets, the Ipv6 socket, or the shared one,
+    /// along with the local port of the ephemeral socket it was received on.
+    fn receive(&mut self, buf: &mut [u8]) -> Option<(usize, SocketAddr, Option<u16>)> {
+        for (port, socket) in &self.ephemeral {
+            if let Ok((amt, from @ SocketAddr::V4(_))) = socket.recv_from(buf) {
+                return Some((amt, from, Some(*port)));
+            }
+        }
+
+        if let Some(socket) = &self.socket6 {
+            if let Ok((amt, from @ SocketAddr::V6(_))) = socket.recv_from(buf) {
+                return Some((amt, from, None));
+            }
+        }
+
+        match self.socket.recv_from(buf) {
+            Ok((amt, from @ SocketAddr::V4(_))) => Some((amt, from, None)),
+            _ => None,
+        }
+    }
+
+    fn is_expected_response(
+        &mut self,
+        message: &Message,
+        from: &SocketAddr,
+        via: Option<u16>,
+    ) -> bool {
         // Positive or an error response or to an inflight request.
-        match self
-            .inflight_requests
-            .binary_search_by(|request| request.tid.cmp(&message.transaction_id))
-        {
+        match self.find_inflight(message.transaction_id) {
             Ok(index) => {
                 let inflight_request = self
                     .inflight_requests
                     .get(index)
                     .expect("should be infallible");
 
-                if compare_socket_addr(&inflight_request.to, from) {
+                if inflight_request.via != via {
+                    trace!(
+                        context = "socket_validation",
+                        message = "Response on the wrong socket"
+                    );
+                } else if compare_socket_addr(&inflight_request.to, from) {
                     // Confirm that it is a response we actually sent.
+                    let rtt = self
+                        .clock
+                        .now()
+                        .saturating_duration_since(inflight_request.sent_at);
+                    self.last_rtt = Some(rtt);
+                    self.rtt_estimator.update(rtt);
                     self.inflight_requests.remove(index);
 
+                    if !self.duplicate_response_window.is_zero() {
+                        self.completed_requests
+                            .put(message.transaction_id, (*from, self.clock.now()));
+                    }
+
                     return true;
                 } else {
                     trace!(
@@ -251,16 +888,76 @@ impl KrpcSocket {
                 }
             }
             Err(_) => {
-                trace!(
-                    context = "socket_validation",
-                    message = "Unexpected response id"
-                );
+                if self.is_duplicate_response(message.transaction_id, from) {
+                    trace!(
+                        context = "socket_validation",
+                        message = "Duplicate response"
+                    );
+                    self.metrics.duplicate_responses += 1;
+                } else {
+                    trace!(
+                        context = "socket_validation",
+                        message = "Unexpected response id"
+                    );
+                    self.metrics.unexpected_responses += 1;
+                }
             }
         }
 
         false
     }
 
+    /// Returns `true` if a request with this transaction_id to `from` was answered
+    /// within the [Config::duplicate_response_window].
+    fn is_duplicate_response(&mut self, tid: u16, from: &SocketAddr) -> bool {
+        let now = self.clock.now();
+
+        self.completed_requests
+            .get(&tid)
+            .is_some_and(|(address, completed_at)| {
+                compare_socket_addr(address, from)
+                    && now.saturating_duration_since(*completed_at)
+                        <= self.duplicate_response_window
+            })
+    }
+
+    fn add_external_address_vote(&mut self, address: SocketAddrV4) {
+        if self.external_address_votes.len() == EXTERNAL_ADDRESS_VOTES {
+            self.external_address_votes.pop_front();
+        }
+
+        self.external_address_votes.push_back(address);
+    }
+
+    /// Number of requests to this address that are inflight and not timed out yet.
+    fn inflight_to(&self, address: SocketAddr) -> usize {
+        let timeout = self.current_timeout();
+        let now = self.clock.now();
+
+        self.inflight_requests
+            .iter()
+            .filter(|request| {
+                request.to == address && now.saturating_duration_since(request.sent_at) <= timeout
+            })
+            .count()
+    }
+
+    /// Binary search for an inflight request by its transaction_id.
+    ///
+    /// Inflight requests are ordered by sent_at, and transaction ids wrap around
+    /// after `u16::MAX`, so we compare ids by their offset from the oldest request.
+    fn find_inflight(&self, transaction_id: u16) -> Result<usize, usize> {
+        let oldest = match self.inflight_requests.first() {
+            Some(request) => request.tid,
+            None => return Err(0),
+        };
+
+        self.inflight_requests
+            .binary_search_by_key(&transaction_id.wrapping_sub(oldest), |request| {
+                request.tid.wrapping_sub(oldest)
+            })
+    }
+
     /// Increments self.next_tid and returns the previous value.
     fn tid(&mut self) -> u16 {
         // We don't bother much with reusing freed transaction ids,
@@ -285,30 +982,222 @@ impl KrpcSocket {
     }
 
     /// Same as request_message but with request transaction_id and the requester_ip.
+    ///
+    /// The `ip` field is only sent to Ipv4 requesters, since it is a compact Ipv4 address.
     fn response_message(
         &mut self,
         message: MessageType,
-        requester_ip: SocketAddrV4,
+        requester: SocketAddr,
         request_tid: u16,
     ) -> Message {
+        let requester_ip = match requester {
+            SocketAddr::V4(address) => Some(address),
+            SocketAddr::V6(_) => None,
+        };
+
         Message {
             transaction_id: request_tid,
             message_type: message,
             version: Some(VERSION),
             read_only: !self.server_mode,
             // BEP_0042 Only relevant in responses.
-            requester_ip: Some(requester_ip),
+            requester_ip,
+        }
+    }
+
+    /// Trim a response until it fits in [Config::max_datagram_size], see [ResponseSpecific::trim].
+    ///
+    /// A response that can't be trimmed enough, like a large immutable value,
+    /// is left as is.
+    fn fit_datagram(&self, message: &mut Message) {
+        while let Ok(bytes) = message.to_bytes() {
+            let excess = bytes.len().saturating_sub(self.max_datagram_size);
+            if excess == 0 {
+                return;
+            }
+
+            let MessageType::Response(response) = &mut message.message_type else {
+                return;
+            };
+
+            if !response.trim(excess) {
+                debug!(
+                    size = bytes.len(),
+                    max = self.max_datagram_size,
+                    "Sending an oversized response that can't be trimmed"
+                );
+
+                return;
+            }
+
+            trace!(excess, "Trimmed an oversized response");
         }
     }
 
-    /// Send a raw dht message
-    fn send(&mut self, address: SocketAddrV4, message: Message) -> Result<(), SendMessageError> {
-        self.socket.send_to(&message.to_bytes()?, address)?;
+    /// Send a raw dht message through the ephemeral socket bound to this local port.
+    fn send_via(
+        &mut self,
+        port: u16,
+        address: SocketAddr,
+        message: Message,
+    ) -> Result<(), SendMessageError> {
+        let socket = self
+            .ephemeral
+            .iter()
+            .find(|(local_port, _)| *local_port == port)
+            .map(|(_, socket)| socket)
+            .ok_or(SendMessageError::NoEphemeralSocket(port))?;
+
+        socket.send_to(&message.to_bytes()?, address)?;
+        trace!(context = "socket_message_sending", via = port, message = ?message);
+        Ok(())
+    }
+
+    /// Send a raw dht message through the socket matching the address's family.
+    fn send(&mut self, address: SocketAddr, message: Message) -> Result<(), SendMessageError> {
+        if self.shut_down {
+            return Err(SendMessageError::ShutDown);
+        }
+
+        let socket = match address {
+            SocketAddr::V4(_) => &self.socket,
+            SocketAddr::V6(address) => self
+                .socket6
+                .as_ref()
+                .ok_or(SendMessageError::NoIpv6Socket(address))?,
+        };
+
+        socket.send_to(&message.to_bytes()?, address)?;
         trace!(context = "socket_message_sending", message = ?message);
         Ok(())
     }
 }
 
+/// Counters of messages sent and received by a [KrpcSocket] since it was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketMetrics {
+    /// Requests sent.
+    pub requests_sent: u64,
+    /// Responses received to our inflight requests.
+    pub responses_received: u64,
+    /// Inflight requests abandoned after the request timeout.
+    pub timeouts: u64,
+    /// Error messages received to our inflight requests.
+    pub errors_received: u64,
+    /// Received packets dropped because they are not valid krpc messages.
+    pub dropped_malformed: u64,
+    /// Responses dropped because their address responded with another node Id before.
+    pub id_mismatches: u64,
+    /// Responses and errors dropped because they repeat one already received,
+    /// see [Config::duplicate_response_window].
+    pub duplicate_responses: u64,
+    /// Responses and errors dropped because their transaction_id matches no inflight request.
+    pub unexpected_responses: u64,
+    /// Requests not sent because an identical one was still inflight,
+    /// see [Config::coalesce_requests].
+    pub requests_coalesced: u64,
+}
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+/// Errors from sending requests through the socket.
+pub enum SocketError {
+    /// All transaction ids are used by inflight requests.
+    #[error("All transaction ids are used by inflight requests")]
+    TransactionIdsExhausted,
+
+    /// Sending more requests would exceed the configured requests per second.
+    #[error("Exceeded the maximum requests per second")]
+    RateLimited,
+
+    /// The address's ip is blacklisted.
+    #[error("Address is blacklisted")]
+    Blacklisted,
+
+    /// The address already has the
+    /// [maximum](crate::DhtBuilder::max_inflight_per_node) inflight requests.
+    #[error("Exceeded the maximum inflight requests to this address")]
+    NodeBusy,
+
+    /// The request exceeds the [maximum datagram size](crate::DhtBuilder::max_datagram_size).
+    #[error("Request exceeds the maximum datagram size")]
+    Oversized,
+
+    /// The socket was [shut down](crate::Dht::shutdown).
+    #[error("Socket is shut down")]
+    ShutDown,
+
+    /// The address is Ipv6, and can't be sent to without an Ipv6 socket,
+    /// see [DhtBuilder::bind_address_v6](crate::DhtBuilder::bind_address_v6).
+    #[error("No Ipv6 socket to send to this address from")]
+    NoIpv6Socket,
+}
+
+/// Token bucket limiting the rate of outgoing requests.
+#[derive(Debug)]
+struct RateLimiter {
+    /// Tokens added per second, which is also the maximum burst.
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    fn new(max_requests_per_sec: NonZeroU32) -> Self {
+        let rate = max_requests_per_sec.get() as f64;
+
+        Self {
+            rate,
+            tokens: rate,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Refill tokens based on the time elapsed since the last refill,
+    /// then take one token if available.
+    fn try_acquire(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Smoothed round trip time and its variation, as in
+/// [RFC 6298](https://www.rfc-editor.org/rfc/rfc6298).
+#[derive(Debug, Default)]
+struct RttEstimator {
+    /// Smoothed round trip time and round trip time variation, once a sample is taken.
+    estimate: Option<(Duration, Duration)>,
+}
+
+impl RttEstimator {
+    fn update(&mut self, rtt: Duration) {
+        self.estimate = Some(match self.estimate {
+            None => (rtt, rtt / 2),
+            Some((srtt, rttvar)) => {
+                let delta = srtt.abs_diff(rtt);
+
+                // alpha = 1/8 and beta = 1/4
+                (srtt * 7 / 8 + rtt / 8, rttvar * 3 / 4 + delta / 4)
+            }
+        });
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.estimate.map(|(srtt, rttvar)| srtt + rttvar * 4)
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 /// Mainline crate error enum.
 pub enum SendMessageError {
@@ -319,10 +1208,83 @@ pub enum SendMessageError {
     #[error(transparent)]
     /// Transparent [std::io::Error]
     IO(#[from] std::io::Error),
+
+    /// Sending to an Ipv6 address without binding an Ipv6 socket.
+    #[error("No Ipv6 socket is bound to send to {0}")]
+    NoIpv6Socket(SocketAddrV6),
+
+    /// Sending from an ephemeral socket that was closed, or never opened.
+    #[error("No ephemeral socket is bound to port {0}")]
+    NoEphemeralSocket(u16),
+
+    /// Sending after the socket was [shut down](KrpcSocket::shutdown).
+    #[error("Socket is shut down")]
+    ShutDown,
 }
 
-// Same as SocketAddr::eq but ignores the ip if it is unspecified for testing reasons.
-fn compare_socket_addr(a: &SocketAddrV4, b: &SocketAddrV4) -> bool {
+#[cfg(unix)]
+fn set_recv_buffer_size(socket: &UdpSocket, bytes: usize) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let value = libc::c_int::try_from(bytes).unwrap_or(libc::c_int::MAX);
+
+    // SAFETY: the file descriptor is owned by `socket` for the duration of the call,
+    // and `value` is a c_int of the size passed, as SO_RCVBUF expects.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUF,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(unix)]
+fn recv_buffer_size(socket: &UdpSocket) -> std::io::Result<usize> {
+    use std::os::fd::AsRawFd;
+
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    // SAFETY: the file descriptor is owned by `socket` for the duration of the call,
+    // and `value` and `len` are valid for writes of the sizes passed.
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUF,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if result == 0 {
+        Ok(value.max(0) as usize)
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn set_recv_buffer_size(_socket: &UdpSocket, _bytes: usize) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(unix))]
+fn recv_buffer_size(_socket: &UdpSocket) -> std::io::Result<usize> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+// Same as SocketAddr::eq but ignores the ip if it is unspecified for testing reasons.
+fn compare_socket_addr(a: &SocketAddr, b: &SocketAddr) -> bool {
     if a.port() != b.port() {
         return false;
     }
@@ -338,7 +1300,10 @@ fn compare_socket_addr(a: &SocketAddrV4, b: &SocketAddrV4) -> bool {
 mod test {
     use std::thread;
 
-    use crate::common::{Id, PingResponseArguments, RequestTypeSpecific};
+    use crate::common::{
+        FindNodeRequestArguments, FindNodeResponseArguments, Id, MockClock, Node,
+        PingResponseArguments,
+    };
 
     use super::*;
 
@@ -359,15 +1324,16 @@ mod test {
     #[test]
     fn recv_request() {
         let mut server = KrpcSocket::server().unwrap();
-        let server_address = server.local_addr();
+        let server_address: SocketAddr = server.local_addr().into();
 
         let mut client = KrpcSocket::client().unwrap();
         client.next_tid = 120;
 
-        let client_address = client.local_addr();
+        let client_address: SocketAddr = client.local_addr().into();
         let request = RequestSpecific {
             requester_id: Id::random(),
             request_type: RequestTypeSpecific::Ping,
+            want: Vec::new(),
         };
 
         let expected_request = request.clone();
@@ -383,24 +1349,159 @@ mod test {
             }
         });
 
-        client.request(server_address, request);
+        client.request(server_address, request).unwrap();
 
         server_thread.join().unwrap();
     }
 
+    #[test]
+    fn coalescing() {
+        let mut server = KrpcSocket::server().unwrap();
+        let server_address: SocketAddr = server.local_addr().into();
+        let mut client = KrpcSocket::client().unwrap().with_coalescing(true);
+
+        let request = RequestSpecific {
+            requester_id: Id::random(),
+            request_type: RequestTypeSpecific::FindNode(FindNodeRequestArguments {
+                target: Id::random(),
+            }),
+            want: Vec::new(),
+        };
+
+        let first = client.request(server_address, request.clone()).unwrap();
+        let second = client.request(server_address, request.clone()).unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(client.metrics().requests_sent, 1);
+        assert_eq!(client.metrics().requests_coalesced, 1);
+
+        // Pings always get their own packet.
+        let ping = RequestSpecific::ping(Id::random());
+        assert_ne!(
+            client.request(server_address, ping.clone()).unwrap(),
+            client.request(server_address, ping).unwrap()
+        );
+
+        // A single packet was received for both find_node requests.
+        let mut find_nodes = 0;
+        while let Some((message, _)) = server.recv_from() {
+            if let MessageType::Request(RequestSpecific {
+                request_type: RequestTypeSpecific::FindNode(_),
+                ..
+            }) = message.message_type
+            {
+                assert_eq!(message.transaction_id, first);
+                find_nodes += 1;
+            }
+        }
+        assert_eq!(find_nodes, 1);
+
+        // Not coalesced once the first one is answered.
+        server.response(
+            client.local_addr().into(),
+            first,
+            ResponseSpecific::FindNode(FindNodeResponseArguments {
+                responder_id: Id::random(),
+                nodes: [].into(),
+            }),
+        );
+        while client.recv_from().is_none() {}
+        assert_ne!(client.request(server_address, request).unwrap(), first);
+    }
+
+    #[test]
+    fn read_only() {
+        let mut server = KrpcSocket::server().unwrap();
+        let server_address: SocketAddr = server.local_addr().into();
+
+        let mut client = KrpcSocket::new(&Config {
+            server_mode: true,
+            read_only: true,
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(!client.server_mode);
+
+        client
+            .request(server_address, RequestSpecific::ping(Id::random()))
+            .unwrap();
+
+        let message = loop {
+            if let Some((message, _)) = server.recv_from() {
+                break message;
+            }
+        };
+        assert!(message.read_only);
+    }
+
+    #[test]
+    fn max_datagram_size() {
+        let mut server = KrpcSocket::server().unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver_address = receiver.local_addr().unwrap();
+
+        // Way more nodes than a response from a large routing table would carry.
+        let nodes = (0..100).map(Node::unique).collect::<Box<[_]>>();
+        server.response(
+            receiver_address,
+            1,
+            ResponseSpecific::FindNode(FindNodeResponseArguments {
+                responder_id: Id::random(),
+                nodes: nodes.clone(),
+            }),
+        );
+
+        let mut buf = [0; 4096];
+        let (len, _) = receiver.recv_from(&mut buf).unwrap();
+        assert!(len <= DEFAULT_MAX_DATAGRAM_SIZE, "{len}");
+
+        let MessageType::Response(ResponseSpecific::FindNode(response)) =
+            Message::from_bytes(&buf[..len]).unwrap().message_type
+        else {
+            panic!("expected a find_node response");
+        };
+        // Trimmed from the end, keeping as many nodes as fit.
+        assert!(response.nodes.len() > 40, "{}", response.nodes.len());
+        assert!(response
+            .nodes
+            .iter()
+            .zip(nodes.iter())
+            .all(|(received, sent)| received.id() == sent.id()));
+
+        // Requests are not trimmed, but refused.
+        let mut client = KrpcSocket::new(&Config {
+            max_datagram_size: 50,
+            ..Default::default()
+        })
+        .unwrap();
+        let request = RequestSpecific {
+            requester_id: Id::random(),
+            request_type: RequestTypeSpecific::FindNode(FindNodeRequestArguments {
+                target: Id::random(),
+            }),
+            want: Vec::new(),
+        };
+
+        assert_eq!(
+            client.request(receiver_address, request),
+            Err(SocketError::Oversized)
+        );
+        assert_eq!(client.metrics().requests_sent, 0);
+    }
+
     #[test]
     fn recv_response() {
         let (tx, rx) = flume::bounded(1);
 
         let mut client = KrpcSocket::client().unwrap();
-        let client_address = client.local_addr();
+        let client_address: SocketAddr = client.local_addr().into();
 
         let responder_id = Id::random();
         let response = ResponseSpecific::Ping(PingResponseArguments { responder_id });
 
         let server_thread = thread::spawn(move || {
             let mut server = KrpcSocket::client().unwrap();
-            let server_address = server.local_addr();
+            let server_address: SocketAddr = server.local_addr().into();
             tx.send(server_address).unwrap();
 
             loop {
@@ -408,6 +1509,8 @@ mod test {
                     tid: 8,
                     to: client_address,
                     sent_at: Instant::now(),
+                    via: None,
+                    request: None,
                 });
 
                 if let Some((message, from)) = server.recv_from() {
@@ -436,16 +1539,18 @@ mod test {
     #[test]
     fn ignore_response_from_wrong_address() {
         let mut server = KrpcSocket::client().unwrap();
-        let server_address = server.local_addr();
+        let server_address: SocketAddr = server.local_addr().into();
 
         let mut client = KrpcSocket::client().unwrap();
 
-        let client_address = client.local_addr();
+        let client_address: SocketAddr = client.local_addr().into();
 
         server.inflight_requests.push(InflightRequest {
             tid: 8,
-            to: SocketAddrV4::new([127, 0, 0, 1].into(), client_address.port() + 1),
+            to: SocketAddr::from(([127, 0, 0, 1], client_address.port() + 1)),
             sent_at: Instant::now(),
+            via: None,
+            request: None,
         });
 
         let response = ResponseSpecific::Ping(PingResponseArguments {
@@ -466,4 +1571,678 @@ mod test {
 
         server_thread.join().unwrap();
     }
+
+    #[test]
+    fn ignore_response_from_wrong_ip() {
+        let mut server = KrpcSocket::client().unwrap();
+        let server_address = SocketAddr::from(([127, 0, 0, 1], server.local_addr().port()));
+
+        let mut client = KrpcSocket::client().unwrap();
+        let client_port = client.local_addr().port();
+
+        // Request sent to a different ip, but the same port as the client.
+        server.inflight_requests.push(InflightRequest {
+            tid: 8,
+            to: SocketAddr::from(([127, 0, 0, 2], client_port)),
+            sent_at: Instant::now(),
+            via: None,
+            request: None,
+        });
+
+        client.response(
+            server_address,
+            8,
+            ResponseSpecific::Ping(PingResponseArguments {
+                responder_id: Id::random(),
+            }),
+        );
+
+        for _ in 0..10 {
+            assert!(
+                server.recv_from().is_none(),
+                "Should not receive a response from wrong ip"
+            );
+        }
+
+        assert!(server.inflight(&8));
+    }
+
+    #[test]
+    fn external_address() {
+        let mut socket = KrpcSocket::client().unwrap();
+
+        let liar = SocketAddrV4::new([1, 1, 1, 1].into(), 1);
+        let address = SocketAddrV4::new([2, 2, 2, 2].into(), 6881);
+
+        socket.add_external_address_vote(liar);
+        assert_eq!(socket.external_address(), None);
+
+        for _ in 0..MIN_EXTERNAL_ADDRESS_VOTES {
+            socket.add_external_address_vote(address);
+        }
+        assert_eq!(socket.external_address(), Some(address));
+
+        // Old reports are forgotten, so the majority can change.
+        for _ in 0..EXTERNAL_ADDRESS_VOTES {
+            socket.add_external_address_vote(liar);
+        }
+        assert_eq!(socket.external_address(), Some(liar));
+    }
+
+    #[test]
+    fn reap_timed_out_requests() {
+        let mut socket = KrpcSocket::client()
+            .unwrap()
+            .with_timeout(Duration::from_millis(100));
+        let address = SocketAddr::from(([127, 0, 0, 1], 1));
+
+        socket.inflight_requests.push(InflightRequest {
+            tid: 0,
+            to: address,
+            sent_at: Instant::now() - Duration::from_millis(200),
+            via: None,
+            request: None,
+        });
+        socket.inflight_requests.push(InflightRequest {
+            tid: 1,
+            to: address,
+            sent_at: Instant::now(),
+            via: None,
+            request: None,
+        });
+
+        socket.recv_from();
+
+        assert!(!socket.inflight(&0));
+        assert!(socket.inflight(&1));
+        assert_eq!(socket.metrics().timeouts, 1);
+    }
+
+    #[test]
+    fn adaptive_timeout() {
+        let mut socket = KrpcSocket::client().unwrap();
+        assert_eq!(socket.current_timeout(), DEFAULT_REQUEST_TIMEOUT);
+
+        for _ in 0..20 {
+            socket.rtt_estimator.update(Duration::from_millis(100));
+        }
+        let timeout = socket.current_timeout();
+        assert!(
+            timeout < DEFAULT_REQUEST_TIMEOUT / 2,
+            "timeout: {timeout:?}"
+        );
+        assert!(timeout >= MIN_REQUEST_TIMEOUT);
+
+        // Fast responses don't go below the lower bound
+        for _ in 0..20 {
+            socket.rtt_estimator.update(Duration::from_millis(1));
+        }
+        assert_eq!(socket.current_timeout(), MIN_REQUEST_TIMEOUT);
+
+        // and slow ones don't exceed the configured timeout.
+        for _ in 0..20 {
+            socket.rtt_estimator.update(Duration::from_secs(5));
+        }
+        assert_eq!(socket.current_timeout(), DEFAULT_REQUEST_TIMEOUT);
+    }
+
+    #[test]
+    fn reap_timed_out_requests_by_clock() {
+        let clock = MockClock::new();
+        let mut socket = KrpcSocket::client()
+            .unwrap()
+            .with_clock(Arc::new(clock.clone()));
+        let address = SocketAddr::from(([127, 0, 0, 1], 1));
+
+        let tid = socket
+            .request(
+                address,
+                RequestSpecific {
+                    requester_id: Id::random(),
+                    request_type: RequestTypeSpecific::Ping,
+                    want: Vec::new(),
+                },
+            )
+            .unwrap();
+
+        socket.recv_from();
+        assert!(socket.inflight(&tid));
+
+        clock.advance(DEFAULT_REQUEST_TIMEOUT + Duration::from_millis(1));
+        socket.recv_from();
+        assert!(!socket.inflight(&tid));
+    }
+
+    #[test]
+    fn bind() {
+        let config = Config::default();
+
+        let socket = KrpcSocket::bind(
+            &config,
+            "127.0.0.1:0".parse().unwrap(),
+            Some("[::1]:0".parse().unwrap()),
+        )
+        .unwrap();
+        assert_eq!(socket.local_addr().ip(), &Ipv4Addr::LOCALHOST);
+        assert!(socket.local_addr6().is_some());
+
+        assert_eq!(
+            KrpcSocket::bind(&config, "[::1]:0".parse().unwrap(), None)
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn recv_buffer() {
+        let socket = KrpcSocket::client().unwrap();
+        let default = socket.recv_buffer_size().unwrap();
+
+        let requested = 64 * 1024;
+        let socket = socket.with_recv_buffer(requested);
+        let effective = socket.recv_buffer_size().unwrap();
+
+        // Linux doubles the requested size, others honor it as is.
+        assert!(effective >= requested, "{effective} < {requested}");
+        assert!(default > 0);
+    }
+
+    #[test]
+    fn send_by_address_family() {
+        let message = || Message {
+            transaction_id: 0,
+            version: None,
+            requester_ip: None,
+            read_only: false,
+            message_type: MessageType::Response(ResponseSpecific::Ping(PingResponseArguments {
+                responder_id: Id::random(),
+            })),
+        };
+        let receiver = UdpSocket::bind("[::1]:0").unwrap();
+        let address = receiver.local_addr().unwrap();
+
+        let mut socket = KrpcSocket::client().unwrap();
+        assert!(matches!(
+            socket.send(address, message()),
+            Err(SendMessageError::NoIpv6Socket(_))
+        ));
+
+        let mut socket = KrpcSocket::bind(
+            &Config::default(),
+            "127.0.0.1:0".parse().unwrap(),
+            Some("[::1]:0".parse().unwrap()),
+        )
+        .unwrap();
+        socket.send(address, message()).unwrap();
+
+        let mut buf = [0; MTU];
+        let (amt, _) = receiver.recv_from(&mut buf).unwrap();
+        assert!(Message::from_bytes(&buf[..amt]).is_ok());
+    }
+
+    #[test]
+    fn ipv6_round_trip() {
+        let dual = || {
+            KrpcSocket::bind(
+                &Config::default(),
+                "127.0.0.1:0".parse().unwrap(),
+                Some("[::1]:0".parse().unwrap()),
+            )
+            .unwrap()
+        };
+        let mut client = dual();
+        let mut server = dual();
+        let server_address = SocketAddr::from(server.local_addr6().unwrap());
+
+        let tid = client
+            .request(server_address, RequestSpecific::ping(Id::random()))
+            .unwrap();
+
+        let (message, from) = loop {
+            if let Some(received) = server.recv_from() {
+                break received;
+            }
+        };
+        assert!(matches!(message.message_type, MessageType::Request(_)));
+        assert_eq!(from, SocketAddr::from(client.local_addr6().unwrap()));
+
+        let responder_id = Id::random();
+        server.response(
+            from,
+            message.transaction_id,
+            ResponseSpecific::Ping(PingResponseArguments { responder_id }),
+        );
+
+        let (message, from) = loop {
+            if let Some(received) = client.recv_from() {
+                break received;
+            }
+        };
+        assert_eq!(message.transaction_id, tid);
+        assert_eq!(message.requester_ip, None);
+        assert_eq!(from, server_address);
+    }
+
+    #[test]
+    fn tid_wrapping() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let address = SocketAddr::from(([127, 0, 0, 1], 1));
+
+        socket.next_tid = u16::MAX - 1;
+
+        let tids = (0..4)
+            .map(|_| {
+                socket.request(
+                    address,
+                    RequestSpecific {
+                        requester_id: Id::random(),
+                        request_type: RequestTypeSpecific::Ping,
+                        want: Vec::new(),
+                    },
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(tids, vec![u16::MAX - 1, u16::MAX, 0, 1]);
+
+        for tid in tids {
+            assert!(socket.inflight(&tid));
+        }
+        assert!(!socket.inflight(&2));
+        assert_eq!(socket.metrics().requests_sent, 4);
+    }
+
+    #[test]
+    fn rate_limited() {
+        let mut socket = KrpcSocket::new(&Config {
+            max_requests_per_sec: NonZeroU32::new(2),
+            ..Default::default()
+        })
+        .unwrap();
+        let address = SocketAddr::from(([127, 0, 0, 1], 1));
+
+        let request = RequestSpecific {
+            requester_id: Id::random(),
+            request_type: RequestTypeSpecific::Ping,
+            want: Vec::new(),
+        };
+
+        assert!(socket.request(address, request.clone()).is_ok());
+        assert!(socket.request(address, request.clone()).is_ok());
+        assert_eq!(
+            socket.request(address, request.clone()),
+            Err(SocketError::RateLimited)
+        );
+
+        thread::sleep(Duration::from_millis(600));
+
+        assert!(socket.request(address, request).is_ok());
+    }
+
+    #[test]
+    fn strict() {
+        let id = [b'a'; 20];
+        let ping = |extra: &[u8], tid: &[u8], id: &[u8]| {
+            [
+                b"d1:ad2:id".as_slice(),
+                format!("{}:", id.len()).as_bytes(),
+                id,
+                b"e1:q4:ping",
+                tid,
+                extra,
+                b"1:y1:qe",
+            ]
+            .concat()
+        };
+
+        let packets = [
+            // Valid
+            ping(b"", b"1:t2:aa", &id),
+            // Version isn't 4 bytes
+            ping(b"1:v2:ab", b"1:t2:aa", &id),
+            // Ip isn't a compact Ipv4 address
+            ping(b"2:ip3:abc", b"1:t2:aa", &id),
+            // Missing transaction id
+            ping(b"", b"", &id),
+            // Node id isn't 20 bytes
+            ping(b"", b"1:t2:aa", &id[..19]),
+        ];
+
+        for (strict, expected_received) in [(true, 1), (false, 3)] {
+            let mut server = KrpcSocket::server().unwrap().with_strict(strict);
+            let server_address = SocketAddr::from(([127, 0, 0, 1], server.local_addr().port()));
+
+            let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+            for packet in &packets {
+                client.send_to(packet, server_address).unwrap();
+            }
+
+            let mut received = 0;
+            for _ in 0..10 {
+                if let Some((message, _)) = server.recv_from() {
+                    assert_eq!(message.version, None);
+                    assert_eq!(message.requester_ip, None);
+                    assert_eq!(message.get_author_id(), Some(Id::from_bytes(id).unwrap()));
+
+                    received += 1;
+                }
+            }
+
+            assert_eq!(received, expected_received, "strict: {strict}");
+            assert_eq!(
+                server.metrics().dropped_malformed,
+                packets.len() as u64 - expected_received,
+                "strict: {strict}"
+            );
+        }
+    }
+
+    #[test]
+    fn shutdown() {
+        let mut server = KrpcSocket::server().unwrap();
+        let server_address = SocketAddr::from(([127, 0, 0, 1], server.local_addr().port()));
+
+        let mut client = KrpcSocket::client().unwrap();
+        let request = RequestSpecific {
+            requester_id: Id::random(),
+            request_type: RequestTypeSpecific::Ping,
+            want: Vec::new(),
+        };
+
+        // No response.
+        let unresponsive = SocketAddr::from(([127, 0, 0, 1], 1));
+        client.request(unresponsive, request.clone()).unwrap();
+
+        let tid = client.request(server_address, request.clone()).unwrap();
+
+        let server_thread = thread::spawn(move || loop {
+            if let Some((message, from)) = server.recv_from() {
+                server.response(
+                    from,
+                    message.transaction_id,
+                    ResponseSpecific::Ping(PingResponseArguments {
+                        responder_id: Id::random(),
+                    }),
+                );
+                break;
+            }
+        });
+
+        let responses = client.shutdown(Duration::from_millis(500));
+        server_thread.join().unwrap();
+
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].0.transaction_id, tid);
+        assert!(!client.inflight(&tid));
+        assert_eq!(client.metrics().timeouts, 1);
+
+        assert_eq!(
+            client.request(server_address, request),
+            Err(SocketError::ShutDown)
+        );
+        assert!(client.recv_from().is_none());
+    }
+
+    #[test]
+    fn ephemeral_ports() {
+        let mut server = KrpcSocket::server().unwrap();
+        let server_address = SocketAddr::from(([127, 0, 0, 1], server.local_addr().port()));
+
+        let mut client = KrpcSocket::client().unwrap().with_ephemeral_ports(1);
+        let client_address = SocketAddr::from(([127, 0, 0, 1], client.local_addr().port()));
+
+        let port = client.open_ephemeral().unwrap();
+        assert_ne!(port, client_address.port());
+        assert_eq!(client.open_ephemeral(), None);
+
+        let tid = client
+            .request_via(
+                Some(port),
+                server_address,
+                RequestSpecific {
+                    requester_id: Id::random(),
+                    request_type: RequestTypeSpecific::Ping,
+                    want: Vec::new(),
+                },
+            )
+            .unwrap();
+
+        let (message, from) = loop {
+            if let Some(received) = server.recv_from() {
+                break received;
+            }
+        };
+        assert_eq!(from.port(), port);
+
+        let response = ResponseSpecific::Ping(PingResponseArguments {
+            responder_id: Id::random(),
+        });
+
+        // Responses are only accepted on the socket the request was sent from.
+        server.response(client_address, message.transaction_id, response.clone());
+        for _ in 0..10 {
+            assert!(client.recv_from().is_none());
+        }
+        assert!(client.inflight(&tid));
+
+        server.response(from, message.transaction_id, response);
+        let (response, _) = loop {
+            if let Some(received) = client.recv_from() {
+                break received;
+            }
+        };
+        assert_eq!(response.transaction_id, tid);
+
+        client.close_ephemeral(port);
+        assert!(client.open_ephemeral().is_some());
+    }
+
+    #[test]
+    fn duplicate_responses() {
+        let mut server = KrpcSocket::server().unwrap();
+        let server_address = SocketAddr::from(([127, 0, 0, 1], server.local_addr().port()));
+
+        let mut client = KrpcSocket::client().unwrap();
+
+        client
+            .request(server_address, RequestSpecific::ping(Id::random()))
+            .unwrap();
+
+        let (message, from) = loop {
+            if let Some(received) = server.recv_from() {
+                break received;
+            }
+        };
+
+        let response = ResponseSpecific::Ping(PingResponseArguments {
+            responder_id: Id::random(),
+        });
+
+        // Delivered twice, and a response to a request that was never sent.
+        server.response(from, message.transaction_id, response.clone());
+        server.response(from, message.transaction_id, response.clone());
+        server.response(from, message.transaction_id.wrapping_add(100), response);
+
+        let mut received = 0;
+        for _ in 0..20 {
+            if client.recv_from().is_some() {
+                received += 1;
+            }
+        }
+
+        assert_eq!(received, 1);
+
+        let metrics = client.metrics();
+        assert_eq!(metrics.responses_received, 1);
+        assert_eq!(metrics.duplicate_responses, 1);
+        assert_eq!(metrics.unexpected_responses, 1);
+    }
+
+    #[test]
+    fn inconsistent_node_id() {
+        let mut server = KrpcSocket::server().unwrap();
+        let server_address = SocketAddr::from(([127, 0, 0, 1], server.local_addr().port()));
+
+        let mut client = KrpcSocket::new(&Config {
+            max_protocol_errors: NonZeroU32::new(2),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let first_id = Id::random();
+        let other_id = Id::random();
+
+        let mut accepted = Vec::new();
+        for responder_id in [first_id, other_id, first_id, other_id] {
+            let tid = client
+                .request(
+                    server_address,
+                    RequestSpecific {
+                        requester_id: Id::random(),
+                        request_type: RequestTypeSpecific::Ping,
+                        want: Vec::new(),
+                    },
+                )
+                .unwrap();
+
+            let (message, from) = loop {
+                if let Some(received) = server.recv_from() {
+                    break received;
+                }
+            };
+            server.response(
+                from,
+                message.transaction_id,
+                ResponseSpecific::Ping(PingResponseArguments { responder_id }),
+            );
+
+            for _ in 0..10 {
+                if let Some((message, _)) = client.recv_from() {
+                    accepted.push(message.get_author_id().unwrap());
+                    break;
+                }
+            }
+
+            assert!(!client.inflight(&tid));
+        }
+
+        // The first seen Id is preferred.
+        assert_eq!(accepted, vec![first_id, first_id]);
+        assert_eq!(client.metrics().id_mismatches, 2);
+        assert!(client.is_blacklisted(&server_address));
+    }
+
+    #[test]
+    fn blacklist() {
+        let mut server = KrpcSocket::new(&Config {
+            server_mode: true,
+            max_protocol_errors: NonZeroU32::new(2),
+            ..Default::default()
+        })
+        .unwrap();
+        let server_address = SocketAddr::from(([127, 0, 0, 1], server.local_addr().port()));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        // Malformed messages.
+        client.send_to(b"d1:y1:q", server_address).unwrap();
+        client.send_to(b"d1:y1:q", server_address).unwrap();
+
+        for _ in 0..10 {
+            server.recv_from();
+        }
+
+        let client_address = client.local_addr().unwrap();
+
+        assert!(server.is_blacklisted(&client_address));
+        assert_eq!(server.metrics().dropped_malformed, 2);
+        assert_eq!(
+            server.request(
+                client_address,
+                RequestSpecific {
+                    requester_id: Id::random(),
+                    request_type: RequestTypeSpecific::Ping,
+                    want: Vec::new(),
+                },
+            ),
+            Err(SocketError::Blacklisted)
+        );
+    }
+
+    #[test]
+    fn tid_exhaustion() {
+        let mut socket = KrpcSocket::client().unwrap();
+        let address = SocketAddr::from(([127, 0, 0, 1], 1));
+
+        socket.next_tid = 10;
+
+        // Saturate the transaction ids space starting from the next tid.
+        for i in 0..=u16::MAX {
+            socket.inflight_requests.push(InflightRequest {
+                tid: i.wrapping_add(10),
+                to: address,
+                sent_at: Instant::now(),
+                via: None,
+                request: None,
+            });
+        }
+
+        let request = RequestSpecific {
+            requester_id: Id::random(),
+            request_type: RequestTypeSpecific::Ping,
+            want: Vec::new(),
+        };
+
+        assert_eq!(
+            socket.request(address, request.clone()),
+            Err(SocketError::TransactionIdsExhausted)
+        );
+        assert_eq!(socket.next_tid, 10);
+
+        // Free the oldest transaction id.
+        socket.inflight_requests.remove(0);
+
+        assert_eq!(socket.request(address, request), Ok(10));
+        assert_eq!(socket.inflight_requests.len(), u16::MAX as usize + 1);
+    }
+
+    #[test]
+    fn rebind() {
+        let mut server = KrpcSocket::server().unwrap();
+        let server_address = SocketAddr::from(([127, 0, 0, 1], server.local_addr().port()));
+
+        let mut client = KrpcSocket::client().unwrap().with_ephemeral_ports(1);
+        let old_address = client.local_addr();
+        let port = client.open_ephemeral().unwrap();
+        let ping = RequestSpecific {
+            requester_id: Id::random(),
+            request_type: RequestTypeSpecific::Ping,
+            want: Vec::new(),
+        };
+        let via_ephemeral = client
+            .request_via(Some(port), server_address, ping.clone())
+            .unwrap();
+
+        client
+            .rebind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .unwrap();
+        assert_ne!(client.local_addr(), old_address);
+
+        // Ephemeral sockets of the old address are closed, with their requests.
+        assert!(!client.inflight(&via_ephemeral));
+        assert!(client.open_ephemeral().is_some());
+
+        client.request(server_address, ping).unwrap();
+        let (_, from) = loop {
+            if let Some((message, from)) = server.recv_from() {
+                if message.transaction_id != via_ephemeral {
+                    break (message, from);
+                }
+            }
+        };
+        assert_eq!(from, client.local_addr().into());
+    }
 }